@@ -95,7 +95,7 @@ const fn u8s(n: u32) -> [u8; 4] {
 
 #[kernel]
 #[allow(improper_ctypes_definitions, clippy::missing_safety_doc)]
-pub unsafe fn finish_hash(nonces: &[u8], prev: &[u32; 11], hash_vars: &[u32; 8], hashes: *mut u8) {
+pub unsafe fn finish_hash(nonces: &[u8], prev: &[u32; 4], hash_vars: &[u32; 8], hashes: *mut u8) {
     let idx = thread::index_1d() as usize;
     let mut schedule: Schedule = [0 as u32; 64];
     let mut hash = hash_vars.clone();
@@ -103,12 +103,18 @@ pub unsafe fn finish_hash(nonces: &[u8], prev: &[u32; 11], hash_vars: &[u32; 8],
     // Index into the nonce array to get the appropriate nonce
     let t = idx * 32;
 
-    schedule[0..11].copy_from_slice(prev);
-    schedule[11] = u32_at(nonces, t);
-    schedule[12] = u32_at(nonces, t + 4);
-    schedule[13] = u32_at(nonces, t + 8);
-    schedule[14] = u32_at(nonces, t + 12);
-    schedule[15] = u32_at(nonces, t + 16);
+    // The header is 112 bytes, so this chunk is the rest of the header with the whole nonce, followed by the padding
+    schedule[0..4].copy_from_slice(prev);
+    schedule[4] = u32_at(nonces, t);
+    schedule[5] = u32_at(nonces, t + 4);
+    schedule[6] = u32_at(nonces, t + 8);
+    schedule[7] = u32_at(nonces, t + 12);
+    schedule[8] = u32_at(nonces, t + 16);
+    schedule[9] = u32_at(nonces, t + 20);
+    schedule[10] = u32_at(nonces, t + 24);
+    schedule[11] = u32_at(nonces, t + 28);
+    schedule[12] = 0x8000_0000;
+    schedule[15] = 0x0000_0380;
 
     // Initialize hash variables
 
@@ -121,7 +127,7 @@ pub unsafe fn finish_hash(nonces: &[u8], prev: &[u32; 11], hash_vars: &[u32; 8],
     let mut g = hash[6];
     let mut h = hash[7];
 
-    // Perform the first round of hashing
+    // Perform the last round of hashing
 
     let mut w0: u32;
     let mut w9: u32;
@@ -173,64 +179,6 @@ pub unsafe fn finish_hash(nonces: &[u8], prev: &[u32; 11], hash_vars: &[u32; 8],
     hash[6] = hash[6].wrapping_add(g);
     hash[7] = hash[7].wrapping_add(h);
 
-    // Set up the schedule and hash vars for the second round of hashing
-
-    schedule[0] = u32_at(nonces, t + 20);
-    schedule[1] = u32_at(nonces, t + 24);
-    schedule[2] = u32_at(nonces, t + 28);
-    schedule[3] = 0x8000_0000;
-    schedule[4..15].copy_from_slice(&[0; 11]);    
-    schedule[15] = 0x0000_0460;
-
-    a = hash[0];
-    b = hash[1];
-    c = hash[2];
-    d = hash[3];
-    e = hash[4];
-    f = hash[5];
-    g = hash[6];
-    h = hash[7];
-
-    // Perform the second round of hashing
-
-    for j in 0..48 {
-        w0 = schedule[j];
-        w9 = schedule[j + 9];
-        w1 = schedule[j + 1];
-        s0 = w1.rotate_right(7) ^ w1.rotate_right(18) ^ (w1 >> 3);
-        w14 = schedule[j + 14];
-        s1 = w14.rotate_right(17) ^ w14.rotate_right(19) ^ (w14 >> 10);
-
-        schedule[j + 16] = w0.wrapping_add(s0).wrapping_add(w9).wrapping_add(s1);
-    }
-
-    for j in 0..64 {
-        majority = (a & b) ^ (a & c) ^ (b & c);
-        s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
-        choice = (e & f) ^ ((!e) & g);
-        s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
-        temp2 = s0.wrapping_add(majority);
-        temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(K[j]).wrapping_add(schedule[j]);
-
-        h = g;
-        g = f;
-        f = e;
-        e = d.wrapping_add(temp1);
-        d = c;
-        c = b;
-        b = a;
-        a = temp1.wrapping_add(temp2);
-    }
-
-    hash[0] = hash[0].wrapping_add(a);
-    hash[1] = hash[1].wrapping_add(b);
-    hash[2] = hash[2].wrapping_add(c);
-    hash[3] = hash[3].wrapping_add(d);
-    hash[4] = hash[4].wrapping_add(e);
-    hash[5] = hash[5].wrapping_add(f);
-    hash[6] = hash[6].wrapping_add(g);
-    hash[7] = hash[7].wrapping_add(h);
-
     // Get the hash variables out and "return" them
     
     hashes.add(t).copy_from(u8s(hash[0]).as_ptr(), 4);
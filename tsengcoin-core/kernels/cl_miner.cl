@@ -108,13 +108,22 @@ __kernel void finish_hash(
 
     memcpy(hash_vars, hash, 8 * sizeof(uint));
 
+    // The header is 112 bytes, so the second chunk holds the last 16 bytes before the nonce, the whole nonce,
+    // and the padding
     const size_t t = idx * 32;
-    memcpy(prev, schedule, 11 * sizeof(uint));
-    schedule[11] = uint_at(nonces, t);
-    schedule[12] = uint_at(nonces, t + 4);
-    schedule[13] = uint_at(nonces, t + 8);
-    schedule[14] = uint_at(nonces, t + 12);
-    schedule[15] = uint_at(nonces, t + 16);
+    memcpy(prev, schedule, 4 * sizeof(uint));
+    schedule[4] = uint_at(nonces, t);
+    schedule[5] = uint_at(nonces, t + 4);
+    schedule[6] = uint_at(nonces, t + 8);
+    schedule[7] = uint_at(nonces, t + 12);
+    schedule[8] = uint_at(nonces, t + 16);
+    schedule[9] = uint_at(nonces, t + 20);
+    schedule[10] = uint_at(nonces, t + 24);
+    schedule[11] = uint_at(nonces, t + 28);
+    schedule[12] = 0x80000000;
+    schedule[13] = 0;
+    schedule[14] = 0;
+    schedule[15] = 0x00000380;
 
     uint a = hash[0];
     uint b = hash[1];
@@ -176,70 +185,5 @@ __kernel void finish_hash(
     hash[6] += g;
     hash[7] += h;
 
-    // Hash next block
-    schedule[0] = uint_at(nonces, t + 20);
-    schedule[1] = uint_at(nonces, t + 24);
-    schedule[2] = uint_at(nonces, t + 28);
-    schedule[3] = 0x80000000;
-    schedule[4] = 0;
-    schedule[5] = 0;
-    schedule[6] = 0;
-    schedule[7] = 0;
-    schedule[8] = 0;
-    schedule[9] = 0;
-    schedule[10] = 0;
-    schedule[11] = 0;
-    schedule[12] = 0;
-    schedule[13] = 0;
-    schedule[14] = 0;
-    schedule[15] = 0x00000460;
-
-    a = hash[0];
-    b = hash[1];
-    c = hash[2];
-    d = hash[3];
-    e = hash[4];
-    f = hash[5];
-    g = hash[6];
-    h = hash[7];
-
-    for (size_t j = 0; j < 48; j++) {
-        w0 = schedule[j];
-        w9 = schedule[j + 9];
-        w1 = schedule[j + 1];
-        s0 = rotr(w1, 7) ^ rotr(w1, 18) ^ (w1 >> 3);
-        w14 = schedule[j + 14];
-        s1 = rotr(w14, 17) ^ rotr(w14, 19) ^ (w14 >> 10);
-
-        schedule[j + 16] = w0 + s0 + w9 + s1;
-    }
-
-    for (size_t j = 0; j < 64; j++) {
-        majority = (a & b) ^ (a & c) ^ (b & c);
-        s0 = rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22);
-        choice = (e & f) ^ ((~e) & g);
-        s1 = rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25);
-        temp2 = s0 + majority;
-        temp1 = h + s1 + choice + K[j] + schedule[j];
-
-        h = g;
-        g = f;
-        f = e;
-        e = d + temp1;
-        d = c;
-        c = b;
-        b = a;
-        a = temp1 + temp2;
-    }
-
-    hash[0] += a;
-    hash[1] += b;
-    hash[2] += c;
-    hash[3] += d;
-    hash[4] += e;
-    hash[5] += f;
-    hash[6] += g;
-    hash[7] += h;
-
     copy_hash_out(hash, hashes, t);
 }
\ No newline at end of file
@@ -1,4 +1,17 @@
-use std::{cmp::max, collections::HashMap, error::Error};
+use std::{
+    cmp::max,
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A flag that can be passed to any command. [dispatch_command] removes it before the command sees its arguments and
+/// prints how long the command took to run.
+pub const TIME_FLAG: &str = "--time";
+/// Pass this to `help` to print the full command reference as markdown instead
+pub const MARKDOWN_FLAG: &str = "--markdown";
 
 pub struct Command<T> {
     pub processor: CommandProcessor<T>,
@@ -31,16 +44,20 @@ pub struct CommandInvocation {
     /// Expected args are passed differently depending on their `FieldType`
     pub fields: HashMap<String, String>,
 
-    /// Optional arguments, set as variables
+    /// Optional arguments, set as variables. If an optional is given more than once, this has the last value
     pub optionals: HashMap<String, String>,
+
+    /// Every value given for each optional argument, in order
+    pub repeated_optionals: HashMap<String, Vec<String>>,
 }
 
 pub struct Field {
     pub name: String,
     pub field_type: FieldType,
     pub desc: String,
-    /// A condition can specify the name of a flag that can disable this field. If the flag is present, the field will not be required.
-    pub condition: Option<Condition>,
+    /// Conditions can specify the names of flags or optionals that can disable this field. If any of them are present,
+    /// the field will not be required.
+    pub conditions: Vec<Condition>,
 }
 
 #[derive(Clone)]
@@ -74,6 +91,8 @@ pub enum FieldType {
 pub struct Condition {
     pub disable_flag: String,
     pub desc: String,
+    /// An environment variable that also disables the field if it's set
+    pub disable_env: Option<String>,
 }
 
 impl CommandInvocation {
@@ -88,6 +107,11 @@ impl CommandInvocation {
     pub fn get_optional(&self, field_name: &str) -> Option<String> {
         self.optionals.get(field_name).cloned()
     }
+
+    /// Get every value given for an optional argument that can be repeated, in the order they were given
+    pub fn get_repeated_optional(&self, field_name: &str) -> Vec<String> {
+        self.repeated_optionals.get(field_name).cloned().unwrap_or_default()
+    }
 }
 
 impl Field {
@@ -96,7 +120,7 @@ impl Field {
             name: name.to_owned(),
             field_type,
             desc: desc.to_owned(),
-            condition: None,
+            conditions: vec![],
         }
     }
 
@@ -110,7 +134,21 @@ impl Field {
             name: name.to_owned(),
             field_type,
             desc: desc.to_owned(),
-            condition: Some(condition),
+            conditions: vec![condition],
+        }
+    }
+
+    pub fn new_conditions(
+        name: &str,
+        field_type: FieldType,
+        desc: &str,
+        conditions: Vec<Condition>,
+    ) -> Self {
+        Field {
+            name: name.to_owned(),
+            field_type,
+            desc: desc.to_owned(),
+            conditions,
         }
     }
 }
@@ -147,11 +185,42 @@ impl Condition {
         Condition {
             disable_flag: flag.to_owned(),
             desc: desc.to_owned(),
+            disable_env: None,
+        }
+    }
+
+    /// Make a condition that is met if the flag or optional is present, or if the environment variable is set
+    pub fn new_env(flag: &str, env_var: &str, desc: &str) -> Self {
+        Condition {
+            disable_flag: flag.to_owned(),
+            desc: desc.to_owned(),
+            disable_env: Some(env_var.to_owned()),
         }
     }
+
+    fn is_met(&self, flags: &[String], optionals: &HashMap<String, String>) -> bool {
+        flags.contains(&self.disable_flag)
+            || optionals.contains_key(&self.disable_flag)
+            || self
+                .disable_env
+                .as_ref()
+                .map(|var| env::var_os(var).is_some())
+                .unwrap_or(false)
+    }
+}
+
+/// Take [TIME_FLAG] off the end of the arguments, if it's there. It only counts at the end, because anywhere else it
+/// could be part of a field that takes the rest of the line, like a chat message.
+fn split_time_flag(args: &[String]) -> (&[String], bool) {
+    match args.split_last() {
+        Some((last, rest)) if last == TIME_FLAG => (rest, true),
+        _ => (args, false),
+    }
 }
 
 pub fn dispatch_command<T>(args: &Vec<String>, map: &CommandMap<T>, state: Option<T>) {
+    let (args, timed) = split_time_flag(args);
+
     if args.is_empty() {
         println!("Missing command");
         return;
@@ -160,7 +229,9 @@ pub fn dispatch_command<T>(args: &Vec<String>, map: &CommandMap<T>, state: Optio
     let cmd_name = &args[0];
 
     if cmd_name == "help" {
-        if args.len() < 2 {
+        if args.iter().any(|arg| arg == MARKDOWN_FLAG) {
+            print!("{}", help_markdown(map));
+        } else if args.len() < 2 {
             help(map);
         } else {
             let cmd_name = args[1].clone();
@@ -170,25 +241,67 @@ pub fn dispatch_command<T>(args: &Vec<String>, map: &CommandMap<T>, state: Optio
         return;
     }
 
+    let (result, elapsed) = time_call(|| run_command(&args.to_vec(), map, state));
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+    }
+
+    if timed {
+        println!("{} took {:.3?}", cmd_name, elapsed);
+    }
+}
+
+/// Call the function and measure how long it took, in wall-clock time
+pub fn time_call<R, F: FnOnce() -> R>(f: F) -> (R, Duration) {
+    let start = Instant::now();
+    let result = f();
+
+    (result, start.elapsed())
+}
+
+/// Runs the command without printing errors. Unlike [dispatch_command], this does not handle `help`.
+pub fn run_command<T>(
+    args: &Vec<String>,
+    map: &CommandMap<T>,
+    state: Option<T>,
+) -> Result<(), Box<dyn Error>> {
+    if args.is_empty() {
+        return Err("Missing command".into());
+    }
+
+    let cmd_name = &args[0];
+
     let command = match map.get(cmd_name) {
         Some(obj) => obj.to_owned(),
-        None => {
-            println!("Unrecognized command: {cmd_name}");
-            return;
-        }
+        None => return Err(format!("Unrecognized command: {cmd_name}").into()),
     };
 
     let invocation = match decompose_raw_args(args, &command.expected_fields, &command.optionals) {
-        Err(err) => {
-            eprintln!("Failed to decompose command: {}", err);
-            return;
-        }
+        Err(err) => return Err(format!("Failed to decompose command: {}", err).into()),
         Ok(inv) => inv,
     };
 
     match (command.processor)(&invocation, state) {
-        Err(err) => eprintln!("Error executing command: {}", err),
-        Ok(_) => (),
+        Err(err) => Err(Box::new(CommandError(err))),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// An error returned by a command's processor. The original error is kept as the [source](Error::source) so that
+/// callers like the RPC endpoint can still tell what kind of error it was.
+#[derive(Debug)]
+pub struct CommandError(pub Box<dyn Error>);
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error executing command: {}", self.0)
+    }
+}
+
+impl Error for CommandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.0.as_ref())
     }
 }
 
@@ -201,6 +314,7 @@ fn decompose_raw_args(
     let trimmed_args = &raw_args[1..];
     let mut assignments: HashMap<String, String> = HashMap::new();
     let mut optionals: HashMap<String, String> = HashMap::new();
+    let mut repeated_optionals: HashMap<String, Vec<String>> = HashMap::new();
     let (specials, ordered_args): (Vec<String>, Vec<String>) = trimmed_args
         .iter()
         .map(|s| s.to_owned())
@@ -217,6 +331,7 @@ fn decompose_raw_args(
         let value = pair[1].to_owned();
 
         if possible_optionals.iter().any(|f| f.name == key) {
+            repeated_optionals.entry(key.clone()).or_default().push(value.clone());
             optionals.insert(key, value);
         } else {
             assignments.insert(key, value);
@@ -232,18 +347,14 @@ fn decompose_raw_args(
         name,
         field_type,
         desc,
-        condition,
+        conditions,
     } in expected_fields
     {
         // Will only be Some if the field was assigned with `--name=value` syntax
         let var_field = assignments.get(name).cloned();
 
-        if condition.is_some() {
-            let cond = condition.as_ref().unwrap();
-
-            if flags.contains(&cond.disable_flag) {
-                continue;
-            }
+        if conditions.iter().any(|c| c.is_met(&flags, &optionals)) {
+            continue;
         }
 
         match (field_type, var_field) {
@@ -294,13 +405,16 @@ fn decompose_raw_args(
         vars: assignments,
         fields,
         optionals,
+        repeated_optionals,
     };
 
     Ok(out)
 }
 
 fn help<T>(map: &CommandMap<T>) {
-    println!("These are the supported commands. Do 'help command_name' to learn more about a specific command.\n");
+    println!("These are the supported commands. Do 'help command_name' to learn more about a specific command.");
+    println!("End any command with {} to see how long it takes to run.", TIME_FLAG);
+    println!("Do 'help {}' to get the full reference as markdown.\n", MARKDOWN_FLAG);
     let mut keys: Vec<String> = map.keys().map(|k| k.to_owned()).collect();
     keys.sort();
 
@@ -335,7 +449,7 @@ fn help_cmd<T>(map: &CommandMap<T>, cmd_name: String) {
 
     let cond_fields = poses
         .iter()
-        .filter(|f| f.condition.is_some())
+        .filter(|f| !f.conditions.is_empty())
         .copied()
         .collect::<Vec<&Field>>();
 
@@ -375,11 +489,12 @@ fn help_cmd<T>(map: &CommandMap<T>, cmd_name: String) {
         }
 
         for field in cond_fields {
-            let cond = field.condition.as_ref().unwrap();
-            println!(
-                "\t--{} (instead of <{}>)\n\t\t{}",
-                cond.disable_flag, field.name, cond.desc
-            );
+            for cond in &field.conditions {
+                println!(
+                    "\t--{} (instead of <{}>)\n\t\t{}",
+                    cond.disable_flag, field.name, cond.desc
+                );
+            }
         }
     }
 
@@ -392,6 +507,104 @@ fn help_cmd<T>(map: &CommandMap<T>, cmd_name: String) {
     }
 }
 
+/// Generate a reference for every command in the map as markdown, with a table of arguments for each command.
+/// Commands are sorted by name so that the output is stable.
+pub fn help_markdown<T>(map: &CommandMap<T>) -> String {
+    let mut out = String::from("# Command Reference\n\n");
+    out.push_str(&format!(
+        "End any command with `{}` to see how long it takes to run.\n",
+        TIME_FLAG
+    ));
+
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+
+    for cmd_name in keys {
+        let command = map.get(cmd_name).unwrap();
+
+        out.push_str(&format!("\n## {}\n\n", cmd_name));
+        out.push_str(&format!("{}\n\n", command.desc));
+        out.push_str(&format!(
+            "Syntax: `{}`\n",
+            make_syntax_string(cmd_name, command)
+        ));
+
+        let rows = markdown_arg_rows(command);
+
+        if rows.is_empty() {
+            continue;
+        }
+
+        out.push_str("\n| Argument | Kind | Description |\n| --- | --- | --- |\n");
+
+        for (arg, kind, desc) in rows {
+            out.push_str(&format!(
+                "| {} | {} | {} |\n",
+                escape_table_cell(&arg),
+                kind,
+                escape_table_cell(&desc)
+            ));
+        }
+    }
+
+    out
+}
+
+/// One (argument, kind, description) row for each argument the command accepts, in the same order that
+/// `help <command>` lists them
+fn markdown_arg_rows<T>(command: &Command<T>) -> Vec<(String, &'static str, String)> {
+    let mut out = vec![];
+
+    let (mut vars, mut poses): (Vec<&Field>, Vec<&Field>) = command
+        .expected_fields
+        .iter()
+        .partition(|f| f.field_type == FieldType::Var);
+
+    poses.sort_by_key(|f| match f.field_type {
+        FieldType::Pos(pos) => pos,
+        FieldType::Spaces(pos) => pos,
+        _ => unreachable!(),
+    });
+    vars.sort_by_key(|f| f.name.clone());
+
+    for field in &poses {
+        out.push((format!("`<{}>`", field.name), "Required", field.desc.clone()));
+    }
+
+    for field in vars {
+        out.push((format!("`--{}=<value>`", field.name), "Required keyword", field.desc.clone()));
+    }
+
+    let mut flags = command.flags.clone();
+    flags.sort_by_key(|f| f.name.clone());
+
+    for Flag { name, desc } in flags {
+        out.push((format!("`--{}`", name), "Flag", desc));
+    }
+
+    for field in poses {
+        for cond in &field.conditions {
+            out.push((
+                format!("`--{}` (instead of `<{}>`)", cond.disable_flag, field.name),
+                "Flag",
+                cond.desc.clone(),
+            ));
+        }
+    }
+
+    for VarField { name, desc, placeholder } in &command.optionals {
+        let placeholder = placeholder.as_deref().unwrap_or("value");
+        out.push((format!("`--{}=<{}>`", name, placeholder), "Optional", desc.clone()));
+    }
+
+    out
+}
+
+/// Pipes end a table cell in markdown, even inside a code span, and newlines end the row
+fn escape_table_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
+
 fn make_syntax_string<T>(name: &String, command: &Command<T>) -> String {
     let mut out = String::from(name);
     let mut max_pos: isize = -1;
@@ -423,13 +636,13 @@ fn make_syntax_string<T>(name: &String, command: &Command<T>) -> String {
         name,
         field_type,
         desc: _,
-        condition,
+        conditions,
     } in &command.expected_fields
     {
-        let format_name = match condition {
-            None => format!("<{}>", name),
-            Some(cond) => format!("<--{}|{}>", cond.disable_flag, name),
-        };
+        let format_name = conditions
+            .iter()
+            .fold(String::new(), |a, c| format!("{}--{}|", a, c.disable_flag));
+        let format_name = format!("<{}{}>", format_name, name);
 
         match field_type {
             FieldType::Pos(pos) => names[pos.to_owned()] = format_name,
@@ -445,3 +658,81 @@ fn make_syntax_string<T>(name: &String, command: &Command<T>) -> String {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::commands::{session::make_session_command_map, top_level::make_command_map};
+
+    const NAP: Duration = Duration::from_millis(20);
+
+    fn nap(_: &CommandInvocation, _: Option<()>) -> Result<(), Box<dyn Error>> {
+        thread::sleep(NAP);
+
+        Ok(())
+    }
+
+    #[test]
+    fn timing_covers_the_processor_call() {
+        let mut map: CommandMap<()> = HashMap::new();
+        map.insert(
+            String::from("nap"),
+            Command {
+                processor: nap,
+                expected_fields: vec![],
+                flags: vec![],
+                optionals: vec![],
+                desc: String::from(""),
+            },
+        );
+
+        let (result, elapsed) = time_call(|| run_command(&vec![String::from("nap")], &map, None));
+
+        assert!(result.is_ok());
+        assert!(elapsed >= NAP);
+    }
+
+    /// Every command in the map has a section, and every required field is in its table
+    fn check_markdown<T>(map: &CommandMap<T>) {
+        let markdown = help_markdown(map);
+
+        for (cmd_name, command) in map {
+            let section = markdown
+                .split(&format!("\n## {}\n", cmd_name))
+                .nth(1)
+                .unwrap_or_else(|| panic!("No section for {}", cmd_name));
+            let section = section.split("\n## ").next().unwrap();
+
+            for field in &command.expected_fields {
+                let arg = match field.field_type {
+                    FieldType::Var => format!("| `--{}=<value>` | Required keyword |", field.name),
+                    _ => format!("| `<{}>` | Required |", field.name),
+                };
+
+                assert!(section.contains(&arg), "{} is missing {}", cmd_name, arg);
+            }
+        }
+    }
+
+    #[test]
+    fn markdown_lists_every_command_and_required_field() {
+        check_markdown(&make_command_map());
+        check_markdown(&make_session_command_map());
+    }
+
+    #[test]
+    fn time_flag_only_counts_at_the_end() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<String>>();
+
+        let timed = args("chat alice see you --time");
+        assert_eq!(split_time_flag(&timed), (&timed[..4], true));
+
+        let message = args("chat alice --time is up");
+        assert_eq!(split_time_flag(&message), (&message[..], false));
+
+        let both = args("chat alice --time is up --time");
+        assert_eq!(split_time_flag(&both), (&both[..5], true));
+    }
+}
@@ -5,9 +5,11 @@ use crate::{
     hash::hash_sha256,
     v1::{
         block::{make_merkle_root_from_hashes, RawBlockHeader},
+        request::GetBlocksRangeReq,
+        response::{blocks_in_range, GetBlocksRes},
         state::State,
     },
-    wallet::Hash256,
+    wallet::{hex_to_hash, Hash256},
 };
 
 fn get_utxos(
@@ -39,6 +41,37 @@ fn hash_test(
     Ok(())
 }
 
+/// Answer a [GetBlocksRangeReq] against our own blockchain and print the result, to check
+/// what another node would get back
+fn blocks_range(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let chain_hint = invocation.get_field("chain-hint").unwrap().parse::<usize>()?;
+    let start_hash = hex_to_hash(&invocation.get_field("start-hash").unwrap())?;
+    let count = invocation.get_field("count").unwrap().parse::<usize>()?;
+    let req = GetBlocksRangeReq {
+        chain_hint,
+        start_hash,
+        count,
+    };
+
+    let guard = state.unwrap().lock().unwrap();
+
+    match blocks_in_range(&guard.blockchain, &req) {
+        GetBlocksRes::Blocks(blocks) => {
+            println!("{} blocks", blocks.len());
+
+            for block in blocks {
+                println!("{}", hex::encode(block.header.hash));
+            }
+        }
+        res => println!("{:?}", res),
+    };
+
+    Ok(())
+}
+
 fn merkle_test(
     invocation: &CommandInvocation,
     _state: Option<&Mutex<State>>,
@@ -48,11 +81,7 @@ fn merkle_test(
     let mut hashes: Vec<Hash256> = vec![];
 
     for raw_hash in raw_hashes {
-        let bytes = hex::decode(&raw_hash).unwrap();
-        let mut hash = [0_u8; 32];
-
-        hash.copy_from_slice(&bytes);
-        hashes.push(hash);
+        hashes.push(hex_to_hash(raw_hash)?);
     }
 
     let root = make_merkle_root_from_hashes(hashes);
@@ -89,6 +118,29 @@ pub fn make_command_map<'a>() -> CommandMap<&'a Mutex<State>> {
         optionals: vec![],
         desc: String::from("Hash test"),
     };
+    let blocks_range_cmd: Command<&Mutex<State>> = Command {
+        processor: blocks_range,
+        expected_fields: vec![
+            Field::new(
+                "chain-hint",
+                FieldType::Pos(0),
+                "0 for the main chain, or the fork index plus one",
+            ),
+            Field::new(
+                "start-hash",
+                FieldType::Pos(1),
+                "Hash of the block to start after",
+            ),
+            Field::new(
+                "count",
+                FieldType::Pos(2),
+                "Max number of blocks to get",
+            ),
+        ],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Print the blocks we would send back for a block range request"),
+    };
     let merkle_test_cmd: Command<&Mutex<State>> = Command {
         processor: merkle_test,
         expected_fields: vec![Field::new(
@@ -110,6 +162,7 @@ pub fn make_command_map<'a>() -> CommandMap<&'a Mutex<State>> {
 
     map.insert(String::from("get-utxos"), get_utxos_cmd);
     map.insert(String::from("hash-test"), hash_test_cmd);
+    map.insert(String::from("blocks-range"), blocks_range_cmd);
     map.insert(String::from("merkle-test"), merkle_test_cmd);
     map.insert(String::from("print-blockchain"), print_blockchain_cmd);
 
@@ -1,3 +1,4 @@
+pub mod rpc;
 pub mod session;
 pub mod top_level;
 
@@ -0,0 +1,321 @@
+use std::{
+    error::Error,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{run_command, CommandMap},
+    script_error::{ErrorKind as ScriptErrorKind, ScriptError},
+    v1::{
+        block_verify_error::{BlockVerifyError, ErrorKind as BlockErrorKind},
+        state::State,
+        txn_verify_error::{ErrorKind as TxnErrorKind, TxnVerifyError},
+    },
+};
+
+use super::session::make_session_command_map;
+
+/// A command sent to the RPC endpoint, as one line of JSON. For example:
+/// `{"command": "getblock", "args": ["--header-only", "0000000713c9..."]}`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcRequest {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// The result of a command, as one line of JSON. Commands print their output to the console, so a successful
+/// command only echoes what was run: `{"ok": true, "result": {"command": "getforks", "args": []}}`. A failed
+/// command gets an error object instead: `{"ok": false, "error": {"message": "...", "code": 210}}`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<RpcRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+/// If the command failed with a script, transaction, or block verification error, `code` is that error's
+/// [code](crate::v1::block_verify_error::ErrorKind::code).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcError {
+    pub message: String,
+    #[serde(default)]
+    pub code: Option<u16>,
+}
+
+impl RpcResponse {
+    fn err(message: String, code: Option<u16>) -> Self {
+        RpcResponse {
+            ok: false,
+            result: None,
+            error: Some(RpcError { message, code }),
+        }
+    }
+}
+
+/// Bind the RPC listener on localhost. This is separate from [listen_for_rpc] so that a port that's already in use
+/// is reported before the listener thread starts.
+pub fn bind_rpc_listener(port: u16) -> Result<TcpListener, Box<dyn Error>> {
+    TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
+        .map_err(|err| format!("Failed to bind RPC listener on localhost:{}: {}", port, err).into())
+}
+
+/// Accept RPC connections. Each connection can send any number of newline-delimited JSON commands, and gets one
+/// line of JSON back for each. Clients are handled one at a time.
+pub fn listen_for_rpc(listener: TcpListener, state_mut: &Mutex<State>) -> Result<(), Box<dyn Error>> {
+    let command_map = make_session_command_map();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Err(err) => {
+                println!("Error accepting RPC connection: {}", err);
+                continue;
+            }
+            Ok(stream) => stream,
+        };
+
+        if let Err(err) = handle_rpc_client(stream, &command_map, state_mut) {
+            println!("Error handling RPC client: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_rpc_client<'a>(
+    stream: TcpStream,
+    command_map: &CommandMap<&'a Mutex<State>>,
+    state_mut: &'a Mutex<State>,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let res = run_rpc_request(&line, command_map, state_mut);
+        writeln!(writer, "{}", serde_json::to_string(&res)?)?;
+    }
+
+    Ok(())
+}
+
+/// Parse one line of JSON and run the command it names
+pub fn run_rpc_request<'a>(
+    line: &str,
+    command_map: &CommandMap<&'a Mutex<State>>,
+    state_mut: &'a Mutex<State>,
+) -> RpcResponse {
+    let req: RpcRequest = match serde_json::from_str(line) {
+        Err(err) => return RpcResponse::err(format!("Invalid request: {}", err), None),
+        Ok(req) => req,
+    };
+
+    let mut args = vec![req.command.clone()];
+    args.extend(req.args.iter().cloned());
+
+    match run_command(&args, command_map, Some(state_mut)) {
+        Err(err) => RpcResponse::err(err.to_string(), error_code(err.as_ref())),
+        Ok(()) => RpcResponse {
+            ok: true,
+            result: Some(req),
+            error: None,
+        },
+    }
+}
+
+/// Get the stable code of a verification or script error. The error can be wrapped in other errors, like the
+/// [CommandError](crate::command::CommandError) that [run_command] returns, so we look through its sources.
+pub fn error_code(err: &(dyn Error + 'static)) -> Option<u16> {
+    let mut next = Some(err);
+
+    while let Some(err) = next {
+        if let Some(code) = own_error_code(err) {
+            return Some(code);
+        }
+
+        next = err.source();
+    }
+
+    None
+}
+
+/// These errors are boxed before they're converted to `Box<dyn Error>`, so we look for the boxed types too.
+fn own_error_code(err: &(dyn Error + 'static)) -> Option<u16> {
+    if let Some(kind) = err.downcast_ref::<BlockVerifyError>() {
+        return Some(kind.code());
+    }
+
+    if let Some(kind) = err.downcast_ref::<BlockErrorKind>() {
+        return Some(kind.code());
+    }
+
+    if let Some(kind) = err.downcast_ref::<TxnVerifyError>() {
+        return Some(kind.code());
+    }
+
+    if let Some(kind) = err.downcast_ref::<TxnErrorKind>() {
+        return Some(kind.code());
+    }
+
+    if let Some(kind) = err.downcast_ref::<ScriptError>() {
+        return Some(kind.code());
+    }
+
+    err.downcast_ref::<ScriptErrorKind>().map(|kind| kind.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+    use crate::{
+        command::{Command, CommandInvocation, CommandProcessor, Field, FieldType},
+        v1::test_util::test_state,
+    };
+
+    fn fail_with_txn_error(_: &CommandInvocation, _: Option<&Mutex<State>>) -> Result<(), Box<dyn Error>> {
+        let err: TxnVerifyError = Box::new(TxnErrorKind::LowFee(1));
+
+        Err(err.into())
+    }
+
+    fn fail_with_block_error(_: &CommandInvocation, _: Option<&Mutex<State>>) -> Result<(), Box<dyn Error>> {
+        let err: BlockVerifyError = Box::new(BlockErrorKind::FeeOverflow);
+
+        Err(err.into())
+    }
+
+    fn fail_with_message(_: &CommandInvocation, _: Option<&Mutex<State>>) -> Result<(), Box<dyn Error>> {
+        Err("Something went wrong".into())
+    }
+
+    fn set_relay_fee(invocation: &CommandInvocation, state: Option<&Mutex<State>>) -> Result<(), Box<dyn Error>> {
+        let fee = invocation.get_field("fee").unwrap().parse::<u64>()?;
+        state.unwrap().lock().unwrap().min_relay_fee = fee;
+
+        Ok(())
+    }
+
+    fn test_command_map<'a>() -> CommandMap<&'a Mutex<State>> {
+        let mut map = CommandMap::new();
+        map.insert(
+            String::from("set-relay-fee"),
+            Command {
+                processor: set_relay_fee,
+                expected_fields: vec![Field::new("fee", FieldType::Pos(0), "")],
+                flags: vec![],
+                optionals: vec![],
+                desc: String::from(""),
+            },
+        );
+
+        let processors = [
+            ("txn-error", fail_with_txn_error as CommandProcessor<_>),
+            ("block-error", fail_with_block_error),
+            ("message", fail_with_message),
+        ];
+
+        for (name, processor) in processors {
+            map.insert(
+                String::from(name),
+                Command {
+                    processor,
+                    expected_fields: vec![],
+                    flags: vec![],
+                    optionals: vec![],
+                    desc: String::from(""),
+                },
+            );
+        }
+
+        map
+    }
+
+    fn run(command: &str) -> RpcResponse {
+        let state_mut = Mutex::new(test_state());
+        let line = format!(r#"{{"command": "{}"}}"#, command);
+
+        run_rpc_request(&line, &test_command_map(), &state_mut)
+    }
+
+    #[test]
+    fn failed_commands_keep_their_error_code() {
+        let res = run("txn-error");
+        assert!(!res.ok);
+
+        let err = res.error.unwrap();
+        assert_eq!(err.code, Some(210));
+        assert!(err.message.starts_with("Error executing command: "));
+
+        assert_eq!(run("block-error").error.unwrap().code, Some(320));
+    }
+
+    #[test]
+    fn other_errors_have_no_code() {
+        let res = run("message");
+        assert!(!res.ok);
+
+        let err = res.error.unwrap();
+        assert_eq!(err.code, None);
+        assert_eq!(err.message, "Error executing command: Something went wrong");
+
+        assert_eq!(run("no-such-command").error.unwrap().code, None);
+    }
+
+    #[test]
+    fn socket_commands_reach_their_processor() {
+        let listener = bind_rpc_listener(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state_arc = Arc::new(Mutex::new(test_state()));
+        let state_arc_rpc = Arc::clone(&state_arc);
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_rpc_client(stream, &test_command_map(), &state_arc_rpc).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+
+        writeln!(stream, r#"{{"command": "set-relay-fee", "args": ["77"]}}"#).unwrap();
+        reader.read_line(&mut line).unwrap();
+
+        let res: RpcResponse = serde_json::from_str(&line).unwrap();
+        assert!(res.ok);
+        assert!(res.error.is_none());
+
+        let result = res.result.unwrap();
+        assert_eq!(result.command, "set-relay-fee");
+        assert_eq!(result.args, vec!["77"]);
+        assert_eq!(state_arc.lock().unwrap().min_relay_fee, 77);
+
+        line.clear();
+        writeln!(stream, "not json").unwrap();
+        reader.read_line(&mut line).unwrap();
+
+        let res: RpcResponse = serde_json::from_str(&line).unwrap();
+        assert!(!res.ok);
+        assert!(res.error.unwrap().message.starts_with("Invalid request: "));
+    }
+
+    #[test]
+    fn port_in_use_is_reported() {
+        let listener = bind_rpc_listener(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(bind_rpc_listener(port).is_err());
+    }
+}
@@ -1,25 +1,42 @@
-use std::{collections::HashMap, error::Error, sync::Mutex};
+use std::{collections::HashMap, error::Error, fs, net::SocketAddr, sync::Mutex};
 
 use ring::signature::KeyPair;
 
 #[cfg(feature = "gui")]
-use crate::v1::encrypted_msg::{is_gui_only, ChainChatReq, ChainRequest};
+use crate::v1::{
+    chain_request::make_chat_req,
+    encrypted_msg::{is_gui_only, ChainChatReq, ChainRequest},
+};
 
 use crate::{
-    command::{dispatch_command, Command, CommandInvocation, Field, FieldType, Flag},
+    command::{
+        dispatch_command, Command, CommandInvocation, CommandMap, Condition, Field, FieldType, Flag, VarField,
+    },
+    tsengscript_interpreter::count_sigops,
     v1::{
-        chain_request::make_dh_connect_req,
-        request::send_new_txn,
+        block::{
+            check_orphans, cumulative_target, hash_block_header, make_merkle_root, remove_chain_tip, Block,
+            BlockchainDB, ForkChain, RawBlockHeader, MAX_BLOCK_SIGOPS, MAX_TRANSACTION_FIELD_SIZE,
+        },
+        block_verify::{verify_historical_block, BlockStatus},
+        chain_request::{make_dh_connect_req, FriendState},
+        encrypted_msg::inspect_enc_req,
+        fees::{estimate_confirmation, recent_block_samples, FeeSample},
+        net::{broadcast_async_blast, peer_graph_to_dot, peer_graph_to_json},
+        miners::api::pick_block_txns,
+        request::{download_blocks_after, get_blocks_range, send_new_txn, Request},
         state::State,
         transaction::{
-            collect_enough_change, hash_txn, make_p2pkh_lock, make_p2pkh_unlock,
-            p2pkh_utxos_for_addr, sign_txn, TxnInput, TxnOutput, UnhashedTransaction,
-            UnsignedTransaction,
+            build_utxos_from_confirmed, coinbase_size_estimate, collect_enough_change, compute_fee, fee_for_rate,
+            get_p2pkh_sender, hash_txn, is_excessive_fee, make_p2pkh_lock, make_p2pkh_unlock, p2pkh_utxos_for_addr,
+            sign_txn, spent_sigops, transaction_spends_only, Transaction, TxnInput, TxnOutput, UnhashedTransaction,
+            UnsignedTransaction, BLOCK_REWARD,
         },
-        txn_verify::verify_transaction,
+        txn_verify::{check_pending_and_orphans, verify_pending_transaction, TxnStatus},
+        txn_verify_error::TxnVerifyError,
         VERSION,
     },
-    wallet::{address_to_b58c, b58c_to_address},
+    wallet::{address_to_b58c, b58c_to_address, hex_to_hash, sign_message, verify_message, Address, Hash256},
 };
 
 #[cfg(feature = "debug")]
@@ -59,14 +76,11 @@ fn getblock(
     invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let hash_vec = hex::decode(invocation.get_field("hash").unwrap())?;
+    let hash = hex_to_hash(&invocation.get_field("hash").unwrap())?;
     let header_only = invocation.get_flag("header-only");
     let guard = state.unwrap().lock().unwrap();
     let state = &*guard;
 
-    let mut hash = [0_u8; 32];
-    hash[32 - hash_vec.len()..].copy_from_slice(&hash_vec);
-
     let block_opt = state.blockchain.get_block(hash);
 
     match block_opt {
@@ -93,45 +107,83 @@ fn gettxn(
     invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let hash_vec = hex::decode(invocation.get_field("hash").unwrap())?;
+    let hash = hex_to_hash(&invocation.get_field("hash").unwrap())?;
     let guard = state.unwrap().lock().unwrap();
     let state = &*guard;
 
-    let mut hash = [0_u8; 32];
-    hash[32 - hash_vec.len()..].copy_from_slice(&hash_vec);
+    // Every transaction gets a status and a confirmation count, in the same format, so that they're
+    // easy to parse
+    match txn_status(hash, state) {
+        Some((status, confirmations)) => println!("Status: {}\nConfirmations: {}", status, confirmations),
+        None => {
+            println!("Status: not found");
+            return Ok(());
+        }
+    };
 
-    let orphan_opt = state.get_orphan_txn(hash);
-    if orphan_opt.is_some() {
-        println!(
-            "Transaction found in orphan pool: {:#?}",
-            orphan_opt.unwrap()
-        );
-        return Ok(());
+    if let Some(txn) = state.get_orphan_txn(hash) {
+        println!("Transaction found in orphan pool: {:#?}", txn);
+    } else if let Some(txn) = state.get_pending_txn(hash) {
+        println!("Transaction found in pending pool: {:#?}", txn);
+    } else if let Some(confirmed) = state.blockchain.find_txn(hash) {
+        println!("Transaction found in blockchain: {:#?}", confirmed);
     }
 
-    let pending_opt = state.get_pending_txn(hash);
-    if pending_opt.is_some() {
-        println!(
-            "Transaction found in pending pool: {:#?}",
-            pending_opt.unwrap()
-        );
-        return Ok(());
+    Ok(())
+}
+
+/// Which pool a transaction is in and how many confirmations it has
+fn txn_status(hash: Hash256, state: &State) -> Option<(&'static str, usize)> {
+    if state.get_orphan_txn(hash).is_some() {
+        return Some(("orphan", 0));
     }
 
-    let confirmed_opt = state.blockchain.find_txn(hash);
-    if confirmed_opt.is_some() {
-        println!(
-            "Transaction found in blockchain: {:#?}",
-            confirmed_opt.unwrap()
-        );
+    if state.get_pending_txn(hash).is_some() {
+        return Some(("pending", 0));
+    }
+
+    state
+        .blockchain
+        .find_txn(hash)
+        .map(|confirmed| ("confirmed", confirmed.confirmations))
+}
+
+fn getrawmempool(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    println!("{} pending transactions", state.pending_txns.len());
+
+    for (hash, fee, size) in mempool_entries(state) {
+        println!("{} fee: {} size: {}B", hex::encode(hash), fee, size);
+    }
+
+    if !invocation.get_flag("orphans") {
         return Ok(());
     }
 
-    println!("Transaction not found");
+    // The fee of an orphan can't be known until we have its inputs
+    println!("{} orphan transactions", state.orphan_txns.len());
+
+    for txn in &state.orphan_txns {
+        println!("{} size: {}B", hex::encode(txn.hash), txn.size());
+    }
 
     Ok(())
 }
 
+/// The hash, fee, and size of every pending transaction
+fn mempool_entries(state: &State) -> Vec<(Hash256, u64, usize)> {
+    state
+        .pending_txns
+        .iter()
+        .map(|txn| (txn.hash, compute_fee(txn, state), txn.size()))
+        .collect()
+}
+
 fn blockchain_stats(
     _invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
@@ -156,6 +208,87 @@ fn blockchain_stats(
     Ok(())
 }
 
+fn getforks(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let forks = &state.blockchain.forks;
+
+    println!("{} forks", forks.len());
+
+    for (i, fork) in forks.iter().enumerate() {
+        println!("Fork {}\n{}", i + 1, describe_fork(fork));
+    }
+
+    Ok(())
+}
+
+fn describe_fork(fork: &ForkChain) -> String {
+    let targets = fork
+        .blocks
+        .iter()
+        .map(|b| b.header.target())
+        .collect::<Vec<Hash256>>();
+    let tip = match fork.blocks.last() {
+        Some(block) => hex::encode(block.header.hash),
+        None => String::from("none"),
+    };
+
+    format!(
+        "\tPrev index: {}\n\tLength: {}\n\tTip: {}\n\tCumulative target: {}",
+        fork.prev_index,
+        fork.blocks.len(),
+        tip,
+        cumulative_target(&targets).to_str_radix(16)
+    )
+}
+
+fn getchaintips(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state.unwrap().lock().unwrap();
+
+    for (hash, height, branch_len, status) in chain_tips(&guard.blockchain) {
+        println!("{}", hash);
+        println!("\tHeight: {}", height);
+        println!("\tBranch length: {}", branch_len);
+        println!("\tStatus: {}", status);
+    }
+
+    Ok(())
+}
+
+/// The hash, height, branch length, and status of the main chain tip, then each fork tip
+fn chain_tips(blockchain: &BlockchainDB) -> Vec<(String, usize, usize, &'static str)> {
+    let (_, best_idx, _) = blockchain.best_chain();
+
+    // Fork heights count the main chain blocks before the fork
+    let main_tip = (0, &blockchain.blocks, blockchain.blocks.len(), 0);
+    let fork_tips = blockchain.forks.iter().enumerate().map(|(i, f)| {
+        (i + 1, &f.blocks, f.prev_index + 1 + f.blocks.len(), f.blocks.len())
+    });
+
+    std::iter::once(main_tip)
+        .chain(fork_tips)
+        .map(|(chain_idx, blocks, height, branch_len)| {
+            let hash = match blocks.last() {
+                Some(block) => hex::encode(block.header.hash),
+                None => String::from("none"),
+            };
+            let status = match chain_idx == best_idx {
+                true => "active",
+                false => "valid-fork",
+            };
+
+            (hash, height, branch_len, status)
+        })
+        .collect()
+}
+
 // TODO: Use state's balance. Keeping this in here for testing because we know this works
 fn balance_p2pkh(
     _invocation: &CommandInvocation,
@@ -173,6 +306,9 @@ fn balance_p2pkh(
     Ok(())
 }
 
+/// How many times to rebuild a transaction while looking for a fee that matches the fee rate.
+const MAX_FEE_RATE_ATTEMPTS: usize = 10;
+
 fn send_coins_p2pkh(
     invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
@@ -182,8 +318,12 @@ fn send_coins_p2pkh(
         .unwrap()
         .parse::<u64>()
         .unwrap();
-    let fee = invocation.get_field("fee").unwrap().parse::<u64>().unwrap();
+    let fee_rate = match invocation.get_optional("fee-rate") {
+        None => None,
+        Some(rate) => Some(rate.parse::<f64>()?),
+    };
     let show_structure = invocation.get_flag("show-structure");
+    let force = invocation.get_flag("force");
     let mut guard = state.unwrap().lock().unwrap();
     let state = &mut *guard;
 
@@ -191,19 +331,124 @@ fn send_coins_p2pkh(
         .friends
         .get_address(invocation.get_field("address").unwrap())?;
 
-    let required_input = amount + fee;
+    let txn_opt = match fee_rate {
+        None => {
+            let fee = invocation.get_field("fee").unwrap().parse::<u64>()?;
+            make_p2pkh_txn(state, &dest_address, amount, fee)?
+        }
+        Some(rate) => make_p2pkh_txn_with_rate(state, &dest_address, amount, rate)?,
+    };
 
-    let change = match collect_enough_change(state, state.address, required_input) {
+    let full_txn = match txn_opt {
         None => {
             println!("You don't have enough TsengCoin to make that transaction");
             return Ok(());
         }
+        Some(txn) => txn,
+    };
+
+    if show_structure {
+        println!("{:#?}", full_txn);
+    }
+
+    // We couldn't unlock these anyway, so this would mean there's a bug in coin selection
+    if !transaction_spends_only(state.address, &full_txn, state) {
+        println!("Warning: This transaction spends outputs that aren't addressed to you. Not sending it");
+        return Ok(());
+    }
+
+    let fee = compute_fee(&full_txn, state);
+
+    if is_excessive_fee(fee, amount) && !force {
+        println!(
+            "The fee of {} TsengCoin looks too high for sending {} TsengCoin. Use --force if you really want to send this transaction",
+            fee, amount
+        );
+        return Ok(());
+    }
+
+    match verify_pending_transaction(full_txn.clone(), state) {
+        Ok(_) => {
+            state.add_pending_txn(full_txn.clone());
+            let (accepted, failed) = send_new_txn(full_txn, state)?;
+
+            if accepted == 0 {
+                println!("Warning: Transaction was not received by any peers ({} failed)", failed);
+            } else {
+                println!("Successfully submitted transaction to {} peers", accepted);
+            }
+        }
+        Err(err) => {
+            println!("There was a problem verifying your transaction: {}", err)
+        }
+    };
+
+    Ok(())
+}
+
+/// Build a P2PKH transaction paying a fee at the given rate. Adding the fee can change which UTXOs are spent and
+/// whether there is a change output, which changes the size of the transaction, so we rebuild it until the fee
+/// matches the rate for the final size. The size used is [Transaction::vsize], so the fee doesn't depend on
+/// the length of the signature.
+fn make_p2pkh_txn_with_rate(
+    state: &State,
+    dest_address: &Address,
+    amount: u64,
+    rate: f64,
+) -> Result<Option<Transaction>, Box<dyn Error>> {
+    // A NaN or negative rate would be cast to a zero fee
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(format!("Fee rate must be a positive number, got {}", rate).into());
+    }
+
+    let mut fee: u64 = 0;
+    let mut txn_opt = None;
+
+    for _ in 0..MAX_FEE_RATE_ATTEMPTS {
+        let txn = match make_p2pkh_txn(state, dest_address, amount, fee)? {
+            None => return Ok(None),
+            Some(txn) => txn,
+        };
+
+        let needed_fee = fee_for_rate(rate, txn.vsize());
+        let done = needed_fee == fee;
+
+        // Never settle on a fee below the rate
+        if needed_fee <= fee {
+            txn_opt = Some(txn);
+        }
+
+        if done {
+            break;
+        }
+
+        fee = needed_fee;
+    }
+
+    match txn_opt {
+        None => Err("Could not find a fee that matches the fee rate".into()),
+        Some(txn) => Ok(Some(txn)),
+    }
+}
+
+/// Build and sign a P2PKH transaction sending `amount` to `dest_address`, with any change going back to us.
+/// Returns None if we don't have enough TsengCoin.
+pub fn make_p2pkh_txn(
+    state: &State,
+    dest_address: &Address,
+    amount: u64,
+    fee: u64,
+) -> Result<Option<Transaction>, Box<dyn Error>> {
+    let required_input = amount + fee;
+
+    let change = match collect_enough_change(state, required_input) {
+        None => return Ok(None),
         Some(utxos) => utxos,
     };
 
     let actual_input = change.iter().fold(0, |a, e| a + e.amount);
 
-    let lock_script = make_p2pkh_lock(&dest_address);
+    let lock_script = make_p2pkh_lock(dest_address);
     let mut outputs: Vec<TxnOutput> = vec![TxnOutput {
         amount,
         lock_script,
@@ -248,127 +493,652 @@ fn send_coins_p2pkh(
     };
 
     let hash = hash_txn(&unhashed)?;
-    let full_txn = unhashed.to_hashed(hash);
 
-    if show_structure {
-        println!("{:#?}", full_txn);
-    }
+    Ok(Some(unhashed.to_hashed(hash)))
+}
 
-    match verify_transaction(full_txn.clone(), state) {
-        Ok(_) => {
-            state.add_pending_txn(full_txn.clone());
-            send_new_txn(full_txn, state)?;
-            println!("Successfully submitted transaction");
-        }
-        Err(err) => {
-            println!("There was a problem verifying your transaction: {}", err)
+fn check_orphans_cmd(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    // Adding a block also re-checks orphan transactions, so remember which ones were orphans to count them at the end
+    let orphan_txns = state
+        .orphan_txns
+        .iter()
+        .map(|t| t.hash)
+        .collect::<Vec<Hash256>>();
+
+    // An orphan can be the parent of another orphan, so keep going until nothing else resolves
+    let mut blocks_resolved: usize = 0;
+    loop {
+        let num_added = check_orphans(state);
+
+        if num_added == 0 {
+            break;
         }
-    };
+
+        blocks_resolved += num_added;
+    }
+
+    if blocks_resolved > 0 {
+        state.resolve_forks();
+    }
+
+    // Pending transactions are already in the UTXO pool, so they would look like double spends if we checked them
+    // against it. Start over from the confirmed UTXOs, the same as when a block is added
+    state.blockchain.utxo_pool = build_utxos_from_confirmed(&state.blockchain.blocks);
+    check_pending_and_orphans(state);
+    state.compute_balance();
+
+    let txns_resolved = state
+        .pending_txns
+        .iter()
+        .filter(|t| orphan_txns.contains(&t.hash))
+        .count();
+
+    println!(
+        "Resolved {} orphan blocks and {} orphan transactions",
+        blocks_resolved, txns_resolved
+    );
+    println!(
+        "{} orphan blocks and {} orphan transactions remain",
+        state.blockchain.orphans.len(),
+        state.orphan_txns.len()
+    );
 
     Ok(())
 }
 
-fn hashrate(
-    _invocation: &CommandInvocation,
+fn rebroadcast_mempool(
+    invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let guard = state.unwrap().lock().unwrap();
-    let state = &*guard;
+    let all = invocation.get_flag("all");
+    let state_mut = state.unwrap();
+    let guard = state_mut.lock().unwrap();
 
-    println!("Hashes per second: {}", state.hashes_per_second);
+    let txns = guard
+        .pending_txns
+        .iter()
+        .filter(|txn| all || get_p2pkh_sender(txn, &guard) == Some(guard.address))
+        .cloned()
+        .collect::<Vec<Transaction>>();
+    let mut peers = guard.network.peer_addrs();
+    let pool = guard.network.conn_pool.clone();
+
+    // Don't hold the lock while broadcasting, or incoming requests will have to wait for every transaction to be sent
+    drop(guard);
+
+    if txns.is_empty() {
+        println!("There are no pending transactions to rebroadcast");
+        return Ok(());
+    }
+
+    let num_peers = peers.len();
+    let mut dead_nodes: Vec<SocketAddr> = vec![];
+
+    for txn in txns {
+        let hash = txn.hash;
+        let mut failed = broadcast_async_blast(Request::NewTxn(txn), &peers, None, pool.as_deref());
+
+        println!(
+            "{}: received by {} of {} peers",
+            hex::encode(hash),
+            peers.len() - failed.len(),
+            num_peers
+        );
+
+        // A peer that couldn't be reached for one transaction won't be reachable for the rest either
+        peers.retain(|addr| !failed.contains(addr));
+        dead_nodes.append(&mut failed);
+    }
+
+    state_mut.lock().unwrap().network.prune_dead_nodes(&mut dead_nodes);
 
     Ok(())
 }
 
-fn connect_to(
-    invocation: &CommandInvocation,
+fn save(
+    _invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let name = invocation.get_field("address").unwrap();
-    let req_amount = invocation.get_field("req-amount").unwrap().parse::<u64>()?;
-    let req_fee = invocation.get_field("fee").unwrap().parse::<u64>()?;
-    let mut guard = state.unwrap().lock().unwrap();
-    let state = &mut *guard;
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
 
-    let dest_address = state.friends.get_address(name)?;
-    let connect_req = make_dh_connect_req(dest_address, req_amount, req_fee, None, state)?;
-    send_new_txn(connect_req, state)?;
+    state.save()?;
+
+    println!(
+        "Saved {} blocks, {} orphan blocks, and {} orphan transactions",
+        state.blockchain.blocks.len(),
+        state.blockchain.orphans.len(),
+        state.orphan_txns.len()
+    );
 
     Ok(())
 }
 
-fn alias(
+fn estimate_confirmation_cmd(
     invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let pkh = invocation.get_field("address").unwrap();
-    let name = invocation.get_field("name").unwrap();
-    let mut guard = state.unwrap().lock().unwrap();
-    let state = &mut *guard;
+    let fee = invocation.get_field("fee").unwrap().parse::<u64>()?;
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let size = match invocation.get_optional("size") {
+        Some(size) => size.parse::<usize>()?,
+        None => {
+            // A typical P2PKH transaction: one input, one output to the recipient, and one for change
+            let output = TxnOutput {
+                amount: 0,
+                lock_script: make_p2pkh_lock(&state.address),
+            };
+            let unsigned = UnsignedTransaction {
+                version: VERSION,
+                outputs: vec![output.clone(), output],
+                meta: String::from(""),
+            };
+
+            unsigned.vsize(1)
+        }
+    };
+
+    if size == 0 {
+        println!("Transaction size must be nonzero");
+        return Ok(());
+    }
 
-    let address = b58c_to_address(pkh)?;
+    let pending = state
+        .pending_txns
+        .iter()
+        .map(|t| FeeSample::new(t, state))
+        .collect::<Vec<FeeSample>>();
+    let recent = recent_block_samples(state);
 
-    state.friends.aliases.insert(address, name);
+    println!(
+        "Fee rate: {:.4} TsengCoin per byte for a {}B transaction",
+        fee as f64 / size as f64,
+        size
+    );
+
+    match estimate_confirmation(fee, size, &pending, &recent) {
+        None => println!(
+            "Unlikely to confirm: none of the last {} blocks would have included this transaction",
+            recent.len()
+        ),
+        Some(blocks) => println!("Estimated blocks until confirmation: {}", blocks),
+    }
+
+    println!("This assumes miners pick the highest fee rates first. Our own miner currently takes pending transactions in the order they arrived.");
 
     Ok(())
 }
 
-fn get_aliases(
+fn hashrate(
     _invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let mut guard = state.unwrap().lock().unwrap();
-    let state = &mut *guard;
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
 
-    for (addr, alias) in state.friends.aliases.iter() {
-        println!("{} -> {}", address_to_b58c(&addr.to_vec()), alias);
-    }
+    println!("Hashes per second: {}", state.hashes_per_second);
 
     Ok(())
 }
 
-fn set_exclusivity(
-    invocation: &CommandInvocation,
+fn miner_stats(
+    _invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let exclusivity = invocation
-        .get_field("exclusivity")
-        .unwrap()
-        .parse::<u64>()
-        .unwrap_or(u64::MAX);
-    let mut guard = state.unwrap().lock().unwrap();
-    let state = &mut *guard;
+    let guard = state.unwrap().lock().unwrap();
+    let counters = &guard.miner_counters;
 
-    state.friends.exclusivity = exclusivity;
+    if counters.mining_since.is_none() {
+        println!("The miner is not running");
+        return Ok(());
+    }
+
+    println!("Blocks found: {}", counters.blocks_found);
+    println!(
+        "Time spent mining: {}s",
+        counters.time_mining().num_seconds()
+    );
+
+    match counters.last_block_found {
+        None => println!("Last block found: never"),
+        Some(time) => println!("Last block found: {}", time),
+    };
 
     Ok(())
 }
 
-fn get_exclusivity(
+fn next_block_preview(
     _invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let mut guard = state.unwrap().lock().unwrap();
-    let state = &mut *guard;
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let (txns, fees) = pick_block_txns(state);
+    let coinbase_size = coinbase_size_estimate() + state.coinbase_msg.len();
+    let size = txns.iter().fold(coinbase_size, |a, t| a + t.size());
+    let coinbase_sigops = count_sigops(&make_p2pkh_lock(&state.address).code);
+    let sigops = txns.iter().fold(coinbase_sigops, |a, t| a + t.sigops() + spent_sigops(t, state));
+
+    for txn in &txns {
+        println!(
+            "{}: {}B, fee {}",
+            hex::encode(txn.hash),
+            txn.size(),
+            compute_fee(txn, state)
+        );
+    }
+
+    println!(
+        "{} of {} pending transactions would be included",
+        txns.len(),
+        state.pending_txns.len()
+    );
+    println!("Transaction size: {}B of {}B, including the coinbase transaction", size, MAX_TRANSACTION_FIELD_SIZE);
+    println!("Signature checks: {} of {}, including the coinbase transaction", sigops, MAX_BLOCK_SIGOPS);
+    println!("Total fees: {}", fees);
+    println!("Coinbase amount: {}", BLOCK_REWARD + fees);
 
-    println!("{} TsengCoin", state.friends.exclusivity);
     Ok(())
 }
 
-#[cfg(feature = "gui")]
-fn start_chat(
+fn connect_to(
     invocation: &CommandInvocation,
     state: Option<&Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
     let name = invocation.get_field("address").unwrap();
     let req_amount = invocation.get_field("req-amount").unwrap().parse::<u64>()?;
     let req_fee = invocation.get_field("fee").unwrap().parse::<u64>()?;
-    let message = invocation.get_field("message").unwrap();
     let mut guard = state.unwrap().lock().unwrap();
     let state = &mut *guard;
 
     let dest_address = state.friends.get_address(name)?;
-    let intent = ChainRequest::ChainChat(ChainChatReq { msg: message });
+    let connect_req = make_dh_connect_req(dest_address, req_amount, req_fee, None, state)?;
+    send_new_txn(connect_req, state)?;
+
+    Ok(())
+}
+
+fn rotate_key(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let name = invocation.get_field("address").unwrap();
+    let req_amount_opt = invocation.get_optional("req-amount");
+    let fee_opt = invocation.get_optional("fee");
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    let dest_address = state.friends.get_address(name)?;
+    let dest_name = state.friends.get_name(dest_address);
+
+    if !state.friends.is_connected(&dest_address) {
+        return Err(format!("No encrypted connection set up with {}. Use connect-to first", dest_name).into());
+    }
+
+    if state.friends.pending_dh.contains_key(&dest_address) {
+        return Err(format!("Already waiting for {} to finish a key exchange", dest_name).into());
+    }
+
+    let req_amount = req_amount_or_default(req_amount_opt, state)?;
+    let fee = match fee_opt {
+        Some(fee) => fee.parse::<u64>()?,
+        None => state.default_fee,
+    };
+
+    // The old keys are kept until the exchange completes, so chain requests can still be sent in the meantime
+    let rotate_req = make_dh_connect_req(dest_address, req_amount, fee, None, state)?;
+    send_new_txn(rotate_req, state)?;
+
+    println!("Started a key exchange with {}. The new keys will be used once they respond", dest_name);
+
+    Ok(())
+}
+
+fn alias(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let pkh = invocation.get_field("address").unwrap();
+    let name = invocation.get_field("name").unwrap();
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    let address = state.friends.get_address(pkh)?;
+
+    state.friends.aliases.insert(address, name);
+
+    Ok(())
+}
+
+fn sign_msg(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let msg = invocation.get_field("message").unwrap();
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let blob = sign_message(&msg, &state.keypair)?;
+
+    println!("{}", hex::encode(blob));
+
+    Ok(())
+}
+
+fn verify_msg(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let name = invocation.get_field("address").unwrap();
+    let blob = hex::decode(invocation.get_field("signature").unwrap())?;
+    let msg = invocation.get_field("message").unwrap();
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let address = state.friends.get_address(name)?;
+
+    match verify_message(&msg, address, &blob) {
+        Ok(()) => println!("Signature is valid"),
+        Err(err) => println!("Signature is not valid: {}", err),
+    };
+
+    Ok(())
+}
+
+fn get_aliases(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    for (addr, alias) in state.friends.aliases.iter() {
+        println!("{} -> {}", address_to_b58c(&addr.to_vec()), alias);
+    }
+
+    Ok(())
+}
+
+fn import_aliases(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let path = invocation.get_field("file").unwrap();
+    let contents = fs::read_to_string(&path)?;
+    let entries = parse_alias_file(&contents)?;
+
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    let (num_imported, skipped) = import_alias_entries(entries, &mut state.friends);
+
+    for reason in &skipped {
+        println!("Skipping {}", reason);
+    }
+
+    println!("Imported {} aliases from {}, skipped {}", num_imported, path, skipped.len());
+
+    Ok(())
+}
+
+/// Add each valid entry from [parse_alias_file] as an alias. Returns how many were added and why each of the
+/// others was skipped.
+fn import_alias_entries(
+    entries: Vec<(String, Result<(String, String), String>)>,
+    friends: &mut FriendState,
+) -> (usize, Vec<String>) {
+    let mut num_imported: usize = 0;
+    let mut skipped: Vec<String> = vec![];
+
+    for (location, entry) in entries {
+        let result = entry.and_then(|(addr, name)| {
+            let address = b58c_to_address(addr.clone()).map_err(|err| format!("{} is not a valid address: {}", addr, err))?;
+            friends.add_alias(address, name)
+        });
+
+        match result {
+            Ok(()) => num_imported += 1,
+            Err(err) => skipped.push(format!("{}: {}", location, err)),
+        }
+    }
+
+    (num_imported, skipped)
+}
+
+/// Read `address,name` pairs from an alias file, one per line. Blank lines and lines starting with `#` are ignored.
+/// A file that starts with `{` is read as a JSON object mapping addresses to names instead. Each entry comes
+/// with a description of where it was found so that malformed entries can be reported.
+fn parse_alias_file(contents: &str) -> Result<Vec<(String, Result<(String, String), String>)>, Box<dyn Error>> {
+    if contents.trim_start().starts_with('{') {
+        let map: HashMap<String, String> = serde_json::from_str(contents)?;
+        let mut entries = map.into_iter().collect::<Vec<(String, String)>>();
+        entries.sort();
+
+        return Ok(entries
+            .into_iter()
+            .map(|(addr, name)| {
+                let location = format!("entry for {}", addr);
+                match name.trim() {
+                    "" => (location, Err(String::from("The alias is empty"))),
+                    trimmed => (location, Ok((addr, trimmed.to_owned()))),
+                }
+            })
+            .collect());
+    }
+
+    Ok(contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            let location = format!("line {}", i + 1);
+            let entry = match line.split_once(',') {
+                None => Err(String::from("Expected address,name")),
+                Some((_, name)) if name.trim().is_empty() => Err(String::from("The alias is empty")),
+                Some((addr, name)) => Ok((addr.trim().to_owned(), name.trim().to_owned())),
+            };
+
+            (location, entry)
+        })
+        .collect())
+}
+
+fn get_pending_connections(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+    let pending = pending_connections(&state.friends);
+
+    println!("{} pending key exchanges", pending.len());
+
+    for (name, has_intent) in pending {
+        let intent = match has_intent {
+            true => "intent queued",
+            false => "no intent",
+        };
+
+        println!("{} ({})", name, intent);
+    }
+
+    Ok(())
+}
+
+/// The name of everyone we're waiting on to finish a key exchange, sorted, and whether a request is queued for them
+fn pending_connections(friends: &FriendState) -> Vec<(String, bool)> {
+    let mut out = friends
+        .pending_dh
+        .keys()
+        .map(|addr| (friends.get_name(*addr), friends.intents.contains_key(addr)))
+        .collect::<Vec<(String, bool)>>();
+    out.sort();
+
+    out
+}
+
+fn inspect_enc(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let hash = hex_to_hash(&invocation.get_field("txn-hash").unwrap())?;
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let txn = state
+        .get_pending_or_confirmed_txn(hash)
+        .or_else(|| state.get_orphan_txn(hash))
+        .ok_or_else(|| format!("Transaction {} not found", hex::encode(hash)))?;
+
+    let info = match inspect_enc_req(&txn, state) {
+        None => {
+            println!("Encrypted request: no");
+            return Ok(());
+        }
+        Some(info) => info,
+    };
+    let name = |addr: Option<Address>| match addr {
+        None => String::from("unknown"),
+        Some(addr) => state.friends.get_name(addr),
+    };
+
+    println!("Encrypted request: yes");
+    println!("Sender: {}", name(info.sender));
+    println!("Recipient: {}", name(info.recipient));
+    println!("To me: {}", info.recipient == Some(state.address));
+
+    match (info.counter, info.ciphertext_len) {
+        (Some(counter), Some(len)) => {
+            println!("Message counter: {}", counter);
+            println!("Ciphertext: {} bytes", len);
+        }
+        _ => println!("Request data could not be decoded"),
+    }
+
+    Ok(())
+}
+
+fn set_exclusivity(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let exclusivity = invocation
+        .get_field("exclusivity")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or(u64::MAX);
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    state.friends.exclusivity = exclusivity;
+
+    Ok(())
+}
+
+fn get_exclusivity(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    println!("{} TsengCoin", state.friends.exclusivity);
+    Ok(())
+}
+
+fn set_chain_req_amount(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let amount = invocation.get_field("amount").unwrap().parse::<u64>()?;
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    // Chain requests with a zero output would be rejected as invalid transactions
+    if amount == 0 {
+        return Err("Chain request amount must be at least 1 TsengCoin".into());
+    }
+
+    state.friends.chain_req_amount = amount;
+
+    Ok(())
+}
+
+/// The amount to send with a chain request: the `req-amount` optional if given, otherwise the amount set with
+/// `set-chain-req-amount`
+fn req_amount_or_default(req_amount_opt: Option<String>, state: &State) -> Result<u64, Box<dyn Error>> {
+    match req_amount_opt {
+        Some(amount) => Ok(amount.parse::<u64>()?),
+        None => Ok(state.friends.chain_req_amount),
+    }
+}
+
+fn get_chain_req_amount(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    println!("{} TsengCoin", state.friends.chain_req_amount);
+    Ok(())
+}
+
+fn set_connection_policy(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let accept = match invocation.get_field("policy").unwrap().as_str() {
+        "accept" => true,
+        "reject" => false,
+        other => return Err(format!("Unknown connection policy: {}. Use accept or reject", other).into()),
+    };
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    state.friends.fallback_accept_connections = accept;
+
+    Ok(())
+}
+
+fn get_connection_policy(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    match state.friends.fallback_accept_connections {
+        true => println!("accept"),
+        false => println!("reject"),
+    };
+
+    Ok(())
+}
+
+#[cfg(feature = "gui")]
+fn start_chat(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let name = invocation.get_field("address").unwrap();
+    let req_amount = invocation.get_field("req-amount").unwrap().parse::<u64>()?;
+    let req_fee = invocation.get_field("fee").unwrap().parse::<u64>()?;
+    let message = invocation.get_field("message").unwrap();
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    let dest_address = state.friends.get_address(name)?;
+    let intent = ChainRequest::ChainChat(ChainChatReq { msg: message });
 
     if is_gui_only(&intent) && !state.has_gui() {
         println!("Chat requests can only be made if TsengCoin is running with a GUI. See the `connect` command for more info.");
@@ -381,8 +1151,462 @@ fn start_chat(
     Ok(())
 }
 
+#[cfg(feature = "gui")]
+fn chat(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let name = invocation.get_field("address").unwrap();
+    let message = invocation.get_field("message").unwrap();
+    let req_amount_opt = invocation.get_optional("req-amount");
+    let fee_opt = invocation.get_optional("fee");
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    if !state.has_gui() {
+        println!("Chat requests can only be made if TsengCoin is running with a GUI. See the `connect` command for more info.");
+        return Ok(());
+    }
+
+    let dest_address = state.friends.get_address(name)?;
+    let req_amount = req_amount_or_default(req_amount_opt, state)?;
+    let fee = match fee_opt {
+        Some(fee) => fee.parse::<u64>()?,
+        None => state.default_fee,
+    };
+
+    let (txn, queued) = make_chat_req(dest_address, message, req_amount, fee, state)?;
+    send_new_txn(txn, state)?;
+
+    if queued {
+        println!(
+            "Started a key exchange with {}. The message will be sent when it completes",
+            state.friends.get_name(dest_address)
+        );
+    } else {
+        println!("Sent message to {}", state.friends.get_name(dest_address));
+    }
+
+    Ok(())
+}
+
+fn export_chain(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let path = invocation.get_field("file").unwrap();
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let json = serde_json::to_string_pretty(&state.blockchain.blocks)?;
+    fs::write(&path, json)?;
+
+    println!("Exported {} blocks to {}", state.blockchain.blocks.len(), path);
+
+    Ok(())
+}
+
+fn export_peer_graph(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let path = invocation.get_field("file").unwrap();
+    let format = invocation.get_optional("format").unwrap_or_else(|| String::from("dot"));
+    let guard = state.unwrap().lock().unwrap();
+    let state = &*guard;
+
+    let addr_me = state.remote_addr_me.unwrap_or(state.local_addr_me);
+    let graph = state.network.peer_graph(addr_me);
+
+    let out = match format.as_str() {
+        "dot" => peer_graph_to_dot(&graph),
+        "json" => peer_graph_to_json(&graph)?,
+        other => return Err(format!("Unknown graph format: {}. Use dot or json", other).into()),
+    };
+
+    fs::write(&path, out)?;
+
+    println!("Exported {} nodes to {}", graph.len(), path);
+
+    Ok(())
+}
+
+fn import_chain(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let path = invocation.get_field("file").unwrap();
+    let json = fs::read_to_string(&path)?;
+    let blocks: Vec<Block> = serde_json::from_str(&json)?;
+
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    let mut num_added: usize = 0;
+
+    for block in blocks {
+        // Skip the genesis block and any other blocks we already have
+        if state.blockchain.get_block(block.header.hash).is_some() {
+            continue;
+        }
+
+        let hash = block.header.hash;
+
+        // An exported chain can be much older than the timestamp tolerance
+        match verify_historical_block(block, state) {
+            Ok(BlockStatus::Added) => num_added += 1,
+            Ok(BlockStatus::MissingParent(parent)) => {
+                println!(
+                    "Block {} is an orphan: missing parent {}. Stopping import",
+                    hex::encode(hash),
+                    hex::encode(parent)
+                );
+                break;
+            }
+            Err(err) => {
+                println!("Block {} is invalid: {}. Stopping import", hex::encode(hash), err);
+                break;
+            }
+        };
+    }
+
+    state.resolve_forks();
+
+    let (best_height, _, _) = state.blockchain.best_chain();
+    println!("Imported {} blocks. Best height is now {}", num_added, best_height);
+
+    Ok(())
+}
+
+fn resolve_forks(
+    _invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut guard = state.unwrap().lock().unwrap();
+
+    println!("{}", resolve_forks_report(&mut guard));
+
+    Ok(())
+}
+
+/// Resolve forks and describe what happened
+fn resolve_forks_report(state: &mut State) -> String {
+    let old_top = state.blockchain.top_hash(0);
+
+    let returned = match state.resolve_forks() {
+        None => return String::from("There were no forks to resolve"),
+        Some(hashes) => hashes,
+    };
+
+    let new_top = state.blockchain.top_hash(0);
+
+    let mut out = match old_top == new_top {
+        true => String::from("Forks resolved. The main chain is still the best chain"),
+        false => format!(
+            "Forks resolved with a reorg. New top of the main chain: {}",
+            hex::encode(new_top)
+        ),
+    };
+
+    out.push_str(&format!("\n{} transactions returned to the pending pool", returned.len()));
+
+    for hash in returned {
+        out.push_str(&format!("\n\t{}", hex::encode(hash)));
+    }
+
+    out
+}
+
+/// Throw away the main chain above a height that we trust and download the rest again from the peer
+/// with the best chain. The blocks are downloaded before anything is thrown away, so nothing is lost if the
+/// download fails.
+fn resync_from(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let height = invocation.get_field("height").unwrap().parse::<usize>()?;
+    let state_mut = state.unwrap();
+
+    // Don't hold the lock while downloading
+    let (addr, best_hash, trusted_hash) = {
+        let guard = state_mut.lock().unwrap();
+        let state = &*guard;
+
+        if height == 0 || height > state.blockchain.blocks.len() {
+            return Err(format!("Height must be between 1 and {}", state.blockchain.blocks.len()).into());
+        }
+
+        let best_node = match state.network.most_updated_node() {
+            None => return Err("No suitable nodes to download blocks from".into()),
+            Some(node) => node,
+        };
+
+        (best_node.addr, best_node.best_hash.unwrap(), state.blockchain.blocks[height - 1].header.hash)
+    };
+
+    let blocks = download_blocks_after(&addr, best_hash, trusted_hash)?;
+
+    let mut guard = state_mut.lock().unwrap();
+    let state = &mut *guard;
+
+    if state.blockchain.blocks.get(height - 1).map(|b| b.header.hash) != Some(trusted_hash) {
+        return Err("The main chain changed during the download. Try again".into());
+    }
+
+    let (num_removed, returned) = state.truncate_main_chain(height)?;
+
+    println!("Removed {} blocks", num_removed);
+    println!("{} transactions returned to the pending pool", returned.len());
+
+    let mut num_added: usize = 0;
+
+    for block in blocks {
+        let hash = block.header.hash;
+
+        match verify_historical_block(block, state) {
+            Ok(BlockStatus::Added) => num_added += 1,
+            Ok(BlockStatus::MissingParent(_)) => {
+                println!("Downloaded block {} is an orphan. Stopping", hex::encode(hash));
+                break;
+            }
+            Err(err) => {
+                println!("Downloaded block {} is invalid: {}. Stopping", hex::encode(hash), err);
+                break;
+            }
+        };
+    }
+
+    state.compute_balance();
+    println!("Downloaded {} blocks. Main chain height is now {}", num_added, state.blockchain.blocks.len());
+
+    Ok(())
+}
+
+/// Throw away our copy of a block and download it again from a peer. Only the tip of a chain can be
+/// refetched, because every block after it depends on it.
+fn refetch_block(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let hash = hex_to_hash(&invocation.get_field("hash").unwrap())?;
+    let state_mut = state.unwrap();
+
+    // Don't hold the lock while waiting on our peers
+    let (prev_hash, chain_idx, peers) = {
+        let guard = state_mut.lock().unwrap();
+        let state = &*guard;
+        let (prev_hash, chain_idx) = refetchable_tip(state, hash)?;
+
+        (prev_hash, chain_idx, state.network.peer_addrs())
+    };
+
+    // Get a new copy before throwing away the old one, so we don't lose the block if no peer has it
+    // The peer might have the block on a different chain than we do
+    let chain_hints = match chain_idx {
+        0 => vec![0],
+        i => vec![0, i],
+    };
+    let mut fetched: Option<Block> = None;
+
+    for addr in peers {
+        for chain_hint in &chain_hints {
+            match get_blocks_range(&addr, *chain_hint, prev_hash, 1) {
+                Ok(blocks) if !blocks.is_empty() && blocks[0].header.hash == hash => {
+                    fetched = Some(blocks[0].clone());
+                    break;
+                }
+                Ok(_) => (),
+                Err(err) => println!("Failed to get block from {}: {}", addr, err),
+            }
+        }
+
+        if fetched.is_some() {
+            break;
+        }
+    }
+
+    let block = match fetched {
+        None => return Err("None of our peers sent back the block".into()),
+        Some(block) => block,
+    };
+
+    // Make sure the new copy is really the block we asked for before we throw ours away. Everything else is
+    // checked when it's verified
+    let raw_header: RawBlockHeader = (&block.header).into();
+    if hash_block_header(&raw_header) != hash || make_merkle_root(&block.transactions) != block.header.merkle_root {
+        return Err("The peer sent back a bad copy of the block".into());
+    }
+
+    let mut guard = state_mut.lock().unwrap();
+    let state = &mut *guard;
+
+    // The chain may have changed while we were waiting
+    let (_, chain_idx) = refetchable_tip(state, hash)?;
+    let old_block = remove_chain_tip(state, chain_idx).unwrap();
+
+    // The block can be older than the timestamp tolerance, so it's verified like any other block we asked for
+    let err = match verify_historical_block(block, state) {
+        Ok(BlockStatus::Added) => {
+            println!("Refetched block {}", hex::encode(hash));
+            return Ok(());
+        }
+        Ok(BlockStatus::MissingParent(_)) => String::from("Refetched block is an orphan"),
+        Err(err) => format!("Refetched block is invalid: {}", err),
+    };
+
+    // Put our old copy back if it's still valid
+    match verify_historical_block(old_block, state) {
+        Ok(BlockStatus::Added) => println!("Kept our old copy of the block"),
+        _ => println!("Our old copy of the block is invalid too, so it was removed"),
+    };
+
+    Err(err.into())
+}
+
+/// Make sure that a block can be refetched, and return its previous hash and the index of its chain
+fn refetchable_tip(state: &State, hash: Hash256) -> Result<(Hash256, usize), Box<dyn Error>> {
+    let (prev_hash, chain_idx, pos) = match state.blockchain.get_block(hash) {
+        None => return Err("We don't have that block".into()),
+        Some((block, chain_idx, pos)) => (block.header.prev_hash, chain_idx, pos),
+    };
+
+    let chain_len = match chain_idx {
+        0 => state.blockchain.blocks.len(),
+        i => state.blockchain.forks[i - 1].blocks.len(),
+    };
+
+    if chain_idx == 0 && pos == 0 {
+        return Err("The genesis block can't be refetched".into());
+    }
+
+    if pos + 1 != chain_len {
+        return Err("Only the block at the tip of a chain can be refetched".into());
+    }
+
+    if chain_idx == 0 && state.blockchain.forks.iter().any(|f| f.prev_index == pos) {
+        return Err("A fork branches off of this block, so it can't be refetched".into());
+    }
+
+    Ok((prev_hash, chain_idx))
+}
+
+fn test_mempool_accept(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let txn_bytes = hex::decode(invocation.get_field("txn-hex").unwrap())?;
+    let txn: Transaction = bincode::deserialize(&txn_bytes)?;
+    let guard = state.unwrap().lock().unwrap();
+
+    println!("{}", mempool_accept_verdict(txn, &guard));
+
+    Ok(())
+}
+
+/// What would happen to a transaction if it were sent to us, without changing any state
+fn mempool_accept_verdict(txn: Transaction, state: &State) -> String {
+    let hash = hex::encode(txn.hash);
+
+    match verify_pending_transaction(txn, state) {
+        Ok(TxnStatus::Valid) => format!("Transaction {} would be accepted", hash),
+        Ok(TxnStatus::MissingInput(input)) => format!(
+            "Transaction {} would be accepted as an orphan: missing input {}",
+            hash,
+            hex::encode(input)
+        ),
+        Err(err) => format!("Transaction {} would be rejected: {}", hash, rejection_reason(&err)),
+    }
+}
+
+fn send_raw_txn(
+    invocation: &CommandInvocation,
+    state: Option<&Mutex<State>>,
+) -> Result<(), Box<dyn Error>> {
+    let txn_bytes = hex::decode(invocation.get_field("txn-hex").unwrap())?;
+    let txn: Transaction = bincode::deserialize(&txn_bytes)?;
+    let force = invocation.get_flag("force");
+    let mut guard = state.unwrap().lock().unwrap();
+    let state = &mut *guard;
+
+    let hash = hex::encode(txn.hash);
+
+    if state.pending_txns.contains(&txn) || state.orphan_txns.contains(&txn) {
+        println!("We already have transaction {}", hash);
+        return Ok(());
+    }
+
+    match verify_pending_transaction(txn.clone(), state) {
+        Ok(TxnStatus::Valid) => state.add_pending_txn(txn.clone()),
+        Ok(TxnStatus::MissingInput(input)) => {
+            println!(
+                "Transaction {} is an orphan: missing input {}",
+                hash,
+                hex::encode(input)
+            );
+            state.orphan_txns.push(txn.clone());
+        }
+        // Peers that haven't seen the pending transaction it conflicts with might still take it, but no one will take
+        // a transaction that breaks a rule
+        Err(err) if force && err.is_mempool_conflict() => {
+            println!("Transaction {} would be rejected: {}", hash, rejection_reason(&err));
+            println!("Sending it anyway. It won't be added to our pending pool");
+        }
+        Err(err) => {
+            return Err(format!("Transaction {} was rejected: {}", hash, rejection_reason(&err)).into());
+        }
+    };
+
+    let (accepted, failed) = send_new_txn(txn, state)?;
+
+    if accepted == 0 {
+        println!("Warning: Transaction was not received by any peers ({} failed)", failed);
+    } else {
+        println!("Successfully submitted transaction {} to {} peers", hash, accepted);
+    }
+
+    Ok(())
+}
+
+/// Explain why a transaction was rejected, making it clear whether it conflicts with a pending transaction
+/// or breaks a rule
+fn rejection_reason(err: &TxnVerifyError) -> String {
+    if err.is_mempool_conflict() {
+        format!("conflicts with a pending transaction (error {}): {}", err.code(), err)
+    } else {
+        format!("invalid (error {}): {}", err.code(), err)
+    }
+}
+
 pub fn listen_for_commands(state_mut: &Mutex<State>) {
-    let mut command_map = HashMap::new();
+    let command_map = make_session_command_map();
+    let mut buffer = String::new();
+    let stdin = std::io::stdin();
+
+    loop {
+        let res = stdin.read_line(&mut buffer);
+
+        if res.is_err() {
+            println!("Error reading command: {:?}", res.err());
+            continue;
+        }
+
+        let args: Vec<&str> = buffer.trim().split(' ').collect();
+
+        if args.is_empty() {
+            println!("Need to supply a command");
+            continue;
+        }
+
+        let cmd_args = args.to_vec().iter().map(|&s| s.into()).collect();
+
+        dispatch_command(&cmd_args, &command_map, Some(state_mut));
+        buffer.clear();
+    }
+}
+
+/// All of the commands that can be run while the client is running
+pub fn make_session_command_map<'a>() -> CommandMap<&'a Mutex<State>> {
+    let mut command_map: CommandMap<&Mutex<State>> = HashMap::new();
     let getpeerinfo_cmd: Command<&Mutex<State>> = Command {
         processor: getpeerinfo,
         expected_fields: vec![],
@@ -422,6 +1646,18 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("Get the transaction with the given hash"),
     };
+    let getrawmempool_cmd: Command<&Mutex<State>> = Command {
+        processor: getrawmempool,
+        expected_fields: vec![],
+        flags: vec![
+            Flag::new(
+                "orphans",
+                "Also list orphan transactions. Their fees aren't shown because their inputs are missing"
+            )
+        ],
+        optionals: vec![],
+        desc: String::from("List the hash, fee, and size of every pending transaction"),
+    };
     let blockchain_stats_cmd: Command<&Mutex<State>> = Command {
         processor: blockchain_stats,
         expected_fields: vec![],
@@ -429,6 +1665,20 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("Get some info about the current state of the blockchain"),
     };
+    let getforks_cmd: Command<&Mutex<State>> = Command {
+        processor: getforks,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("List each fork with its starting index, length, tip hash, and cumulative difficulty target. Lower targets mean more work"),
+    };
+    let getchaintips_cmd: Command<&Mutex<State>> = Command {
+        processor: getchaintips,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("List the tip of the main chain and every fork with its height, branch length, and status"),
+    };
     let balance_p2pkh_cmd: Command<&Mutex<State>> = Command {
         processor: balance_p2pkh,
         expected_fields: vec![],
@@ -449,21 +1699,76 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
                 FieldType::Pos(1),
                 "The amount of TsengCoin you want to send"
             ),
-            Field::new(
+            Field::new_condition(
                 "fee",
                 FieldType::Pos(2),
-                "The transaction fee you will pay, must be nonzero"
+                "The transaction fee you will pay, must be nonzero",
+                Condition::new(
+                    "fee-rate",
+                    "Pay a fee rate in TsengCoin per byte instead of an absolute fee"
+                )
             )
         ],
         flags: vec![
             Flag::new(
                 "show-structure",
                 "Show the structure of the transaction after it is created"
+            ),
+            Flag::new(
+                "force",
+                "Send the transaction even if the fee looks too high"
+            )
+        ],
+        optionals: vec![
+            VarField::new(
+                "fee-rate",
+                "Pay a fee rate in TsengCoin per byte instead of an absolute fee"
             )
         ],
-        optionals: vec![],
         desc: String::from("Send a recipient TsengCoins in a P2PKH transaction. This is the most widely used style of transaction")
     };
+    let check_orphans_cmd: Command<&Mutex<State>> = Command {
+        processor: check_orphans_cmd,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Try to add orphan blocks to the blockchain and move orphan transactions into the pending pool. Reports how many were resolved and how many are still orphans."),
+    };
+    let rebroadcast_mempool_cmd: Command<&Mutex<State>> = Command {
+        processor: rebroadcast_mempool,
+        expected_fields: vec![],
+        flags: vec![Flag::new(
+            "all",
+            "Rebroadcast every pending transaction, not just the ones you sent",
+        )],
+        optionals: vec![],
+        desc: String::from("Send your pending transactions to your peers again, in case they didn't propagate the first time. Reports how many peers received each one"),
+    };
+    let save_cmd: Command<&Mutex<State>> = Command {
+        processor: save,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Save the blockchain, the orphan pools, and friend settings to the data directory. They are loaded again on startup."),
+    };
+    let estimate_confirmation_cmd: Command<&Mutex<State>> = Command {
+        processor: estimate_confirmation_cmd,
+        expected_fields: vec![
+            Field::new(
+                "fee",
+                FieldType::Pos(0),
+                "The transaction fee you would pay"
+            )
+        ],
+        flags: vec![],
+        optionals: vec![
+            VarField::new(
+                "size",
+                "Size of the transaction in bytes. Defaults to the size of a typical P2PKH transaction"
+            )
+        ],
+        desc: String::from("Estimate how many blocks it will take for a transaction with the given fee to be confirmed, based on recent blocks and pending transactions")
+    };
     let hashrate_cmd: Command<&Mutex<State>> = Command {
         processor: hashrate,
         expected_fields: vec![],
@@ -471,6 +1776,20 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("Get the hashrate of the miner, if it's running."),
     };
+    let miner_stats_cmd: Command<&Mutex<State>> = Command {
+        processor: miner_stats,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Get the number of blocks mined, time spent mining, and when the last block was found."),
+    };
+    let next_block_preview_cmd: Command<&Mutex<State>> = Command {
+        processor: next_block_preview,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Show which pending transactions the miner would put in the next block, their total fees, and the coinbase amount, without mining"),
+    };
     let connect_to_cmd: Command<&Mutex<State>> = Command {
         processor: connect_to,
         expected_fields: vec![
@@ -494,13 +1813,33 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("Initiate a request to connect to the node owning the given address and start an encrypted session")
     };
+    let rotate_key_cmd: Command<&Mutex<State>> = Command {
+        processor: rotate_key,
+        expected_fields: vec![Field::new(
+            "address",
+            FieldType::Pos(0),
+            "The address or alias to rotate keys with. You must already have an encrypted connection with them"
+        )],
+        flags: vec![],
+        optionals: vec![
+            VarField::new(
+                "req-amount",
+                "TsengCoin to send with the key exchange request. Defaults to the chain request amount setting"
+            ),
+            VarField::new(
+                "fee",
+                "Transaction fee. Defaults to the default fee setting"
+            )
+        ],
+        desc: String::from("Replace the keys for an encrypted connection by doing a new key exchange. The other party accepts automatically because they already accepted a connection from you")
+    };
     let alias_cmd: Command<&Mutex<State>> = Command {
         processor: alias,
         expected_fields: vec![
             Field::new(
                 "address",
                 FieldType::Pos(0),
-                "The address to give an alias to",
+                "The address to give an alias to. Can also be an existing alias, which renames it",
             ),
             Field::new("name", FieldType::Pos(1), "The name/alias for the address"),
         ],
@@ -508,6 +1847,36 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("Give a name to an address whose owner you know"),
     };
+    let sign_message_cmd: Command<&Mutex<State>> = Command {
+        processor: sign_msg,
+        expected_fields: vec![Field::new(
+            "message",
+            FieldType::Spaces(0),
+            "The message to sign",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Sign a message with your private key. The signature includes your address and public key, so anyone can check it with verify-message"),
+    };
+    let verify_message_cmd: Command<&Mutex<State>> = Command {
+        processor: verify_msg,
+        expected_fields: vec![
+            Field::new(
+                "address",
+                FieldType::Pos(0),
+                "The address or alias that supposedly signed the message",
+            ),
+            Field::new(
+                "signature",
+                FieldType::Pos(1),
+                "The signature in hex, as printed by sign-message",
+            ),
+            Field::new("message", FieldType::Spaces(2), "The message that was signed"),
+        ],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Check that a message was signed by the owner of an address"),
+    };
     let get_aliases_cmd: Command<&Mutex<State>> = Command {
         processor: get_aliases,
         expected_fields: vec![],
@@ -515,6 +1884,35 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("List all aliases"),
     };
+    let import_aliases_cmd: Command<&Mutex<State>> = Command {
+        processor: import_aliases,
+        expected_fields: vec![Field::new(
+            "file",
+            FieldType::Spaces(0),
+            "A file with one address,name pair per line, or a JSON object mapping addresses to names",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Add aliases in bulk from a file. Malformed entries, addresses that already have an alias, and names that are already taken are skipped and reported"),
+    };
+    let get_pending_connections_cmd: Command<&Mutex<State>> = Command {
+        processor: get_pending_connections,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("List addresses that we started a key exchange with but that haven't responded yet, and whether a request is waiting to be sent to them when they do"),
+    };
+    let inspect_enc_req_cmd: Command<&Mutex<State>> = Command {
+        processor: inspect_enc,
+        expected_fields: vec![Field::new(
+            "txn-hash",
+            FieldType::Pos(0),
+            "The hash of the transaction to inspect",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Show whether a transaction is an encrypted chain request, who sent it, and whether it's to you, without decrypting it"),
+    };
     let set_exclusivity_cmd: Command<&Mutex<State>> = Command {
         processor: set_exclusivity,
         expected_fields: vec![Field::new(
@@ -535,6 +1933,136 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         optionals: vec![],
         desc: String::from("Print your current exclusivity"),
     };
+    let set_chain_req_amount_cmd: Command<&Mutex<State>> = Command {
+        processor: set_chain_req_amount,
+        expected_fields: vec![Field::new(
+            "amount",
+            FieldType::Pos(0),
+            "How many TsengCoins to send with a chain request when you don't give an amount"
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from(
+            "Set the default amount of TsengCoin sent with chain requests, like the key exchanges started by chat and rotate-key. Saved with your other settings"
+        )
+    };
+    let get_chain_req_amount_cmd: Command<&Mutex<State>> = Command {
+        processor: get_chain_req_amount,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Print the default amount of TsengCoin sent with chain requests"),
+    };
+    let set_connection_policy_cmd: Command<&Mutex<State>> = Command {
+        processor: set_connection_policy,
+        expected_fields: vec![Field::new(
+            "policy",
+            FieldType::Pos(0),
+            "accept or reject"
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from(
+            "Set whether to accept direct connection requests when there's no GUI to ask you. Saved with the `save` command."
+        )
+    };
+    let get_connection_policy_cmd: Command<&Mutex<State>> = Command {
+        processor: get_connection_policy,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Print whether direct connection requests are accepted when there's no GUI to ask you"),
+    };
+    let export_chain_cmd: Command<&Mutex<State>> = Command {
+        processor: export_chain,
+        expected_fields: vec![Field::new(
+            "file",
+            FieldType::Spaces(0),
+            "The file to write the blockchain to. The file will be created if it doesn't exist",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Write the main chain to a file as JSON, including block headers and transactions"),
+    };
+    let export_peer_graph_cmd: Command<&Mutex<State>> = Command {
+        processor: export_peer_graph,
+        expected_fields: vec![Field::new(
+            "file",
+            FieldType::Spaces(0),
+            "The file to write the graph to. The file will be created if it doesn't exist",
+        )],
+        flags: vec![],
+        optionals: vec![VarField::new(
+            "format",
+            "dot for a Graphviz graph, or json for an object mapping each address to its neighbors. Defaults to dot",
+        )],
+        desc: String::from("Write your peers and the neighbors each of them last reported to a file, so you can draw the network around you"),
+    };
+    let import_chain_cmd: Command<&Mutex<State>> = Command {
+        processor: import_chain,
+        expected_fields: vec![Field::new(
+            "file",
+            FieldType::Spaces(0),
+            "A file created with `export-chain`",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Load blocks from a JSON file created with `export-chain`. Each block is verified before it's added, and the import stops at the first bad block"),
+    };
+    let resolve_forks_cmd: Command<&Mutex<State>> = Command {
+        processor: resolve_forks,
+        expected_fields: vec![],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Try to resolve forks now. Reports whether the main chain changed and which transactions went back to the pending pool"),
+    };
+    let resync_from_cmd: Command<&Mutex<State>> = Command {
+        processor: resync_from,
+        expected_fields: vec![Field::new(
+            "height",
+            FieldType::Pos(0),
+            "The height of the last block to keep. The genesis block is at height 1",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Throw away every main chain block above the given height and download them again. Transactions from removed blocks go back into the pending pool"),
+    };
+    let refetch_block_cmd: Command<&Mutex<State>> = Command {
+        processor: refetch_block,
+        expected_fields: vec![Field::new(
+            "hash",
+            FieldType::Pos(0),
+            "Hash of the block to refetch",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Remove our copy of a block at the tip of a chain, download it again from a peer, and verify it"),
+    };
+    let test_mempool_accept_cmd: Command<&Mutex<State>> = Command {
+        processor: test_mempool_accept,
+        expected_fields: vec![Field::new(
+            "txn-hex",
+            FieldType::Pos(0),
+            "A serialized transaction in hex",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Check if a transaction would be accepted into the pending pool without adding it or sending it to anyone"),
+    };
+    let send_raw_txn_cmd: Command<&Mutex<State>> = Command {
+        processor: send_raw_txn,
+        expected_fields: vec![Field::new(
+            "txn-hex",
+            FieldType::Pos(0),
+            "A serialized transaction in hex",
+        )],
+        flags: vec![Flag::new(
+            "force",
+            "Send the transaction to peers even if it conflicts with a pending transaction. It still won't be added to our pending pool",
+        )],
+        optionals: vec![],
+        desc: String::from("Verify a transaction built elsewhere, add it to the pending pool, and send it to peers. Conflicts with pending transactions are reported separately from invalid transactions"),
+    };
     #[cfg(feature = "gui")]
     let start_chat_cmd: Command<&Mutex<State>> = Command {
         processor: start_chat,
@@ -566,22 +2094,81 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
             "Send a chain request to another address to start a chat session"
         )
     };
+    #[cfg(feature = "gui")]
+    let chat_cmd: Command<&Mutex<State>> = Command {
+        processor: chat,
+        expected_fields: vec![
+            Field::new(
+                "address",
+                FieldType::Pos(0),
+                "The address or alias to chat with"
+            ),
+            Field::new(
+                "message",
+                FieldType::Spaces(1),
+                "The message to send"
+            )
+        ],
+        flags: vec![],
+        optionals: vec![
+            VarField::new(
+                "req-amount",
+                "TsengCoin to send with the key exchange request, if one is needed. Defaults to the chain request amount setting"
+            ),
+            VarField::new(
+                "fee",
+                "Transaction fee. Defaults to the default fee setting"
+            )
+        ],
+        desc: String::from(
+            "Send a chat message to another address. If you haven't exchanged keys with them yet, this starts a key exchange and sends the message once it completes"
+        )
+    };
 
     command_map.insert(String::from("getpeerinfo"), getpeerinfo_cmd);
     command_map.insert(String::from("getknowninfo"), getknowninfo_cmd);
     command_map.insert(String::from("getblock"), getblock_cmd);
     command_map.insert(String::from("gettxn"), gettxn_cmd);
+    command_map.insert(String::from("getrawmempool"), getrawmempool_cmd);
     command_map.insert(String::from("blockchain-stats"), blockchain_stats_cmd);
+    command_map.insert(String::from("getforks"), getforks_cmd);
+    command_map.insert(String::from("getchaintips"), getchaintips_cmd);
     command_map.insert(String::from("balance-p2pkh"), balance_p2pkh_cmd);
     command_map.insert(String::from("send-coins-p2pkh"), send_coins_p2pkh_cmd);
+    command_map.insert(String::from("check-orphans"), check_orphans_cmd);
+    command_map.insert(String::from("save"), save_cmd);
+    command_map.insert(String::from("rebroadcast-mempool"), rebroadcast_mempool_cmd);
+    command_map.insert(String::from("estimate-confirmation"), estimate_confirmation_cmd);
     command_map.insert(String::from("hashrate"), hashrate_cmd);
+    command_map.insert(String::from("miner-stats"), miner_stats_cmd);
+    command_map.insert(String::from("next-block-preview"), next_block_preview_cmd);
     command_map.insert(String::from("connect-to"), connect_to_cmd);
+    command_map.insert(String::from("rotate-key"), rotate_key_cmd);
     command_map.insert(String::from("alias"), alias_cmd);
     command_map.insert(String::from("get-aliases"), get_aliases_cmd);
+    command_map.insert(String::from("import-aliases"), import_aliases_cmd);
+    command_map.insert(String::from("sign-message"), sign_message_cmd);
+    command_map.insert(String::from("verify-message"), verify_message_cmd);
+    command_map.insert(String::from("get-pending-connections"), get_pending_connections_cmd);
+    command_map.insert(String::from("inspect-enc-req"), inspect_enc_req_cmd);
     command_map.insert(String::from("set-exclusivity"), set_exclusivity_cmd);
     command_map.insert(String::from("get-exclusivity"), get_exclusivity_cmd);
+    command_map.insert(String::from("set-chain-req-amount"), set_chain_req_amount_cmd);
+    command_map.insert(String::from("get-chain-req-amount"), get_chain_req_amount_cmd);
+    command_map.insert(String::from("set-connection-policy"), set_connection_policy_cmd);
+    command_map.insert(String::from("get-connection-policy"), get_connection_policy_cmd);
+    command_map.insert(String::from("export-chain"), export_chain_cmd);
+    command_map.insert(String::from("import-chain"), import_chain_cmd);
+    command_map.insert(String::from("export-peer-graph"), export_peer_graph_cmd);
+    command_map.insert(String::from("test-mempool-accept"), test_mempool_accept_cmd);
+    command_map.insert(String::from("send-raw-txn"), send_raw_txn_cmd);
+    command_map.insert(String::from("resolve-forks"), resolve_forks_cmd);
+    command_map.insert(String::from("refetch-block"), refetch_block_cmd);
+    command_map.insert(String::from("resync-from"), resync_from_cmd);
     #[cfg(feature = "gui")]
     command_map.insert(String::from("start-chat"), start_chat_cmd);
+    #[cfg(feature = "gui")]
+    command_map.insert(String::from("chat"), chat_cmd);
 
     // Include debug commands if the feature is enabled
     #[cfg(feature = "debug")]
@@ -592,27 +2179,555 @@ pub fn listen_for_commands(state_mut: &Mutex<State>) {
         }
     }
 
-    let mut buffer = String::new();
-    let stdin = std::io::stdin();
+    command_map
+}
 
-    loop {
-        let res = stdin.read_line(&mut buffer);
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+    use x25519_dalek::EphemeralSecret;
+
+    use super::*;
+    use crate::difficulty::get_difficulty_bits;
+    use crate::v1::{
+        block_verify::verify_block,
+        chain_request::make_encrypted_chain_req,
+        encrypted_msg::{decompose_enc_req, ChainRequest, FindMeAtReq, Ratchet},
+        net::ConnectionPool,
+        sim_net::{wait_until, SimNode},
+        test_util::{
+            add_blocks_after, add_old_blocks, make_block, next_block, pay, sign_p2pkh, temp_data_dir, test_genesis,
+            test_state,
+        },
+        transaction::compute_output_sum,
+    };
 
-        if res.is_err() {
-            println!("Error reading command: {:?}", res.err());
-            continue;
+    /// A state that has mined one block, so it has [BLOCK_REWARD] to spend
+    fn funded_state() -> State {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        state
+    }
+
+    fn invocation(fields: &[(&str, &str)]) -> CommandInvocation {
+        CommandInvocation {
+            name: String::new(),
+            flags: vec![],
+            args: vec![],
+            vars: HashMap::new(),
+            fields: fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            optionals: HashMap::new(),
+            repeated_optionals: HashMap::new(),
         }
+    }
 
-        let args: Vec<&str> = buffer.trim().split(' ').collect();
+    #[test]
+    fn fee_matches_rate_for_final_size() {
+        let state = funded_state();
 
-        if args.is_empty() {
-            println!("Need to supply a command");
-            continue;
+        for rate in [0.25, 0.5, 1.0] {
+            let txn = make_p2pkh_txn_with_rate(&state, &[1; 20], 100, rate).unwrap().unwrap();
+            let fee = BLOCK_REWARD - compute_output_sum(&txn);
+
+            assert_eq!(fee, fee_for_rate(rate, txn.vsize()));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_fee_rates() {
+        let state = funded_state();
+
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(make_p2pkh_txn_with_rate(&state, &[1; 20], 100, rate).is_err());
         }
+    }
 
-        let cmd_args = args.to_vec().iter().map(|&s| s.into()).collect();
+    #[test]
+    fn chain_req_amount_is_used_by_default() {
+        let state = Mutex::new(test_state());
 
-        dispatch_command(&cmd_args, &command_map, Some(state_mut));
-        buffer.clear();
+        set_chain_req_amount(&invocation(&[("amount", "25")]), Some(&state)).unwrap();
+
+        let state = state.lock().unwrap();
+        assert_eq!(req_amount_or_default(None, &state).unwrap(), 25);
+        assert_eq!(req_amount_or_default(Some(String::from("7")), &state).unwrap(), 7);
+    }
+
+    #[test]
+    fn chain_req_amount_must_be_positive() {
+        let state = Mutex::new(test_state());
+
+        assert!(set_chain_req_amount(&invocation(&[("amount", "0")]), Some(&state)).is_err());
+        assert_eq!(state.lock().unwrap().friends.chain_req_amount, 1);
+    }
+
+    #[test]
+    fn check_orphans_resolves_orphan_with_parent() {
+        let mut state = test_state();
+        let parent = next_block(&state, vec![]);
+        let orphan = make_block(parent.header.hash, &state.address, 0, vec![]);
+
+        assert!(matches!(verify_block(orphan, &mut state), Ok(BlockStatus::MissingParent(_))));
+        verify_block(parent, &mut state).unwrap();
+
+        let state = Mutex::new(state);
+        check_orphans_cmd(&invocation(&[]), Some(&state)).unwrap();
+
+        let state = state.lock().unwrap();
+        assert_eq!(state.blockchain.blocks.len(), 3);
+        assert!(state.blockchain.orphans.is_empty());
+    }
+
+    #[test]
+    fn check_orphans_keeps_pending_txns() {
+        let mut state = funded_state();
+        let txn = pay(&state, &[1; 20], 10, 1);
+        state.add_pending_txn(txn.clone());
+
+        let state = Mutex::new(state);
+        check_orphans_cmd(&invocation(&[]), Some(&state)).unwrap();
+
+        let state = state.lock().unwrap();
+        assert_eq!(state.pending_txns.len(), 1);
+        assert_eq!(state.pending_txns[0].hash, txn.hash);
+    }
+
+    #[test]
+    fn exported_chain_can_be_imported() {
+        let mut exporter = test_state();
+        add_old_blocks(&mut exporter, 3);
+        let exporter = Mutex::new(exporter);
+
+        let dir = temp_data_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/chain.json", dir);
+        export_chain(&invocation(&[("file", &path)]), Some(&exporter)).unwrap();
+
+        let importer = Mutex::new(test_state());
+        import_chain(&invocation(&[("file", &path)]), Some(&importer)).unwrap();
+
+        let importer = importer.lock().unwrap();
+        assert_eq!(importer.blockchain.blocks.len(), 4);
+        assert_eq!(importer.blockchain.top_hash(0), exporter.lock().unwrap().blockchain.top_hash(0));
+    }
+
+    #[test]
+    fn resync_downloads_old_blocks_again() {
+        let seed = SimNode::start();
+        add_old_blocks(&mut seed.state.lock().unwrap(), 3);
+        let node = SimNode::start();
+        node.join(&seed).unwrap();
+
+        resync_from(&invocation(&[("height", "2")]), Some(&node.state)).unwrap();
+
+        assert_eq!(node.height(), 4);
+        assert_eq!(node.state.lock().unwrap().blockchain.top_hash(0), seed.state.lock().unwrap().blockchain.top_hash(0));
+    }
+
+    #[test]
+    fn failed_resync_keeps_blocks() {
+        let seed = SimNode::start();
+        add_old_blocks(&mut seed.state.lock().unwrap(), 3);
+        let node = SimNode::start();
+        node.join(&seed).unwrap();
+
+        seed.set_reachable(false);
+
+        assert!(resync_from(&invocation(&[("height", "2")]), Some(&node.state)).is_err());
+        assert_eq!(node.height(), 4);
+    }
+
+    #[test]
+    fn refetch_keeps_old_tip() {
+        let seed = SimNode::start();
+        add_old_blocks(&mut seed.state.lock().unwrap(), 2);
+        let node = SimNode::start();
+        node.join(&seed).unwrap();
+
+        let tip = hex::encode(node.state.lock().unwrap().blockchain.top_hash(0));
+        refetch_block(&invocation(&[("hash", &tip)]), Some(&node.state)).unwrap();
+
+        assert_eq!(node.height(), 3);
+        assert_eq!(hex::encode(node.state.lock().unwrap().blockchain.top_hash(0)), tip);
+    }
+
+    #[test]
+    fn failed_refetch_keeps_block() {
+        let seed = SimNode::start();
+        add_old_blocks(&mut seed.state.lock().unwrap(), 2);
+        let node = SimNode::start();
+        node.join(&seed).unwrap();
+
+        seed.set_reachable(false);
+
+        let tip = hex::encode(node.state.lock().unwrap().blockchain.top_hash(0));
+        assert!(refetch_block(&invocation(&[("hash", &tip)]), Some(&node.state)).is_err());
+        assert_eq!(node.height(), 3);
+    }
+
+    #[test]
+    fn mempool_accept_accepts_valid_txn() {
+        let state = funded_state();
+        let txn = pay(&state, &[1; 20], 10, 1);
+
+        assert!(mempool_accept_verdict(txn, &state).ends_with("would be accepted"));
+        assert!(state.pending_txns.is_empty());
+    }
+
+    #[test]
+    fn mempool_accept_reports_orphan() {
+        let state = funded_state();
+        let missing: Hash256 = rand::random();
+        let output = TxnOutput {
+            amount: 10,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        };
+        let txn = sign_p2pkh(&state, &[(missing, 0)], vec![output]);
+        let verdict = mempool_accept_verdict(txn, &state);
+
+        assert!(verdict.contains("would be accepted as an orphan"));
+        assert!(verdict.ends_with(&hex::encode(missing)));
+        assert!(state.orphan_txns.is_empty());
+    }
+
+    #[test]
+    fn mempool_accept_rejects_double_spend() {
+        let mut state = funded_state();
+        let first = pay(&state, &[1; 20], 10, 1);
+        let second = pay(&state, &[2; 20], 10, 1);
+        state.add_pending_txn(first);
+
+        let verdict = mempool_accept_verdict(second, &state);
+
+        assert!(verdict.contains("would be rejected: conflicts with a pending transaction"));
+        assert_eq!(state.pending_txns.len(), 1);
+    }
+
+    #[test]
+    fn txn_status_reports_each_pool() {
+        let mut state = funded_state();
+        let coinbase = state.blockchain.blocks[1].transactions[0].hash;
+        let pending = pay(&state, &[1; 20], 10, 1);
+        let orphan = sign_p2pkh(&state, &[(rand::random(), 0)], vec![]);
+
+        state.add_pending_txn(pending.clone());
+        state.orphan_txns.push(orphan.clone());
+
+        assert_eq!(txn_status(orphan.hash, &state), Some(("orphan", 0)));
+        assert_eq!(txn_status(pending.hash, &state), Some(("pending", 0)));
+        assert_eq!(txn_status(coinbase, &state), Some(("confirmed", 1)));
+        assert_eq!(txn_status(rand::random(), &state), None);
+    }
+
+    #[test]
+    fn describes_known_fork() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        let main = add_blocks_after(&mut state, genesis, 3);
+        let fork = add_blocks_after(&mut state, main[0].header.hash, 2);
+        let target = cumulative_target(&[fork[0].header.target(), fork[1].header.target()]);
+
+        assert_eq!(state.blockchain.forks.len(), 1);
+        assert_eq!(
+            describe_fork(&state.blockchain.forks[0]),
+            format!(
+                "\tPrev index: 1\n\tLength: 2\n\tTip: {}\n\tCumulative target: {}",
+                hex::encode(fork[1].header.hash),
+                target.to_str_radix(16)
+            )
+        );
+    }
+
+    #[test]
+    fn chain_tips_lists_main_chain_and_forks() {
+        // Blocks on the main chain are harder than the forks, so the main chain is the best chain
+        let block = |target_byte: u8| {
+            let mut block = test_genesis();
+            let mut target = [0; 32];
+            target[1] = target_byte;
+            block.header.bits = get_difficulty_bits(&target);
+            block.header.hash = rand::random();
+
+            block
+        };
+        let mut blockchain = BlockchainDB::new(test_genesis());
+        blockchain.blocks.extend([block(1), block(1), block(1)]);
+        blockchain.forks.push(ForkChain {
+            prev_index: 1,
+            blocks: vec![block(3), block(3)],
+        });
+        blockchain.forks.push(ForkChain {
+            prev_index: 2,
+            blocks: vec![block(3)],
+        });
+        let tip = |blocks: &[Block]| hex::encode(blocks.last().unwrap().header.hash);
+
+        assert_eq!(
+            chain_tips(&blockchain),
+            vec![
+                (tip(&blockchain.blocks), 4, 0, "active"),
+                (tip(&blockchain.forks[0].blocks), 4, 2, "valid-fork"),
+                (tip(&blockchain.forks[1].blocks), 4, 1, "valid-fork"),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_forks_reports_nothing_without_forks() {
+        let mut state = funded_state();
+
+        assert_eq!(resolve_forks_report(&mut state), "There were no forks to resolve");
+        assert_eq!(state.resolve_forks(), None);
+    }
+
+    #[test]
+    fn resolve_forks_reports_reorg_and_returned_txns() {
+        let mut state = funded_state();
+        let funding = state.blockchain.top_hash(0);
+        let txn = pay(&state, &[1; 20], 10, 1);
+        state.add_pending_txn(txn.clone());
+        let block = next_block(&state, vec![txn.clone()]);
+        assert_eq!(verify_block(block, &mut state).unwrap(), BlockStatus::Added);
+
+        // A fork with less total difficulty target than the main chain since the fork point, so it wins
+        let fork_block = make_block(funding, &state.address, 0, vec![]);
+        let new_top = fork_block.header.hash;
+        state.blockchain.forks.push(ForkChain {
+            prev_index: 1,
+            blocks: vec![fork_block],
+        });
+
+        let report = resolve_forks_report(&mut state);
+
+        assert_eq!(
+            report,
+            format!(
+                "Forks resolved with a reorg. New top of the main chain: {}\n1 transactions returned to the pending pool\n\t{}",
+                hex::encode(new_top),
+                hex::encode(txn.hash)
+            )
+        );
+        assert_eq!(state.blockchain.top_hash(0), new_top);
+        assert_eq!(state.pending_txns[0].hash, txn.hash);
+    }
+
+    #[cfg(not(feature = "gui"))]
+    #[test]
+    fn connection_policy_decides_headless_requests() {
+        use crate::{gui::bridge::is_connection_accepted, v1::state::GUIChannels};
+
+        let state = Mutex::new(test_state());
+
+        for (policy, accepted) in [("accept", true), ("reject", false)] {
+            set_connection_policy(&invocation(&[("policy", policy)]), Some(&state)).unwrap();
+            let default = state.lock().unwrap().friends.fallback_accept_connections;
+
+            assert_eq!(is_connection_accepted(String::from("friend"), &GUIChannels {}, false, default).unwrap(), accepted);
+        }
+
+        assert!(set_connection_policy(&invocation(&[("policy", "maybe")]), Some(&state)).is_err());
+    }
+
+    #[test]
+    fn mempool_lists_pending_hashes_and_fees() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        let blocks = add_blocks_after(&mut state, genesis, 2);
+
+        let txns = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| {
+                let output = TxnOutput {
+                    amount: BLOCK_REWARD - 1 - i as u64,
+                    lock_script: make_p2pkh_lock(&[1; 20]),
+                };
+
+                sign_p2pkh(&state, &[(block.transactions[0].hash, 0)], vec![output])
+            })
+            .collect::<Vec<Transaction>>();
+
+        for txn in &txns {
+            state.add_pending_txn(txn.clone());
+        }
+
+        let expected = txns
+            .iter()
+            .enumerate()
+            .map(|(i, txn)| (txn.hash, 1 + i as u64, txn.size()))
+            .collect::<Vec<(Hash256, u64, usize)>>();
+
+        assert_eq!(mempool_entries(&state), expected);
+    }
+
+    #[test]
+    fn conflicting_raw_txn_is_reported_as_conflict() {
+        let mut state = funded_state();
+        let first = pay(&state, &[1; 20], 10, 1);
+        let conflict = pay(&state, &[2; 20], 10, 1);
+        let output = TxnOutput {
+            amount: BLOCK_REWARD + 1,
+            lock_script: make_p2pkh_lock(&[2; 20]),
+        };
+        let overspend = sign_p2pkh(&state, &[(conflict.inputs[0].txn_hash, 0)], vec![output]);
+
+        let err = verify_pending_transaction(overspend, &state).err().unwrap();
+        assert!(rejection_reason(&err).starts_with("invalid (error 209): "));
+
+        state.add_pending_txn(first);
+
+        let err = verify_pending_transaction(conflict, &state).err().unwrap();
+        assert!(rejection_reason(&err).starts_with("conflicts with a pending transaction (error 215): "));
+    }
+
+    #[test]
+    fn pending_connections_show_queued_intents() {
+        let mut friends = test_state().friends;
+        friends.aliases.insert([1; 20], String::from("alice"));
+        friends.pending_dh.insert([1; 20], EphemeralSecret::new(OsRng));
+        friends.pending_dh.insert([2; 20], EphemeralSecret::new(OsRng));
+        friends.intents.insert(
+            [1; 20],
+            ChainRequest::FindMeAt(FindMeAtReq {
+                addr: "10.0.0.1:8333".parse().unwrap(),
+            }),
+        );
+
+        let expected = vec![(address_to_b58c(&[2; 20].to_vec()), false), (String::from("alice"), true)];
+        assert_eq!(pending_connections(&friends), expected);
+    }
+
+    #[test]
+    fn only_valid_aliases_are_imported() {
+        let mut friends = test_state().friends;
+        let alice = address_to_b58c(&[1; 20].to_vec());
+        let bob = address_to_b58c(&[2; 20].to_vec());
+        let carol = address_to_b58c(&[3; 20].to_vec());
+        let contents = format!(
+            "# address,name\n{},alice\n\nnot an address,mallory\n{}\n{},\n{},bob\n{},alice\n{},alice2\n",
+            alice, bob, bob, bob, carol, alice
+        );
+
+        let (num_imported, skipped) = import_alias_entries(parse_alias_file(&contents).unwrap(), &mut friends);
+
+        assert_eq!(num_imported, 2);
+        let skipped_lines = skipped.iter().map(|s| s.split(':').next().unwrap()).collect::<Vec<&str>>();
+        assert_eq!(skipped_lines, vec!["line 4", "line 5", "line 6", "line 8", "line 9"]);
+        assert_eq!(friends.aliases.get(&[1; 20]).unwrap(), "alice");
+        assert_eq!(friends.aliases.get(&[2; 20]).unwrap(), "bob");
+        assert_eq!(friends.aliases.len(), 2);
+    }
+
+    #[test]
+    fn rebroadcast_sends_every_pending_txn() {
+        let seed = SimNode::start();
+        let node = SimNode::start();
+
+        node.join(&seed).unwrap();
+        node.mine();
+        assert!(wait_until(|| seed.height() == 2));
+
+        let mut state = node.state.lock().unwrap();
+        state.network.conn_pool = Some(std::sync::Arc::new(ConnectionPool::default()));
+
+        // The second one spends the change from the first, so they only get in if they're sent in order
+        let mut txns = vec![];
+        for _ in 0..2 {
+            let txn = pay(&state, &[1; 20], 10, 1);
+            state.add_pending_txn(txn.clone());
+            txns.push(txn);
+        }
+        drop(state);
+
+        assert!(seed.state.lock().unwrap().pending_txns.is_empty());
+
+        rebroadcast_mempool(&invocation(&[]), Some(&node.state)).unwrap();
+
+        assert!(wait_until(|| seed.state.lock().unwrap().pending_txns == txns));
+    }
+
+    #[test]
+    fn force_only_sends_mempool_conflicts() {
+        let mut state = funded_state();
+        let first = pay(&state, &[1; 20], 10, 1);
+        let conflict = pay(&state, &[2; 20], 10, 1);
+        let output = TxnOutput {
+            amount: 10,
+            lock_script: make_p2pkh_lock(&[2; 20]),
+        };
+        let bad_index = sign_p2pkh(&state, &[(conflict.inputs[0].txn_hash, 5)], vec![output]);
+        state.add_pending_txn(first);
+
+        let state_mut = Mutex::new(state);
+        let send = |txn: &Transaction, force: bool| {
+            let mut invocation = invocation(&[("txn-hex", &hex::encode(bincode::serialize(txn).unwrap()))]);
+            if force {
+                invocation.flags.push(String::from("force"));
+            }
+
+            send_raw_txn(&invocation, Some(&state_mut))
+        };
+
+        let err = send(&bad_index, true).unwrap_err();
+        assert!(err.to_string().contains(": invalid (error "));
+
+        let err = send(&conflict, false).unwrap_err();
+        assert!(err.to_string().contains("conflicts with a pending transaction (error 215): "));
+
+        assert!(send(&conflict, true).is_ok());
+        assert!(!state_mut.lock().unwrap().pending_txns.contains(&conflict));
+    }
+
+    #[test]
+    fn rotated_keys_are_used_once_the_exchange_completes() {
+        let alice = SimNode::start();
+        let bob = SimNode::start();
+
+        bob.join(&alice).unwrap();
+        alice.mine();
+        assert!(wait_until(|| bob.height() == 2));
+        bob.mine();
+        assert!(wait_until(|| alice.height() == 3));
+
+        let alice_address = alice.state.lock().unwrap().address;
+        let bob_address = bob.state.lock().unwrap().address;
+        let mut state = alice.state.lock().unwrap();
+        state.friends.keys.insert(bob_address, Ratchet::new(&[7; 32], &alice_address, &bob_address));
+        drop(state);
+        let mut state = bob.state.lock().unwrap();
+        state.friends.keys.insert(alice_address, Ratchet::new(&[7; 32], &bob_address, &alice_address));
+        state.friends.fallback_accept_connections = true;
+        drop(state);
+
+        // Encrypt a request from one node and decrypt it on the other, returning the request's counter
+        let exchange = |from: &SimNode, from_address: Address, to: &mut State, to_address: Address| {
+            let mut state = from.state.lock().unwrap();
+            let req = ChainRequest::FindMeAt(FindMeAtReq { addr: from.addr });
+            let txn = make_encrypted_chain_req(req, to_address, &mut state).unwrap();
+            let counter = inspect_enc_req(&txn, &state).unwrap().counter.unwrap();
+
+            to.friends.decrypt_from_sender(decompose_enc_req(&txn).unwrap(), from_address).unwrap();
+
+            counter
+        };
+
+        assert_eq!(exchange(&alice, alice_address, &mut bob.state.lock().unwrap(), bob_address), 0);
+
+        // Bob can't answer while we hold his state, so Alice keeps using the old keys until then
+        let mut bob_state = bob.state.lock().unwrap();
+        let bob_b58c = address_to_b58c(&bob_address.to_vec());
+        rotate_key(&invocation(&[("address", &bob_b58c)]), Some(&alice.state)).unwrap();
+
+        assert!(alice.state.lock().unwrap().friends.pending_dh.contains_key(&bob_address));
+        assert_eq!(exchange(&alice, alice_address, &mut bob_state, bob_address), 1);
+        drop(bob_state);
+
+        assert!(wait_until(|| alice.state.lock().unwrap().friends.pending_dh.is_empty()));
+
+        // Both sides start over with new ratchets
+        assert_eq!(exchange(&alice, alice_address, &mut bob.state.lock().unwrap(), bob_address), 0);
+        assert_eq!(exchange(&bob, bob_address, &mut alice.state.lock().unwrap(), alice_address), 0);
     }
 }
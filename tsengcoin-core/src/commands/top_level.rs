@@ -6,25 +6,33 @@ use std::{
     thread,
 };
 
-use ring::signature::KeyPair;
+use chrono::Duration;
+use num_bigint::BigUint;
+use ring::signature::{EcdsaKeyPair, KeyPair};
 use thread_priority::{ThreadBuilderExt, ThreadPriority};
 
-use super::session::listen_for_commands;
+use super::{rpc::{bind_rpc_listener, listen_for_rpc}, session::listen_for_commands};
 use crate::{
+    difficulty::get_difficulty_target,
     command::{
-        Command, CommandInvocation, CommandMap, Field, FieldType, Flag, VarField,
+        Command, CommandInvocation, CommandMap, Condition, Field, FieldType, Flag, VarField,
     },
-    gui::{bridge::get_wallet_password_arg},
+    gui::bridge::{get_password_override, get_wallet_password_arg, WALLET_PASSWORD_ENV},
+    script_examples::script_examples,
     tsengscript_interpreter::{execute, ExecutionResult, Token},
     v1::{
         miners::{api::{miners, num_miners, start_miner}, stats::{MinerStatsState, DEFAULT_GRANULARITY}},
-        net::listen_for_connections,
-        request::{advertise_self, discover, download_latest_blocks, get_first_peers},
+        net::{
+            bind_listener, listen_for_connections, maintain_peers, ConnectionPool, DEFAULT_BLOCK_ANNOUNCE_SAMPLE,
+            DEFAULT_LISTEN_BACKLOG,
+        },
+        request::{advertise_self, bootstrap, download_latest_blocks},
         state::{State, GUIChannels},
+        transaction::{truncate_meta, MAX_META_LENGTH, MIN_TXN_FEE},
     },
     wallet::{
-        address_from_public_key, address_to_b58c, b58c_to_address, create_keypair, load_keypair,
-        Address,
+        address_from_public_key, address_to_b58c, b58c_to_address, check_address_round_trip,
+        create_keypair, hex_to_hash, load_keypair, Address,
     },
 };
 
@@ -32,8 +40,6 @@ use crate::{
 use std::sync::mpsc::channel;
 #[cfg(feature = "gui")]
 use crate::gui::gui::{gui_req_loop, main_gui_loop, GUIState};
-#[cfg(feature = "gui")]
-use crate::command::Condition;
 
 #[cfg(all(feature = "debug", feature = "cuda_miner"))]
 use super::cuda_debug::make_command_map as make_cuda_dbg_command_map;
@@ -41,15 +47,33 @@ use super::cuda_debug::make_command_map as make_cuda_dbg_command_map;
 fn run_script(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box<dyn Error>> {
     let script = invocation.get_field("script").unwrap();
     let show_stack = invocation.get_flag("show-stack");
-    let ExecutionResult { top, stack } = execute(&script, &vec![])?;
+    let target = match invocation.get_optional("target") {
+        None => None,
+        Some(hex) => Some(BigUint::from_bytes_be(&hex_to_hash(&hex)?)),
+    };
+    let init_stack = initial_stack(invocation)?;
 
-    match top {
+    if show_stack && !init_stack.is_empty() {
+        println!("Initial stack: {:?}", init_stack);
+    }
+
+    let ExecutionResult { top, stack } = execute(&script, &init_stack)?;
+
+    match &top {
         None => println!("Stack was empty"),
         Some(Token::Bool(val)) => println!("Bool: {}", val),
         Some(Token::UByteSeq(bigint)) => println!("UByteSeq: {}", bigint),
         Some(Token::Operator(_)) => println!("Result is an operator!"),
     };
 
+    if let Some(target) = target {
+        match passes_target(&top, &target) {
+            Some(true) => println!("Result is below the target and would pass proof-of-work"),
+            Some(false) => println!("Result is not below the target and would fail proof-of-work"),
+            None => println!("Result is not a UByteSeq, so it can't be compared to the target"),
+        }
+    }
+
     if show_stack {
         println!("Stack: {:?}", stack);
     }
@@ -57,6 +81,54 @@ fn run_script(invocation: &CommandInvocation, _state: Option<()>) -> Result<(),
     Ok(())
 }
 
+/// The values given with `--push`, in the order they should be pushed before the script runs
+fn initial_stack(invocation: &CommandInvocation) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut init_stack: Vec<Token> = vec![];
+
+    for hex_str in invocation.get_repeated_optional("push") {
+        let bytes = hex::decode(hex_str.trim_start_matches("0x"))?;
+        init_stack.push(Token::UByteSeq(BigUint::from_bytes_be(&bytes)));
+    }
+
+    Ok(init_stack)
+}
+
+/// Whether a script result would pass proof-of-work with the given target, or None if it isn't a UByteSeq
+fn passes_target(top: &Option<Token>, target: &BigUint) -> Option<bool> {
+    match top {
+        Some(Token::UByteSeq(bigint)) => Some(bigint < target),
+        _ => None,
+    }
+}
+
+fn run_script_examples(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box<dyn Error>> {
+    let show_scripts = invocation.get_flag("show-scripts");
+    let examples = script_examples();
+    let mut num_passed: usize = 0;
+
+    for example in &examples {
+        match example.run() {
+            Ok(()) => {
+                println!("PASS: {}", example.name);
+                num_passed += 1;
+            }
+            Err(err) => println!("FAIL: {}: {}", example.name, err),
+        };
+
+        if show_scripts {
+            println!("\t{}\n\tExpected: {:?}", example.script, example.expected);
+        }
+    }
+
+    println!("{}/{} examples passed", num_passed, examples.len());
+
+    if num_passed != examples.len() {
+        return Err("Some script examples failed".into());
+    }
+
+    Ok(())
+}
+
 fn random_test_address(
     _invocation: &CommandInvocation,
     _state: Option<()>,
@@ -79,6 +151,18 @@ fn b58c_encode(invocation: &CommandInvocation, _state: Option<()>) -> Result<(),
     Ok(())
 }
 
+fn get_target(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box<dyn Error>> {
+    let bits_str = invocation.get_field("bits").unwrap();
+    let bits = u32::from_str_radix(bits_str.trim_start_matches("0x"), 16)?;
+
+    match get_difficulty_target(bits) {
+        None => println!("Target is too large to fit in 32 bytes"),
+        Some(target) => println!("{}", hex::encode(target)),
+    };
+
+    Ok(())
+}
+
 fn b58c_decode(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box<dyn Error>> {
     let raw = invocation.get_field("encoded").unwrap();
     let decoded = b58c_to_address(raw)?;
@@ -94,7 +178,10 @@ fn create_address(
     _state: Option<()>,
 ) -> Result<(), Box<dyn Error>> {
     let path = invocation.get_field("keypair-path").unwrap();
-    let password = invocation.get_field("password").unwrap();
+    let password = match get_password_override(invocation)? {
+        Some(password) => password,
+        None => invocation.get_field("password").unwrap(),
+    };
     let keypair = create_keypair(&password, &path)?;
 
     let pubkey = keypair.public_key().as_ref();
@@ -126,6 +213,59 @@ fn test_load_keypair(
     Ok(())
 }
 
+fn check_wallet(
+    invocation: &CommandInvocation,
+    _state: Option<()>,
+) -> Result<(), Box<dyn Error>> {
+    let path = invocation.get_field("wallet-path").unwrap();
+    let password = invocation.get_field("password").unwrap();
+    let keypair = load_keypair(&password, &path)?;
+    let pubkey = keypair.public_key().as_ref().to_vec();
+
+    println!("Loaded keypair");
+
+    let encoded = check_address_round_trip(&pubkey)?;
+
+    println!("Address {} survives a base58check round trip", encoded);
+
+    if let Some(expected) = invocation.get_optional("address") {
+        let expected_addr = b58c_to_address(expected.clone())?;
+
+        if expected_addr != address_from_public_key(&pubkey) {
+            return Err(format!("Wallet address is {}, but expected {}", encoded, expected).into());
+        }
+
+        println!("Address matches {}", expected);
+    }
+
+    println!("Wallet is OK");
+
+    Ok(())
+}
+
+fn export_pubkey(
+    invocation: &CommandInvocation,
+    _state: Option<()>,
+) -> Result<(), Box<dyn Error>> {
+    let path = invocation.get_field("wallet-path").unwrap();
+    let password = invocation.get_field("password").unwrap();
+    let keypair = load_keypair(&password, &path)?;
+    let (pubkey, address) = pubkey_and_address(&keypair);
+
+    println!("Public key: {}", pubkey);
+    println!("Address: {}", address);
+
+    Ok(())
+}
+
+/// The hex public key and base58check address printed by `export-pubkey`
+fn pubkey_and_address(keypair: &EcdsaKeyPair) -> (String, String) {
+    let pubkey = keypair.public_key().as_ref();
+    let address = address_from_public_key(&pubkey.to_vec());
+
+    (hex::encode(pubkey), address_to_b58c(&address.to_vec()))
+}
+
 fn connect(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box<dyn Error>> {
     let seed_ip = invocation
         .get_field("seed-ip")
@@ -144,7 +284,7 @@ fn connect(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box
         .parse::<u16>()
         .unwrap();
     let wallet_path = invocation.get_field("wallet-path").unwrap();
-    let wallet_password = get_wallet_password_arg(invocation);
+    let wallet_password = get_wallet_password_arg(invocation)?;
     let miner_names = miners();
     let miner = match num_miners() {
         0 => None,
@@ -196,7 +336,7 @@ fn connect(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box
             gui_req_sender,
             gui_state,
             miner.clone(),
-        );
+        )?;
 
         state.compute_balance();
 
@@ -209,20 +349,36 @@ fn connect(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box
             addr_me,
             keypair,
             miner.clone()
-        );
+        )?;
 
         (state, miner_receiver, GUIChannels {})
     };
 
-    state.wg_size = invocation.get_optional("wg-size").map(|s| s.parse::<usize>().unwrap());
-    state.num_work_groups = invocation.get_optional("work-groups").map(|s| s.parse::<usize>().unwrap());
-    state.miner_stats = miner_stats(invocation);
+    apply_node_options(invocation, &mut state)?;
+    let listen_backlog = match invocation.get_optional("listen-backlog") {
+        Some(backlog) => backlog.parse::<i32>()?,
+        None => DEFAULT_LISTEN_BACKLOG,
+    };
+
+    state.seeds.push(seed_addr);
+
+    for seed in invocation.get_repeated_optional("seed") {
+        let extra_seed = seed
+            .parse::<SocketAddr>()
+            .map_err(|err| format!("Invalid seed address {}: {}", seed, err))?;
+
+        if !state.seeds.contains(&extra_seed) {
+            state.seeds.push(extra_seed);
+        }
+    }
 
-    get_first_peers(seed_addr, &mut state)?;
-    discover(seed_addr, &mut state)?;
+    let seeds = state.seeds.clone();
+    bootstrap(&seeds, &mut state)?;
     download_latest_blocks(&mut state)?;
     advertise_self(&mut state).expect("Failed to advertise self to network");
 
+    let listener = bind_listener(addr_me, listen_backlog)?;
+
     let state_mut = Mutex::new(state);
     let state_arc = Arc::new(state_mut);
     let state_arc_2 = Arc::clone(&state_arc);
@@ -231,11 +387,21 @@ fn connect(invocation: &CommandInvocation, _state: Option<()>) -> Result<(), Box
     thread::Builder::new()
         .name(String::from("network-listener"))
         .spawn(move || {
-            listen_for_connections(addr_me, &gui_channels, &state_arc_2)
+            listen_for_connections(listener, &gui_channels, &state_arc_2)
                 .expect("Network listener thread crashed");
         })
         .unwrap();
 
+    let state_arc_peers = Arc::clone(&state_arc);
+    thread::Builder::new()
+        .name(String::from("peer-maintenance"))
+        .spawn(move || {
+            maintain_peers(&state_arc_peers);
+        })
+        .unwrap();
+
+    start_rpc(invocation, &state_arc)?;
+
     println!("Bootstrapping complete\nStarting worker threads");
 
     if miner.is_some() {
@@ -286,7 +452,7 @@ fn start_seed(invocation: &CommandInvocation, _state: Option<()>) -> Result<(),
         .parse::<u16>()
         .unwrap();
     let wallet_path = invocation.get_field("wallet-path").unwrap();
-    let wallet_password = get_wallet_password_arg(invocation);
+    let wallet_password = get_wallet_password_arg(invocation)?;
     let miner_names = miners();
     let miner = match num_miners() {
         0 => None,
@@ -332,7 +498,7 @@ fn start_seed(invocation: &CommandInvocation, _state: Option<()>) -> Result<(),
             gui_req_sender,
             gui_state,
             miner.clone(),
-        );
+        )?;
 
         state.compute_balance();
 
@@ -345,14 +511,18 @@ fn start_seed(invocation: &CommandInvocation, _state: Option<()>) -> Result<(),
             addr_me,
             keypair,
             miner.clone()
-        );
+        )?;
 
         (state, miner_receiver, GUIChannels {})
     };
 
-    state.wg_size = invocation.get_optional("wg-size").map(|s| s.parse::<usize>().unwrap());
-    state.num_work_groups = invocation.get_optional("work-groups").map(|s| s.parse::<usize>().unwrap());
-    state.miner_stats = miner_stats(invocation);
+    apply_node_options(invocation, &mut state)?;
+    let listen_backlog = match invocation.get_optional("listen-backlog") {
+        Some(backlog) => backlog.parse::<i32>()?,
+        None => DEFAULT_LISTEN_BACKLOG,
+    };
+
+    let listener = bind_listener(addr_me, listen_backlog)?;
 
     let state_mut = Mutex::new(state);
     let state_arc = Arc::new(state_mut);
@@ -364,11 +534,13 @@ fn start_seed(invocation: &CommandInvocation, _state: Option<()>) -> Result<(),
     thread::Builder::new()
         .name(String::from("network-listener"))
         .spawn(move || {
-            listen_for_connections(addr_me, &gui_channels, &state_arc_2)
+            listen_for_connections(listener, &gui_channels, &state_arc_2)
                 .expect("Network listener thread crashed");
         })
         .unwrap();
 
+    start_rpc(invocation, &state_arc)?;
+
     if miner.is_some() {
         let state_arc_miner = Arc::clone(&state_arc);
 
@@ -422,9 +594,28 @@ pub fn make_command_map() -> CommandMap<()> {
             "show-stack",
             "Print the contents of the stack when the program finishes",
         )],
-        optionals: vec![],
+        optionals: vec![
+            VarField::new(
+                "target",
+                "A difficulty target in hex. If the result is a UByteSeq, check if it's below the target",
+            ),
+            VarField::new(
+                "push",
+                "A value in hex to push on the stack before the script runs. Can be given more than once; values are pushed in order",
+            ),
+        ],
         desc: String::from("Run a TsengScript program and see the output and stack trace"),
     };
+    let script_examples_cmd: Command<()> = Command {
+        processor: run_script_examples,
+        expected_fields: vec![],
+        flags: vec![Flag::new(
+            "show-scripts",
+            "Print each example's script and expected result",
+        )],
+        optionals: vec![],
+        desc: String::from("Run a set of example TsengScript programs with known results and check that each one gives the right result. This shows how the language works and checks that the interpreter is working"),
+    };
     let random_test_address_hex_cmd: Command<()> = Command {
         processor: random_test_address,
         expected_fields: vec![],
@@ -445,6 +636,17 @@ pub fn make_command_map() -> CommandMap<()> {
             "Encode a hex string in base58check. The hex string is treated as a TsengCoin address",
         ),
     };
+    let get_target_cmd: Command<()> = Command {
+        processor: get_target,
+        expected_fields: vec![Field::new(
+            "bits",
+            FieldType::Pos(0),
+            "The difficulty target in compact form, as 8 hex digits (e.g. 1d0f0000)",
+        )],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Expand a compact difficulty target into the full 32-byte target"),
+    };
     let b58c_decode_cmd: Command<()> = Command {
         processor: b58c_decode,
         expected_fields: vec![
@@ -466,14 +668,15 @@ pub fn make_command_map() -> CommandMap<()> {
                 FieldType::Pos(0),
                 "Path to a keypair file"
             ),
-            Field::new(
+            Field::new_condition(
                 "password",
                 FieldType::Spaces(1),
-                "Password to the given keypair file"
+                "Password to the given keypair file",
+                password_file_condition()
             )
         ],
         flags: vec![],
-        optionals: vec![],
+        optionals: vec![password_file_optional()],
         desc: String::from(
             "Create a TsengCoin address and lock it with a password. The file created by this command must be protected because it contains your private key"
         )
@@ -496,10 +699,57 @@ pub fn make_command_map() -> CommandMap<()> {
         optionals: vec![],
         desc: String::from("Load a keypair file locked with a password and get the address out of it. The file is encrypted so this only works if you have the right password")
     };
+    let check_wallet_cmd: Command<()> = Command {
+        processor: check_wallet,
+        expected_fields: vec![
+            Field::new(
+                "wallet-path",
+                FieldType::Pos(0),
+                "Path to your wallet file"
+            ),
+            Field::new(
+                "password",
+                FieldType::Spaces(1),
+                "Password to your wallet file"
+            )
+        ],
+        flags: vec![],
+        optionals: vec![VarField::new(
+            "address",
+            "The address you expect this wallet to have, in base58check"
+        )],
+        desc: String::from("Load a wallet, derive its address, and check that the address survives base58check encoding and decoding")
+    };
+    let export_pubkey_cmd: Command<()> = Command {
+        processor: export_pubkey,
+        expected_fields: vec![
+            Field::new(
+                "wallet-path",
+                FieldType::Pos(0),
+                "Path to your wallet file"
+            ),
+            Field::new(
+                "password",
+                FieldType::Spaces(1),
+                "Password to the given wallet file"
+            )
+        ],
+        flags: vec![],
+        optionals: vec![],
+        desc: String::from("Print the full public key in a wallet file along with the address derived from it. Use this if another tool needs your public key and not just your address")
+    };
 
     let num_miners = num_miners();
     let miners = miners();
     let mut connect_flags = vec![
+        Flag::new(
+            "conn-pool",
+            "Set this flag to keep connections to peers open and reuse them when relaying transactions and blocks, instead of opening a new connection for every message."
+        ),
+        Flag::new(
+            "clean-stack",
+            "Set this flag to only accept and relay transactions whose scripts leave exactly one item on the stack. Blocks can still contain other transactions."
+        ),
         #[cfg(feature = "gui")]
         Flag::new(
             "gui",
@@ -511,6 +761,23 @@ pub fn make_command_map() -> CommandMap<()> {
             "ip",
             "Your IP address. Use this to specify a different IP to listen on."
         ),
+        VarField::new(
+            "block-announce-sample",
+            &format!("Number of known nodes that aren't peers to announce new blocks to, in addition to peers. Unreachable nodes are forgotten. Set this to 0 to only announce blocks to peers. Defaults to {}", DEFAULT_BLOCK_ANNOUNCE_SAMPLE)
+        ),
+        VarField::new(
+            "listen-backlog",
+            &format!("Number of incoming connections the OS will queue before we accept them. Defaults to {}", DEFAULT_LISTEN_BACKLOG)
+        ),
+        VarField::new(
+            "min-relay-fee",
+            &format!("Don't accept or relay transactions paying less than this fee. Blocks can still contain them. Defaults to {}", MIN_TXN_FEE)
+        ),
+        password_file_optional(),
+        VarField::new(
+            "rpc-port",
+            "Set this to accept newline-delimited JSON commands on this port, on localhost only. Each line should look like {\"command\": \"getforks\", \"args\": []}"
+        ),
     ];
     if num_miners == 1 {
         connect_flags.append(&mut vec![
@@ -538,9 +805,21 @@ pub fn make_command_map() -> CommandMap<()> {
             "work-groups",
             "Number of work groups. Only meaningful if using the OpenCL miner. The number of nonces per round will be (work-groups * wg-size)"
         ));
+        connect_flags.push(Flag::new(
+            "cl-autotune",
+            "Set this flag to have the OpenCL miner try a few work group sizes and numbers of work groups at startup and mine with the fastest. Overrides wg-size and work-groups."
+        ));
     }
 
     if num_miners > 0 {
+        connect_optionals.push(VarField::new(
+            "coinbase-msg",
+            &format!("A message to put in the coinbase transaction of every block you mine. Messages longer than {} bytes are cut off.", MAX_META_LENGTH)
+        ));
+        connect_optionals.push(VarField::new(
+            "block-refresh",
+            "How often (in seconds) the miner should make a new candidate block. By default, this is 30 minutes"
+        ));
         connect_optionals.push(VarField::new_placeholder(
             "miner-stats-file",
             "Set this variable to record miner stats in the background. Stats will be saved as CSV to the file provided. The file will be created if it doesn't exist. You can tune the measurement parameters with the other `miner-stats` options.",
@@ -580,24 +859,35 @@ pub fn make_command_map() -> CommandMap<()> {
                 "Path to your wallet file"
             ),
             #[cfg(feature = "gui")]
-            Field::new_condition(
+            Field::new_conditions(
                 "wallet-password",
                 FieldType::Spaces(4),
                 "Password to your wallet file",
-                Condition::new(
-                    "pwgui",
-                    "Set this flag to enter the password through a dialog box instead of passing it in as a command line argument."
-                )
+                vec![
+                    Condition::new(
+                        "pwgui",
+                        "Set this flag to enter the password through a dialog box instead of passing it in as a command line argument."
+                    ),
+                    password_file_condition()
+                ]
             ),
             #[cfg(not(feature = "gui"))]
-            Field::new(
+            Field::new_condition(
                 "wallet-password",
                 FieldType::Spaces(4),
                 "Password to your wallet file",
+                password_file_condition()
             )
         ],
         flags: connect_flags.clone(),
-        optionals: connect_optionals.clone(),
+        optionals: [
+            connect_optionals.clone(),
+            vec![VarField::new_placeholder(
+                "seed",
+                "Another node to bootstrap from, in addition to the seed IP and port. Can be given more than once. Seeds that don't respond are skipped, and the remote address reported by most seeds is used",
+                "ip:port"
+            )]
+        ].concat(),
         desc: String::from("Connect to the TsengCoin network as a full node. Unless you're trying to do fancy stuff, this is probably the command you want. If you don't have a wallet yet, run `create-address` first.")
     };
 
@@ -615,20 +905,24 @@ pub fn make_command_map() -> CommandMap<()> {
                 "Path to your wallet file"
             ),
             #[cfg(feature = "gui")]
-            Field::new_condition(
+            Field::new_conditions(
                 "wallet-password",
                 FieldType::Spaces(2),
                 "Password to your wallet file",
-                Condition::new(
-                    "pwgui",
-                    "Set this flag to enter the password through a dialog box instead of passing it in as a command line argument."
-                )
+                vec![
+                    Condition::new(
+                        "pwgui",
+                        "Set this flag to enter the password through a dialog box instead of passing it in as a command line argument."
+                    ),
+                    password_file_condition()
+                ]
             ),
             #[cfg(not(feature = "gui"))]
-            Field::new(
+            Field::new_condition(
                 "wallet-password",
                 FieldType::Spaces(2),
                 "Password to your wallet file",
+                password_file_condition()
             )
         ],
         flags: connect_flags,
@@ -637,14 +931,18 @@ pub fn make_command_map() -> CommandMap<()> {
     };
 
     out.insert(String::from("run-script"), run_script_cmd);
+    out.insert(String::from("script-examples"), script_examples_cmd);
     out.insert(
         String::from("random-test-address-hex"),
         random_test_address_hex_cmd,
     );
     out.insert(String::from("b58c-encode"), b58c_encode_cmd);
     out.insert(String::from("b58c-decode"), b58c_decode_cmd);
+    out.insert(String::from("get-target"), get_target_cmd);
     out.insert(String::from("create-address"), create_address_cmd);
     out.insert(String::from("test-load-keypair"), test_load_keypair_cmd);
+    out.insert(String::from("check-wallet"), check_wallet_cmd);
+    out.insert(String::from("export-pubkey"), export_pubkey_cmd);
     out.insert(String::from("connect"), connect_cmd);
     out.insert(String::from("start-seed"), start_seed_cmd);
 
@@ -659,6 +957,22 @@ pub fn make_command_map() -> CommandMap<()> {
     out
 }
 
+fn password_file_optional() -> VarField {
+    VarField::new_placeholder(
+        "password-file",
+        &format!("Read the password from the first line of this file. You can also set the password in {}. Either one takes precedence over a password passed in as an argument", WALLET_PASSWORD_ENV),
+        "path"
+    )
+}
+
+fn password_file_condition() -> Condition {
+    Condition::new_env(
+        "password-file",
+        WALLET_PASSWORD_ENV,
+        &format!("Read the password from a file instead of passing it in as a command line argument. You can also set {} instead", WALLET_PASSWORD_ENV)
+    )
+}
+
 fn miner_placeholder(miners: &Vec<String>) -> String {
     let mut out = String::from("(");
 
@@ -685,6 +999,61 @@ fn miner_list(miners: &Vec<String>) -> String {
     out
 }
 
+/// Start the RPC listener thread if an RPC port was given
+fn start_rpc(invocation: &CommandInvocation, state_arc: &Arc<Mutex<State>>) -> Result<(), Box<dyn Error>> {
+    let port = match invocation.get_optional("rpc-port") {
+        None => return Ok(()),
+        Some(port) => port.parse::<u16>()?,
+    };
+
+    let listener = bind_rpc_listener(port)?;
+    let state_arc_rpc = Arc::clone(state_arc);
+
+    println!("Starting RPC listener thread. Listening on localhost:{}", port);
+    thread::Builder::new()
+        .name(String::from("rpc-listener"))
+        .spawn(move || {
+            listen_for_rpc(listener, &state_arc_rpc).expect("RPC listener thread crashed");
+        })
+        .unwrap();
+
+    Ok(())
+}
+
+/// Parse the `block-refresh` interval. The miners would make a new candidate block on every pass if it were zero
+fn parse_block_refresh(secs: &str) -> Result<Duration, Box<dyn Error>> {
+    let secs = secs.parse::<i64>()?;
+    if secs <= 0 {
+        return Err(format!("Block refresh interval must be at least 1 second, got {}", secs).into());
+    }
+
+    Ok(Duration::seconds(secs))
+}
+
+/// Set up a new node's state with the options that `connect` and `start-seed` share
+fn apply_node_options(invocation: &CommandInvocation, state: &mut State) -> Result<(), Box<dyn Error>> {
+    state.wg_size = invocation.get_optional("wg-size").map(|s| s.parse::<usize>().unwrap());
+    state.num_work_groups = invocation.get_optional("work-groups").map(|s| s.parse::<usize>().unwrap());
+    state.cl_autotune = invocation.get_flag("cl-autotune");
+    state.miner_stats = miner_stats(invocation);
+    state.coinbase_msg = truncate_meta(&invocation.get_optional("coinbase-msg").unwrap_or_default());
+    if let Some(secs) = invocation.get_optional("block-refresh") {
+        state.block_refresh = parse_block_refresh(&secs)?;
+    }
+    if let Some(fee) = invocation.get_optional("min-relay-fee") {
+        state.min_relay_fee = fee.parse::<u64>()?;
+    }
+    if let Some(sample) = invocation.get_optional("block-announce-sample") {
+        state.network.block_announce_sample = sample.parse::<usize>()?;
+    }
+    if invocation.get_flag("conn-pool") {
+        state.network.conn_pool = Some(Arc::new(ConnectionPool::default()));
+    }
+    state.clean_stack = invocation.get_flag("clean-stack");
+
+    Ok(())
+}
+
 fn miner_stats(invocation: &CommandInvocation) -> Option<MinerStatsState> {
     let filename = match invocation.get_optional("miner-stats-file") {
         None => return None,
@@ -700,3 +1069,131 @@ fn miner_stats(invocation: &CommandInvocation) -> Option<MinerStatsState> {
         filename
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::v1::{
+        miners::api::{next_reset, DEFAULT_BLOCK_REFRESH},
+        test_util::{temp_data_dir, test_keypair, test_state},
+    };
+
+    fn invocation(fields: &[(&str, &str)], optionals: &[(&str, &str)]) -> CommandInvocation {
+        let to_map = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+
+        CommandInvocation {
+            name: String::new(),
+            flags: vec![],
+            args: vec![],
+            vars: HashMap::new(),
+            fields: to_map(fields),
+            optionals: to_map(optionals),
+            repeated_optionals: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn exported_pubkey_hashes_to_address() {
+        let keypair = test_keypair();
+        let (pubkey, address) = pubkey_and_address(&keypair);
+        let pubkey = hex::decode(pubkey).unwrap();
+
+        assert_eq!(pubkey, keypair.public_key().as_ref());
+        assert_eq!(address_from_public_key(&pubkey), b58c_to_address(address).unwrap());
+    }
+
+    #[test]
+    fn block_refresh_is_read_in_seconds() {
+        assert_eq!(parse_block_refresh("90").unwrap(), Duration::seconds(90));
+    }
+
+    #[test]
+    fn miner_uses_configured_block_refresh() {
+        let mut state = test_state();
+        let now = Utc::now();
+        assert_eq!(next_reset(now, &state), now + *DEFAULT_BLOCK_REFRESH);
+
+        apply_node_options(&invocation(&[], &[("block-refresh", "90")]), &mut state).unwrap();
+
+        assert_eq!(state.block_refresh, Duration::seconds(90));
+        assert_eq!(next_reset(now, &state), now + Duration::seconds(90));
+    }
+
+    #[test]
+    fn block_refresh_must_be_positive() {
+        assert!(parse_block_refresh("0").is_err());
+        assert!(parse_block_refresh("-5").is_err());
+        assert!(parse_block_refresh("soon").is_err());
+    }
+
+    #[test]
+    fn script_result_is_compared_to_target() {
+        let target = BigUint::from(1000u32);
+        let result = |n: u32| Some(Token::UByteSeq(BigUint::from(n)));
+
+        assert_eq!(passes_target(&result(999), &target), Some(true));
+        assert_eq!(passes_target(&result(1000), &target), Some(false));
+        assert_eq!(passes_target(&result(1001), &target), Some(false));
+        assert_eq!(passes_target(&Some(Token::Bool(true)), &target), None);
+        assert_eq!(passes_target(&None, &target), None);
+    }
+
+    #[test]
+    fn check_wallet_accepts_its_own_address() {
+        let dir = temp_data_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/wallet", dir);
+        let keypair = create_keypair("password", &path).unwrap();
+        let address = address_to_b58c(&address_from_public_key(&keypair.public_key().as_ref().to_vec()).to_vec());
+        let fields = [("wallet-path", path.as_str()), ("password", "password")];
+
+        assert!(check_wallet(&invocation(&fields, &[]), None).is_ok());
+        assert!(check_wallet(&invocation(&fields, &[("address", &address)]), None).is_ok());
+    }
+
+    #[test]
+    fn check_wallet_rejects_other_address() {
+        let dir = temp_data_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/wallet", dir);
+        create_keypair("password", &path).unwrap();
+        let other = address_to_b58c(&[1; 20].to_vec());
+        let fields = [("wallet-path", path.as_str()), ("password", "password")];
+
+        assert!(check_wallet(&invocation(&fields, &[("address", &other)]), None).is_err());
+    }
+
+    #[test]
+    fn address_round_trip_for_known_keypair() {
+        let keypair = test_keypair();
+        let pubkey = keypair.public_key().as_ref().to_vec();
+        let encoded = check_address_round_trip(&pubkey).unwrap();
+
+        assert_eq!(b58c_to_address(encoded).unwrap(), address_from_public_key(&pubkey));
+    }
+
+    #[test]
+    fn pushed_values_are_on_stack_before_script_runs() {
+        let mut inv = invocation(&[("script", "SUB")], &[]);
+        inv.repeated_optionals.insert(String::from("push"), vec![String::from("02"), String::from("0x09")]);
+
+        let init_stack = initial_stack(&inv).unwrap();
+        let ExecutionResult { stack, .. } = execute(&String::from("NOP1"), &init_stack).unwrap();
+        assert_eq!(format!("{:?}", stack), format!("{:?}", execute(&String::from("02 09"), &vec![]).unwrap().stack));
+
+        let ExecutionResult { top, .. } = execute(&inv.get_field("script").unwrap(), &init_stack).unwrap();
+        assert_eq!(format!("{:?}", top), format!("{:?}", Some(Token::UByteSeq(BigUint::from(7_u8)))));
+    }
+
+    #[test]
+    fn push_must_be_hex() {
+        let mut inv = invocation(&[], &[]);
+        inv.repeated_optionals.insert(String::from("push"), vec![String::from("0xzz")]);
+
+        assert!(initial_stack(&inv).is_err());
+    }
+}
@@ -12,6 +12,86 @@ pub const NUM_BLOCKS_RETARGET: usize = 50;
 
 pub const RETARGET_INTERVAL: u64 = (NUM_BLOCKS_RETARGET as u64) * TARGET_BLOCK_INTERVAL;
 
+/// The hardest allowed difficulty target. A target of zero could never be satisfied
+pub const MIN_TARGET: Hash256 = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+];
+/// The easiest allowed difficulty target
+pub const MAX_TARGET: Hash256 = [
+    0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// Every block on a regtest network uses this target, no matter what the genesis block says. It's the easiest
+/// compact target within bounds, and regtest nodes don't check proof of work anyway.
+pub const REGTEST_TARGET: Hash256 = [
+    0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// True if the target is within [MIN_TARGET] and [MAX_TARGET]. Hashes are big endian so they can be compared
+/// directly.
+pub fn target_in_bounds(target: &Hash256) -> bool {
+    *target >= MIN_TARGET && *target <= MAX_TARGET
+}
+
+/// The compact 4-byte "bits" form of a target, as in Bitcoin. Only the top 3 bytes of the target are kept
+pub fn get_difficulty_bits(target: &Hash256) -> u32 {
+    let start = target.iter().position(|b| *b != 0).unwrap_or(32);
+    let mut size = (32 - start) as u32;
+    let significant = &target[start..];
+
+    let mut mantissa = significant
+        .iter()
+        .take(3)
+        .fold(0_u32, |a, e| (a << 8) | (*e as u32));
+
+    if significant.len() < 3 {
+        mantissa <<= 8 * (3 - significant.len() as u32);
+    }
+
+    // The top bit of the mantissa is a sign bit in Bitcoin, so move everything over a byte if it's set
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
+/// Expand compact "bits" into a difficulty target. None if it doesn't fit in 32 bytes
+pub fn get_difficulty_target(bits: u32) -> Option<Hash256> {
+    let size = (bits >> 24) as usize;
+    let mantissa = BigUint::from(bits & 0x007f_ffff);
+
+    let target = match size {
+        0..=3 => mantissa >> (8 * (3 - size)),
+        _ => mantissa << (8 * (size - 3)),
+    };
+
+    let bytes = target.to_bytes_be();
+
+    if bytes.len() > 32 {
+        return None;
+    }
+
+    let mut out = [0_u8; 32];
+    out[(32 - bytes.len())..].copy_from_slice(&bytes);
+
+    Some(out)
+}
+
+/// True if the target can be written in compact form
+pub fn target_is_compact(target: &Hash256) -> bool {
+    get_difficulty_target(get_difficulty_bits(target)) == Some(*target)
+}
+
+/// Round a target down to the nearest target that can be written in compact form
+pub fn compact_target(target: &Hash256) -> Hash256 {
+    get_difficulty_target(get_difficulty_bits(target)).unwrap_or(*target)
+}
+
 pub fn retarget_difficulty(old: Hash256, last_block: &Block, first_block: &Block) -> Hash256 {
     let secs = last_block.header.timestamp - first_block.header.timestamp;
     let mut adjustment = secs / RETARGET_INTERVAL;
@@ -25,10 +105,93 @@ pub fn retarget_difficulty(old: Hash256, last_block: &Block, first_block: &Block
     }
 
     let new_hash_uint = BigUint::from_bytes_be(&old) * adjustment;
+
+    // Keep the new target within bounds
+    if new_hash_uint > BigUint::from_bytes_be(&MAX_TARGET) {
+        return compact_target(&MAX_TARGET);
+    }
+
     let bytes = new_hash_uint.to_bytes_be();
     let mut out = [0_u8; 32];
 
     out[(32 - bytes.len())..].copy_from_slice(&bytes);
 
-    out
+    if out < MIN_TARGET {
+        return MIN_TARGET;
+    }
+
+    compact_target(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_target_is_out_of_bounds() {
+        assert!(!target_in_bounds(&[0; 32]));
+        assert!(target_in_bounds(&MIN_TARGET));
+    }
+
+    #[test]
+    fn oversized_target_is_out_of_bounds() {
+        let mut just_over = [0; 32];
+        just_over[1] = 1;
+
+        assert!(!target_in_bounds(&[0xff; 32]));
+        assert!(!target_in_bounds(&just_over));
+        assert!(target_in_bounds(&MAX_TARGET));
+        assert!(target_in_bounds(&REGTEST_TARGET));
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        for size in 3..=32_u32 {
+            for mantissa in [0x01_0000, 0x12_3456, 0x7f_ffff] {
+                let bits = (size << 24) | mantissa;
+                let target = get_difficulty_target(bits).unwrap();
+
+                assert_eq!(get_difficulty_bits(&target), bits, "{:08x}", bits);
+                assert!(target_is_compact(&target));
+            }
+        }
+    }
+
+    #[test]
+    fn small_exponents_round_trip() {
+        for bits in [0x0112_0000, 0x0212_3400] {
+            let target = get_difficulty_target(bits).unwrap();
+
+            assert_eq!(get_difficulty_bits(&target), bits);
+        }
+
+        assert_eq!(get_difficulty_target(0x0112_0000).unwrap()[31], 0x12);
+    }
+
+    #[test]
+    fn bitcoin_genesis_bits() {
+        let mut target = [0; 32];
+        target[4] = 0xff;
+        target[5] = 0xff;
+
+        assert_eq!(get_difficulty_target(0x1d00_ffff), Some(target));
+        assert_eq!(get_difficulty_bits(&target), 0x1d00_ffff);
+        assert_eq!(get_difficulty_bits(&REGTEST_TARGET), 0x1f00_ffff);
+    }
+
+    #[test]
+    fn oversized_bits_have_no_target() {
+        assert_eq!(get_difficulty_target(0x2101_0000), None);
+    }
+
+    #[test]
+    fn compact_target_rounds_down() {
+        assert!(!target_is_compact(&MAX_TARGET));
+
+        let rounded = compact_target(&MAX_TARGET);
+
+        assert!(target_is_compact(&rounded));
+        assert!(rounded < MAX_TARGET);
+        assert_eq!(rounded, REGTEST_TARGET);
+    }
 }
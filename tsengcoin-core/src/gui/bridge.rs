@@ -1,5 +1,9 @@
 use crate::{v1::state::GUIChannels, command::CommandInvocation};
-use std::error::Error;
+use std::{
+    env::{self, VarError},
+    error::Error,
+    fs,
+};
 
 #[cfg(feature = "gui")]
 pub fn is_connection_accepted(
@@ -45,16 +49,93 @@ pub fn is_connection_accepted(
     Ok(default)
 }
 
+/// Environment variable that can hold the wallet password, so it doesn't have to be passed as an argument
+pub const WALLET_PASSWORD_ENV: &str = "TSENGCOIN_WALLET_PASSWORD";
+
+/// Get a password given with `--password-file` or [WALLET_PASSWORD_ENV], in that order. These take precedence over
+/// a password passed in as an argument because they don't leak into shell history or process listings.
+pub fn get_password_override(invocation: &CommandInvocation) -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(path) = invocation.get_optional("password-file") {
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read password file {}: {}", path, err))?;
+        let password = contents.lines().next().unwrap_or("").to_owned();
+
+        return Ok(Some(password));
+    }
+
+    match env::var(WALLET_PASSWORD_ENV) {
+        Ok(password) => Ok(Some(password)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(format!("{} is not valid unicode", WALLET_PASSWORD_ENV).into()),
+    }
+}
+
 #[cfg(feature = "gui")]
-pub fn get_wallet_password_arg(invocation: &CommandInvocation) -> String {
-    invocation.get_field("wallet-password")
+pub fn get_wallet_password_arg(invocation: &CommandInvocation) -> Result<String, Box<dyn Error>> {
+    if let Some(password) = get_password_override(invocation)? {
+        return Ok(password);
+    }
+
+    Ok(invocation.get_field("wallet-password")
     .unwrap_or_else(|| {
         fltk::dialog::password_default("Enter your wallet password", "")
             .expect("Need to supply a password!")
-    })
+    }))
 }
 
 #[cfg(not(feature = "gui"))]
-pub fn get_wallet_password_arg(invocation: &CommandInvocation) -> String {
-    invocation.get_field("wallet-password").unwrap()
+pub fn get_wallet_password_arg(invocation: &CommandInvocation) -> Result<String, Box<dyn Error>> {
+    if let Some(password) = get_password_override(invocation)? {
+        return Ok(password);
+    }
+
+    invocation
+        .get_field("wallet-password")
+        .ok_or_else(|| "Missing wallet password".into())
+}
+
+#[cfg(all(test, not(feature = "gui")))]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::v1::test_util::temp_data_dir;
+
+    fn invocation(password_file: Option<&str>) -> CommandInvocation {
+        let mut optionals = HashMap::new();
+
+        if let Some(path) = password_file {
+            optionals.insert(String::from("password-file"), path.to_owned());
+        }
+
+        CommandInvocation {
+            name: String::new(),
+            flags: vec![],
+            args: vec![],
+            vars: HashMap::new(),
+            fields: HashMap::from([(String::from("wallet-password"), String::from("from arg"))]),
+            optionals,
+            repeated_optionals: HashMap::new(),
+        }
+    }
+
+    // Every case is in one test because the environment is shared by tests running at the same time
+    #[test]
+    fn password_file_then_env_then_arg() {
+        let dir = temp_data_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/password", dir);
+        fs::write(&path, "from file\nignored\n").unwrap();
+        let missing = format!("{}/missing", dir);
+
+        env::remove_var(WALLET_PASSWORD_ENV);
+        assert_eq!(get_wallet_password_arg(&invocation(None)).unwrap(), "from arg");
+
+        env::set_var(WALLET_PASSWORD_ENV, "from env");
+        assert_eq!(get_wallet_password_arg(&invocation(None)).unwrap(), "from env");
+        assert_eq!(get_wallet_password_arg(&invocation(Some(&path))).unwrap(), "from file");
+        assert!(get_wallet_password_arg(&invocation(Some(&missing))).is_err());
+
+        env::remove_var(WALLET_PASSWORD_ENV);
+    }
 }
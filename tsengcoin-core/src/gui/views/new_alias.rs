@@ -9,7 +9,7 @@ use fltk::{
     window::Window,
 };
 
-use crate::{gui::views::BasicVisible, v1::state::State, wallet::b58c_to_address};
+use crate::{gui::views::BasicVisible, v1::state::State};
 use basic_visible_derive::BasicVisible;
 
 #[derive(BasicVisible)]
@@ -57,7 +57,7 @@ impl NewAliasUI {
             let address_b58c = address_input.value();
             let alias = alias_input.value();
 
-            let address = match b58c_to_address(address_b58c) {
+            let address = match state.friends.get_address(address_b58c) {
                 Err(_) => {
                     error_display.show();
                     return;
@@ -14,7 +14,7 @@ use crate::{
     gui::views::BasicVisible,
     v1::{
         request::send_new_txn,
-        state::State, transaction::{make_single_p2pkh_txn, sign_txn, make_p2pkh_unlock, TxnInput, UnhashedTransaction, hash_txn}, VERSION, txn_verify::verify_transaction,
+        state::State, transaction::{make_single_p2pkh_txn, sign_txn, make_p2pkh_unlock, TxnInput, UnhashedTransaction, hash_txn}, VERSION, txn_verify::verify_pending_transaction,
     },
 };
 use basic_visible_derive::BasicVisible;
@@ -138,7 +138,7 @@ impl NewTxnUI {
             };
             let full_txn = unhashed.to_hashed(hash);
 
-            match verify_transaction(full_txn.clone(), state) {
+            match verify_pending_transaction(full_txn.clone(), state) {
                 Ok(_) => (),
                 Err(err) => {
                     fltk::dialog::alert_default(&format!("Error: {}", err));
@@ -348,3 +348,91 @@ pub fn to_bytes(hash: [u32; 8]) -> [u8; 32] {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use ring::digest::{digest, SHA256};
+
+    use super::*;
+    use crate::v1::{block::hash_block_header, test_util::test_genesis};
+
+    fn ring_sha256(input: &[u8]) -> Hash256 {
+        digest(&SHA256, input).as_ref().try_into().unwrap()
+    }
+
+    fn genesis_header_bytes() -> Vec<u8> {
+        let header: RawBlockHeader = (&test_genesis().header).into();
+
+        bincode::serialize(&header).unwrap()
+    }
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            hex::encode(hash_sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex::encode(hash_sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn matches_ring_across_chunk_boundaries() {
+        for len in [55, 56, 63, 64, 65, 119, 120, 128, 200] {
+            let input = vec![0x61_u8; len];
+
+            assert_eq!(hash_sha256(&input), ring_sha256(&input), "{} byte input", len);
+        }
+    }
+
+    #[test]
+    fn block_header_hash_matches_ring() {
+        let header: RawBlockHeader = (&test_genesis().header).into();
+        let bytes = genesis_header_bytes();
+
+        assert_eq!(hash_block_header(&header), ring_sha256(&bytes));
+        assert_eq!(hash_block(&header).unwrap(), ring_sha256(&bytes));
+    }
+
+    #[test]
+    fn first_chunk_starts_from_initial_hash() {
+        let bytes = genesis_header_bytes();
+        let (schedule, hash_vars) = hash_chunks(&bytes, 0);
+
+        assert_eq!(hash_vars, H);
+        assert_eq!(schedule[0], u32::from_be_bytes(bytes[0..4].try_into().unwrap()));
+        assert_eq!(schedule[15], u32::from_be_bytes(bytes[60..64].try_into().unwrap()));
+    }
+
+    /// The miners hash the first chunk of the header once, and only the first 4 words of the second chunk come
+    /// before the nonce
+    #[test]
+    fn header_midstate_does_not_depend_on_nonce() {
+        let bytes = genesis_header_bytes();
+        let mut other = bytes.clone();
+        let len = other.len();
+        other[(len - 32)..].copy_from_slice(&[0xFF; 32]);
+
+        let (schedule, hash_vars) = hash_chunks(&bytes, 1);
+        let (other_schedule, other_hash_vars) = hash_chunks(&other, 1);
+
+        assert_ne!(hash_vars, H);
+        assert_eq!(hash_vars, other_hash_vars);
+        assert_eq!(schedule[0..4], other_schedule[0..4]);
+        assert_ne!(schedule[4..12], other_schedule[4..12]);
+        assert_eq!(schedule[0], u32::from_be_bytes(bytes[64..68].try_into().unwrap()));
+    }
+
+    /// The GPU kernels build the rest of the second chunk themselves, so it has to match the padding here
+    #[test]
+    fn second_header_chunk_is_nonce_and_padding() {
+        let bytes = genesis_header_bytes();
+        let (schedule, _) = hash_chunks(&bytes, 1);
+
+        assert_eq!(schedule[4], u32::from_be_bytes(bytes[80..84].try_into().unwrap()));
+        assert_eq!(schedule[11], u32::from_be_bytes(bytes[108..112].try_into().unwrap()));
+        assert_eq!(schedule[12..], [0x8000_0000, 0, 0, 0x0000_0380]);
+    }
+}
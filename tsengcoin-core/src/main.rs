@@ -6,11 +6,16 @@ pub mod command;
 pub mod difficulty;
 pub mod hash;
 pub mod script_error;
+pub mod script_examples;
 pub mod tsengscript_interpreter;
 pub mod wallet;
 
 use std::{env, error::Error};
 
+// Regtest nodes accept blocks without proof of work, so they must never end up in a release build
+#[cfg(all(feature = "regtest", not(debug_assertions)))]
+compile_error!("The regtest feature can't be used in release builds");
+
 use command::dispatch_command;
 use commands::top_level::make_command_map;
 
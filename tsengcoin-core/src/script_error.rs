@@ -20,6 +20,22 @@ pub enum ErrorKind {
     EqualVerifyFailed,
 }
 
+impl ErrorKind {
+    /// A number that identifies this kind of script error. Codes never change once assigned, so tools can
+    /// rely on them instead of parsing error messages.
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorKind::ScriptTooLong(_, _) => 101,
+            ErrorKind::InvalidScriptToken(_) => 102,
+            ErrorKind::ScriptStackOverflow => 103,
+            ErrorKind::ScriptStackUnderflow => 104,
+            ErrorKind::InvalidTokenType => 105,
+            ErrorKind::IntegerOverflow => 106,
+            ErrorKind::EqualVerifyFailed => 107,
+        }
+    }
+}
+
 impl StdError for ErrorKind {
     fn description(&self) -> &str {
         match *self {
@@ -62,3 +78,25 @@ impl fmt::Display for ErrorKind {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_kind_has_its_own_code() {
+        let kinds = [
+            (ErrorKind::ScriptTooLong(0, 0), 101),
+            (ErrorKind::InvalidScriptToken(String::new()), 102),
+            (ErrorKind::ScriptStackOverflow, 103),
+            (ErrorKind::ScriptStackUnderflow, 104),
+            (ErrorKind::InvalidTokenType, 105),
+            (ErrorKind::IntegerOverflow, 106),
+            (ErrorKind::EqualVerifyFailed, 107),
+        ];
+
+        for (kind, code) in kinds {
+            assert_eq!(kind.code(), code, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use num_bigint::BigUint;
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+
+use crate::{
+    tsengscript_interpreter::{execute, Token},
+    v1::transaction::{make_p2pkh_lock, make_p2pkh_unlock},
+    wallet::{address_from_public_key, Address},
+};
+
+/// The data signed in the P2PKH examples. In a real transaction this would be the serialized unsigned transaction.
+/// It can't start with a zero byte because the interpreter drops leading zeros from byte sequences.
+const EXAMPLE_DATA: &[u8] = b"TsengCoin script example";
+
+/// A TsengScript program with a known result, used to show how the language works and to check the interpreter
+pub struct ScriptExample {
+    pub name: &'static str,
+    pub script: String,
+    /// Pushed onto the stack before the script runs
+    pub init_stack: Vec<Token>,
+    pub expected: ExpectedResult,
+}
+
+/// What should be on top of the stack after an example runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpectedResult {
+    Bool(bool),
+    UByteSeq(BigUint),
+    /// The script should fail to execute
+    Error,
+}
+
+impl ScriptExample {
+    fn new(name: &'static str, script: &str, expected: ExpectedResult) -> Self {
+        Self {
+            name,
+            script: script.to_owned(),
+            init_stack: vec![],
+            expected,
+        }
+    }
+
+    /// Run the example and compare the top of the stack to the expected result. Returns a description of
+    /// what happened instead if they don't match.
+    pub fn run(&self) -> Result<(), String> {
+        let actual = match execute(&self.script, &self.init_stack) {
+            Err(_) if self.expected == ExpectedResult::Error => return Ok(()),
+            Err(err) => return Err(format!("script failed: {}", err)),
+            Ok(res) => res.top,
+        };
+
+        match (&self.expected, actual) {
+            (ExpectedResult::Bool(exp), Some(Token::Bool(val))) if *exp == val => Ok(()),
+            (ExpectedResult::UByteSeq(exp), Some(Token::UByteSeq(val))) if *exp == val => Ok(()),
+            (exp, actual) => Err(format!("expected {:?}, got {:?}", exp, actual)),
+        }
+    }
+}
+
+/// The bundled examples. The P2PKH examples sign with a fresh keypair every time, so they are built at runtime.
+pub fn script_examples() -> Vec<ScriptExample> {
+    let num = |n: u64| ExpectedResult::UByteSeq(BigUint::from(n));
+
+    let mut out = vec![
+        ScriptExample::new("Addition", "5 2 ADD", num(7)),
+        ScriptExample::new("Subtraction pops the first operand last", "2 9 SUB", num(7)),
+        ScriptExample::new("Subtraction can't go below zero", "9 2 SUB", ExpectedResult::Error),
+        ScriptExample::new("Arithmetic and comparison", "5 2 ADD 9 SUB 2 EQUAL", ExpectedResult::Bool(true)),
+        ScriptExample::new("Literals are hex", "a 5 5 ADD EQUAL", ExpectedResult::Bool(true)),
+        ScriptExample::new("WITHIN includes the minimum", "1 1 5 WITHIN", ExpectedResult::Bool(true)),
+        ScriptExample::new("WITHIN excludes the maximum", "5 1 5 WITHIN", ExpectedResult::Bool(false)),
+        ScriptExample::new("REQUIRE_EQUAL stops the script", "1 2 REQUIRE_EQUAL", ExpectedResult::Error),
+        ScriptExample::new("DUP", "3 DUP ADD", num(6)),
+        ScriptExample::new("2DUP keeps the order", "3 4 2DUP SUB ADD ADD", num(8)),
+        ScriptExample::new("2DROP", "1 2 3 2DROP", num(1)),
+        ScriptExample::new("TUCK copies the top below the second", "3 4 TUCK ADD SUB", num(3)),
+        ScriptExample::new("Operators need enough arguments", "1 ADD", ExpectedResult::Error),
+        ScriptExample::new("Unknown operators are no-ops", "1 NOP1 FUTURE_OP", num(1)),
+    ];
+
+    out.append(&mut p2pkh_examples());
+
+    out
+}
+
+fn p2pkh_examples() -> Vec<ScriptExample> {
+    let rng = SystemRandom::new();
+    let alg = &ECDSA_P256_SHA256_ASN1_SIGNING;
+    let keypair = |rng| {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, rng).expect("Failed to generate ECDSA pkcs8");
+        EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).expect("Failed to create ECDSA keypair")
+    };
+    let signer = keypair(&rng);
+    let someone_else = keypair(&rng);
+
+    let pubkey = signer.public_key().as_ref().to_vec();
+    let address = address_from_public_key(&pubkey);
+    let other_address = address_from_public_key(&someone_else.public_key().as_ref().to_vec());
+    let sig = signer
+        .sign(&rng, EXAMPLE_DATA)
+        .expect("Failed to sign example data")
+        .as_ref()
+        .to_vec();
+    let unlock = make_p2pkh_unlock(sig, pubkey).code;
+    let data = Token::UByteSeq(BigUint::from_bytes_be(EXAMPLE_DATA));
+    let wrong_data = Token::UByteSeq(BigUint::from_bytes_be(b"Some other data"));
+
+    let p2pkh = |name: &'static str, lock_address: &Address, init: &Token, expected: ExpectedResult| ScriptExample {
+        name,
+        script: format!("{} {}", unlock, make_p2pkh_lock(lock_address).code),
+        init_stack: vec![init.clone()],
+        expected,
+    };
+
+    vec![
+        p2pkh("P2PKH unlock", &address, &data, ExpectedResult::Bool(true)),
+        p2pkh("P2PKH with someone else's address", &other_address, &data, ExpectedResult::Error),
+        p2pkh("P2PKH with a signature over different data", &address, &wrong_data, ExpectedResult::Bool(false)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples_give_expected_result() {
+        for example in script_examples() {
+            if let Err(err) = example.run() {
+                panic!("{}: {}", example.name, err);
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_expected_result_fails() {
+        let example = ScriptExample::new("Addition", "5 2 ADD", ExpectedResult::Bool(true));
+
+        assert!(example.run().is_err());
+
+        let example = ScriptExample::new("Addition", "5 2 ADD", ExpectedResult::Error);
+
+        assert!(example.run().is_err());
+    }
+}
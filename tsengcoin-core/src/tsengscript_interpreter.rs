@@ -14,7 +14,7 @@ use crate::{
 };
 
 /// Scripts can be 1kb max to mitigate malicious transactions
-const MAX_SCRIPT_LEN: usize = 1024;
+pub const MAX_SCRIPT_LEN: usize = 1024;
 
 /// Stack can have up to 2048 tokens
 /// This will allow TsengScript to support small, non-recursive procedures
@@ -23,6 +23,12 @@ const MAX_STACK_SIZE: usize = 2048;
 
 type OperatorFn = fn(stack: &mut Vec<Token>) -> ScriptResult<()>;
 
+#[cfg(test)]
+thread_local! {
+    /// How many scripts this thread has executed. Tests use this to check that a script never ran.
+    pub static EXECUTIONS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
 #[derive(Clone)]
 pub enum Token {
     UByteSeq(BigUint),
@@ -52,13 +58,36 @@ fn make_operator_name_map() -> HashMap<String, OperatorFn> {
     out.insert(String::from("SUB"), op_sub);
     out.insert(String::from("EQUAL"), op_equal);
     out.insert(String::from("REQUIRE_EQUAL"), op_require_equal);
+    out.insert(String::from("WITHIN"), op_within);
     out.insert(String::from("DUP"), op_dup);
+    out.insert(String::from("2DUP"), op_2dup);
+    out.insert(String::from("2DROP"), op_2drop);
+    out.insert(String::from("TUCK"), op_tuck);
     out.insert(String::from("HASH160"), op_hash160);
     out.insert(String::from("CHECKSIG"), op_checksig);
 
+    // Reserved for future opcodes
+    for i in 1..=10 {
+        out.insert(format!("NOP{}", i), op_nop);
+    }
+
     out
 }
 
+/// An unknown token that looks like an opcode (uppercase letters, digits, and underscores, starting
+/// with a letter). These are treated as no-ops so that new opcodes can be added without old nodes
+/// rejecting scripts that use them.
+fn is_unknown_opcode(raw_token: &str) -> bool {
+    let mut chars = raw_token.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_uppercase() => {
+            chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        }
+        _ => false,
+    }
+}
+
 fn split(input: &String) -> Vec<String> {
     input.split(' ').map(|s| s.to_owned()).collect()
 }
@@ -94,6 +123,11 @@ fn tokenize(raw_tokens: &Vec<String>) -> ScriptResult<Vec<Token>> {
         // Pad and check if it is a hex string
         let hex_opt = hex::decode(padded_token);
         if hex_opt.is_err() {
+            if is_unknown_opcode(raw_token) {
+                out.push(Token::Operator(op_nop));
+                continue;
+            }
+
             return Err(Box::new(InvalidScriptToken(raw_token.to_owned())));
         }
 
@@ -105,8 +139,25 @@ fn tokenize(raw_tokens: &Vec<String>) -> ScriptResult<Vec<Token>> {
     Ok(out)
 }
 
+/// Count the signature checks in a script without running it. Signature checks are by far the most expensive
+/// operators, so this is what limits how costly a block is to verify. Every `CHECKSIG` counts, even if it would
+/// never be reached.
+pub fn count_sigops(script: &String) -> usize {
+    split(script).iter().filter(|t| *t == "CHECKSIG").count()
+}
+
+/// Returns true if the script only pushes data onto the stack and doesn't contain any operators.
+pub fn is_push_only(script: &String) -> ScriptResult<bool> {
+    let tokens = tokenize(&split(script))?;
+
+    Ok(!tokens.iter().any(|t| matches!(t, Token::Operator(_))))
+}
+
 /// Executes a TsengScript, returning the top of the stack plus the stack's contents.
 pub fn execute(script: &String, stack_init: &Vec<Token>) -> ScriptResult<ExecutionResult> {
+    #[cfg(test)]
+    EXECUTIONS.with(|n| n.set(n.get() + 1));
+
     let script_len = script.as_bytes().len();
     if script_len > MAX_SCRIPT_LEN {
         return Err(Box::new(ScriptTooLong(MAX_SCRIPT_LEN, script_len)));
@@ -222,6 +273,29 @@ fn op_require_equal(stack: &mut Vec<Token>) -> ScriptResult<()> {
     Ok(())
 }
 
+fn op_within(stack: &mut Vec<Token>) -> ScriptResult<()> {
+    if stack.len() < 3 {
+        return Err(Box::new(ScriptStackUnderflow));
+    }
+
+    let max = stack.pop().unwrap();
+    let min = stack.pop().unwrap();
+    let x = stack.pop().unwrap();
+
+    match (x, min, max) {
+        (Token::UByteSeq(x), Token::UByteSeq(min), Token::UByteSeq(max)) => {
+            stack.push(Token::Bool(min <= x && x < max));
+        }
+        (_, _, _) => return Err(Box::new(InvalidTokenType)),
+    };
+
+    Ok(())
+}
+
+fn op_nop(_stack: &mut Vec<Token>) -> ScriptResult<()> {
+    Ok(())
+}
+
 fn op_dup(stack: &mut Vec<Token>) -> ScriptResult<()> {
     if stack.is_empty() {
         return Err(Box::new(ScriptStackUnderflow));
@@ -235,6 +309,41 @@ fn op_dup(stack: &mut Vec<Token>) -> ScriptResult<()> {
     Ok(())
 }
 
+fn op_2dup(stack: &mut Vec<Token>) -> ScriptResult<()> {
+    if stack.len() < 2 {
+        return Err(Box::new(ScriptStackUnderflow));
+    }
+
+    let top_two = stack[stack.len() - 2..].to_vec();
+    stack.extend(top_two);
+
+    Ok(())
+}
+
+fn op_2drop(stack: &mut Vec<Token>) -> ScriptResult<()> {
+    if stack.len() < 2 {
+        return Err(Box::new(ScriptStackUnderflow));
+    }
+
+    stack.truncate(stack.len() - 2);
+
+    Ok(())
+}
+
+fn op_tuck(stack: &mut Vec<Token>) -> ScriptResult<()> {
+    if stack.len() < 2 {
+        return Err(Box::new(ScriptStackUnderflow));
+    }
+
+    let op1 = stack.pop().unwrap();
+    let op2 = stack.pop().unwrap();
+    stack.push(op1.clone());
+    stack.push(op2);
+    stack.push(op1);
+
+    Ok(())
+}
+
 fn op_hash160(stack: &mut Vec<Token>) -> ScriptResult<()> {
     if stack.is_empty() {
         return Err(Box::new(ScriptStackUnderflow));
@@ -282,3 +391,66 @@ fn op_checksig(stack: &mut Vec<Token>) -> ScriptResult<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack_after(script: &str) -> String {
+        let ExecutionResult { stack, .. } = execute(&script.to_owned(), &vec![]).unwrap();
+
+        format!("{:?}", stack)
+    }
+
+    #[test]
+    fn nops_leave_stack_alone() {
+        let expected = stack_after("5 2");
+
+        assert_eq!(stack_after("5 NOP1 2 NOP10"), expected);
+        assert_eq!(stack_after("NOP5 5 2"), expected);
+    }
+
+    #[test]
+    fn unknown_opcodes_are_nops() {
+        assert_eq!(stack_after("5 FUTURE_OP 2 OP2"), stack_after("5 2"));
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        for script in ["5 12G4", "5 abcz", "5 Nop1", "5 _OP"] {
+            assert!(execute(&script.to_owned(), &vec![]).is_err(), "{}", script);
+        }
+    }
+
+    #[test]
+    fn push_only_scripts() {
+        assert!(is_push_only(&String::from("01 ab TRUE FALSE")).unwrap());
+        assert!(is_push_only(&String::new()).unwrap());
+        assert!(!is_push_only(&String::from("01 02 ADD")).unwrap());
+        assert!(!is_push_only(&String::from("01 NOP1")).unwrap());
+        assert!(is_push_only(&String::from("01 12G4")).is_err());
+    }
+
+    #[test]
+    fn within_checks_half_open_range() {
+        // x min max WITHIN
+        for (script, expected) in [("05 02 0a", true), ("01 02 0a", false), ("02 02 0a", true), ("0a 02 0a", false)] {
+            assert_eq!(stack_after(&format!("{} WITHIN", script)), stack_after(if expected { "TRUE" } else { "FALSE" }));
+        }
+    }
+
+    #[test]
+    fn two_item_stack_ops() {
+        assert_eq!(stack_after("01 02 03 2DUP"), stack_after("01 02 03 02 03"));
+        assert_eq!(stack_after("01 02 03 2DROP"), stack_after("01"));
+        assert_eq!(stack_after("01 02 03 TUCK"), stack_after("01 03 02 03"));
+    }
+
+    #[test]
+    fn two_item_stack_ops_need_two_items() {
+        for op in ["2DUP", "2DROP", "TUCK"] {
+            let err = execute(&format!("01 {}", op), &vec![]).err().unwrap();
+            assert!(matches!(*err, ScriptStackUnderflow), "{}", op);
+        }
+    }
+}
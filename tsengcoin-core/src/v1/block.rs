@@ -1,22 +1,28 @@
-use std::mem::{size_of, size_of_val};
+use std::{
+    error::Error,
+    mem::{self, size_of, size_of_val},
+};
 
 use chrono::Duration;
 use lazy_static::lazy_static;
 use num_bigint::BigUint;
-use num_traits::Zero;
+use num_traits::{One, Zero};
 use ring::digest::{Context, SHA256};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    difficulty::{get_difficulty_target, REGTEST_TARGET},
     hash::hash_sha256,
     wallet::{b58c_to_address, Hash256},
 };
 
 use super::{
-    block_verify::verify_block,
+    block_verify::{verify_block, BlockStatus},
+    block_verify_error::{BlockVerifyResult, ErrorKind::NonContiguousFork},
     state::State,
     transaction::{
-        build_utxos_from_confirmed, make_coinbase_txn, ConfirmedTransaction, Transaction, UTXOPool,
+        build_utxos_from_confirmed, make_coinbase_txn, ConfirmedTransaction, Transaction, TransactionIndex,
+        UTXOPool,
     },
     txn_verify::check_pending_and_orphans,
 };
@@ -26,6 +32,19 @@ pub const MAX_BLOCK_SIZE: usize = 16384;
 
 pub const MAX_TRANSACTION_FIELD_SIZE: usize = MAX_BLOCK_SIZE - size_of::<BlockHeader>();
 
+/// Max number of transactions in a block, including the coinbase transaction. This keeps the cost of
+/// computing the Merkle root predictable.
+pub const MAX_TXNS_PER_BLOCK: usize = 256;
+
+/// Max number of signature checks in a block, counted with [Transaction::sigops] and
+/// [spent_sigops](super::transaction::spent_sigops). A block full of signature checks can be expensive to verify even
+/// if it's under the size limit.
+pub const MAX_BLOCK_SIGOPS: usize = MAX_BLOCK_SIZE / 50;
+
+/// Max number of fork chains we keep at once. Any block that branches off of the main chain starts a new fork, so
+/// without a cap a peer could make us store as many forks as it can mine blocks for. See [BlockchainDB::evict_weakest_fork].
+pub const MAX_FORKS: usize = 16;
+
 lazy_static! {
     pub static ref BLOCK_TIMESTAMP_TOLERANCE: Duration = Duration::hours(2);
 }
@@ -45,7 +64,8 @@ pub struct BlockHeader {
     pub merkle_root: Hash256,
     // Creation time of the block in seconds since Unix epoch
     pub timestamp: u64,
-    pub difficulty_target: Hash256,
+    /// The difficulty target in compact form. Use [BlockHeader::target] to expand it
+    pub bits: u32,
     pub nonce: BlockNonce,
     pub hash: Hash256,
 }
@@ -63,7 +83,7 @@ pub struct RawBlockHeader {
     pub prev_hash: Hash256,
     pub merkle_root: Hash256,
     pub timestamp: u64,
-    pub difficulty_target: Hash256,
+    pub bits: u32,
     pub nonce: BlockNonce,
 }
 
@@ -83,13 +103,18 @@ pub struct ForkChain {
 }
 
 impl RawBlockHeader {
+    /// The difficulty target that the header's bits expand to, or zero if they don't fit in a hash
+    pub fn target(&self) -> Hash256 {
+        get_difficulty_target(self.bits).unwrap_or([0; 32])
+    }
+
     pub fn to_block_header(&self, nonce: Hash256, hash: Hash256) -> BlockHeader {
         BlockHeader {
             version: self.version,
             prev_hash: self.prev_hash,
             merkle_root: self.merkle_root,
             timestamp: self.timestamp,
-            difficulty_target: self.difficulty_target,
+            bits: self.bits,
             nonce,
             hash,
         }
@@ -112,7 +137,7 @@ impl From<&BlockHeader> for RawBlockHeader {
             prev_hash: block.prev_hash,
             merkle_root: block.merkle_root,
             timestamp: block.timestamp,
-            difficulty_target: block.difficulty_target,
+            bits: block.bits,
             nonce: block.nonce,
         }
     }
@@ -125,7 +150,7 @@ impl std::fmt::Debug for BlockHeader {
             .field("prev_hash", &hex::encode(&self.prev_hash))
             .field("merkle_root", &hex::encode(&self.merkle_root))
             .field("timestamp", &self.timestamp)
-            .field("difficulty_target", &hex::encode(&self.difficulty_target))
+            .field("bits", &format!("{:08x}", self.bits))
             .field("nonce", &hex::encode(&self.nonce))
             .field("hash", &hex::encode(&self.hash))
             .finish()
@@ -139,13 +164,18 @@ impl std::fmt::Debug for RawBlockHeader {
             .field("prev_hash", &hex::encode(&self.prev_hash))
             .field("merkle_root", &hex::encode(&self.merkle_root))
             .field("timestamp", &self.timestamp)
-            .field("difficulty_target", &hex::encode(&self.difficulty_target))
+            .field("bits", &format!("{:08x}", self.bits))
             .field("nonce", &hex::encode(&self.nonce))
             .finish()
     }
 }
 
 impl Block {
+    /// The total number of signature checks in the block's transactions
+    pub fn sigops(&self) -> usize {
+        self.transactions.iter().fold(0, |a, t| a + t.sigops())
+    }
+
     pub fn get_txn(&self, hash: Hash256) -> Option<Transaction> {
         self.transactions.iter().find(|t| t.hash == hash).cloned()
     }
@@ -167,18 +197,93 @@ impl Block {
 }
 
 impl BlockHeader {
+    /// The difficulty target that the header's bits expand to. Blocks are only stored once their bits have been
+    /// checked, so this is only zero for bits that don't fit in a hash.
+    pub fn target(&self) -> Hash256 {
+        get_difficulty_target(self.bits).unwrap_or([0; 32])
+    }
+
     pub fn size(&self) -> usize {
         size_of_val(&self.version)
             + size_of_val(&self.prev_hash)
             + size_of_val(&self.merkle_root)
             + size_of_val(&self.timestamp)
-            + size_of_val(&self.difficulty_target)
+            + size_of_val(&self.bits)
             + size_of_val(&self.nonce)
             + size_of_val(&self.hash)
     }
 }
 
+/// Sums the difficulty targets of a chain of blocks. Because a lower target means a harder block, a lower
+/// sum means more cumulative work.
+pub fn cumulative_target(targets: &[Hash256]) -> BigUint {
+    targets
+        .iter()
+        .fold(BigUint::zero(), |a, e| a + BigUint::from_bytes_be(e))
+}
+
+/// The expected number of hashes needed to mine a block with the given target: 2^256 / (target + 1)
+pub fn block_work(target: &Hash256) -> BigUint {
+    (BigUint::one() << 256) / (BigUint::from_bytes_be(target) + BigUint::one())
+}
+
+/// Sums the work of a chain of blocks. See [block_work].
+pub fn chain_work(targets: &[Hash256]) -> BigUint {
+    targets.iter().fold(BigUint::zero(), |a, e| a + block_work(e))
+}
+
+/// The chain index and duplicate flag of [BlockchainDB::best_chain], from each chain's difficulty targets
+pub fn best_chain_index(main_targets: &[Hash256], fork_targets: &[Vec<Hash256>]) -> (usize, bool) {
+    if fork_targets.is_empty() {
+        return (0, false);
+    }
+
+    let main_diff = cumulative_target(main_targets);
+    let fork_diffs = fork_targets
+        .iter()
+        .map(|f| cumulative_target(f))
+        .collect::<Vec<BigUint>>();
+
+    // A higher difficulty target corresponds to an easier difficulty, so what we actually want after summing
+    // up difficulty targets is their minimum.
+    let min_fork_diff = fork_diffs.iter().min().unwrap();
+    let min_index = fork_diffs.iter().position(|f| f == min_fork_diff).unwrap();
+
+    // After computing the best fork difficulty, check if the main chain is still more difficult
+    if main_diff < *min_fork_diff {
+        return (0, false);
+    }
+
+    // Check if the main chain has the same difficulty as a fork and that this is the best difficulty
+    if fork_diffs.contains(&main_diff) && main_diff == *min_fork_diff {
+        // Select the main chain if we have a fork with duplicate difficulty
+        return (0, true);
+    }
+
+    // There may be two forks with duplicate validity but we don't care because this is rare
+    (min_index + 1, false)
+}
+
 impl BlockchainDB {
+    /// A blockchain with nothing but the given genesis block
+    pub fn new(genesis: Block) -> Self {
+        let block_hash = genesis.header.hash;
+        let txn_hash = genesis.transactions[0].hash;
+
+        Self {
+            blocks: vec![genesis],
+            forks: vec![],
+            orphans: vec![],
+            utxo_pool: UTXOPool {
+                utxos: vec![TransactionIndex {
+                    block: Some(block_hash),
+                    txn: txn_hash,
+                    outputs: vec![0],
+                }],
+            },
+        }
+    }
+
     /// Returns the size of the best chain (the "best height"), the index of the best chain, and whether or not
     /// the best chain is not uniquely the best (i.e., true if there is another equally valid chain).
     /// The index will be 0 if the best chain is the main chain and 1,2,3...n for the nth fork.
@@ -188,51 +293,70 @@ impl BlockchainDB {
             return (self.blocks.len(), 0, false);
         }
 
+        let (main_targets, fork_targets) = self.chain_targets();
+
+        match best_chain_index(&main_targets, &fork_targets) {
+            (0, is_dup) => (self.blocks.len(), 0, is_dup),
+            (i, is_dup) => (
+                self.forks[i - 1].blocks.len() + self.forks[i - 1].prev_index,
+                i,
+                is_dup,
+            ),
+        }
+    }
+
+    /// The difficulty targets of the main chain and of every fork, starting from the point of the earliest fork.
+    /// These can be passed to [best_chain_index]. There must be at least one fork.
+    fn chain_targets(&self) -> (Vec<Hash256>, Vec<Vec<Hash256>>) {
         let start_i = self.forks[0].prev_index;
+        let targets = |blocks: &[Block]| {
+            blocks
+                .iter()
+                .map(|b| b.header.target())
+                .collect::<Vec<Hash256>>()
+        };
 
-        // The total difficulty targets from the point of the earliest fork to the last block on the
+        // The difficulty targets from the point of the earliest fork to the last block on the
         // main chain
-        let main_diff = self.blocks[start_i..].iter().fold(BigUint::zero(), |a, e| {
-            a + BigUint::from_bytes_be(&e.header.difficulty_target)
-        });
-        let fork_diffs = self.forks
-                .iter()
-                .map(|f| {
-                    // Add up the difficulties between the earliest fork and the current fork (on the main chain)
-                    self.blocks[start_i..f.prev_index]
-                        .iter()
-                        .fold(BigUint::zero(), |a, e| a + BigUint::from_bytes_be(&e.header.difficulty_target))
-                    +
-
-                    // Add up the difficulties on the current fork
-                    f.blocks[0..]
-                        .iter()
-                        .fold(BigUint::zero(), |a, e| a + BigUint::from_bytes_be(&e.header.difficulty_target))
-                })
-                .collect::<Vec<BigUint>>();
-
-        // A higher difficulty target corresponds to an easier difficulty, so what we actually want after summing
-        // up difficulty targets is their minimum.
-        let min_fork_diff = fork_diffs.iter().min().unwrap();
-        let min_index = fork_diffs.iter().position(|f| f == min_fork_diff).unwrap();
-
-        // After computing the best fork difficulty, check if the main chain is still more difficult
-        if main_diff < *min_fork_diff {
-            return (self.blocks.len(), 0, false);
-        }
+        let main_targets = targets(&self.blocks[start_i..]);
+        let fork_targets = self
+            .forks
+            .iter()
+            .map(|f| {
+                // The difficulties between the earliest fork and the current fork (on the main chain),
+                // followed by the difficulties on the current fork
+                let mut out = targets(&self.blocks[start_i..f.prev_index]);
+                out.append(&mut targets(&f.blocks));
+                out
+            })
+            .collect::<Vec<Vec<Hash256>>>();
+
+        (main_targets, fork_targets)
+    }
 
-        // Check if the main chain has the same difficulty as a fork and that this is the best difficulty
-        if fork_diffs.contains(&main_diff) && main_diff == *min_fork_diff {
-            // Select the main chain if we have a fork with duplicate difficulty
-            return (self.blocks.len(), 0, true);
+    /// Remove the fork with the least cumulative work and return it. The best chain is never removed, even if it's a
+    /// fork, and neither is the main chain. Returns None if there is no fork that can be removed.
+    pub fn evict_weakest_fork(&mut self) -> Option<ForkChain> {
+        if self.forks.is_empty() {
+            return None;
         }
 
-        // There may be two forks with duplicate validity but we don't care because this is rare
-        (
-            self.forks[min_index].blocks.len() + self.forks[min_index].prev_index,
-            min_index + 1,
-            false,
-        )
+        let (_, best_idx, _) = self.best_chain();
+        let (_, fork_targets) = self.chain_targets();
+
+        let weakest = fork_targets
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i + 1 != best_idx)
+            .min_by_key(|(_, targets)| chain_work(targets))
+            .map(|(i, _)| i)?;
+
+        Some(self.forks.remove(weakest))
+    }
+
+    /// True if a block added after the block at `pos` in the given chain would start a new fork
+    pub fn starts_fork(&self, chain_idx: usize, pos: usize) -> bool {
+        chain_idx == 0 && pos + 1 != self.blocks.len()
     }
 
     pub fn get_chain(&'_ self, index: usize) -> &'_ Vec<Block> {
@@ -243,6 +367,35 @@ impl BlockchainDB {
         &self.forks[index].blocks
     }
 
+    /// The block at a position in a chain. Positions in a fork start at the first block of the fork, the same as
+    /// the positions returned by [BlockchainDB::get_block].
+    pub fn block_at(&self, chain_idx: usize, pos: usize) -> Option<&Block> {
+        if chain_idx == 0 {
+            return self.blocks.get(pos);
+        }
+
+        self.forks.get(chain_idx - 1)?.blocks.get(pos)
+    }
+
+    /// The chain index and position of the parent of the block at the given position, or None for the genesis block.
+    /// The parent of the first block in a fork is in the main chain.
+    pub fn parent_pos(&self, chain_idx: usize, pos: usize) -> Option<(usize, usize)> {
+        if pos > 0 {
+            return Some((chain_idx, pos - 1));
+        }
+
+        if chain_idx == 0 {
+            return None;
+        }
+
+        Some((0, self.forks.get(chain_idx - 1)?.prev_index))
+    }
+
+    /// Nodes with different genesis blocks are on different networks and can't share blocks
+    pub fn genesis_hash(&self) -> Hash256 {
+        self.blocks[0].header.hash
+    }
+
     pub fn top_hash(&self, chain_idx: usize) -> Hash256 {
         self.get_chain(chain_idx).last().unwrap().header.hash
     }
@@ -350,7 +503,7 @@ impl BlockchainDB {
             return out;
         }
 
-        let chain = &self.forks[chain];
+        let chain = &self.forks[chain - 1];
 
         for block in &self.blocks[start_pos..(chain.prev_index + 1)] {
             out.push(block.clone());
@@ -403,11 +556,30 @@ impl BlockchainDB {
     }
 
     pub fn current_difficulty(&self) -> Hash256 {
-        self.blocks.last().unwrap().header.difficulty_target
+        if cfg!(feature = "regtest") {
+            return REGTEST_TARGET;
+        }
+
+        self.blocks.last().unwrap().header.target()
+    }
+
+    /// True if a block can be added after the block at `pos` in the given chain. New blocks can
+    /// branch off from anywhere on the main chain, but a fork can only be extended from its tip.
+    pub fn can_extend(&self, chain_idx: usize, pos: usize) -> bool {
+        match chain_idx {
+            0 => true,
+            i => pos + 1 == self.forks[i - 1].blocks.len(),
+        }
     }
 
-    pub fn add_block(&mut self, block: Block) {
+    pub fn add_block(&mut self, block: Block) -> BlockVerifyResult<()> {
         let (_, chain, pos) = self.get_block(block.header.prev_hash).unwrap();
+
+        // We don't support forks of forks, so the new block has to go on the end of its fork
+        if !self.can_extend(chain, pos) {
+            return Err(Box::new(NonContiguousFork(block.header.prev_hash)));
+        }
+
         let top = match chain {
             0 => self.blocks.last().unwrap(),
             i => self.forks[i - 1].blocks.last().unwrap(),
@@ -419,22 +591,15 @@ impl BlockchainDB {
                 0 => self.blocks.push(block),
                 i => self.forks[i - 1].blocks.push(block),
             };
-            return;
-        }
-
-        // TODO: Support this?
-        // It's so rare that it might not even be worth supporting - it's a lot
-        // of extra logic. If it does happen it will definitely cause weird bugs
-        // but it might not be worth it given how rarely such a bug would occur.
-        if chain != 0 {
-            println!("We have encoutered a fork of a fork");
-            return;
+            return Ok(());
         }
 
         self.forks.push(ForkChain {
             prev_index: pos,
             blocks: vec![block],
         });
+
+        Ok(())
     }
 
     fn resolve_forks(&mut self) -> Vec<Block> {
@@ -490,30 +655,24 @@ impl BlockchainDB {
     }
 }
 
-pub fn check_orphans(state: &mut State) {
-    let mut orphans_to_remove: Vec<usize> = vec![];
-
-    for i in 0..state.blockchain.orphans.len() {
-        let block = &state.blockchain.orphans[i];
-        let verify_result = verify_block(block.clone(), state);
+/// Try to add each orphan block to the blockchain. Orphans that are added or turn out to be invalid are removed
+/// from the orphan pool. Returns the number of orphans that were added.
+pub fn check_orphans(state: &mut State) -> usize {
+    // A block that is still an orphan goes back into the pool when it's verified, so take them all out first
+    let orphans = mem::take(&mut state.blockchain.orphans);
+    let mut num_added: usize = 0;
 
-        match verify_result {
+    for block in orphans {
+        match verify_block(block, state) {
             // Block is no longer an orphan!
-            Ok(false) => {
-                orphans_to_remove.push(i);
-            }
-            Err(err) => {
-                println!("Error verifying orphan block: {}", err);
-                orphans_to_remove.push(i);
-            }
+            Ok(BlockStatus::Added) => num_added += 1,
+            Err(err) => println!("Error verifying orphan block: {}", err),
             // Block is still an orphan
-            Ok(true) => (),
+            Ok(BlockStatus::MissingParent(_)) => (),
         };
     }
 
-    for pos in orphans_to_remove {
-        state.blockchain.orphans.remove(pos);
-    }
+    num_added
 }
 
 /// Tries to resolve any forks in the blockchain. If there is a unique best chain,
@@ -521,12 +680,13 @@ pub fn check_orphans(state: &mut State) {
 /// and it will add their transactions back to the pending/orphan pools as well as
 /// update the UTXO database accordingly.
 ///
-/// Returns true if forks were present and resolved.
-pub fn resolve_forks(state: &mut State) -> bool {
+/// Returns None if there were no forks to resolve, otherwise returns the hashes of the transactions
+/// from removed blocks that made it back into the pending pool.
+pub fn resolve_forks(state: &mut State) -> Option<Vec<Hash256>> {
     let mut fork_blocks = state.blockchain.resolve_forks();
 
     if fork_blocks.is_empty() {
-        return false;
+        return None;
     }
 
     let mut txns: Vec<Transaction> = vec![];
@@ -535,6 +695,7 @@ pub fn resolve_forks(state: &mut State) -> bool {
         txns.append(&mut block.to_network_txns());
     }
 
+    let txn_hashes = txns.iter().map(|t| t.hash).collect::<Vec<Hash256>>();
     state.pending_txns.append(&mut txns);
 
     // Reset the UTXO database, then check all pending and orphan transactions.
@@ -543,7 +704,98 @@ pub fn resolve_forks(state: &mut State) -> bool {
     state.blockchain.utxo_pool = build_utxos_from_confirmed(&state.blockchain.blocks);
     check_pending_and_orphans(state);
 
-    true
+    let returned = txn_hashes
+        .into_iter()
+        .filter(|h| state.pending_txns.iter().any(|t| t.hash == *h))
+        .collect::<Vec<Hash256>>();
+
+    Some(returned)
+}
+
+/// Throws away every main chain block above `height`, along with any forks that branch off of them. The height
+/// counts the genesis block, so a height of 1 keeps only the genesis block. Transactions from the removed blocks
+/// go back into the pending pool and the UTXO database is rebuilt, the same as when forks are resolved.
+///
+/// Returns the number of blocks removed and the hashes of the transactions that made it back into the pending pool.
+pub fn truncate_main_chain(state: &mut State, height: usize) -> Result<(usize, Vec<Hash256>), Box<dyn Error>> {
+    if height == 0 {
+        return Err("The genesis block can't be removed".into());
+    }
+
+    if height > state.blockchain.blocks.len() {
+        return Err(format!(
+            "The main chain is only {} blocks long",
+            state.blockchain.blocks.len()
+        )
+        .into());
+    }
+
+    let mut removed = state.blockchain.blocks.split_off(height);
+
+    // Forks that branch off of a removed block have nothing to attach to anymore
+    let (dead_forks, live_forks): (Vec<ForkChain>, Vec<ForkChain>) = state
+        .blockchain
+        .forks
+        .drain(0..)
+        .partition(|fork| fork.prev_index >= height);
+
+    state.blockchain.forks = live_forks;
+
+    for mut fork in dead_forks {
+        removed.append(&mut fork.blocks);
+    }
+
+    let num_removed = removed.len();
+    let mut txns: Vec<Transaction> = vec![];
+
+    for block in removed {
+        txns.append(&mut block.to_network_txns());
+    }
+
+    let txn_hashes = txns.iter().map(|t| t.hash).collect::<Vec<Hash256>>();
+    state.pending_txns.append(&mut txns);
+
+    state.blockchain.utxo_pool = build_utxos_from_confirmed(&state.blockchain.blocks);
+    check_pending_and_orphans(state);
+
+    let returned = txn_hashes
+        .into_iter()
+        .filter(|h| state.pending_txns.iter().any(|t| t.hash == *h))
+        .collect::<Vec<Hash256>>();
+
+    Ok((num_removed, returned))
+}
+
+/// Removes the block at the tip of the given chain. If the block was on the main chain, its transactions
+/// go back into the pending pool and the UTXO database is rebuilt. Returns None if the chain doesn't exist,
+/// or if the tip is the genesis block.
+///
+/// The caller must make sure that no fork branches off of the removed block.
+pub fn remove_chain_tip(state: &mut State, chain_idx: usize) -> Option<Block> {
+    if chain_idx != 0 {
+        let fork = state.blockchain.forks.get_mut(chain_idx - 1)?;
+        let block = fork.blocks.pop();
+
+        if fork.blocks.is_empty() {
+            state.blockchain.forks.remove(chain_idx - 1);
+        }
+
+        return block;
+    }
+
+    if state.blockchain.blocks.len() <= 1 {
+        return None;
+    }
+
+    let block = state.blockchain.blocks.pop().unwrap();
+    let mut txns = block.clone().to_network_txns();
+    state.pending_txns.append(&mut txns);
+
+    state.blockchain.utxo_pool = build_utxos_from_confirmed(&state.blockchain.blocks);
+    check_pending_and_orphans(state);
+    state.compute_balance();
+
+    Some(block)
 }
 
 pub fn genesis_block() -> Block {
@@ -551,14 +803,9 @@ pub fn genesis_block() -> Block {
         .expect("Failed to create genesis block");
     let coinbase = make_coinbase_txn(&genesis_miner, String::from("genesis block"), 0, [0x69; 32]);
 
-    let target_bytes =
-        hex::decode("0000000f00000000000000000000000000000000000000000000000000000000").unwrap();
-    let mut target = [0_u8; 32];
-    target.copy_from_slice(&target_bytes);
-
-    // This nonce will produce the hash "0000000c9785be4989caa7cf9b7dca9161bbe8334f692fbf277fce1e23f9df2a"
+    // This nonce will produce the hash "0000000713c9488816b9d340861e91845b117934983fdb62c6f22bbc223ed2cc"
     let nonce_bytes =
-        hex::decode("0487ec8e16f44da6d0d17e6e9c2bdc097c1eda445879a7df3d96a06b4acd0aa2").unwrap();
+        hex::decode("540a8b6ce3c8e01e0a59ef858d2721ba1648614f4e6e29c3974973c00280606c").unwrap();
     let mut nonce = [0_u8; 32];
     nonce.copy_from_slice(&nonce_bytes);
 
@@ -573,7 +820,8 @@ pub fn genesis_block() -> Block {
         prev_hash: [0; 32],
         merkle_root,
         timestamp: 1669939462,
-        difficulty_target: target,
+        // Expands to the target 0000000f00000000000000000000000000000000000000000000000000000000
+        bits: 0x1d0f_0000,
         nonce,
         hash: [0; 32],
     };
@@ -659,3 +907,163 @@ fn merkle_round(hashes: Vec<Hash256>) -> Vec<Hash256> {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difficulty::get_difficulty_bits;
+    use crate::v1::test_util::{add_blocks_after, make_block, test_genesis, test_state};
+
+    /// A block that's only good for its difficulty target
+    fn block_with_target(target: Hash256) -> Block {
+        let mut block = test_genesis();
+        block.header.bits = get_difficulty_bits(&target);
+        block.header.hash = rand::random();
+
+        block
+    }
+
+    fn target(bytes: &[u8]) -> Hash256 {
+        let mut out = [0; 32];
+        out[..bytes.len()].copy_from_slice(bytes);
+
+        out
+    }
+
+    #[test]
+    fn genesis_block_satisfies_its_bits() {
+        let genesis = genesis_block();
+        let raw: RawBlockHeader = (&genesis.header).into();
+
+        assert_eq!(hash_block_header(&raw), genesis.header.hash);
+        assert!(genesis.header.hash < genesis.header.target());
+    }
+
+    /// The GPU miners hash the header in two chunks, and expect the nonce to start 16 bytes into the second one
+    #[test]
+    fn raw_header_fits_in_two_chunks() {
+        let raw: RawBlockHeader = (&genesis_block().header).into();
+        let bytes = bincode::serialize(&raw).unwrap();
+
+        assert_eq!(bytes.len(), 112);
+        assert_eq!(&bytes[80..], &raw.nonce);
+    }
+
+    #[test]
+    fn check_orphans_keeps_pool_size() {
+        let mut state = test_state();
+        let orphan = make_block(rand::random(), &state.address, 0, vec![]);
+        verify_block(orphan, &mut state).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(check_orphans(&mut state), 0);
+            assert_eq!(state.blockchain.orphans.len(), 1);
+        }
+    }
+
+    #[test]
+    fn evicts_fork_with_least_work() {
+        let base = target(&[0, 1]);
+        let mut db = BlockchainDB::new(test_genesis());
+        for _ in 0..3 {
+            db.blocks.push(block_with_target(base));
+        }
+
+        // Two blocks add up to a higher target than one block at 1.5x the target, but they are twice the work
+        let two_blocks = vec![block_with_target(base), block_with_target(base)];
+        let one_easy_block = vec![block_with_target(target(&[0, 1, 0x80]))];
+        // This one is the best chain, so it can't be evicted
+        let best = vec![block_with_target(target(&[0, 0, 0x80]))];
+
+        for blocks in [two_blocks, one_easy_block, best] {
+            db.forks.push(ForkChain { prev_index: 1, blocks });
+        }
+
+        let evicted = db.evict_weakest_fork().unwrap();
+
+        assert_eq!(evicted.blocks.len(), 1);
+        assert_eq!(evicted.blocks[0].header.target(), target(&[0, 1, 0x80]));
+        assert_eq!(db.forks.len(), 2);
+        assert_eq!(db.forks[0].blocks.len(), 2);
+    }
+
+    #[test]
+    fn block_work_is_inverse_of_target() {
+        assert_eq!(block_work(&[0xff; 32]), BigUint::one());
+        assert_eq!(block_work(&target(&[0, 0xff, 0xff, 0xff])), BigUint::from(256u32));
+    }
+
+    #[test]
+    fn get_blocks_rel_follows_fork() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        let main = add_blocks_after(&mut state, genesis, 3);
+        let fork = add_blocks_after(&mut state, main[0].header.hash, 1);
+
+        assert_eq!(state.blockchain.forks.len(), 1);
+
+        let path = state.blockchain.get_blocks_rel(1, 1, 1);
+        let hashes = path.iter().map(|b| b.header.hash).collect::<Vec<Hash256>>();
+
+        assert_eq!(hashes, vec![main[0].header.hash, fork[0].header.hash]);
+    }
+
+    #[test]
+    fn block_can_extend_fork() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        add_blocks_after(&mut state, genesis, 3);
+        let fork = add_blocks_after(&mut state, genesis, 2);
+
+        assert_eq!(state.blockchain.forks.len(), 1);
+        assert_eq!(state.blockchain.forks[0].blocks.len(), 2);
+        assert_eq!(state.blockchain.forks[0].blocks[1].header.hash, fork[1].header.hash);
+    }
+
+    #[test]
+    fn block_in_middle_of_fork_is_rejected() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        add_blocks_after(&mut state, genesis, 3);
+        let fork = add_blocks_after(&mut state, genesis, 2);
+        let block = make_block(fork[0].header.hash, &state.address, 0, vec![]);
+
+        let err = verify_block(block.clone(), &mut state).err().unwrap();
+        assert!(matches!(*err, NonContiguousFork(hash) if hash == fork[0].header.hash));
+
+        assert!(state.blockchain.add_block(block).is_err());
+        assert_eq!(state.blockchain.forks.len(), 1);
+        assert_eq!(state.blockchain.forks[0].blocks.len(), 2);
+    }
+
+    #[test]
+    fn best_chain_index_without_forks_is_main_chain() {
+        assert_eq!(best_chain_index(&[target(&[1])], &[]), (0, false));
+    }
+
+    #[test]
+    fn best_chain_index_picks_lowest_total_target() {
+        let easy = target(&[2]);
+        let hard = target(&[1]);
+
+        assert_eq!(best_chain_index(&[hard, hard], &[vec![easy, easy]]), (0, false));
+        assert_eq!(best_chain_index(&[easy, easy], &[vec![hard, hard]]), (1, false));
+        assert_eq!(best_chain_index(&[easy], &[vec![easy, hard], vec![hard], vec![easy]]), (2, false));
+    }
+
+    #[test]
+    fn best_chain_index_prefers_main_chain_on_tie() {
+        let t = target(&[1]);
+
+        assert_eq!(best_chain_index(&[t, t], &[vec![t, t]]), (0, true));
+        assert_eq!(best_chain_index(&[t], &[vec![t, t], vec![t]]), (0, true));
+    }
+
+    #[test]
+    fn best_chain_index_prefers_earlier_fork_on_tie() {
+        let easy = target(&[2]);
+        let hard = target(&[1]);
+
+        assert_eq!(best_chain_index(&[easy], &[vec![easy], vec![hard], vec![hard]]), (2, false));
+    }
+}
@@ -1,29 +1,59 @@
+use std::collections::HashSet;
+
 use chrono::Utc;
 
 use super::{
     block::{
         hash_block_header, make_merkle_root, Block, RawBlockHeader, BLOCK_TIMESTAMP_TOLERANCE,
-        MAX_BLOCK_SIZE,
+        MAX_BLOCK_SIGOPS, MAX_BLOCK_SIZE, MAX_FORKS, MAX_TXNS_PER_BLOCK,
     },
     block_verify_error::BlockVerifyResult,
     state::State,
     transaction::{
-        build_utxos_from_confirmed, compute_input_sum, hash_txn, Transaction, UnhashedTransaction,
-        BLOCK_REWARD,
+        build_utxos_from_confirmed, compute_input_sum, compute_output_sum, hash_txn, spent_sigops, Transaction,
+        UnhashedTransaction, BLOCK_REWARD,
     },
-    txn_verify::{check_pending_and_orphans, verify_transaction},
+    txn_verify::{check_pending_and_orphans, verify_transaction, TxnStatus},
+};
+
+use crate::{
+    difficulty::{get_difficulty_bits, get_difficulty_target, target_in_bounds},
+    wallet::Hash256,
 };
 
 use super::block_verify_error::ErrorKind::{
-    EmptyBlock, FailedProofOfWork, IncorrectDifficulty, InvalidCoinbase, InvalidCoinbaseAmount,
-    InvalidHeaderHash, InvalidMerkleRoot, OldBlock, OrphanTxn, TooLarge, TxnError,
+    DuplicateTxn, EmptyBlock, FailedProofOfWork, FeeOverflow, FutureBlock, IncorrectDifficulty, InvalidBits, InvalidCoinbase,
+    InvalidCoinbaseAmount, InvalidHeaderHash, InvalidMerkleRoot, MisplacedCoinbase,
+    NonContiguousFork, OldBlock, OrphanTxn, TargetOutOfRange, TooLarge, TooManySigops, TooManyTxns, TxnError,
 };
 
-/// Verifies a new block. Returns true if the block is an orphan. Unlike [verify_transaction],
+/// The outcome of verifying a block that broke no rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// The block was added to the blockchain
+    Added,
+    /// The block's parent isn't in the blockchain, so the block went in the orphan pool. The argument is the
+    /// hash of the missing parent.
+    MissingParent(Hash256),
+}
+
+/// Verifies a new block. Returns [BlockStatus::MissingParent] if the block is an orphan. Unlike [verify_transaction],
 /// this function will mutate the state. If the block is an orphan, it will add the block to the orphan
 /// pool; otherwise it will add the block to the blockchain. It is the caller's job to check the blockchain
 /// afterward and try to resolve any forks.
-pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool> {
+pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<BlockStatus> {
+    verify_block_with_policy(block, state, true)
+}
+
+/// Like [verify_block], but the block can be older than [BLOCK_TIMESTAMP_TOLERANCE]. Use this for blocks that we
+/// asked for, like when catching up or importing a chain, because those can be as old as the blockchain. Blocks
+/// that are announced to us still have to be recent.
+pub fn verify_historical_block(block: Block, state: &mut State) -> BlockVerifyResult<BlockStatus> {
+    verify_block_with_policy(block, state, false)
+}
+
+/// Like [verify_block], but if `check_age` is false, blocks with old timestamps are accepted
+fn verify_block_with_policy(block: Block, state: &mut State, check_age: bool) -> BlockVerifyResult<BlockStatus> {
     let block_size = block.size();
 
     // The block cannot be too big
@@ -36,31 +66,92 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
         return Err(Box::new(EmptyBlock));
     }
 
+    // The block cannot have too many transactions
+    if block.transactions.len() > MAX_TXNS_PER_BLOCK {
+        return Err(Box::new(TooManyTxns(MAX_TXNS_PER_BLOCK, block.transactions.len())));
+    }
+
+    // The merkle root needs to match the actual merkle root. This is cheap compared to verifying transactions,
+    // so it's checked first: a block that lies about its contents is rejected before any scripts run, and
+    // it can't end up in the orphan pool either.
+    let expected_merkle_root = make_merkle_root(&block.transactions);
+    if expected_merkle_root != block.header.merkle_root {
+        return Err(Box::new(InvalidMerkleRoot));
+    }
+
+    // The block cannot have too many signature checks. Like the merkle root, this is checked before any scripts run.
+    // The lock scripts of spent outputs are added in as each transaction is verified
+    let mut sigops = block.sigops();
+    if sigops > MAX_BLOCK_SIGOPS {
+        return Err(Box::new(TooManySigops(MAX_BLOCK_SIGOPS, sigops)));
+    }
+
+    // The same transaction cannot appear twice in a block
+    let mut txn_hashes: HashSet<Hash256> = HashSet::new();
+    for txn in &block.transactions {
+        if !txn_hashes.insert(txn.hash) {
+            return Err(Box::new(DuplicateTxn(txn.hash)));
+        }
+    }
+
+    // The first transaction must be a coinbase with exactly one input and one output. The input must have a zero
+    // hash and the coinbase output index. This is checked before the coinbase goes into the UTXO pool, because the
+    // pool uses the same test to decide whether a transaction spends anything.
+    let coinbase = &block.transactions[0];
+    if !coinbase.is_coinbase() || coinbase.outputs.len() != 1 {
+        return Err(Box::new(InvalidCoinbase));
+    }
+
+    // The coinbase transaction can only be the first transaction in the block
+    for txn in &block.transactions[1..] {
+        if txn.inputs.iter().any(|i| i.has_coinbase_marker()) {
+            return Err(Box::new(MisplacedCoinbase(txn.hash)));
+        }
+    }
+
     // Get the previous block in the blockchain. There may not be such a block: if there isn't,
     // then this new block is an orphan.
     let prev_block_opt = state.blockchain.get_block(block.header.prev_hash);
     let (_, chain_idx, pos) = match prev_block_opt {
         None => {
+            let parent = block.header.prev_hash;
             state.blockchain.orphans.push(block);
-            return Ok(true);
+            return Ok(BlockStatus::MissingParent(parent));
         }
         Some(data) => data,
     };
 
+    // The block has to extend the tip of its fork. It can't start a new fork in the middle of another one
+    if !state.blockchain.can_extend(chain_idx, pos) {
+        return Err(Box::new(NonContiguousFork(block.header.prev_hash)));
+    }
+
     // Get the blocks leading up to where this one should go
     let block_path = state.blockchain.get_blocks_rel(chain_idx, 0, pos + 1);
 
     let current_difficulty = state.blockchain.current_difficulty();
 
+    // The difficulty bits must expand to a target, and they must be the one way of writing that target. Otherwise
+    // the same block could be sent with different bits, and each version would have a different hash.
+    let target = match get_difficulty_target(block.header.bits) {
+        Some(target) if get_difficulty_bits(&target) == block.header.bits => target,
+        _ => return Err(Box::new(InvalidBits(block.header.bits))),
+    };
+
+    // The difficulty target must be sane
+    if !target_in_bounds(&target) {
+        return Err(Box::new(TargetOutOfRange(target)));
+    }
+
     // The block must have the correct difficulty
-    if current_difficulty != block.header.difficulty_target {
+    if current_difficulty != target {
         return Err(Box::new(IncorrectDifficulty));
     }
 
     let block_hash = block.header.hash;
 
-    // The hash must satisy proof of work
-    if block_hash >= current_difficulty {
+    // The hash must satisy proof of work. Regtest skips this so that tests don't have to mine their blocks
+    if !cfg!(feature = "regtest") && block_hash >= target {
         return Err(Box::new(FailedProofOfWork));
     }
 
@@ -74,10 +165,17 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
 
     let now: u64 = Utc::now().timestamp().try_into().unwrap();
 
-    let time_diff = now - block.header.timestamp;
+    let tolerance: u64 = BLOCK_TIMESTAMP_TOLERANCE.num_seconds().try_into().unwrap();
 
-    // The block cannot have a timestamp too far in the past or too far in the future
-    if time_diff > BLOCK_TIMESTAMP_TOLERANCE.num_seconds().try_into().unwrap() {
+    // The block cannot have a timestamp too far in the past or too far in the future. Our clock may
+    // be a little behind the miner's, so the block can be ahead of us as long as it's within the tolerance.
+    if block.header.timestamp > now {
+        let ahead_by = block.header.timestamp - now;
+
+        if ahead_by > tolerance {
+            return Err(Box::new(FutureBlock(ahead_by)));
+        }
+    } else if check_age && now - block.header.timestamp > tolerance {
         return Err(Box::new(OldBlock));
     }
 
@@ -99,7 +197,6 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
     let mut pending_to_remove: Vec<usize> = vec![];
     let mut orphans_to_remove: Vec<usize> = vec![];
 
-    let coinbase = &block.transactions[0];
     let mut total_fees: u64 = 0;
 
     // First add the coinbase transaction as an unconfirmed UTXO. This needs to happen before we
@@ -114,9 +211,9 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
         // We also need to restore any pending transactions we removed from the pending transaction
         // pool.
         match verify_result {
-            Ok(true) => {
+            Ok(TxnStatus::MissingInput(missing_input)) => {
                 restore_utxo_pool(state, &block_path, old_pending);
-                return Err(Box::new(OrphanTxn(txn.hash)));
+                return Err(Box::new(OrphanTxn(txn.hash, missing_input)));
             }
             Err(error) => {
                 restore_utxo_pool(state, &block_path, old_pending);
@@ -125,6 +222,12 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
             _ => (),
         };
 
+        sigops += spent_sigops(txn, state);
+        if sigops > MAX_BLOCK_SIGOPS {
+            restore_utxo_pool(state, &block_path, old_pending);
+            return Err(Box::new(TooManySigops(MAX_BLOCK_SIGOPS, sigops)));
+        }
+
         // Now that the transaction is verified, find it in the pending txns pool and add
         // the index to an array (if it exists in the pool). When the block has been validated,
         // these pending transactions will be removed from the pool. The transaction may also
@@ -147,32 +250,33 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
         // Add up the input amounts and output amounts and compute the fee
         let input_sum: u64 = compute_input_sum(txn, state);
 
-        let output_sum = txn.outputs.iter().fold(0, |a, e| a + e.amount);
+        let output_sum = compute_output_sum(txn);
 
-        total_fees += input_sum - output_sum;
+        // Each transaction's amounts were checked on their own, but the fees could still overflow when added up
+        total_fees = match input_sum.checked_sub(output_sum).and_then(|fee| total_fees.checked_add(fee)) {
+            None => {
+                restore_utxo_pool(state, &block_path, old_pending);
+                return Err(Box::new(FeeOverflow));
+            }
+            Some(fees) => fees,
+        };
     }
 
     // Now verify the coinbase transaction
 
-    // The coinbase transaction must have exactly one input and one output
-    if coinbase.inputs.len() != 1 || coinbase.outputs.len() != 1 {
-        restore_utxo_pool(state, &block_path, old_pending);
-        return Err(Box::new(InvalidCoinbase));
-    }
-
-    let input = &coinbase.inputs[0];
     let output = &coinbase.outputs[0];
 
-    // The transaction's sole input hash must be zero
-    if input.txn_hash != [0; 32] {
-        restore_utxo_pool(state, &block_path, old_pending);
-        return Err(Box::new(InvalidCoinbase));
-    }
-
-    let expected_amount = BLOCK_REWARD + total_fees;
+    let expected_amount = match BLOCK_REWARD.checked_add(total_fees) {
+        None => {
+            restore_utxo_pool(state, &block_path, old_pending);
+            return Err(Box::new(FeeOverflow));
+        }
+        Some(amount) => amount,
+    };
 
     // The miner must have claimed the expected amount
     if output.amount != expected_amount {
+        restore_utxo_pool(state, &block_path, old_pending);
         return Err(Box::new(InvalidCoinbaseAmount(
             expected_amount,
             output.amount,
@@ -194,13 +298,6 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
         return Err(Box::new(InvalidCoinbase));
     }
 
-    // The merkle root needs to match the actual merkle root
-    let expected_merkle_root = make_merkle_root(&block.transactions);
-    if expected_merkle_root != block.header.merkle_root {
-        restore_utxo_pool(state, &block_path, old_pending);
-        return Err(Box::new(InvalidMerkleRoot));
-    }
-
     // At this point, the block is valid. Now we just need to do some bookkeeping and update our UTXO
     // database, pending transaction pool, and orphan transaction pool.
 
@@ -219,9 +316,22 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
         state.orphan_txns.remove(pos);
     }
 
+    // Make room if the block starts a new fork and we already have as many as we'll keep. Every fork
+    // branches off of the main chain, so removing one doesn't change the UTXOs we just verified against
+    if state.blockchain.starts_fork(chain_idx, pos) && state.blockchain.forks.len() >= MAX_FORKS {
+        if let Some(evicted) = state.blockchain.evict_weakest_fork() {
+            println!(
+                "Too many forks. Dropped the fork with the least work: {} blocks after main chain block {}",
+                evicted.blocks.len(),
+                hex::encode(state.blockchain.blocks[evicted.prev_index].header.hash)
+            );
+        }
+    }
+
     // We can't leave the blockchain in an invalid state. We must add the newly verified block to the
-    // blockchain before returning
-    state.add_block(block);
+    // blockchain before returning. This can't fail because we already checked that the block
+    // extends its chain
+    state.add_block(block)?;
 
     // At this point, all the unconfirmed UTXOs in the UTXO pool are from the block we just verified.
     // Now that the block has been added to the blockchain, we can confirm those and then
@@ -231,7 +341,7 @@ pub fn verify_block(block: Block, state: &mut State) -> BlockVerifyResult<bool>
     // Add the pending transactions and check orphans as well
     check_pending_and_orphans(state);
 
-    Ok(false)
+    Ok(BlockStatus::Added)
 }
 
 fn restore_utxo_pool(state: &mut State, utxo_blocks: &Vec<Block>, old_pending: Vec<Transaction>) {
@@ -250,7 +360,7 @@ fn restore_utxo_pool(state: &mut State, utxo_blocks: &Vec<Block>, old_pending: V
         let verify_result = verify_transaction(txn.clone(), state);
         match verify_result {
             // We shouldn't have any orphans here
-            Ok(true) => {
+            Ok(TxnStatus::MissingInput(_)) => {
                 println!("Unexpected orphan");
                 pending_to_remove.push(i);
             }
@@ -262,7 +372,7 @@ fn restore_utxo_pool(state: &mut State, utxo_blocks: &Vec<Block>, old_pending: V
                 );
                 pending_to_remove.push(i);
             }
-            Ok(false) => {
+            Ok(TxnStatus::Valid) => {
                 state.blockchain.utxo_pool.update_unconfirmed(txn);
             }
         }
@@ -275,3 +385,408 @@ fn restore_utxo_pool(state: &mut State, utxo_blocks: &Vec<Block>, old_pending: V
         state.pending_txns.remove(pos);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        difficulty::REGTEST_TARGET,
+        tsengscript_interpreter::EXECUTIONS,
+        v1::{
+            block_verify_error::ErrorKind,
+            test_util::{make_block, make_block_at, make_txn, mine, next_block, pay, sign_p2pkh, test_state},
+            transaction::{
+                make_coinbase_txn, make_p2pkh_lock, Script, ScriptType, TxnInput, TxnOutput, COINBASE_OUTPUT_IDX,
+            },
+            VERSION,
+        },
+    };
+
+    /// The most signature checks that fit in one lock script
+    const SIGOPS_PER_LOCK: usize = 37;
+
+    /// A lock script with `n` signature checks that anyone can spend. Each check fails, but the script still ends
+    /// with `TRUE`.
+    fn checksig_lock(n: usize) -> Script {
+        Script {
+            code: format!("{}TRUE", "01 01 01 01 CHECKSIG 2DROP ".repeat(n)),
+            script_type: ScriptType::TsengScript,
+        }
+    }
+
+    /// Mine a block, then spend its coinbase into outputs with the given lock script sigops in another block.
+    /// Returns the new outputs.
+    fn fund(state: &mut State, locks: &[usize]) -> Vec<(Hash256, usize)> {
+        let block = next_block(state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, state).unwrap();
+
+        let mut outputs = locks
+            .iter()
+            .map(|n| TxnOutput {
+                amount: 10,
+                lock_script: checksig_lock(*n),
+            })
+            .collect::<Vec<TxnOutput>>();
+        outputs.push(TxnOutput {
+            amount: BLOCK_REWARD - 20 * locks.len() as u64,
+            lock_script: make_p2pkh_lock(&state.address),
+        });
+
+        let txn = sign_p2pkh(state, &[(coinbase, 0)], outputs);
+        state.add_pending_txn(txn.clone());
+        let block = next_block(state, vec![txn.clone()]);
+        verify_block(block, state).unwrap();
+
+        (0..locks.len()).map(|i| (txn.hash, i)).collect()
+    }
+
+    /// Try to mine a block that spends outputs with `spent_sigops` signature checks in their lock scripts. The
+    /// coinbase and the spending transaction's output add one more each.
+    fn spend_sigops(spent_sigops: usize) -> BlockVerifyResult<BlockStatus> {
+        let mut state = test_state();
+        let mut locks = vec![SIGOPS_PER_LOCK; spent_sigops / SIGOPS_PER_LOCK];
+        locks.push(spent_sigops % SIGOPS_PER_LOCK);
+
+        // Funding transactions have to fit under the limit too
+        let (first, second) = locks.split_at(locks.len() / 2);
+        let mut spent = fund(&mut state, first);
+        spent.append(&mut fund(&mut state, second));
+
+        let inputs = spent
+            .iter()
+            .map(|(txn_hash, output_idx)| TxnInput {
+                txn_hash: *txn_hash,
+                output_idx: *output_idx,
+                unlock_script: Script {
+                    code: String::from("01"),
+                    script_type: ScriptType::TsengScript,
+                },
+            })
+            .collect();
+        let output = TxnOutput {
+            amount: 5 * spent.len() as u64,
+            lock_script: make_p2pkh_lock(&state.address),
+        };
+        let txn = make_txn(inputs, vec![output]);
+        state.add_pending_txn(txn.clone());
+
+        let block = next_block(&state, vec![txn]);
+        verify_block(block, &mut state)
+    }
+
+    #[test]
+    fn block_at_sigop_limit_is_valid() {
+        assert_eq!(spend_sigops(MAX_BLOCK_SIGOPS - 2).unwrap(), BlockStatus::Added);
+    }
+
+    #[test]
+    fn spent_lock_scripts_count_toward_sigop_limit() {
+        let err = spend_sigops(MAX_BLOCK_SIGOPS - 1).err().unwrap();
+
+        assert!(matches!(*err, ErrorKind::TooManySigops(MAX_BLOCK_SIGOPS, actual) if actual == MAX_BLOCK_SIGOPS + 1));
+    }
+
+    /// A block on the main chain that spends the first block's coinbase
+    fn block_with_spend(state: &mut State) -> Block {
+        let block = next_block(state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, state).unwrap();
+
+        let output = TxnOutput {
+            amount: BLOCK_REWARD - 10,
+            lock_script: make_p2pkh_lock(&state.address),
+        };
+        let txn = sign_p2pkh(state, &[(coinbase, 0)], vec![output]);
+        state.add_pending_txn(txn.clone());
+
+        next_block(state, vec![txn])
+    }
+
+    #[test]
+    fn block_without_parent_is_missing_parent() {
+        let mut state = test_state();
+        let parent = rand::random();
+        let block = make_block(parent, &state.address, 0, vec![]);
+
+        assert_eq!(verify_block(block, &mut state).unwrap(), BlockStatus::MissingParent(parent));
+        assert_eq!(state.blockchain.orphans.len(), 1);
+    }
+
+    #[test]
+    fn block_with_orphan_txn_is_rejected() {
+        let mut state = test_state();
+        let missing = rand::random();
+        let output = TxnOutput {
+            amount: 10,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        };
+        let txn = sign_p2pkh(&state, &[(missing, 0)], vec![output]);
+        let block = make_block(state.blockchain.top_hash(0), &state.address, 0, vec![txn.clone()]);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::OrphanTxn(hash, input) if hash == txn.hash && input == missing));
+        assert!(state.blockchain.orphans.is_empty());
+    }
+
+    #[test]
+    fn txn_below_relay_fee_is_valid_in_block() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+        state.min_relay_fee = 5;
+
+        let txn = pay(&state, &[1; 20], 10, 2);
+        let block = make_block(state.blockchain.top_hash(0), &state.address, 2, vec![txn]);
+
+        assert_eq!(verify_block(block, &mut state).unwrap(), BlockStatus::Added);
+    }
+
+    #[test]
+    fn duplicate_txn_is_rejected() {
+        let mut state = test_state();
+        let block = block_with_spend(&mut state);
+        let txn = block.transactions[1].clone();
+        let block = make_block(block.header.prev_hash, &state.address, 0, vec![txn.clone(), txn.clone()]);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::DuplicateTxn(hash) if hash == txn.hash));
+    }
+
+    #[test]
+    fn second_coinbase_is_rejected() {
+        let mut state = test_state();
+        let coinbase = make_coinbase_txn(&state.address, String::from(""), 0, rand::random());
+        let block = make_block(state.blockchain.top_hash(0), &state.address, 0, vec![coinbase.clone()]);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::MisplacedCoinbase(hash) if hash == coinbase.hash));
+    }
+
+    #[test]
+    fn fake_coinbase_spending_real_utxo_is_rejected() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let real_utxo = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        // Looks like a coinbase by its output index, but spends the last block's coinbase
+        let mut coinbase = make_coinbase_txn(&state.address, String::from(""), 0, rand::random());
+        coinbase.inputs[0].txn_hash = real_utxo;
+        let txns = vec![make_txn(coinbase.inputs, coinbase.outputs)];
+
+        let header = RawBlockHeader {
+            version: VERSION,
+            prev_hash: state.blockchain.top_hash(0),
+            merkle_root: make_merkle_root(&txns),
+            timestamp: Utc::now().timestamp() as u64,
+            bits: get_difficulty_bits(&REGTEST_TARGET),
+            nonce: [0; 32],
+        };
+        let block = mine(header, txns);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::InvalidCoinbase));
+    }
+
+    #[test]
+    fn txn_with_coinbase_output_idx_is_misplaced_coinbase() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        let output = TxnOutput {
+            amount: 10,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        };
+        let mut txn = sign_p2pkh(&state, &[(coinbase, 0)], vec![output]);
+        txn.inputs[0].output_idx = COINBASE_OUTPUT_IDX;
+        let txn = make_txn(txn.inputs, txn.outputs);
+        let block = make_block(state.blockchain.top_hash(0), &state.address, 0, vec![txn.clone()]);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::MisplacedCoinbase(hash) if hash == txn.hash));
+    }
+
+    /// A block with `count` transactions, counting the coinbase. The transactions are as small as possible and
+    /// don't spend anything, so the block only gets as far as the transaction checks.
+    fn block_with_txn_count(state: &State, count: usize) -> Block {
+        let txns = (1..count)
+            .map(|i| {
+                let output = TxnOutput {
+                    amount: i as u64,
+                    lock_script: Script {
+                        code: String::new(),
+                        script_type: ScriptType::TsengScript,
+                    },
+                };
+
+                make_txn(vec![], vec![output])
+            })
+            .collect();
+
+        make_block(state.blockchain.top_hash(0), &state.address, 0, txns)
+    }
+
+    #[test]
+    fn block_at_txn_limit_passes_txn_count_check() {
+        let mut state = test_state();
+        let block = block_with_txn_count(&state, MAX_TXNS_PER_BLOCK);
+        assert!(block.size() <= MAX_BLOCK_SIZE);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(!matches!(*err, ErrorKind::TooManyTxns(_, _)), "{}", err);
+    }
+
+    #[test]
+    fn block_over_txn_limit_is_rejected() {
+        let mut state = test_state();
+        let block = block_with_txn_count(&state, MAX_TXNS_PER_BLOCK + 1);
+        assert!(block.size() <= MAX_BLOCK_SIZE);
+
+        let err = verify_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::TooManyTxns(MAX_TXNS_PER_BLOCK, actual) if actual == MAX_TXNS_PER_BLOCK + 1));
+    }
+
+    #[test]
+    fn out_of_range_targets_are_rejected() {
+        let mut state = test_state();
+
+        // Zero, and the largest target that fits in a hash
+        for bits in [0, 0x2100_ffff] {
+            let mut block = next_block(&state, vec![]);
+            block.header.bits = bits;
+            let target = get_difficulty_target(bits).unwrap();
+
+            let err = verify_block(block, &mut state).err().unwrap();
+            assert!(matches!(*err, ErrorKind::TargetOutOfRange(t) if t == target));
+        }
+    }
+
+    #[test]
+    fn bits_must_be_a_canonical_compact_target() {
+        let mut state = test_state();
+
+        // Too big for a hash, a set sign bit, and a mantissa that should have been shifted into the exponent
+        for bits in [0x2201_0000, 0x1d80_0000, 0x1f00_00ff] {
+            let mut block = next_block(&state, vec![]);
+            block.header.bits = bits;
+
+            let err = verify_block(block, &mut state).err().unwrap();
+            assert!(matches!(*err, ErrorKind::InvalidBits(b) if b == bits), "{:08x}", bits);
+        }
+    }
+
+    #[test]
+    fn bad_merkle_root_is_rejected_before_scripts_run() {
+        let mut state = test_state();
+        let mut block = block_with_spend(&mut state);
+        block.header.merkle_root = rand::random();
+
+        let executions = EXECUTIONS.with(|n| n.get());
+        let err = verify_block(block, &mut state).err().unwrap();
+
+        assert!(matches!(*err, ErrorKind::InvalidMerkleRoot));
+        assert_eq!(EXECUTIONS.with(|n| n.get()), executions);
+    }
+
+    #[test]
+    fn good_block_runs_scripts() {
+        let mut state = test_state();
+        let block = block_with_spend(&mut state);
+
+        let executions = EXECUTIONS.with(|n| n.get());
+        assert_eq!(verify_block(block, &mut state).unwrap(), BlockStatus::Added);
+        assert!(EXECUTIONS.with(|n| n.get()) > executions);
+    }
+
+    /// A block on top of the main chain that was never mined. Its coinbase claims `fees` on top of the block reward,
+    /// and its header has `merkle_root` if one is given.
+    fn unmined_block(state: &State, fees: u64, merkle_root: Option<Hash256>) -> Block {
+        let txns = vec![make_coinbase_txn(&state.address, String::from(""), fees, rand::random())];
+        let mut header = RawBlockHeader {
+            version: VERSION,
+            prev_hash: state.blockchain.top_hash(0),
+            merkle_root: merkle_root.unwrap_or_else(|| make_merkle_root(&txns)),
+            timestamp: Utc::now().timestamp() as u64,
+            bits: get_difficulty_bits(&REGTEST_TARGET),
+            nonce: [0; 32],
+        };
+
+        loop {
+            header.nonce = rand::random();
+            let hash = hash_block_header(&header);
+
+            if hash >= REGTEST_TARGET {
+                return Block {
+                    header: header.to_block_header(header.nonce, hash),
+                    transactions: txns,
+                };
+            }
+        }
+    }
+
+    #[cfg(feature = "regtest")]
+    #[test]
+    fn regtest_accepts_unmined_blocks() {
+        let mut state = test_state();
+
+        for _ in 0..3 {
+            let block = unmined_block(&state, 0, None);
+            assert_eq!(verify_block(block, &mut state).unwrap(), BlockStatus::Added);
+        }
+
+        assert_eq!(state.blockchain.blocks.len(), 4);
+    }
+
+    #[cfg(feature = "regtest")]
+    #[test]
+    fn regtest_still_checks_merkle_root() {
+        let mut state = test_state();
+        let block = unmined_block(&state, 0, Some(rand::random()));
+
+        assert!(matches!(*verify_block(block, &mut state).err().unwrap(), ErrorKind::InvalidMerkleRoot));
+    }
+
+    #[cfg(feature = "regtest")]
+    #[test]
+    fn regtest_still_checks_coinbase_amount() {
+        let mut state = test_state();
+        let block = unmined_block(&state, 1, None);
+
+        assert!(matches!(*verify_block(block, &mut state).err().unwrap(), ErrorKind::InvalidCoinbaseAmount(_, _)));
+    }
+
+    #[cfg(not(feature = "regtest"))]
+    #[test]
+    fn unmined_blocks_fail_proof_of_work() {
+        let mut state = test_state();
+        let block = unmined_block(&state, 0, None);
+
+        assert!(matches!(*verify_block(block, &mut state).err().unwrap(), ErrorKind::FailedProofOfWork));
+    }
+
+    #[test]
+    fn far_future_block_is_rejected() {
+        let mut state = test_state();
+        let tolerance = BLOCK_TIMESTAMP_TOLERANCE.num_seconds() as u64;
+        let timestamp = Utc::now().timestamp() as u64 + tolerance + 60;
+        let block = make_block_at(state.blockchain.top_hash(0), &state.address, 0, vec![], timestamp);
+
+        let err = verify_block(block.clone(), &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::FutureBlock(ahead_by) if ahead_by > tolerance));
+
+        let err = verify_historical_block(block, &mut state).err().unwrap();
+        assert!(matches!(*err, ErrorKind::FutureBlock(_)));
+    }
+
+    #[test]
+    fn slightly_future_block_is_accepted() {
+        let mut state = test_state();
+        let timestamp = Utc::now().timestamp() as u64 + 60;
+        let block = make_block_at(state.blockchain.top_hash(0), &state.address, 0, vec![], timestamp);
+
+        assert_eq!(verify_block(block, &mut state).unwrap(), BlockStatus::Added);
+    }
+}
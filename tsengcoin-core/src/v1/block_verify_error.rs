@@ -22,10 +22,48 @@ pub enum ErrorKind {
     TooLarge(usize, usize),
     EmptyBlock,
     TxnError(TxnVerifyError, Hash256),
-    OrphanTxn(Hash256),
+    OrphanTxn(Hash256, Hash256),
     InvalidCoinbase,
     InvalidCoinbaseAmount(u64, u64),
     InvalidMerkleRoot,
+    NonContiguousFork(Hash256),
+    DuplicateTxn(Hash256),
+    MisplacedCoinbase(Hash256),
+    TargetOutOfRange(Hash256),
+    TooManyTxns(usize, usize),
+    InvalidBits(u32),
+    FutureBlock(u64),
+    TooManySigops(usize, usize),
+    FeeOverflow,
+}
+
+impl ErrorKind {
+    /// A number that identifies this kind of block verification error. Codes never change once assigned, so tools can
+    /// rely on them instead of parsing error messages.
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorKind::IncorrectDifficulty => 301,
+            ErrorKind::FailedProofOfWork => 302,
+            ErrorKind::InvalidHeaderHash => 303,
+            ErrorKind::OldBlock => 304,
+            ErrorKind::TooLarge(_, _) => 305,
+            ErrorKind::EmptyBlock => 306,
+            ErrorKind::TxnError(_, _) => 307,
+            ErrorKind::OrphanTxn(_, _) => 308,
+            ErrorKind::InvalidCoinbase => 309,
+            ErrorKind::InvalidCoinbaseAmount(_, _) => 310,
+            ErrorKind::InvalidMerkleRoot => 311,
+            ErrorKind::NonContiguousFork(_) => 312,
+            ErrorKind::DuplicateTxn(_) => 313,
+            ErrorKind::MisplacedCoinbase(_) => 314,
+            ErrorKind::TargetOutOfRange(_) => 315,
+            ErrorKind::TooManyTxns(_, _) => 316,
+            ErrorKind::InvalidBits(_) => 317,
+            ErrorKind::FutureBlock(_) => 318,
+            ErrorKind::TooManySigops(_, _) => 319,
+            ErrorKind::FeeOverflow => 320,
+        }
+    }
 }
 
 impl StdError for ErrorKind {
@@ -38,10 +76,19 @@ impl StdError for ErrorKind {
             ErrorKind::TooLarge(_, _) => "Block is too big",
             ErrorKind::EmptyBlock => "Block has no transactions",
             ErrorKind::TxnError(_, _) => "Invalid transaction in block",
-            ErrorKind::OrphanTxn(_) => "Orphan transaction in block",
+            ErrorKind::OrphanTxn(_, _) => "Orphan transaction in block",
             ErrorKind::InvalidCoinbase => "Invalid coinbase transaction",
             ErrorKind::InvalidCoinbaseAmount(_, _) => "Invalid coinbase transaction amount",
             ErrorKind::InvalidMerkleRoot => "Invalid Merkle root",
+            ErrorKind::NonContiguousFork(_) => "Block does not extend the tip of its fork",
+            ErrorKind::DuplicateTxn(_) => "Block contains the same transaction more than once",
+            ErrorKind::MisplacedCoinbase(_) => "Coinbase transaction is not the first transaction in the block",
+            ErrorKind::TargetOutOfRange(_) => "Block difficulty target is out of range",
+            ErrorKind::TooManyTxns(_, _) => "Block has too many transactions",
+            ErrorKind::InvalidBits(_) => "Block difficulty bits are not a valid compact target",
+            ErrorKind::FutureBlock(_) => "Block header timestamp is too far in the future",
+            ErrorKind::TooManySigops(_, _) => "Block has too many signature checks",
+            ErrorKind::FeeOverflow => "Block transaction fees are too large to add up",
         }
     }
 
@@ -73,9 +120,13 @@ impl fmt::Display for ErrorKind {
                 err,
                 hex::encode(txn)
             ),
-            ErrorKind::OrphanTxn(txn) => {
-                write!(fmt, "{}: txn: {}", self.description(), hex::encode(txn))
-            }
+            ErrorKind::OrphanTxn(txn, missing_input) => write!(
+                fmt,
+                "{}: txn: {}, missing input: {}",
+                self.description(),
+                hex::encode(txn),
+                hex::encode(missing_input)
+            ),
             ErrorKind::InvalidCoinbase => write!(fmt, "{}", self.description()),
             ErrorKind::InvalidCoinbaseAmount(exp, actual) => write!(
                 fmt,
@@ -85,6 +136,76 @@ impl fmt::Display for ErrorKind {
                 actual
             ),
             ErrorKind::InvalidMerkleRoot => write!(fmt, "{}", self.description()),
+            ErrorKind::NonContiguousFork(prev_hash) => write!(
+                fmt,
+                "{}: prev hash: {}",
+                self.description(),
+                hex::encode(prev_hash)
+            ),
+            ErrorKind::DuplicateTxn(txn) => {
+                write!(fmt, "{}: txn: {}", self.description(), hex::encode(txn))
+            }
+            ErrorKind::MisplacedCoinbase(txn) => {
+                write!(fmt, "{}: txn: {}", self.description(), hex::encode(txn))
+            }
+            ErrorKind::TargetOutOfRange(target) => {
+                write!(fmt, "{}: target: {}", self.description(), hex::encode(target))
+            }
+            ErrorKind::TooManyTxns(max, actual) => write!(
+                fmt,
+                "{}: max is {}, block has {}",
+                self.description(),
+                max,
+                actual
+            ),
+            ErrorKind::InvalidBits(bits) => write!(fmt, "{}: bits: {:08x}", self.description(), bits),
+            ErrorKind::FutureBlock(ahead_by) => {
+                write!(fmt, "{}: block is {}s ahead of our clock", self.description(), ahead_by)
+            }
+            ErrorKind::TooManySigops(max, actual) => write!(
+                fmt,
+                "{}: max is {}, block has {}",
+                self.description(),
+                max,
+                actual
+            ),
+            ErrorKind::FeeOverflow => write!(fmt, "{}", self.description()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::txn_verify_error::ErrorKind as TxnErrorKind;
+
+    #[test]
+    fn each_kind_has_its_own_code() {
+        let kinds = [
+            (ErrorKind::IncorrectDifficulty, 301),
+            (ErrorKind::FailedProofOfWork, 302),
+            (ErrorKind::InvalidHeaderHash, 303),
+            (ErrorKind::OldBlock, 304),
+            (ErrorKind::TooLarge(0, 0), 305),
+            (ErrorKind::EmptyBlock, 306),
+            (ErrorKind::TxnError(Box::new(TxnErrorKind::LowFee(0)), [0; 32]), 307),
+            (ErrorKind::OrphanTxn([0; 32], [0; 32]), 308),
+            (ErrorKind::InvalidCoinbase, 309),
+            (ErrorKind::InvalidCoinbaseAmount(0, 0), 310),
+            (ErrorKind::InvalidMerkleRoot, 311),
+            (ErrorKind::NonContiguousFork([0; 32]), 312),
+            (ErrorKind::DuplicateTxn([0; 32]), 313),
+            (ErrorKind::MisplacedCoinbase([0; 32]), 314),
+            (ErrorKind::TargetOutOfRange([0; 32]), 315),
+            (ErrorKind::TooManyTxns(0, 0), 316),
+            (ErrorKind::InvalidBits(0), 317),
+            (ErrorKind::FutureBlock(0), 318),
+            (ErrorKind::TooManySigops(0, 0), 319),
+            (ErrorKind::FeeOverflow, 320),
+        ];
+
+        for (kind, code) in kinds {
+            assert_eq!(kind.code(), code, "{:?}", kind);
         }
     }
 }
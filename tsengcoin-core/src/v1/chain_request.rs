@@ -11,29 +11,34 @@ use crate::{
     wallet::{address_to_b58c, b58c_to_address, Address},
 };
 
+use chrono::{DateTime, Duration, Utc};
 use lazy_static::lazy_static;
 use rand_core::OsRng;
 use regex::Regex;
-use ring::{
-    aead::{OpeningKey, SealingKey},
-    signature::KeyPair,
-};
+use ring::signature::KeyPair;
+use serde::{Deserialize, Serialize};
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
 use super::{
     encrypted_msg::{
-        decrypt_request, enc_req_meta, encrypt_request, make_opening_key, make_sealing_key,
-        ChainRequest, EncryptedChainRequest, NonceGen,
+        decrypt_request, enc_req_meta, encrypt_request, ChainRequest, EncryptedChainRequest,
+        Ratchet,
     },
     state::State,
     transaction::{
         get_p2pkh_sender, hash_txn, make_p2pkh_unlock, make_single_p2pkh_txn, sign_txn,
         Transaction, TxnInput, TxnOutput, UnhashedTransaction,
     },
-    txn_verify::verify_transaction,
+    txn_verify::verify_pending_transaction,
     VERSION,
 };
 
+lazy_static! {
+    /// How long another address has to wait between asking to rotate keys with us. Each rotation makes us pay a
+    /// transaction fee to answer.
+    pub static ref MIN_KEY_ROTATION_INTERVAL: Duration = Duration::minutes(10);
+}
+
 pub struct FriendState {
     /// Pending Diffie-Hellman key exchanges - we have shared our public key but they haven't given us
     /// theirs yet
@@ -42,8 +47,8 @@ pub struct FriendState {
     pub intents: HashMap<Address, ChainRequest>,
     /// Maps addresses to readable names
     pub aliases: HashMap<Address, String>,
-    /// Keys used for encrypting/decrypting messages after a handshake has been completed
-    pub keys: HashMap<Address, Keypair>,
+    /// Key ratchets used for encrypting/decrypting messages after a handshake has been completed
+    pub keys: HashMap<Address, Ratchet>,
     /// How many TsengCoins another address needs to pay for you to reciprocate their connection request
     pub exclusivity: u64,
     /// How many TsengCoins to send when making a chain request (default)
@@ -53,6 +58,8 @@ pub struct FriendState {
     /// When a dialog or other option can't be presented prompting the user to accept/reject and incoming
     /// connection, this setting indicates whether the connection should be accepted (true) or not (false).
     pub fallback_accept_connections: bool,
+    /// When each address last asked to rotate keys with us
+    pub key_rotations: HashMap<Address, DateTime<Utc>>,
 }
 
 #[derive(Clone)]
@@ -68,12 +75,29 @@ pub struct ChatMessage {
     pub message: String,
 }
 
-pub struct Keypair {
-    pub sealing: SealingKey<NonceGen>,
-    pub opening: OpeningKey<NonceGen>,
+/// The parts of [FriendState] that are user settings, saved with [State::save]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FriendSettings {
+    pub exclusivity: u64,
+    pub chain_req_amount: u64,
+    pub fallback_accept_connections: bool,
 }
 
 impl FriendState {
+    pub fn settings(&self) -> FriendSettings {
+        FriendSettings {
+            exclusivity: self.exclusivity,
+            chain_req_amount: self.chain_req_amount,
+            fallback_accept_connections: self.fallback_accept_connections,
+        }
+    }
+
+    pub fn apply_settings(&mut self, settings: &FriendSettings) {
+        self.exclusivity = settings.exclusivity;
+        self.chain_req_amount = settings.chain_req_amount;
+        self.fallback_accept_connections = settings.fallback_accept_connections;
+    }
+
     pub fn get_name(&self, addr: Address) -> String {
         match self.aliases.get(&addr) {
             Some(name) => name.clone(),
@@ -81,6 +105,28 @@ impl FriendState {
         }
     }
 
+    /// Add an alias unless it clashes with an existing one. An address can only have one alias, and two addresses
+    /// can't share a name or [FriendState::get_address] couldn't tell them apart.
+    pub fn add_alias(&mut self, address: Address, name: String) -> Result<(), String> {
+        if let Some(existing) = self.aliases.get(&address) {
+            return Err(format!(
+                "{} already has the alias {}",
+                address_to_b58c(&address.to_vec()),
+                existing
+            ));
+        }
+
+        if self.aliases.values().any(|alias| *alias == name) {
+            return Err(format!("The alias {} is already taken", name));
+        }
+
+        self.aliases.insert(address, name);
+
+        Ok(())
+    }
+
+    /// Resolve a saved alias or a base58check address. Commands that take an address should use this so that
+    /// aliases work everywhere.
     pub fn get_address(&self, name: String) -> Result<Address, Box<dyn Error>> {
         for (addr, alias) in self.aliases.iter() {
             if *alias == name {
@@ -88,7 +134,8 @@ impl FriendState {
             }
         }
 
-        b58c_to_address(name)
+        b58c_to_address(name.clone())
+            .map_err(|err| format!("{} is not a saved alias or a valid address: {}", name, err).into())
     }
 
     pub fn decrypt_from_sender(
@@ -104,8 +151,8 @@ impl FriendState {
             .into());
         }
 
-        let keypair = self.keys.get_mut(&sender).unwrap();
-        let chain_req = decrypt_request(enc_req, &mut keypair.opening)?;
+        let ratchet = self.keys.get_mut(&sender).unwrap();
+        let chain_req = decrypt_request(enc_req, ratchet)?;
 
         Ok(chain_req)
     }
@@ -113,6 +160,20 @@ impl FriendState {
     pub fn is_connected(&self, address: &Address) -> bool {
         self.keys.contains_key(address)
     }
+
+    /// Record a key rotation request from `address`. Returns false if they already asked in the last
+    /// [MIN_KEY_ROTATION_INTERVAL], in which case the request should be ignored.
+    pub fn allow_key_rotation(&mut self, address: Address, now: DateTime<Utc>) -> bool {
+        if let Some(last) = self.key_rotations.get(&address) {
+            if now - *last < *MIN_KEY_ROTATION_INTERVAL {
+                return false;
+            }
+        }
+
+        self.key_rotations.insert(address, now);
+
+        true
+    }
 }
 
 impl std::fmt::Debug for FriendState {
@@ -137,17 +198,9 @@ pub fn check_pending_dh(
     let shared_secret = my_secret.diffie_hellman(&your_pubkey);
 
     let secret = shared_secret.as_bytes();
+    let ratchet = Ratchet::new(secret, &state.address, &sender);
 
-    let nonce_seed: [u8; 12] = [0; 12];
-    let sealing_key = make_sealing_key(secret, nonce_seed)?;
-    let opening_key = make_opening_key(secret, nonce_seed)?;
-
-    let keypair = Keypair {
-        sealing: sealing_key,
-        opening: opening_key,
-    };
-
-    state.friends.keys.insert(sender, keypair);
+    state.friends.keys.insert(sender, ratchet);
 
     Ok(false)
 }
@@ -159,16 +212,16 @@ pub fn make_encrypted_chain_req(
     dest: Address,
     state: &mut State,
 ) -> Result<Transaction, Box<dyn Error>> {
-    let keypair = match state.friends.keys.get_mut(&dest) {
+    let ratchet = match state.friends.keys.get_mut(&dest) {
         None => {
             return Err(
                 "Can't send encrypted request before performing Diffie Hellman key exchange".into(),
             )
         }
-        Some(key) => key,
+        Some(ratchet) => ratchet,
     };
 
-    let enc_req = encrypt_request(req, &mut keypair.sealing)?;
+    let enc_req = encrypt_request(req, ratchet)?;
 
     let (mut unsigned_txn, input_utxos, outputs) = make_single_p2pkh_txn(dest, 1, 1, state)?;
     unsigned_txn.meta = enc_req_meta(&enc_req)?;
@@ -194,7 +247,7 @@ pub fn make_encrypted_chain_req(
     let hash = hash_txn(&unhashed)?;
     let full_txn = unhashed.to_hashed(hash);
 
-    match verify_transaction(full_txn.clone(), state) {
+    match verify_pending_transaction(full_txn.clone(), state) {
         Ok(_) => Ok(full_txn),
         Err(err) => {
             return Err(format!("Error verifying encrypted request transaction: {}", err).into())
@@ -224,6 +277,42 @@ pub fn make_intent_req(
     }
 }
 
+/// Send a chat message to another address. If we already share keys with them, the message is encrypted and the
+/// transaction is returned right away. Otherwise a Diffie-Hellman exchange is started and the message is queued as
+/// an intent, which [make_intent_req] sends once the exchange completes. Either way the caller has to send the
+/// returned transaction. The flag is true if the message was queued.
+#[cfg(feature = "gui")]
+pub fn make_chat_req(
+    dest: Address,
+    msg: String,
+    req_amount: u64,
+    fee: u64,
+    state: &mut State,
+) -> Result<(Transaction, bool), Box<dyn Error>> {
+    let data = ChainChatReq { msg };
+
+    if state.friends.is_connected(&dest) {
+        let txn = make_encrypted_chain_req(ChainRequest::ChainChat(data.clone()), dest, state)?;
+        handle_chat_intent_req(data, dest, state);
+
+        return Ok((txn, false));
+    }
+
+    // There is only room for one intent per address, so don't overwrite a message that's still waiting
+    if state.friends.pending_dh.contains_key(&dest) {
+        return Err(format!(
+            "Still waiting for {} to complete a key exchange. Try again once it's done",
+            state.friends.get_name(dest)
+        )
+        .into());
+    }
+
+    let intent = ChainRequest::ChainChat(data);
+    let txn = make_dh_connect_req(dest, req_amount, fee, Some(intent), state)?;
+
+    Ok((txn, true))
+}
+
 #[cfg(feature = "gui")]
 fn handle_chat_intent_req(data: ChainChatReq, dest: Address, state: &mut State) {
     let sender_name = state.friends.get_name(dest);
@@ -285,20 +374,13 @@ pub fn make_dh_response_req(
     let hash = hash_txn(&unhashed)?;
     let full_txn = unhashed.to_hashed(hash);
 
-    match verify_transaction(full_txn.clone(), state) {
+    match verify_pending_transaction(full_txn.clone(), state) {
         Ok(_) => {
             let shared_secret = my_secret.diffie_hellman(&your_pubkey);
             let secret = shared_secret.as_bytes();
-            let nonce_seed: [u8; 12] = [0; 12];
-            let sealing_key = make_sealing_key(secret, nonce_seed)?;
-            let opening_key = make_opening_key(secret, nonce_seed)?;
+            let ratchet = Ratchet::new(secret, &state.address, &your_address);
 
-            let keypair = Keypair {
-                sealing: sealing_key,
-                opening: opening_key,
-            };
-
-            state.friends.keys.insert(your_address, keypair);
+            state.friends.keys.insert(your_address, ratchet);
 
             Ok((full_txn, your_address))
         }
@@ -341,7 +423,7 @@ pub fn make_dh_connect_req(
     let hash = hash_txn(&unhashed)?;
     let full_txn = unhashed.to_hashed(hash);
 
-    match verify_transaction(full_txn.clone(), state) {
+    match verify_pending_transaction(full_txn.clone(), state) {
         Ok(_) => {
             state.friends.pending_dh.insert(dest, secret);
             match intent {
@@ -427,3 +509,34 @@ fn get_dh_req_amount(txn: &Transaction, my_address: Address) -> Option<u64> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::test_util::test_state;
+
+    #[test]
+    fn key_rotations_are_rate_limited() {
+        let mut friends = test_state().friends;
+        let now = Utc::now();
+        let them: Address = [1; 20];
+
+        assert!(friends.allow_key_rotation(them, now));
+        assert!(!friends.allow_key_rotation(them, now + Duration::minutes(1)));
+        assert!(friends.allow_key_rotation([2; 20], now + Duration::minutes(1)));
+        assert!(friends.allow_key_rotation(them, now + *MIN_KEY_ROTATION_INTERVAL));
+    }
+
+    #[test]
+    fn get_address_resolves_aliases_and_addresses() {
+        let mut friends = test_state().friends;
+        let them: Address = [1; 20];
+        friends.aliases.insert(them, String::from("alice"));
+
+        assert_eq!(friends.get_address(String::from("alice")).unwrap(), them);
+        assert_eq!(friends.get_address(address_to_b58c(&[2; 20].to_vec())).unwrap(), [2; 20]);
+
+        let err = friends.get_address(String::from("bob")).err().unwrap();
+        assert!(err.to_string().starts_with("bob is not a saved alias"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
+    collections::HashMap,
     error::Error,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
 };
 
@@ -8,8 +9,8 @@ use base58check::{FromBase58Check, ToBase58Check};
 use lazy_static::lazy_static;
 use regex::Regex;
 use ring::{
-    aead::{Aad, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, AES_256_GCM},
-    error::Unspecified,
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    hmac,
 };
 use serde::{Deserialize, Serialize};
 
@@ -33,22 +34,39 @@ use super::{
 
 const B58C_VERSION_PREFIX: u8 = 0x07;
 
+/// The most message keys we will keep around for messages that haven't arrived yet. This also limits
+/// how far ahead of the receiving chain an incoming message can be.
+const MAX_SKIPPED_KEYS: u64 = 64;
+
 /// An encrypted request made on the blockchain instead of over the network. The two parties must
 /// perform a Diffie-Hellman key exchange first in order to determine a shared secret. The shared secret
 /// is used to encrypt and decrypt these requests.
 #[derive(Serialize, Deserialize, Clone)]
 pub enum ChainRequest {
     FindMeAt(FindMeAtReq),
-    // TODO: Double ratchet!!
     #[cfg(feature = "gui")]
     ChainChat(ChainChatReq),
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedChainRequest {
+    /// Position of the message in the sender's chain. The receiver uses this to find the right key.
+    pub counter: u64,
     pub ciphertext: Vec<u8>,
 }
 
+/// What can be learned about an encrypted request without the key
+#[derive(Debug)]
+pub struct EncReqInfo {
+    /// None if the transaction spends an output we don't know about
+    pub sender: Option<Address>,
+    pub recipient: Option<Address>,
+    /// Position of the message in the sender's chain. None if the metadata looks like an encrypted request
+    /// but can't be decoded.
+    pub counter: Option<u64>,
+    pub ciphertext_len: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FindMeAtReq {
     pub addr: SocketAddr,
@@ -60,39 +78,69 @@ pub struct ChainChatReq {
     pub msg: String,
 }
 
-pub struct NonceGen {
-    current: u128,
-    start: u128,
+/// A symmetric key ratchet for an encrypted connection with one other address. Each direction has
+/// its own chain key, and the chain key is advanced with every message so that each message is encrypted
+/// with a different key. Old message keys can't be derived from the current chain key, so if the current
+/// state is leaked, past messages are still safe.
+/// TODO: Diffie-Hellman ratchet, so that future messages are also safe
+pub struct Ratchet {
+    send_chain: [u8; 32],
+    send_counter: u64,
+    recv_chain: [u8; 32],
+    recv_counter: u64,
+    /// Keys for messages that were skipped over because a later message arrived first
+    skipped: HashMap<u64, [u8; 32]>,
 }
 
-impl NonceGen {
-    fn new(start: [u8; 12]) -> Self {
-        let mut nonce_bytes = [0_u8; 16];
-        nonce_bytes[4..].copy_from_slice(&start);
-
-        let start: u128 = u128::from_be_bytes(nonce_bytes);
-
+impl Ratchet {
+    /// Set up a ratchet from the shared secret of a Diffie-Hellman exchange. The addresses are used to give each
+    /// direction a different chain, so that the two parties never encrypt with the same key.
+    pub fn new(shared_secret: &[u8; 32], me: &Address, you: &Address) -> Self {
         Self {
-            start,
-            current: start.wrapping_add(1),
+            send_chain: derive_chain_key(shared_secret, me, you),
+            send_counter: 0,
+            recv_chain: derive_chain_key(shared_secret, you, me),
+            recv_counter: 0,
+            skipped: HashMap::new(),
         }
     }
+
+    /// Get the key for the next outgoing message and advance the sending chain.
+    fn next_send_key(&mut self) -> ([u8; 32], u64) {
+        let (next_chain, msg_key) = advance_chain(&self.send_chain);
+        let counter = self.send_counter;
+
+        self.send_chain = next_chain;
+        self.send_counter += 1;
+
+        (msg_key, counter)
+    }
 }
 
-impl NonceSequence for NonceGen {
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        let prev = self.current;
+fn derive_chain_key(shared_secret: &[u8; 32], from: &Address, to: &Address) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, shared_secret);
+    let mut context = hmac::Context::with_key(&key);
+    context.update(b"TsengCoin chain key");
+    context.update(from);
+    context.update(to);
 
-        if prev == self.start {
-            return Err(Unspecified);
-        }
+    context.sign().as_ref().try_into().unwrap()
+}
 
-        self.current = prev.wrapping_add(1);
+/// Returns the next chain key and the message key for the current position in the chain
+fn advance_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, chain_key);
+    let msg_key = hmac::sign(&key, &[0x01]).as_ref().try_into().unwrap();
+    let next_chain = hmac::sign(&key, &[0x02]).as_ref().try_into().unwrap();
 
-        Ok(Nonce::assume_unique_for_key(
-            prev.to_be_bytes()[4..].try_into().unwrap(),
-        ))
-    }
+    (next_chain, msg_key)
+}
+
+fn make_message_key(msg_key: &[u8; 32]) -> Result<LessSafeKey, Box<dyn Error>> {
+    let unbound_key =
+        UnboundKey::new(&AES_256_GCM, msg_key).map_err(|_| "Failed to create unbound key")?;
+
+    Ok(LessSafeKey::new(unbound_key))
 }
 
 pub fn handle_chain_request(
@@ -114,49 +162,86 @@ pub fn handle_chain_request(
     }
 }
 
-pub fn make_sealing_key(
-    secret: &[u8; 32],
-    nonce_seed: [u8; 12],
-) -> Result<SealingKey<NonceGen>, Box<dyn Error>> {
-    let unbound_key =
-        UnboundKey::new(&AES_256_GCM, secret).map_err(|_| "Failed to create unbound key")?;
-
-    Ok(SealingKey::new(unbound_key, NonceGen::new(nonce_seed)))
-}
-
-pub fn make_opening_key(
-    secret: &[u8; 32],
-    nonce_seed: [u8; 12],
-) -> Result<OpeningKey<NonceGen>, Box<dyn Error>> {
-    let unbound_key =
-        UnboundKey::new(&AES_256_GCM, secret).map_err(|_| "Failed to create unbound key")?;
-
-    Ok(OpeningKey::new(unbound_key, NonceGen::new(nonce_seed)))
-}
-
 pub fn encrypt_request(
     req: ChainRequest,
-    sealing: &mut SealingKey<NonceGen>,
+    ratchet: &mut Ratchet,
 ) -> Result<EncryptedChainRequest, Box<dyn Error>> {
     let mut data = bincode::serialize(&req)?;
-    sealing
-        .seal_in_place_append_tag(Aad::empty(), &mut data)
+    let (msg_key, counter) = ratchet.next_send_key();
+
+    // Every message key is only used once, so the nonce doesn't need to change
+    make_message_key(&msg_key)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key([0; 12]),
+            Aad::from(counter.to_be_bytes()),
+            &mut data,
+        )
         .map_err(|_| "Failed to encrypt request")?;
 
-    Ok(EncryptedChainRequest { ciphertext: data })
+    Ok(EncryptedChainRequest {
+        counter,
+        ciphertext: data,
+    })
 }
 
+/// Decrypt a request and advance the receiving chain. Requests can arrive out of order: if a request
+/// is ahead of the receiving chain, the keys for the requests in between are saved so that those requests
+/// can be decrypted later. A request whose key has already been used is rejected. The ratchet is only
+/// changed if the request is decrypted successfully.
 pub fn decrypt_request(
     req: EncryptedChainRequest,
-    opening: &mut OpeningKey<NonceGen>,
+    ratchet: &mut Ratchet,
 ) -> Result<ChainRequest, Box<dyn Error>> {
+    let counter = req.counter;
     let mut data = req.ciphertext;
+    let mut new_chain = ratchet.recv_chain;
+    let mut new_skipped: Vec<(u64, [u8; 32])> = vec![];
 
-    let decrypted_bytes = opening
-        .open_in_place(Aad::empty(), &mut data)
+    let msg_key = if counter < ratchet.recv_counter {
+        match ratchet.skipped.get(&counter) {
+            Some(key) => *key,
+            None => return Err("Chain request has already been received".into()),
+        }
+    } else {
+        if counter - ratchet.recv_counter >= MAX_SKIPPED_KEYS {
+            return Err("Chain request is too far ahead of the receiving chain".into());
+        }
+
+        for i in ratchet.recv_counter..counter {
+            let (next_chain, skipped_key) = advance_chain(&new_chain);
+            new_skipped.push((i, skipped_key));
+            new_chain = next_chain;
+        }
+
+        let (next_chain, msg_key) = advance_chain(&new_chain);
+        new_chain = next_chain;
+
+        msg_key
+    };
+
+    let decrypted_bytes = make_message_key(&msg_key)?
+        .open_in_place(
+            Nonce::assume_unique_for_key([0; 12]),
+            Aad::from(counter.to_be_bytes()),
+            &mut data,
+        )
         .map_err(|_| "Failed to decrypt chat request")?;
     let chat_request: ChainRequest = bincode::deserialize(decrypted_bytes)?;
 
+    if counter < ratchet.recv_counter {
+        ratchet.skipped.remove(&counter);
+    } else {
+        ratchet.recv_chain = new_chain;
+        ratchet.recv_counter = counter + 1;
+        ratchet.skipped.extend(new_skipped);
+
+        // Forget the oldest skipped keys if there are too many
+        while ratchet.skipped.len() as u64 > MAX_SKIPPED_KEYS {
+            let oldest = *ratchet.skipped.keys().min().unwrap();
+            ratchet.skipped.remove(&oldest);
+        }
+    }
+
     Ok(chat_request)
 }
 
@@ -203,10 +288,13 @@ pub fn decompose_enc_req(txn: &Transaction) -> Option<EncryptedChainRequest> {
 
 /// Assumes that the transaction has already been determined to be an encrypted request
 pub fn is_enc_req_to_me(txn: &Transaction, state: &State) -> bool {
-    let sender = match get_p2pkh_sender(txn, state) {
-        Some(data) => data,
-        None => return false,
-    };
+    enc_req_recipient(txn, state) == Some(state.address)
+}
+
+/// The address an encrypted request is sent to: the only P2PKH output that doesn't go back to the sender.
+/// Assumes that the transaction has already been determined to be an encrypted request.
+pub fn enc_req_recipient(txn: &Transaction, state: &State) -> Option<Address> {
+    let sender = get_p2pkh_sender(txn, state)?;
 
     let outputs = &txn
         .outputs
@@ -221,25 +309,64 @@ pub fn is_enc_req_to_me(txn: &Transaction, state: &State) -> bool {
         .collect::<Vec<&TxnOutput>>();
 
     if outputs.len() != 1 {
-        return false;
+        return None;
     }
 
-    match get_p2pkh_addr(&outputs[0].lock_script.code) {
-        None => false,
-        Some(addr) => addr == state.address,
+    get_p2pkh_addr(&outputs[0].lock_script.code)
+}
+
+/// Describe an encrypted request without decrypting it. Returns None if the transaction isn't an encrypted request.
+pub fn inspect_enc_req(txn: &Transaction, state: &State) -> Option<EncReqInfo> {
+    if !is_enc_req(txn) {
+        return None;
     }
+
+    let enc_req = decompose_enc_req(txn);
+
+    Some(EncReqInfo {
+        sender: get_p2pkh_sender(txn, state),
+        recipient: enc_req_recipient(txn, state),
+        counter: enc_req.as_ref().map(|req| req.counter),
+        ciphertext_len: enc_req.as_ref().map(|req| req.ciphertext.len()),
+    })
 }
 
 fn handle_find_me_at(
     req: FindMeAtReq,
     _sender: Address,
-    _state: &mut State,
+    state: &mut State,
 ) -> Result<(), Box<dyn Error>> {
+    let mut own_addrs = vec![state.local_addr_me];
+    own_addrs.extend(state.remote_addr_me);
+
+    if !is_dialable_addr(&req.addr, &own_addrs) {
+        println!("Ignoring \"FindMe\" with invalid address {}", req.addr);
+        return Ok(());
+    }
+
     println!("Received \"FindMe\": {:#?}", req);
 
     Ok(())
 }
 
+/// Returns false for addresses that we should never try to connect to: unspecified, broadcast,
+/// or multicast addresses, port 0, or one of our own addresses.
+pub fn is_dialable_addr(addr: &SocketAddr, own_addrs: &[SocketAddr]) -> bool {
+    let ip = addr.ip();
+
+    if ip.is_unspecified() || ip.is_multicast() || addr.port() == 0 {
+        return false;
+    }
+
+    if let IpAddr::V4(ipv4) = ip {
+        if ipv4.is_broadcast() {
+            return false;
+        }
+    }
+
+    !own_addrs.contains(addr)
+}
+
 #[cfg(feature = "gui")]
 fn handle_chain_chat(
     req: ChainChatReq,
@@ -345,3 +472,155 @@ pub fn is_gui_only(req: &ChainRequest) -> bool {
 pub fn is_gui_only(_req: &ChainRequest) -> bool {
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::{
+        block_verify::verify_block,
+        chain_request::make_encrypted_chain_req,
+        test_util::{next_block, pay, test_state},
+    };
+
+    /// Ratchets for both ends of a connection between `[1; 20]` and `[2; 20]`
+    fn ratchet_pair() -> (Ratchet, Ratchet) {
+        let secret = [7; 32];
+
+        (Ratchet::new(&secret, &[1; 20], &[2; 20]), Ratchet::new(&secret, &[2; 20], &[1; 20]))
+    }
+
+    fn find_me_at(port: u16) -> ChainRequest {
+        ChainRequest::FindMeAt(FindMeAtReq {
+            addr: SocketAddr::new([127, 0, 0, 1].into(), port),
+        })
+    }
+
+    fn assert_decrypts_to(req: EncryptedChainRequest, ratchet: &mut Ratchet, expected: &ChainRequest) {
+        let decrypted = decrypt_request(req, ratchet).unwrap();
+
+        assert_eq!(bincode::serialize(&decrypted).unwrap(), bincode::serialize(expected).unwrap());
+    }
+
+    #[test]
+    fn sequential_messages_use_distinct_keys() {
+        let (mut sender, _) = ratchet_pair();
+        let (first_key, first_counter) = sender.next_send_key();
+        let (second_key, second_counter) = sender.next_send_key();
+
+        assert_ne!(first_key, second_key);
+        assert_eq!((first_counter, second_counter), (0, 1));
+
+        let first = encrypt_request(find_me_at(1), &mut sender).unwrap();
+        let second = encrypt_request(find_me_at(1), &mut sender).unwrap();
+
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn directions_use_different_chains() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        assert_ne!(alice.next_send_key(), bob.next_send_key());
+    }
+
+    #[test]
+    fn messages_decrypt_in_order() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        for port in 1..=3 {
+            let req = encrypt_request(find_me_at(port), &mut alice).unwrap();
+            assert_decrypts_to(req, &mut bob, &find_me_at(port));
+        }
+    }
+
+    #[test]
+    fn out_of_order_messages_decrypt_once() {
+        let (mut alice, mut bob) = ratchet_pair();
+        let first = encrypt_request(find_me_at(1), &mut alice).unwrap();
+        let second = encrypt_request(find_me_at(2), &mut alice).unwrap();
+        let first_again = EncryptedChainRequest {
+            counter: first.counter,
+            ciphertext: first.ciphertext.clone(),
+        };
+        let second_again = EncryptedChainRequest {
+            counter: second.counter,
+            ciphertext: second.ciphertext.clone(),
+        };
+
+        assert_decrypts_to(second, &mut bob, &find_me_at(2));
+        assert_decrypts_to(first, &mut bob, &find_me_at(1));
+
+        assert!(decrypt_request(first_again, &mut bob).is_err());
+        assert!(decrypt_request(second_again, &mut bob).is_err());
+    }
+
+    #[test]
+    fn messages_too_far_ahead_are_rejected() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        for _ in 0..MAX_SKIPPED_KEYS {
+            alice.next_send_key();
+        }
+
+        let req = encrypt_request(find_me_at(1), &mut alice).unwrap();
+
+        assert!(decrypt_request(req, &mut bob).is_err());
+        assert_eq!(bob.recv_counter, 0);
+    }
+
+    #[test]
+    fn tampered_message_leaves_ratchet_alone() {
+        let (mut alice, mut bob) = ratchet_pair();
+        let mut req = encrypt_request(find_me_at(1), &mut alice).unwrap();
+        req.ciphertext[0] ^= 1;
+
+        assert!(decrypt_request(req, &mut bob).is_err());
+        assert_eq!(bob.recv_counter, 0);
+        assert!(bob.skipped.is_empty());
+
+        let req = encrypt_request(find_me_at(2), &mut alice).unwrap();
+        assert_decrypts_to(req, &mut bob, &find_me_at(2));
+    }
+
+    #[test]
+    fn dialable_addrs() {
+        let me: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let own = [me, "127.0.0.1:8333".parse().unwrap()];
+        let dialable = |addr: &str| is_dialable_addr(&addr.parse().unwrap(), &own);
+
+        assert!(dialable("10.0.0.2:8333"));
+        assert!(dialable("10.0.0.1:8334"));
+        assert!(dialable("[2001:db8::1]:8333"));
+
+        assert!(!dialable("10.0.0.1:8333"));
+        assert!(!dialable("127.0.0.1:8333"));
+        assert!(!dialable("0.0.0.0:8333"));
+        assert!(!dialable("[::]:8333"));
+        assert!(!dialable("255.255.255.255:8333"));
+        assert!(!dialable("224.0.0.1:8333"));
+        assert!(!dialable("[ff02::1]:8333"));
+        assert!(!dialable("10.0.0.2:0"));
+    }
+
+    #[test]
+    fn inspects_enc_req_without_key() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let dest: Address = [2; 20];
+        let ratchet = Ratchet::new(&[7; 32], &state.address, &dest);
+        state.friends.keys.insert(dest, ratchet);
+
+        let txn = make_encrypted_chain_req(find_me_at(8333), dest, &mut state).unwrap();
+        let info = inspect_enc_req(&txn, &state).unwrap();
+
+        assert_eq!(info.sender, Some(state.address));
+        assert_eq!(info.recipient, Some(dest));
+        assert_eq!(info.counter, Some(0));
+        assert!(info.ciphertext_len.unwrap() > 0);
+        assert!(!is_enc_req_to_me(&txn, &state));
+
+        assert!(inspect_enc_req(&pay(&state, &dest, 10, 1), &state).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+use super::{
+    block::{Block, MAX_TRANSACTION_FIELD_SIZE, MAX_TXNS_PER_BLOCK},
+    state::State,
+    transaction::{coinbase_size_estimate, compute_fee, Transaction},
+};
+
+/// Number of blocks at the top of the main chain to look at when estimating confirmation times
+pub const RECENT_BLOCKS: usize = 10;
+
+/// A transaction's fee and size, as seen by a miner choosing transactions
+#[derive(Debug, Clone, Copy)]
+pub struct FeeSample {
+    pub fee: u64,
+    pub size: usize,
+}
+
+/// What a recent block tells us about which fee rates get confirmed
+#[derive(Debug, Clone, Copy)]
+pub struct BlockFeeSample {
+    /// The lowest fee rate of any transaction in the block, or None if the block only has a coinbase
+    pub min_rate: Option<f64>,
+    /// Bytes left in the block after all of its transactions
+    pub free_space: usize,
+}
+
+impl FeeSample {
+    pub fn new(txn: &Transaction, state: &State) -> Self {
+        Self {
+            fee: compute_fee(txn, state),
+            size: txn.vsize(),
+        }
+    }
+
+    /// Fee in TsengCoin per byte
+    pub fn rate(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+}
+
+impl BlockFeeSample {
+    pub fn new(block: &Block, state: &State) -> Self {
+        let txns = &block.transactions[1..];
+        let min_rate = txns
+            .iter()
+            .map(|t| FeeSample::new(t, state).rate())
+            .reduce(f64::min);
+        let used = txns.iter().fold(0, |a, e| a + e.size());
+
+        Self {
+            min_rate,
+            free_space: block_capacity().saturating_sub(used),
+        }
+    }
+
+    /// True if a transaction with this fee rate and size would have made it into the block, assuming
+    /// the miner picked the transactions with the highest fee rates
+    pub fn would_include(&self, rate: f64, size: usize) -> bool {
+        match self.min_rate {
+            None => true,
+            Some(min_rate) => size <= self.free_space || rate >= min_rate,
+        }
+    }
+}
+
+/// Bytes available for non-coinbase transactions in a block
+pub fn block_capacity() -> usize {
+    MAX_TRANSACTION_FIELD_SIZE - coinbase_size_estimate()
+}
+
+/// Get fee samples for the last [RECENT_BLOCKS] blocks in the main chain. The genesis block is skipped.
+pub fn recent_block_samples(state: &State) -> Vec<BlockFeeSample> {
+    let blocks = &state.blockchain.blocks;
+    let start = blocks.len().saturating_sub(RECENT_BLOCKS).max(1);
+
+    blocks[start..]
+        .iter()
+        .map(|b| BlockFeeSample::new(b, state))
+        .collect()
+}
+
+/// Estimate how many blocks it will take to confirm a transaction with the given fee and size. This assumes
+/// miners take the highest fee rates first, which our own miner doesn't do yet. Two estimates are made and the
+/// larger one is returned:
+///
+/// 1. How many blocks it takes to mine every pending transaction paying at least our rate, plus ours
+/// 2. How often recent blocks would have had room for us, given the lowest rate they accepted
+///
+/// Returns None if no recent block would have included the transaction. A higher fee never gives a larger estimate.
+pub fn estimate_confirmation(
+    fee: u64,
+    size: usize,
+    pending: &[FeeSample],
+    recent: &[BlockFeeSample],
+) -> Option<usize> {
+    let rate = fee as f64 / size as f64;
+    let ahead = pending.iter().filter(|p| p.rate() >= rate);
+    let (ahead_bytes, ahead_count) = ahead.fold((0, 0), |(bytes, count), p| (bytes + p.size, count + 1));
+
+    let by_size = div_ceil(ahead_bytes + size, block_capacity());
+    let by_count = div_ceil(ahead_count + 1, MAX_TXNS_PER_BLOCK - 1);
+    let from_mempool = by_size.max(by_count);
+
+    if recent.is_empty() {
+        return Some(from_mempool);
+    }
+
+    let included = recent.iter().filter(|b| b.would_include(rate, size)).count();
+
+    if included == 0 {
+        return None;
+    }
+
+    let from_history = div_ceil(recent.len(), included);
+
+    Some(from_mempool.max(from_history))
+}
+
+/// `usize::div_ceil` isn't stable on the pinned toolchain
+fn div_ceil(num: usize, denom: usize) -> usize {
+    let quot = num / denom;
+
+    if quot * denom < num { quot + 1 } else { quot }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough pending transactions at a spread of fee rates to fill several blocks
+    fn busy_mempool() -> Vec<FeeSample> {
+        (1..=200)
+            .map(|i| FeeSample {
+                fee: i * 40,
+                size: block_capacity() / 50,
+            })
+            .collect()
+    }
+
+    fn recent_blocks() -> Vec<BlockFeeSample> {
+        [None, Some(0.5), Some(1.0), Some(2.0), Some(4.0)]
+            .iter()
+            .map(|&min_rate| BlockFeeSample { min_rate, free_space: 0 })
+            .collect()
+    }
+
+    #[test]
+    fn empty_mempool_confirms_in_next_block() {
+        assert_eq!(estimate_confirmation(1, 250, &[], &[]), Some(1));
+    }
+
+    #[test]
+    fn higher_fee_never_waits_longer() {
+        let pending = busy_mempool();
+        let recent = recent_blocks();
+        let size = 250;
+
+        // None means no recent block would have taken it, which is the worst estimate
+        let estimates = (0..20)
+            .map(|i| estimate_confirmation(i * 100, size, &pending, &recent).unwrap_or(usize::MAX))
+            .collect::<Vec<usize>>();
+
+        assert!(estimates.windows(2).all(|w| w[1] <= w[0]), "{:?}", estimates);
+        assert!(estimates[estimates.len() - 1] < estimates[0]);
+    }
+}
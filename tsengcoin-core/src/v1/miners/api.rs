@@ -1,12 +1,12 @@
 use std::{
     collections::HashMap,
-    sync::{mpsc::Receiver, Mutex},
+    sync::{mpsc::{Receiver, TryRecvError}, Mutex},
 };
 
-use chrono::{Utc, Duration};
+use chrono::{DateTime, Utc, Duration};
 use lazy_static::lazy_static;
 
-use crate::{v1::{state::State, block::{RawBlock, MAX_TRANSACTION_FIELD_SIZE, make_merkle_root, RawBlockHeader}, transaction::{coinbase_size_estimate, make_coinbase_txn, Transaction, compute_fee}, VERSION}, wallet::Hash256};
+use crate::{difficulty::get_difficulty_bits, tsengscript_interpreter::count_sigops, v1::{state::State, block::{RawBlock, MAX_BLOCK_SIGOPS, MAX_TRANSACTION_FIELD_SIZE, MAX_TXNS_PER_BLOCK, make_merkle_root, RawBlockHeader}, transaction::{coinbase_size_estimate, make_coinbase_txn, make_p2pkh_lock, spent_sigops, Transaction, compute_fee}, VERSION}, wallet::Hash256};
 
 /// Update the hashes per sec metric every 5 seconds by default
 pub const HASH_PER_SEC_INTERVAL: i64 = 5;
@@ -14,6 +14,12 @@ pub const HASH_PER_SEC_INTERVAL: i64 = 5;
 lazy_static! {
     /// Poll the MinerMessage receiver every 5 seconds
     pub static ref POLL_INTERVAL: Duration = Duration::seconds(5);
+    /// If a round of hashing is faster than this, the miner polls before every round instead of every [POLL_INTERVAL].
+    /// When the difficulty is low, a new block can come in well before the next poll, and every round until then
+    /// is wasted on a stale candidate block.
+    pub static ref FAST_ROUND: Duration = Duration::milliseconds(500);
+    /// Make a new candidate block every 30 minutes by default
+    pub static ref DEFAULT_BLOCK_REFRESH: Duration = Duration::minutes(30);
 }
 
 pub type MineFunc = fn(&Mutex<State>, Receiver<MinerMessage>);
@@ -29,6 +35,114 @@ pub enum MinerMessage {
     NewDifficulty(Hash256),
 }
 
+/// What the miner should do after checking its messages
+pub enum PollResult {
+    /// Keep mining the current candidate block
+    Continue,
+    /// Make a new candidate block
+    Reset,
+    /// The channel has closed and the miner should stop
+    Stop,
+}
+
+/// Drain every message waiting in the channel and combine them into one decision, so that a burst of
+/// new transactions or blocks only causes one reset. `only_coinbase` should be true if the current candidate
+/// block has no transactions besides the coinbase.
+pub fn poll_messages(receiver: &Receiver<MinerMessage>, only_coinbase: bool) -> PollResult {
+    let mut reset = false;
+
+    loop {
+        match receiver.try_recv() {
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => return PollResult::Stop,
+            Ok(MinerMessage::NewBlock(_, _)) | Ok(MinerMessage::NewTransactions(_))
+                if only_coinbase =>
+            {
+                reset = true;
+            }
+            Ok(MinerMessage::NewDifficulty(diff)) => {
+                reset = true;
+                println!("New difficulty target: {}", hex::encode(diff));
+            }
+            _ => (),
+        }
+    }
+
+    match reset {
+        true => PollResult::Reset,
+        false => PollResult::Continue,
+    }
+}
+
+/// When the miner should make a new candidate block if nothing else comes up, given that it just made one. This picks
+/// up new pending transactions and a fresh timestamp.
+pub fn next_reset(now: DateTime<Utc>, state: &State) -> DateTime<Utc> {
+    now + state.block_refresh
+}
+
+/// Decide whether the miner should poll for messages before its next round. `last_round` is how long the last round
+/// took, or None if there hasn't been one yet. Slow rounds poll every [POLL_INTERVAL], and fast rounds poll every
+/// time. See [FAST_ROUND].
+pub fn should_poll(now: DateTime<Utc>, last_poll_time: DateTime<Utc>, last_round: Option<Duration>) -> bool {
+    if now - last_poll_time > *POLL_INTERVAL {
+        return true;
+    }
+
+    match last_round {
+        None => false,
+        Some(round) => round < *FAST_ROUND,
+    }
+}
+
+/// How many of the largest work group sizes the device allows to try when auto-tuning the CL miner
+pub const AUTOTUNE_WG_SIZES: usize = 4;
+/// Auto-tuning skips configurations with more nonces per round than this, so that the nonce and hash buffers
+/// stay a reasonable size
+pub const MAX_AUTOTUNE_NONCES: usize = 1 << 20;
+/// Each work group size is tried with this many work groups per compute unit
+static AUTOTUNE_GROUPS_PER_UNIT: [usize; 3] = [1, 4, 16];
+
+/// The (work group size, number of work groups) configurations to try when auto-tuning the CL miner. Work group sizes
+/// are the [AUTOTUNE_WG_SIZES] largest powers of two up to `max_wg_size`, and each one is tried with a few multiples
+/// of the device's compute units.
+pub fn autotune_candidates(max_wg_size: usize, max_compute_units: usize) -> Vec<(usize, usize)> {
+    let mut wg_sizes: Vec<usize> = vec![];
+    let mut wg_size: usize = 1;
+
+    while wg_size <= max_wg_size {
+        wg_sizes.push(wg_size);
+        wg_size *= 2;
+    }
+
+    let start = wg_sizes.len().saturating_sub(AUTOTUNE_WG_SIZES);
+    let compute_units = max_compute_units.max(1);
+
+    wg_sizes[start..]
+        .iter()
+        .flat_map(|wg_size| {
+            AUTOTUNE_GROUPS_PER_UNIT
+                .iter()
+                .map(move |per_unit| (*wg_size, compute_units * per_unit))
+        })
+        .filter(|(wg_size, work_groups)| wg_size * work_groups <= MAX_AUTOTUNE_NONCES)
+        .collect()
+}
+
+/// Pick the configuration that hashed the most nonces per second, given how long one round took with each one.
+/// Rounds too short to measure are ignored. Returns None if there are no usable timings.
+pub fn fastest_config(timings: &[((usize, usize), Duration)]) -> Option<(usize, usize)> {
+    timings
+        .iter()
+        .filter_map(|((wg_size, work_groups), round)| {
+            round
+                .num_microseconds()
+                .filter(|micros| *micros > 0)
+                .map(|micros| ((*wg_size, *work_groups), (wg_size * work_groups) as f64 / micros as f64))
+        })
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map(|(config, _)| config)
+}
+
 /// Assumes that the miner name is a valid miner.
 #[allow(unused_variables)]
 pub fn start_miner(
@@ -82,15 +196,14 @@ fn make_miner_map() -> HashMap<String, MineFunc> {
 
 pub fn make_raw_block(state_mut: &Mutex<State>) -> RawBlock {
     let state = state_mut.lock().unwrap();
-    let txns = state.pending_txns.clone();
-    let (mut best_txns, fees) = pick_best_transactions(&txns, &state, coinbase_size_estimate());
-    let coinbase = make_coinbase_txn(&state.address, String::from(""), fees, rand::random());
+    let (mut best_txns, fees) = pick_block_txns(&state);
+    let coinbase = make_coinbase_txn(&state.address, state.coinbase_msg.clone(), fees, rand::random());
 
     let mut block_txns = vec![coinbase];
     block_txns.append(&mut best_txns);
 
     let prev_hash = state.blockchain.top_hash(0);
-    let difficulty_target = state.blockchain.current_difficulty();
+    let bits = get_difficulty_bits(&state.blockchain.current_difficulty());
 
     let merkle_root = make_merkle_root(&block_txns);
     let header = RawBlockHeader {
@@ -98,7 +211,7 @@ pub fn make_raw_block(state_mut: &Mutex<State>) -> RawBlock {
         prev_hash,
         merkle_root,
         timestamp: Utc::now().timestamp().try_into().unwrap(),
-        difficulty_target,
+        bits,
         nonce: [0; 32],
     };
 
@@ -108,6 +221,15 @@ pub fn make_raw_block(state_mut: &Mutex<State>) -> RawBlock {
     }
 }
 
+/// Pick the pending transactions that will go in our next block, leaving room for our coinbase transaction.
+/// Returns the transactions and their total fees.
+pub fn pick_block_txns(state: &State) -> (Vec<Transaction>, u64) {
+    let coinbase_size = coinbase_size_estimate() + state.coinbase_msg.len();
+    let coinbase_sigops = count_sigops(&make_p2pkh_lock(&state.address).code);
+
+    pick_best_transactions(&state.pending_txns, state, coinbase_size, coinbase_sigops)
+}
+
 /// The problem here is to pick which transactions we will include in a block. Generally we want to maximize
 /// the total fees while staying under the block size limit. This is the knapsack problem, and it is NP hard -
 /// so rather than deal with it here we just take as many transactions as we can fit regardless of fee. We could take
@@ -118,21 +240,35 @@ pub fn pick_best_transactions(
     txns: &[Transaction],
     state: &State,
     coinbase_size: usize,
+    coinbase_sigops: usize,
 ) -> (Vec<Transaction>, u64) {
     let mut out: Vec<Transaction> = vec![];
     let mut size: usize = coinbase_size;
+    let mut sigops: usize = coinbase_sigops;
     let mut fees: u64 = 0;
 
     for txn in txns {
+        // Leave room for the coinbase transaction
+        if out.len() + 1 >= MAX_TXNS_PER_BLOCK {
+            break;
+        }
+
         let txn_size = txn.size();
 
         if (txn_size + size) > MAX_TRANSACTION_FIELD_SIZE {
             continue;
         }
 
+        let txn_sigops = txn.sigops() + spent_sigops(txn, state);
+
+        if (txn_sigops + sigops) > MAX_BLOCK_SIGOPS {
+            continue;
+        }
+
         let fee = compute_fee(txn, state);
         out.push(txn.clone());
         size += txn_size;
+        sigops += txn_sigops;
         fees += fee;
     }
 
@@ -159,3 +295,140 @@ pub fn find_winner(nonces: &[u8], hashes: &[u8], difficulty: &Hash256) -> Option
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::v1::{
+        block_verify::verify_block,
+        test_util::{next_block, pay, test_state},
+        transaction::BLOCK_REWARD,
+    };
+
+    #[test]
+    fn queued_messages_cause_one_reset() {
+        let (sender, receiver) = channel();
+        for i in 0..5 {
+            sender.send(MinerMessage::NewTransactions(i)).unwrap();
+        }
+        sender.send(MinerMessage::NewBlock([1; 32], true)).unwrap();
+
+        assert!(matches!(poll_messages(&receiver, true), PollResult::Reset));
+        assert!(matches!(poll_messages(&receiver, true), PollResult::Continue));
+    }
+
+    #[test]
+    fn full_block_ignores_new_transactions() {
+        let (sender, receiver) = channel();
+        sender.send(MinerMessage::NewTransactions(3)).unwrap();
+
+        assert!(matches!(poll_messages(&receiver, false), PollResult::Continue));
+
+        sender.send(MinerMessage::NewDifficulty([1; 32])).unwrap();
+        sender.send(MinerMessage::NewDifficulty([2; 32])).unwrap();
+
+        assert!(matches!(poll_messages(&receiver, false), PollResult::Reset));
+        assert!(matches!(poll_messages(&receiver, false), PollResult::Continue));
+    }
+
+    #[test]
+    fn closed_channel_stops_miner() {
+        let (sender, receiver) = channel();
+        sender.send(MinerMessage::NewTransactions(1)).unwrap();
+        drop(sender);
+
+        assert!(matches!(poll_messages(&receiver, true), PollResult::Stop));
+    }
+
+    #[test]
+    fn candidates_use_largest_wg_sizes() {
+        let candidates = autotune_candidates(256, 2);
+
+        assert_eq!(candidates.len(), AUTOTUNE_WG_SIZES * AUTOTUNE_GROUPS_PER_UNIT.len());
+        assert!(candidates.contains(&(256, 2)));
+        assert!(candidates.contains(&(32, 32)));
+        assert!(candidates.iter().all(|(wg_size, _)| *wg_size >= 32 && *wg_size <= 256));
+    }
+
+    #[test]
+    fn candidates_skip_huge_configs() {
+        let candidates = autotune_candidates(1024, 1024);
+
+        assert!(!candidates.is_empty());
+        assert!(candidates.iter().all(|(wg_size, work_groups)| wg_size * work_groups <= MAX_AUTOTUNE_NONCES));
+        assert!(!candidates.contains(&(1024, 1024 * 16)));
+    }
+
+    #[test]
+    fn candidates_on_small_device() {
+        assert_eq!(autotune_candidates(1, 0), vec![(1, 1), (1, 4), (1, 16)]);
+        assert_eq!(autotune_candidates(3, 1).len(), 2 * AUTOTUNE_GROUPS_PER_UNIT.len());
+    }
+
+    #[test]
+    fn fastest_config_has_most_nonces_per_sec() {
+        let timings = [
+            ((64, 4), Duration::milliseconds(10)),
+            ((128, 4), Duration::milliseconds(15)),
+            ((256, 4), Duration::milliseconds(40)),
+        ];
+
+        assert_eq!(fastest_config(&timings), Some((128, 4)));
+    }
+
+    #[test]
+    fn fastest_config_ignores_unmeasured_rounds() {
+        let timings = [((64, 4), Duration::milliseconds(10)), ((256, 16), Duration::zero())];
+
+        assert_eq!(fastest_config(&timings), Some((64, 4)));
+        assert_eq!(fastest_config(&[((256, 16), Duration::zero())]), None);
+        assert_eq!(fastest_config(&[]), None);
+    }
+
+    #[test]
+    fn coinbase_has_configured_message() {
+        let mut state = test_state();
+        state.coinbase_msg = String::from("mined by tests");
+
+        let block = make_raw_block(&Mutex::new(state));
+
+        assert_eq!(block.transactions[0].meta, "mined by tests");
+    }
+
+    #[test]
+    fn preview_picks_same_txns_as_miner() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        for fee in [3, 1] {
+            let txn = pay(&state, &[1; 20], 10, fee);
+            state.add_pending_txn(txn);
+        }
+
+        let (txns, fees) = pick_block_txns(&state);
+        let raw_block = make_raw_block(&Mutex::new(state));
+
+        assert_eq!(txns.len(), 2);
+        assert_eq!(fees, 4);
+        assert_eq!(raw_block.transactions[1..], txns[..]);
+        assert_eq!(raw_block.transactions[0].outputs[0].amount, BLOCK_REWARD + fees);
+    }
+
+    #[test]
+    fn fast_rounds_poll_every_time() {
+        let last_poll_time = Utc::now();
+        let soon = last_poll_time + Duration::milliseconds(1);
+        let later = last_poll_time + *POLL_INTERVAL + Duration::milliseconds(1);
+        let fast = *FAST_ROUND - Duration::milliseconds(1);
+        let slow = *FAST_ROUND * 2;
+
+        assert!(!should_poll(soon, last_poll_time, None));
+        assert!(!should_poll(soon, last_poll_time, Some(slow)));
+        assert!(should_poll(soon, last_poll_time, Some(fast)));
+        assert!(should_poll(later, last_poll_time, None));
+        assert!(should_poll(later, last_poll_time, Some(slow)));
+    }
+}
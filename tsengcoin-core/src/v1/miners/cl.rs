@@ -1,6 +1,6 @@
 use std::{
     ptr,
-    sync::{mpsc::{Receiver, TryRecvError}, Mutex},
+    sync::{mpsc::Receiver, Mutex},
 };
 
 use chrono::{DateTime, Utc, Duration};
@@ -19,7 +19,7 @@ use crate::{
     hash::{hash_chunks},
     v1::{
         block::{BlockHeader, Block},
-        state::State, miners::{api::{make_raw_block, POLL_INTERVAL, randomize, find_winner}, stats::DEFAULT_GRANULARITY}, block_verify::verify_block, request::Request, net::{broadcast_async_blast},
+        state::State, miners::{api::{autotune_candidates, fastest_config, make_raw_block, poll_messages, next_reset, should_poll, PollResult, randomize, find_winner}, stats::DEFAULT_GRANULARITY}, block_verify::{verify_block, BlockStatus}, net::announce_block,
     },
 };
 
@@ -27,6 +27,9 @@ use super::api::MinerMessage;
 
 static MINER_CL_CODE: &str = include_str!("../../../kernels/cl_miner.cl");
 
+/// How many rounds to time each configuration for when auto-tuning
+const AUTOTUNE_ROUNDS: i32 = 5;
+
 // TODO: Remove
 #[allow(deprecated)]
 pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
@@ -47,23 +50,37 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     println!("Max compute units: {}", max_compute_units);
     println!("Max work group size: {}", max_wg_size);
 
-    let (wg_size, work_groups) = {
+    let context = Context::from_device(&device).expect("Failed to create OpenCL context");
+    let queue = CommandQueue::create_default(&context, CL_QUEUE_PROFILING_ENABLE)
+        .expect("Failed to create command queue");
+    let program = Program::create_and_build_from_source(&context, MINER_CL_CODE, "")
+        .expect("Failed to build OpenCL program");
+    let kernel = Kernel::create(&program, "finish_hash").expect("Failed to create OpenCL kernel");
+
+    let (default_config, autotune) = {
         let state = &state_mut.lock().unwrap();
 
-        (state.wg_size.unwrap_or(1), state.num_work_groups.unwrap_or(max_compute_units.try_into().unwrap()))
+        (
+            (state.wg_size.unwrap_or(1), state.num_work_groups.unwrap_or(max_compute_units.try_into().unwrap())),
+            state.cl_autotune,
+        )
+    };
+
+    let (wg_size, work_groups) = match autotune {
+        false => default_config,
+        true => match autotune_work_groups(&context, &queue, &kernel, max_wg_size, max_compute_units.try_into().unwrap()) {
+            Some(config) => config,
+            None => {
+                println!("No work group configuration could be timed. Falling back to work group size {} and {} work groups", default_config.0, default_config.1);
+                default_config
+            }
+        },
     };
     // The global work size
     let num_nonces = wg_size * work_groups;
 
     println!("Running OpenCL miner with work group size {} and {} work groups: {} nonces per round", wg_size, work_groups, num_nonces);
 
-    let context = Context::from_device(&device).expect("Failed to create OpenCL context");
-    let queue = CommandQueue::create_default(&context, CL_QUEUE_PROFILING_ENABLE)
-        .expect("Failed to create command queue");
-    let program = Program::create_and_build_from_source(&context, MINER_CL_CODE, "")
-        .expect("Failed to build OpenCL program");
-    let kernel = Kernel::create(&program, "finish_hash").expect("Failed to create OpenCL kernel");
-
     let mut raw_block = make_raw_block(state_mut);
     let mut raw_header_bytes = bincode::serialize(&raw_block.header).unwrap();
     let (mut schedule, mut hash_vars) = hash_chunks(&raw_header_bytes, 1);
@@ -77,7 +94,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     };
 
     let mut schedule_buf = unsafe {
-        Buffer::<cl_uint>::create(&context, CL_MEM_READ_ONLY, 11, ptr::null_mut())
+        Buffer::<cl_uint>::create(&context, CL_MEM_READ_ONLY, 4, ptr::null_mut())
             .expect("Failed to create buffer for schedule")
     };
 
@@ -93,7 +110,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
 
     let schedule_write_event = unsafe {
         queue
-            .enqueue_write_buffer(&mut schedule_buf, CL_NON_BLOCKING, 0, &schedule[0..11], &[])
+            .enqueue_write_buffer(&mut schedule_buf, CL_NON_BLOCKING, 0, &schedule[0..4], &[])
             .expect("Failed to write to schedule buffer")
     };
 
@@ -107,15 +124,18 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     hash_vars_write_event.wait().unwrap();
 
     let mut now: DateTime<Utc>;
-    let mut reset_time = Utc::now() + Duration::minutes(30);
+    let mut reset_time = next_reset(Utc::now(), &state_mut.lock().unwrap());
 
     let mut print_time = Utc::now();
     let mut total_hashes: usize = 0;
     let mut last_poll_time = Utc::now();
+    let mut last_round: Option<Duration> = None;
 
     let hashrate_interval = state_mut.lock().unwrap().miner_stats.as_ref().map(|m| m.granularity).unwrap_or(DEFAULT_GRANULARITY);
     let hash_per_sec_duration = Duration::milliseconds(hashrate_interval as i64);
 
+    state_mut.lock().unwrap().miner_counters.start();
+
     if let Some(stats) = &mut state_mut.lock().unwrap().miner_stats {
         println!("Recording miner stats for {}s. Stats will be saved to {}", stats.record_for / 1000, stats.filename);
         stats.start();
@@ -126,25 +146,18 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     loop {
         now = Utc::now();
 
-        if now - last_poll_time > *POLL_INTERVAL {
-            let msg_result = receiver.try_recv();
-            match msg_result {
-                Err(TryRecvError::Disconnected) => {
+        if should_poll(now, last_poll_time, last_round) {
+            match poll_messages(&receiver, raw_block.transactions.len() == 1) {
+                PollResult::Stop => {
                     println!("Stopping miner thread due to unexpected channel closing");
                     return;
                 }
-                Ok(MinerMessage::NewBlock(_, _)) | Ok(MinerMessage::NewTransactions(_))
-                    if raw_block.transactions.len() == 1 =>
-                {
+                PollResult::Reset => {
                     // Force a reset by moving the reset time into the past
                     reset_time = Utc::now() - Duration::hours(1);
                     println!("Miner received instruction to reset");
                 }
-                Ok(MinerMessage::NewDifficulty(diff)) => {
-                    reset_time = Utc::now() - Duration::hours(1);
-                    println!("New difficulty target: {}", hex::encode(diff));
-                }
-                _ => (),
+                PollResult::Continue => (),
             }
 
             last_poll_time = now;
@@ -160,7 +173,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
 
             let schedule_write_event = unsafe {
                 queue
-                    .enqueue_write_buffer(&mut schedule_buf, CL_NON_BLOCKING, 0, &schedule[0..11], &[])
+                    .enqueue_write_buffer(&mut schedule_buf, CL_NON_BLOCKING, 0, &schedule[0..4], &[])
                     .expect("Failed to write to schedule buffer")
             };
         
@@ -173,7 +186,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
             schedule_write_event.wait().unwrap();
             hash_vars_write_event.wait().unwrap();
 
-            reset_time = now + Duration::minutes(30);
+            reset_time = next_reset(now, &state_mut.lock().unwrap());
         }
 
         randomize(&mut nonces);
@@ -206,6 +219,8 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     
         read_event.wait().unwrap();
 
+        last_round = Some(Utc::now() - now);
+
         total_hashes += num_nonces;
 
         if now - print_time > hash_per_sec_duration {
@@ -229,7 +244,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
             }
         }
 
-        match find_winner(&nonces, &hashes, &raw_block.header.difficulty_target) {
+        match find_winner(&nonces, &hashes, &raw_block.header.target()) {
             None => (),
             Some((nonce, hash)) => {
                 let mut guard = state_mut.lock().unwrap();
@@ -241,7 +256,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
                         prev_hash: raw_block.header.prev_hash,
                         merkle_root: raw_block.header.merkle_root,
                         timestamp: raw_block.header.timestamp,
-                        difficulty_target: raw_block.header.difficulty_target,
+                        bits: raw_block.header.bits,
                         nonce,
                         hash,
                     },
@@ -250,17 +265,18 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
 
                 let verify_result = verify_block(new_block.clone(), state);
                 match verify_result {
-                    Ok(true) => {
+                    Ok(BlockStatus::MissingParent(_)) => {
                         println!("New block is an orphan. Rejecting");
                     }
                     Err(err) => {
                         println!("Rejecting new block: {}", err);
                     }
-                    Ok(false) => {
-                        let peers = state.network.peer_addrs();
+                    Ok(BlockStatus::Added) => {
+                        state.miner_counters.record_block();
+
                         drop(guard);
 
-                        broadcast_async_blast(Request::NewBlock(new_block), &peers, None);
+                        announce_block(new_block, state_mut, None);
                     }
                 }
 
@@ -270,6 +286,118 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     }
 }
 
+/// Time a few rounds of the mining kernel with each of the [autotune_candidates] and return the fastest configuration.
+/// The kernel hashes junk here, but it does just as much work as it would for a real block header.
+fn autotune_work_groups(
+    context: &Context,
+    queue: &CommandQueue,
+    kernel: &Kernel,
+    max_wg_size: usize,
+    max_compute_units: usize,
+) -> Option<(usize, usize)> {
+    let candidates = autotune_candidates(max_wg_size, max_compute_units);
+
+    println!("Auto-tuning the OpenCL miner with {} configurations", candidates.len());
+
+    let schedule = vec![0 as cl_uint; 4];
+    let hash_vars = vec![0 as cl_uint; 8];
+
+    let mut schedule_buf = unsafe {
+        Buffer::<cl_uint>::create(context, CL_MEM_READ_ONLY, 4, ptr::null_mut())
+            .expect("Failed to create buffer for schedule")
+    };
+
+    let mut hash_vars_buf = unsafe {
+        Buffer::<cl_uint>::create(context, CL_MEM_READ_ONLY, 8, ptr::null_mut())
+            .expect("Failed to create buffer for hash variables")
+    };
+
+    let schedule_write_event = unsafe {
+        queue
+            .enqueue_write_buffer(&mut schedule_buf, CL_NON_BLOCKING, 0, &schedule, &[])
+            .expect("Failed to write to schedule buffer")
+    };
+
+    let hash_vars_write_event = unsafe {
+        queue
+            .enqueue_write_buffer(&mut hash_vars_buf, CL_NON_BLOCKING, 0, &hash_vars, &[])
+            .expect("Failed to write to hash vars buffer")
+    };
+
+    schedule_write_event.wait().unwrap();
+    hash_vars_write_event.wait().unwrap();
+
+    let mut timings: Vec<((usize, usize), Duration)> = vec![];
+
+    for (wg_size, work_groups) in candidates {
+        // Some configurations may be too big for this kernel on this device. Those are skipped
+        match time_work_groups(context, queue, kernel, &schedule_buf, &hash_vars_buf, wg_size, work_groups) {
+            Err(err) => println!("Skipping work group size {} with {} work groups: {}", wg_size, work_groups, err),
+            Ok(round) => {
+                println!("Work group size {} with {} work groups: {:.3}ms per round", wg_size, work_groups, round.num_microseconds().unwrap_or(0) as f64 / 1000.0);
+                timings.push(((wg_size, work_groups), round));
+            }
+        }
+    }
+
+    fastest_config(&timings)
+}
+
+/// Run [AUTOTUNE_ROUNDS] rounds of the mining kernel with the given configuration and return how long one round took
+/// on average
+fn time_work_groups(
+    context: &Context,
+    queue: &CommandQueue,
+    kernel: &Kernel,
+    schedule_buf: &Buffer<cl_uint>,
+    hash_vars_buf: &Buffer<cl_uint>,
+    wg_size: usize,
+    work_groups: usize,
+) -> Result<Duration, ClError> {
+    let num_nonces = wg_size * work_groups;
+    let mut nonces = vec![0 as cl_uchar; num_nonces * 32];
+    let mut hashes = vec![0 as cl_uchar; num_nonces * 32];
+
+    let mut nonces_buf = unsafe {
+        Buffer::<cl_uchar>::create(context, CL_MEM_READ_ONLY, nonces.len(), ptr::null_mut())?
+    };
+
+    let hashes_buf = unsafe {
+        Buffer::<cl_uchar>::create(context, CL_MEM_WRITE_ONLY, hashes.len(), ptr::null_mut())?
+    };
+
+    let start = Utc::now();
+
+    for _ in 0..AUTOTUNE_ROUNDS {
+        randomize(&mut nonces);
+        let nonces_write_event = unsafe {
+            queue.enqueue_write_buffer(&mut nonces_buf, CL_NON_BLOCKING, 0, &nonces, &[])?
+        };
+
+        let kernel_event = unsafe {
+            ExecuteKernel::new(kernel)
+                .set_arg(&nonces_buf)
+                .set_arg(schedule_buf)
+                .set_arg(hash_vars_buf)
+                .set_arg(&hashes_buf)
+                .set_global_work_size(num_nonces)
+                .set_local_work_size(wg_size)
+                .set_wait_event(&nonces_write_event)
+                .enqueue_nd_range(queue)?
+        };
+
+        let events: Vec<cl_event> = vec![kernel_event.get()];
+
+        let read_event = unsafe {
+            queue.enqueue_read_buffer(&hashes_buf, CL_NON_BLOCKING, 0, &mut hashes, &events)?
+        };
+
+        read_event.wait()?;
+    }
+
+    Ok((Utc::now() - start) / AUTOTUNE_ROUNDS)
+}
+
 fn pick_best_device() -> Result<Option<Device>, ClError> {
     let mut devices = get_all_devices(CL_DEVICE_TYPE_GPU)?;
     let mut cpus = get_all_devices(CL_DEVICE_TYPE_CPU)?;
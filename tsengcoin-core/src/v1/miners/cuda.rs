@@ -1,9 +1,6 @@
 use chrono::{DateTime, Duration, Utc};
 use cust::prelude::*;
-use std::sync::{
-    mpsc::{Receiver, TryRecvError},
-    Mutex,
-};
+use std::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
     hash::hash_chunks,
@@ -11,10 +8,9 @@ use crate::{
         block::{
             Block, BlockHeader,
         },
-        block_verify::verify_block,
-        request::Request,
+        block_verify::{verify_block, BlockStatus},
         state::State,
-        miners::{api::{make_raw_block, POLL_INTERVAL, randomize, find_winner}, stats::DEFAULT_GRANULARITY}, net::{broadcast_async_blast},
+        miners::{api::{make_raw_block, poll_messages, next_reset, should_poll, PollResult, randomize, find_winner}, stats::DEFAULT_GRANULARITY}, net::announce_block,
     },
 };
 
@@ -52,7 +48,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
 
     println!(
         "Difficulty target is {}",
-        hex::encode(raw_block.header.difficulty_target)
+        hex::encode(raw_block.header.target())
     );
 
     let mut raw_header_bytes = bincode::serialize(&raw_block.header).unwrap();
@@ -63,23 +59,26 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
 
     let mut nonces_gpu = DeviceBuffer::from_slice(&nonces).expect("Failed to create device memory");
     let mut prev_gpu =
-        DeviceBuffer::from_slice(&schedule[0..11]).expect("Failed to create device memory");
+        DeviceBuffer::from_slice(&schedule[0..4]).expect("Failed to create device memory");
     let mut hash_vars_gpu =
         DeviceBuffer::from_slice(&hash_vars).expect("Failed to create device memory");
     let hashes_gpu = DeviceBuffer::from_slice(&hashes).expect("Failed to create device memory");
 
     let mut now: DateTime<Utc>;
 
-    let mut reset_time = Utc::now() + Duration::minutes(30);
+    let mut reset_time = next_reset(Utc::now(), &state_mut.lock().unwrap());
 
     let mut print_time = Utc::now();
     let mut total_hashes: usize = 0;
 
     let mut last_poll_time = Utc::now();
+    let mut last_round: Option<Duration> = None;
 
     let hashrate_interval = state_mut.lock().unwrap().miner_stats.as_ref().map(|m| m.granularity).unwrap_or(DEFAULT_GRANULARITY);
     let hash_per_sec_duration = Duration::milliseconds(hashrate_interval as i64);
 
+    state_mut.lock().unwrap().miner_counters.start();
+
     if let Some(stats) = &mut state_mut.lock().unwrap().miner_stats {
         println!("Recording miner stats for {}s. Stats will be saved to {}", stats.record_for / 1000, stats.filename);
         stats.start();
@@ -90,25 +89,18 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
     loop {
         now = Utc::now();
 
-        if now - last_poll_time > *POLL_INTERVAL {
-            let msg_result = receiver.try_recv();
-            match msg_result {
-                Err(TryRecvError::Disconnected) => {
+        if should_poll(now, last_poll_time, last_round) {
+            match poll_messages(&receiver, raw_block.transactions.len() == 1) {
+                PollResult::Stop => {
                     println!("Stopping miner thread due to unexpected channel closing");
                     return;
                 }
-                Ok(MinerMessage::NewBlock(_, _)) | Ok(MinerMessage::NewTransactions(_))
-                    if raw_block.transactions.len() == 1 =>
-                {
+                PollResult::Reset => {
                     // Force a reset by moving the reset time into the past
                     reset_time = Utc::now() - Duration::hours(1);
                     println!("Miner received instruction to reset");
                 }
-                Ok(MinerMessage::NewDifficulty(diff)) => {
-                    reset_time = Utc::now() - Duration::hours(1);
-                    println!("New difficulty target: {}", hex::encode(diff));
-                }
-                _ => (),
+                PollResult::Continue => (),
             }
 
             last_poll_time = now;
@@ -124,13 +116,13 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
             hash_vars = temp.1;
 
             prev_gpu
-                .copy_from(&schedule[0..11])
+                .copy_from(&schedule[0..4])
                 .expect("Failed to copy from host to device memory");
             hash_vars_gpu
                 .copy_from(&hash_vars)
                 .expect("Failed to copy from host to device memory");
 
-            reset_time = now + Duration::minutes(30);
+            reset_time = next_reset(now, &state_mut.lock().unwrap());
         }
 
         randomize(&mut nonces);
@@ -158,6 +150,8 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
             .copy_to(&mut hashes)
             .expect("Failed to copy memory from device to host");
 
+        last_round = Some(Utc::now() - now);
+
         total_hashes += num_nonces;
 
         if now - print_time > hash_per_sec_duration {
@@ -181,7 +175,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
             }
         }
 
-        match find_winner(&nonces, &hashes, &raw_block.header.difficulty_target) {
+        match find_winner(&nonces, &hashes, &raw_block.header.target()) {
             None => (),
             Some((nonce, hash)) => {
                 let mut guard = state_mut.lock().unwrap();
@@ -193,7 +187,7 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
                         prev_hash: raw_block.header.prev_hash,
                         merkle_root: raw_block.header.merkle_root,
                         timestamp: raw_block.header.timestamp,
-                        difficulty_target: raw_block.header.difficulty_target,
+                        bits: raw_block.header.bits,
                         nonce,
                         hash,
                     },
@@ -202,18 +196,19 @@ pub fn mine(state_mut: &Mutex<State>, receiver: Receiver<MinerMessage>) {
 
                 let verify_result = verify_block(new_block.clone(), state);
                 match verify_result {
-                    Ok(true) => {
+                    Ok(BlockStatus::MissingParent(_)) => {
                         // Why would this even happen? Who would mine a block with no parent?
                         println!("New block is an orphan. Rejecting");
                     }
                     Err(err) => {
                         println!("Rejecting new block: {}", err);
                     }
-                    Ok(false) => {
-                        let peers = state.network.peer_addrs();
+                    Ok(BlockStatus::Added) => {
+                        state.miner_counters.record_block();
+
                         drop(guard);
 
-                        broadcast_async_blast(Request::NewBlock(new_block), &peers, None);
+                        announce_block(new_block, state_mut, None);
                     }
                 }
                 // Force a reset! If we don't do this, we may start working on a fork block because we may loop
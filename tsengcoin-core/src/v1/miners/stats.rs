@@ -1,5 +1,7 @@
 use std::{fs::{OpenOptions}, io::Write, time::{SystemTime, UNIX_EPOCH}};
 
+use chrono::{DateTime, Duration, Utc};
+
 use super::api::HASH_PER_SEC_INTERVAL;
 
 pub const DEFAULT_GRANULARITY: TimeElapsedMillis = (HASH_PER_SEC_INTERVAL * 1000) as TimeElapsedMillis;
@@ -79,4 +81,57 @@ impl MinerStatsState {
 
         out
     }
-}
\ No newline at end of file
+}
+
+/// Running totals for the miner, kept for the lifetime of the client
+#[derive(Debug, Default)]
+pub struct MinerCounters {
+    /// Number of blocks we mined that were accepted into our blockchain
+    pub blocks_found: usize,
+    /// When the miner started
+    pub mining_since: Option<DateTime<Utc>>,
+    /// When we last mined a block
+    pub last_block_found: Option<DateTime<Utc>>,
+}
+
+impl MinerCounters {
+    pub fn start(&mut self) {
+        self.mining_since = Some(Utc::now());
+    }
+
+    pub fn record_block(&mut self) {
+        self.blocks_found += 1;
+        self.last_block_found = Some(Utc::now());
+    }
+
+    /// How long the miner has been running
+    pub fn time_mining(&self) -> Duration {
+        match self.mining_since {
+            None => Duration::zero(),
+            Some(since) => Utc::now() - since,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_block_counts_blocks() {
+        let mut counters = MinerCounters::default();
+        assert_eq!(counters.time_mining(), Duration::zero());
+
+        counters.start();
+        assert_eq!(counters.blocks_found, 0);
+        assert!(counters.last_block_found.is_none());
+
+        counters.record_block();
+        let first = counters.last_block_found.unwrap();
+        counters.record_block();
+
+        assert_eq!(counters.blocks_found, 2);
+        assert!(counters.last_block_found.unwrap() >= first);
+        assert!(counters.time_mining() >= Duration::zero());
+    }
+}
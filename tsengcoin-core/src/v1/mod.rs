@@ -5,10 +5,15 @@ pub mod block_verify;
 pub mod block_verify_error;
 pub mod chain_request;
 pub mod encrypted_msg;
+pub mod fees;
 pub mod net;
 pub mod request;
 pub mod response;
+#[cfg(test)]
+pub mod sim_net;
 pub mod state;
+#[cfg(test)]
+pub mod test_util;
 pub mod transaction;
 pub mod txn_verify;
 pub mod txn_verify_error;
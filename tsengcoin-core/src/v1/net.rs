@@ -1,33 +1,86 @@
 use std::{
     cmp::min,
+    collections::{BTreeMap, HashMap},
     error::Error,
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
     sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, SyncSender},
         Arc, Mutex,
     },
+    thread,
+    time::Duration,
 };
 
-use chrono::{DateTime, Utc};
-use crossbeam::thread::{ScopedJoinHandle};
+use bincode::Options;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use lazy_static::lazy_static;
 use rand::seq::SliceRandom;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::wallet::Hash256;
 
 use super::{
-    request::{send_msg, send_req, GetAddrReq, Request},
+    block::{Block, MAX_BLOCK_SIZE},
+    request::{bootstrap_unlocked, connect, send_msg, send_req, AdvertiseReq, GetAddrReq, Request},
     response::{handle_request, Response},
     state::State,
 };
 use super::state::GUIChannels;
 
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Bump this whenever a message changes shape. Version 2 added the genesis hash to `GetAddr` and streamed block
+/// downloads. Version 3 replaced the difficulty target in block headers with compact bits
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Every frame sent between nodes starts with these bytes, so that nodes on different networks
+/// can't talk to each other by accident
+#[cfg(not(any(feature = "testnet", feature = "regtest")))]
+pub const NETWORK_MAGIC: [u8; 4] = *b"TSNG";
+#[cfg(all(feature = "testnet", not(feature = "regtest")))]
+pub const NETWORK_MAGIC: [u8; 4] = *b"TSTN";
+#[cfg(feature = "regtest")]
+pub const NETWORK_MAGIC: [u8; 4] = *b"TSRG";
+/// Identifies this client to other nodes, for diagnostics
+pub const USER_AGENT: &str = concat!("tsengcoin-core/", env!("CARGO_PKG_VERSION"));
+/// User agents longer than this are truncated
+pub const MAX_USER_AGENT_LEN: usize = 64;
 pub const MAX_NEIGHBORS: usize = 8;
+/// Most streams that can be read on their own threads at once
+pub const MAX_STREAM_READERS: usize = 32;
+/// Most requests that can wait to be handled before the acceptor thread blocks
+pub const MAX_QUEUED_REQUESTS: usize = 128;
+/// Most bytes a single block can take up in a block stream. A block's serialized size is a bit bigger than its
+/// size as counted by [Block::size], so this leaves plenty of room above [MAX_BLOCK_SIZE].
+pub const MAX_BLOCK_FRAME_SIZE: u64 = 2 * MAX_BLOCK_SIZE as u64;
+/// Default number of connections the OS will queue for us before we accept them
+pub const DEFAULT_LISTEN_BACKLOG: i32 = 128;
+/// How long to wait before accepting again after the first accept error that isn't about a single connection.
+/// The wait doubles with each error in a row, up to [MAX_ACCEPT_BACKOFF].
+pub const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+pub const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// Most outgoing connections a single broadcast can have open at once
+pub const MAX_BROADCAST_THREADS: usize = 16;
 pub const MAX_GET_ADDRS: usize = 3;
+/// Default number of known nodes that aren't peers to also announce new blocks to
+pub const DEFAULT_BLOCK_ANNOUNCE_SAMPLE: usize = 4;
+/// If we have fewer peers than this, the maintenance thread will look for more
+pub const MIN_PEERS: usize = 3;
+/// How often the maintenance thread checks the number of peers
+pub const PEER_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    /// How long to remember an advertisement after relaying it
+    pub static ref ADVERT_MEMORY: ChronoDuration = ChronoDuration::minutes(10);
+    /// Known nodes that we haven't heard from in this long are dropped, unless they're peers
+    pub static ref KNOWN_NODE_MAX_AGE: ChronoDuration = ChronoDuration::hours(3);
+}
 
 #[derive(Debug, Clone)]
 pub struct DistantNode {
     pub addr: SocketAddr,
+    /// The last time we talked to this node or a peer told us about it
+    pub last_seen: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -37,6 +90,11 @@ pub struct Node {
     pub last_send: DateTime<Utc>,
     pub best_height: Option<usize>,
     pub best_hash: Option<Hash256>,
+    pub user_agent: Option<String>,
+    /// The neighbors this node sent us the last time we asked it for addresses. We only keep this for ourselves,
+    /// so it's never sent to other nodes.
+    #[serde(skip)]
+    pub neighbors: Vec<SocketAddr>,
 }
 
 impl std::fmt::Debug for Node {
@@ -49,6 +107,8 @@ impl std::fmt::Debug for Node {
             .field("last_send", &self.last_send)
             .field("best_height", &self.best_height)
             .field("best_hash", &hash_debug)
+            .field("user_agent", &self.user_agent)
+            .field("neighbors", &self.neighbors)
             .finish()
     }
 }
@@ -72,18 +132,25 @@ impl PartialEq<SocketAddr> for &Node {
 }
 
 impl DistantNode {
+    pub fn new(addr: SocketAddr) -> Self {
+        DistantNode {
+            addr,
+            last_seen: Utc::now(),
+        }
+    }
+
     pub fn send_req(&self, req: Request) -> Result<Response, Box<dyn Error>> {
-        let stream = TcpStream::connect(self.addr)?;
-        bincode::serialize_into(&stream, &req)?;
+        let stream = connect(&self.addr)?;
+        write_frame(&stream, &req)?;
 
-        let res: Response = bincode::deserialize_from(&stream)?;
+        let res: Response = read_frame(&stream)?;
 
         Ok(res)
     }
 
     pub fn send_res(&self, res: Response) -> Result<(), Box<dyn Error>> {
-        let stream = TcpStream::connect(self.addr)?;
-        bincode::serialize_into(&stream, &res)?;
+        let stream = connect(&self.addr)?;
+        write_frame(&stream, &res)?;
 
         Ok(())
     }
@@ -91,13 +158,13 @@ impl DistantNode {
 
 impl From<&Node> for DistantNode {
     fn from(node: &Node) -> Self {
-        DistantNode { addr: node.addr }
+        DistantNode::new(node.addr)
     }
 }
 
 impl From<Node> for DistantNode {
     fn from(node: Node) -> Self {
-        DistantNode { addr: node.addr }
+        DistantNode::new(node.addr)
     }
 }
 
@@ -167,6 +234,69 @@ impl Ord for DistantNode {
 pub struct Network {
     pub peers: Vec<Node>,
     pub known_nodes: Vec<DistantNode>,
+    /// Open streams to peers, if connection pooling is enabled. This is an Arc so that it can
+    /// be used to broadcast after the state lock has been released.
+    pub conn_pool: Option<Arc<ConnectionPool>>,
+    /// Addresses whose advertisements we have relayed recently, and when we relayed them
+    pub seen_adverts: HashMap<SocketAddr, DateTime<Utc>>,
+    /// How many known nodes that aren't peers to announce new blocks to, in addition to peers
+    pub block_announce_sample: usize,
+    /// Accept loopback addresses from other nodes. Only true if we are listening on a loopback address ourselves,
+    /// because then we're on a local test network.
+    pub allow_loopback: bool,
+}
+
+/// Keeps one open stream per peer so that repeated messages to the same peer don't each
+/// open a new connection. Only used for one-way messages; requests that expect a response
+/// still get their own connection.
+#[derive(Debug, Default)]
+pub struct ConnectionPool {
+    streams: Mutex<HashMap<SocketAddr, TcpStream>>,
+}
+
+impl ConnectionPool {
+    /// Send a message over the open stream to `addr`, or open a new one if there is no stream
+    /// or the old one has been closed. The stream is only kept if the message was sent successfully.
+    /// Fails if the message couldn't be sent over a new stream either.
+    pub fn send_msg(&self, msg: &Request, addr: &SocketAddr) -> bincode::Result<()> {
+        // Take the stream out of the map so that the lock isn't held while writing
+        let existing = self.streams.lock().unwrap().remove(addr);
+
+        // Writing to a stream that the peer has closed can still succeed, and the message is lost. The peer
+        // closes streams it won't read any more messages from, so check before writing.
+        if let Some(stream) = existing.filter(is_open) {
+            if write_frame(&stream, msg).is_ok() {
+                self.streams.lock().unwrap().insert(*addr, stream);
+                return Ok(());
+            }
+        }
+
+        let stream = connect(addr)?;
+        stream.set_nodelay(true).unwrap();
+        write_frame(&stream, msg)?;
+
+        self.streams.lock().unwrap().insert(*addr, stream);
+
+        Ok(())
+    }
+
+    /// Close the stream to `addr` if there is one
+    pub fn remove(&self, addr: &SocketAddr) {
+        self.streams.lock().unwrap().remove(addr);
+    }
+}
+
+/// True if the other end hasn't closed the stream. Nothing is ever sent back over a pooled stream, so if there's
+/// anything to read, the stream was closed or something went wrong.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+
+    let mut buf = [0; 1];
+    let nothing_to_read = matches!(stream.peek(&mut buf), Err(err) if err.kind() == io::ErrorKind::WouldBlock);
+
+    stream.set_nonblocking(false).is_ok() && nothing_to_read
 }
 
 impl Network {
@@ -176,7 +306,11 @@ impl Network {
         DistantNode: PartialEq<T>,
     {
         if let Some(pos) = self.peers.iter().position(|n| *n == node) {
-            drop(self.peers.remove(pos));
+            let removed = self.peers.remove(pos);
+
+            if let Some(pool) = &self.conn_pool {
+                pool.remove(&removed.addr);
+            }
         }
 
         if let Some(pos) = self.known_nodes.iter().position(|n| *n == node) {
@@ -193,7 +327,31 @@ impl Network {
         self.peers.sort();
         self.known_nodes.sort();
         self.peers.dedup();
-        self.known_nodes.dedup();
+        // Keep the most recent last seen time out of any duplicates
+        self.known_nodes.dedup_by(|dup, kept| {
+            if dup.addr != kept.addr {
+                return false;
+            }
+
+            kept.last_seen = kept.last_seen.max(dup.last_seen);
+            true
+        });
+        self.prune_stale_nodes();
+    }
+
+    /// True if we should be willing to talk to a node at this address. See [is_routable].
+    pub fn is_routable(&self, addr: &SocketAddr) -> bool {
+        is_routable(addr, self.allow_loopback)
+    }
+
+    /// Drop known nodes that we haven't heard from in [KNOWN_NODE_MAX_AGE]. Peers are kept no matter what.
+    pub fn prune_stale_nodes(&mut self) {
+        let now = Utc::now();
+        let peers = &self.peers;
+
+        self.known_nodes.retain(|n| {
+            now - n.last_seen < *KNOWN_NODE_MAX_AGE || peers.iter().any(|p| p.addr == n.addr)
+        });
     }
 
     pub fn shuffle(&mut self) {
@@ -207,6 +365,50 @@ impl Network {
         self.peers.iter().map(|n| n.addr).collect::<Vec<SocketAddr>>()
     }
 
+    /// Our peers and the neighbors each of them last reported, as an adjacency list. `addr_me` is connected to
+    /// every peer. Peers we haven't asked for addresses have no neighbors.
+    pub fn peer_graph(&self, addr_me: SocketAddr) -> BTreeMap<SocketAddr, Vec<SocketAddr>> {
+        let mut out: BTreeMap<SocketAddr, Vec<SocketAddr>> = BTreeMap::new();
+        out.insert(addr_me, self.peer_addrs());
+
+        for peer in &self.peers {
+            out.entry(peer.addr).or_default().extend(peer.neighbors.iter().copied());
+        }
+
+        out
+    }
+
+    /// Pick up to [block_announce_sample](Network::block_announce_sample) known nodes at random that aren't peers
+    pub fn sample_known_non_peers(&self) -> Vec<SocketAddr> {
+        let rng = &mut rand::thread_rng();
+        let candidates = self
+            .known_nodes
+            .iter()
+            .filter(|n| !self.has_peer(n.addr))
+            .map(|n| n.addr)
+            .collect::<Vec<SocketAddr>>();
+
+        candidates
+            .choose_multiple(rng, self.block_announce_sample)
+            .copied()
+            .collect()
+    }
+
+    /// Remember that an advertisement for the given address has been relayed. Returns false if it was
+    /// already relayed in the last [ADVERT_MEMORY], in which case it shouldn't be relayed again.
+    pub fn mark_advert_seen(&mut self, addr: SocketAddr) -> bool {
+        let now = Utc::now();
+        self.seen_adverts.retain(|_, seen_at| now - *seen_at < *ADVERT_MEMORY);
+
+        if self.seen_adverts.contains_key(&addr) {
+            return false;
+        }
+
+        self.seen_adverts.insert(addr, now);
+
+        true
+    }
+
     pub fn prune_dead_nodes(&mut self, broadcast_result: &mut [SocketAddr]) {
         for addr in broadcast_result.into_iter() {
             self.remove(addr);
@@ -249,9 +451,7 @@ impl Network {
 
     pub fn merge(&mut self, addr_me: SocketAddr) {
         for node in &self.peers {
-            self.known_nodes.push(DistantNode {
-                addr: node.addr
-            });
+            self.known_nodes.push(node.into());
         }
 
         self.clean(addr_me);
@@ -269,6 +469,7 @@ pub fn find_new_friends(state_mut: &Mutex<State>) {
     let addr_me = state.remote_addr_me.unwrap();
     let (best_height, chain_idx, _) = state.blockchain.best_chain();
     let best_hash = state.blockchain.top_hash(chain_idx);
+    let genesis_hash = state.blockchain.genesis_hash();
     let listen_port = state.port();
 
     state.network.merge(addr_me);
@@ -289,6 +490,8 @@ pub fn find_new_friends(state_mut: &Mutex<State>) {
             listen_port,
             best_height,
             best_hash,
+            genesis_hash,
+            user_agent: String::from(USER_AGENT),
         })
     }, &get_addr_addrs);
 
@@ -302,6 +505,10 @@ pub fn find_new_friends(state_mut: &Mutex<State>) {
         }
 
         match res_opt.unwrap() {
+            Response::GetAddr(data) if data.genesis_hash != genesis_hash => {
+                println!("Dropping node {} because it is on a different network", addr);
+                state.network.remove(addr);
+            }
             Response::GetAddr(data) => {
                 let node = Node {
                     version: data.version,
@@ -309,6 +516,8 @@ pub fn find_new_friends(state_mut: &Mutex<State>) {
                     last_send: Utc::now(),
                     best_height: Some(data.best_height),
                     best_hash: Some(data.best_hash),
+                    user_agent: Some(truncate_user_agent(data.user_agent)),
+                    neighbors: data.neighbors.iter().map(|n| n.addr).collect(),
                 };
 
                 state.network.peers.push(node);
@@ -316,6 +525,7 @@ pub fn find_new_friends(state_mut: &Mutex<State>) {
                 let mut neighbors = data
                     .neighbors
                     .iter()
+                    .filter(|n| state.network.is_routable(&n.addr))
                     .map(|n| n.into())
                     .collect::<Vec<DistantNode>>();
                 state.network.known_nodes.append(&mut neighbors);
@@ -327,131 +537,400 @@ pub fn find_new_friends(state_mut: &Mutex<State>) {
     state.network.clean(addr_me);
 }
 
+/// Check that an address reported by another node could belong to a real node. Unspecified, broadcast, and multicast
+/// addresses and port 0 are always rejected. Loopback addresses are only accepted if `allow_loopback` is set, which
+/// should only be the case on a local test network.
+pub fn is_routable(addr: &SocketAddr, allow_loopback: bool) -> bool {
+    if addr.port() == 0 {
+        return false;
+    }
+
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() => false,
+        IpAddr::V6(ip) if ip.is_unspecified() || ip.is_multicast() => false,
+        ip if ip.is_loopback() => allow_loopback,
+        _ => true,
+    }
+}
+
+/// Write a message to the stream, prefixed with [NETWORK_MAGIC]
+pub fn write_frame<T: Serialize>(stream: &TcpStream, msg: &T) -> bincode::Result<()> {
+    let bytes = encode_frame(msg)?;
+
+    let mut writer = stream;
+    writer.write_all(&bytes)?;
+
+    Ok(())
+}
+
+/// Serialize a message the way [write_frame] sends it, so that several frames can be written at once
+pub fn encode_frame<T: Serialize>(msg: &T) -> bincode::Result<Vec<u8>> {
+    let mut bytes = NETWORK_MAGIC.to_vec();
+    bincode::serialize_into(&mut bytes, msg)?;
+
+    Ok(bytes)
+}
+
+/// Read a message from the stream. Fails if the message doesn't start with [NETWORK_MAGIC],
+/// which means that it came from a node on a different network.
+pub fn read_frame<T: DeserializeOwned>(stream: &TcpStream) -> bincode::Result<T> {
+    read_magic(stream)?;
+
+    bincode::deserialize_from(stream)
+}
+
+/// Same as [read_frame], but fails instead of reading more than `limit` bytes after the magic. Use this when
+/// the other node could send something huge and we know how big the message should be.
+pub fn read_frame_limited<T: DeserializeOwned>(stream: &TcpStream, limit: u64) -> bincode::Result<T> {
+    read_magic(stream)?;
+
+    // Same encoding as bincode::deserialize_from, with a limit
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize_from(stream)
+}
+
+fn read_magic(stream: &TcpStream) -> bincode::Result<()> {
+    let mut magic = [0_u8; 4];
+    let mut reader = stream;
+    reader.read_exact(&mut magic)?;
+
+    if magic != NETWORK_MAGIC {
+        return Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "Wrong network magic: {}",
+            hex::encode(magic)
+        ))));
+    }
+
+    Ok(())
+}
+
+/// Write a graph from [Network::peer_graph] in Graphviz DOT format
+pub fn peer_graph_to_dot(graph: &BTreeMap<SocketAddr, Vec<SocketAddr>>) -> String {
+    let mut out = String::from("digraph peers {\n");
+
+    for (node, neighbors) in graph {
+        out.push_str(&format!("    \"{}\";\n", node));
+
+        for neighbor in neighbors {
+            out.push_str(&format!("    \"{}\" -> \"{}\";\n", node, neighbor));
+        }
+    }
+
+    out.push_str("}\n");
+
+    out
+}
+
+/// Write a graph from [Network::peer_graph] as a JSON object that maps each address to the addresses it's
+/// connected to
+pub fn peer_graph_to_json(graph: &BTreeMap<SocketAddr, Vec<SocketAddr>>) -> serde_json::Result<String> {
+    let stringified = graph
+        .iter()
+        .map(|(node, neighbors)| {
+            (
+                node.to_string(),
+                neighbors.iter().map(|n| n.to_string()).collect::<Vec<String>>(),
+            )
+        })
+        .collect::<BTreeMap<String, Vec<String>>>();
+
+    serde_json::to_string_pretty(&stringified)
+}
+
+/// Cut a user agent received from another node down to [MAX_USER_AGENT_LEN] characters
+pub fn truncate_user_agent(user_agent: String) -> String {
+    match user_agent.char_indices().nth(MAX_USER_AGENT_LEN) {
+        None => user_agent,
+        Some((end, _)) => user_agent[..end].to_owned(),
+    }
+}
+
+/// Periodically check how many peers we have, and if we've fallen below [MIN_PEERS], find new ones
+/// from the list of known nodes until we're back up to [MAX_NEIGHBORS]. This runs forever and is meant to be
+/// run on its own thread.
+pub fn maintain_peers(state_mut: &Mutex<State>) {
+    loop {
+        thread::sleep(PEER_MAINTENANCE_INTERVAL);
+
+        maintain_peers_once(state_mut);
+    }
+}
+
+/// One pass of [maintain_peers]. If we've lost every peer, we bootstrap again from the seed nodes first, and go on
+/// to the known nodes if none of the seeds give us any peers.
+pub fn maintain_peers_once(state_mut: &Mutex<State>) {
+    let mut num_peers = state_mut.lock().unwrap().network.peers.len();
+
+    // With no peers, nothing we send goes anywhere, so start over from the seed nodes
+    if num_peers == 0 {
+        rebootstrap(state_mut);
+        num_peers = state_mut.lock().unwrap().network.peers.len();
+    }
+
+    let has_candidates = !state_mut.lock().unwrap().network.known_nodes.is_empty() || num_peers > 0;
+
+    if !needs_more_peers(num_peers) || !has_candidates {
+        return;
+    }
+
+    while num_peers < MAX_NEIGHBORS {
+        find_new_friends(state_mut);
+
+        let new_num_peers = state_mut.lock().unwrap().network.peers.len();
+
+        // Stop if we've run out of nodes to try
+        if new_num_peers <= num_peers {
+            break;
+        }
+
+        num_peers = new_num_peers;
+    }
+}
+
+/// Run the bootstrap process again with each of the seed nodes we connected to originally, stopping
+/// at the first one that gives us some peers. The state is not locked while we wait for the seeds.
+fn rebootstrap(state_mut: &Mutex<State>) {
+    let seeds = state_mut.lock().unwrap().seeds.clone();
+
+    for seed in seeds {
+        println!("Lost all peers, bootstrapping again from seed node {}", seed);
+
+        if let Err(err) = bootstrap_unlocked(seed, state_mut) {
+            println!("Failed to bootstrap from seed node {}: {}", seed, err);
+            continue;
+        }
+
+        let guard = state_mut.lock().unwrap();
+
+        if guard.network.peers.is_empty() {
+            println!("Seed node {} did not give us any peers", seed);
+            continue;
+        }
+
+        let peers = guard.network.peer_addrs();
+        let pool = guard.network.conn_pool.clone();
+        let req = Request::Advertise(AdvertiseReq {
+            addr_me: guard.remote_addr_me.unwrap(),
+        });
+        drop(guard);
+
+        let mut dead_nodes = broadcast_async_blast(req, &peers, None, pool.as_deref());
+        state_mut.lock().unwrap().network.prune_dead_nodes(&mut dead_nodes);
+
+        return;
+    }
+}
+
+fn needs_more_peers(num_peers: usize) -> bool {
+    num_peers < MIN_PEERS
+}
+
 pub fn broadcast_async_req_fn<F>(req_fn: F, peers: &[SocketAddr]) -> Vec<(Option<Response>, SocketAddr)>
-    where F: Fn(SocketAddr) -> Request
+    where F: Fn(SocketAddr) -> Request + Sync
 {
-    crossbeam::scope(|scope| {
-        let join_handles = peers
-            .iter()
-            .map(|addr| {
-                let req = req_fn(addr.clone());
-                scope.spawn(move |_| {
-                    let res = match send_req(&req, &addr) {
-                        Ok(data) => Some(data),
-                        Err(_) => None
-                    };
-
-                    (res, addr)
-                })
-            })
-            .collect::<Vec<ScopedJoinHandle<(Option<Response>, &SocketAddr)>>>();
+    map_bounded(peers, |addr| {
+        let req = req_fn(addr);
 
-        join_handles
-            .into_iter()
-            .map(|j| {
-                let (res, addr) = j.join().unwrap();
-
-                (res, addr.clone())
-            })
-            .collect::<Vec<(Option<Response>, SocketAddr)>>()
-    }).unwrap()
+        (send_req(&req, &addr).ok(), addr)
+    })
 }
 
 pub fn broadcast_async_req(req: Request, peers: &[SocketAddr], except: Option<SocketAddr>) -> Vec<(Option<Response>, SocketAddr)> {
-    let req_arc = Arc::new(req);
+    let targets = without(peers, except);
 
-    crossbeam::scope(|scope| {
-        let join_handles = peers
-            .iter()
-            .filter(|addr| except.is_none() || *addr != &except.unwrap())
-            .map(|addr| {
-                let req_arc_clone = Arc::clone(&req_arc);
-                scope.spawn(move |_| {
-                    let res = match send_req(&req_arc_clone, &addr) {
-                        Ok(data) => Some(data),
-                        Err(_) => None
-                    };
-
-                    (res, addr)
-                })
-            })
-            .collect::<Vec<ScopedJoinHandle<(Option<Response>, &SocketAddr)>>>();
+    map_bounded(&targets, |addr| (send_req(&req, &addr).ok(), addr))
+}
 
-        join_handles
-            .into_iter()
-            .map(|j| {
-                let (res, addr) = j.join().unwrap();
+pub fn broadcast_async(msg: Request, peers: &[SocketAddr], except: Option<SocketAddr>, pool: Option<&ConnectionPool>) -> Vec<SocketAddr> {
+    let targets = without(peers, except);
 
-                (res, addr.clone())
-            })
-            .collect::<Vec<(Option<Response>, SocketAddr)>>()
-    }).unwrap()
+    map_bounded(&targets, |addr| (addr, send_msg_with(&msg, &addr, pool).is_err()))
+        .into_iter()
+        // Only keep the nodes that are dead
+        .filter_map(|(addr, failed)| if failed { Some(addr) } else { None })
+        .collect::<Vec<SocketAddr>>()
 }
 
-pub fn broadcast_async(msg: Request, peers: &[SocketAddr], except: Option<SocketAddr>) -> Vec<SocketAddr> {
-    let msg_arc = Arc::new(msg);
+/// Send a message to every peer without waiting for a response. Returns the nodes that couldn't be reached, which
+/// the caller should prune from the network once it has the state again.
+pub fn broadcast_async_blast(msg: Request, peers: &[SocketAddr], except: Option<SocketAddr>, pool: Option<&ConnectionPool>) -> Vec<SocketAddr> {
+    broadcast_async(msg, peers, except, pool)
+}
 
-    crossbeam::scope(|scope| {
-        let join_handles = peers
-            .iter()
-            .filter(|addr| except.is_none() || *addr != &except.unwrap())
-            .map(|addr| {
-                let msg_arc_clone = Arc::clone(&msg_arc);
-                scope.spawn(move |_| {
-                    let res = send_msg(&msg_arc_clone, &addr);
-
-                    (addr, res.is_err())
-                })
-            })
-            .collect::<Vec<ScopedJoinHandle<(&SocketAddr, bool)>>>();
-
-        join_handles
-            .into_iter()
-            .filter_map(|j| {
-                let (a, ok) = j.join().unwrap();
-                match ok {
-                    // Node is not dead
-                    false => None,
-                    // Node is dead
-                    true => Some(a.clone())
-                }
-            })
-            .collect::<Vec<SocketAddr>>()
-    }).unwrap()
+fn without(peers: &[SocketAddr], except: Option<SocketAddr>) -> Vec<SocketAddr> {
+    peers
+        .iter()
+        .filter(|addr| except.is_none() || **addr != except.unwrap())
+        .copied()
+        .collect::<Vec<SocketAddr>>()
 }
 
-pub fn broadcast_async_blast(msg: Request, peers: &[SocketAddr], except: Option<SocketAddr>) {
-    let msg_arc = Arc::new(msg);
+/// Call `f` with every address on a pool of at most [MAX_BROADCAST_THREADS] threads, so that a broadcast
+/// to a lot of nodes doesn't open a socket to all of them at once. The results are in the same order as the addresses.
+fn map_bounded<F, R>(addrs: &[SocketAddr], f: F) -> Vec<R>
+    where F: Fn(SocketAddr) -> R + Sync, R: Send
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(addrs.iter().map(|_| None).collect());
+    let num_threads = min(addrs.len(), MAX_BROADCAST_THREADS);
 
     crossbeam::scope(|scope| {
-        let _join_handles = peers
-            .iter()
-            .filter(|addr| except.is_none() || *addr != &except.unwrap())
-            .map(|addr| {
-                let msg_arc_clone = Arc::clone(&msg_arc);
-                scope.spawn(move |_| {
-                    let _res = send_msg(&msg_arc_clone, &addr);
-                })
-            })
-            .collect::<Vec<ScopedJoinHandle<()>>>();
+        for _ in 0..num_threads {
+            scope.spawn(|_| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+
+                if i >= addrs.len() {
+                    break;
+                }
+
+                let res = f(addrs[i]);
+                results.lock().unwrap()[i] = Some(res);
+            });
+        }
     }).unwrap();
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.unwrap())
+        .collect()
 }
 
-#[cfg(feature = "gui")]
+/// Announce a new block to every peer, and to a random sample of known nodes that aren't peers. If we only have a few
+/// peers, or they've gone stale, a block sent only to them might not reach the rest of the network. Sampled nodes that
+/// can't be reached are dropped, along with peers that can't be reached. The connection pool is only used for peers.
+/// Don't call this with the state locked. This waits until every node has been tried, so the request handler runs it
+/// on its own thread.
+pub fn announce_block(block: Block, state_mut: &Mutex<State>, except: Option<SocketAddr>) {
+    let guard = state_mut.lock().unwrap();
+    let peers = guard.network.peer_addrs();
+    let sampled = guard.network.sample_known_non_peers();
+    let pool = guard.network.conn_pool.clone();
+    drop(guard);
+
+    let mut dead_nodes = broadcast_async_blast(Request::NewBlock(block.clone()), &peers, except, pool.as_deref());
+
+    if !sampled.is_empty() {
+        dead_nodes.append(&mut broadcast_async(Request::NewBlock(block), &sampled, except, None));
+    }
+
+    state_mut.lock().unwrap().network.prune_dead_nodes(&mut dead_nodes);
+}
+
+/// Send a message through the connection pool if there is one, or over a new connection if not
+fn send_msg_with(msg: &Request, addr: &SocketAddr, pool: Option<&ConnectionPool>) -> bincode::Result<()> {
+    match pool {
+        Some(pool) => pool.send_msg(msg, addr),
+        None => send_msg(msg, addr),
+    }
+}
+
+/// Bind the socket that [listen_for_connections] accepts connections on. `backlog` is the number of connections the
+/// OS will queue for us before we accept them. Failing to bind is fatal, so do this before starting the listener
+/// thread so that the caller can report it.
+pub fn bind_listener(listen_addr: SocketAddr, backlog: i32) -> Result<TcpListener, Box<dyn Error>> {
+    let bind = || -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(listen_addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        // Same as the standard library's TcpListener::bind, so that we can restart without waiting for old
+        // connections to time out
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+
+        socket.bind(&listen_addr.into())?;
+        socket.listen(backlog)?;
+
+        Ok(socket.into())
+    };
+
+    bind().map_err(|err| format!("Failed to listen on {}: {}", listen_addr, err).into())
+}
+
+/// What to do after accepting a connection fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptAction {
+    /// Something went wrong with one connection, so accept the next one right away
+    Continue,
+    /// Something is wrong with the listener or we're out of a resource like file descriptors. Trying again right
+    /// away would fail the same way, so wait this long first
+    Backoff(Duration),
+}
+
+/// Decide what to do about an accept error. `prev_backoff` is the last wait if the previous accept also failed
+/// and we backed off, so that waits get longer while the error keeps happening.
+pub fn accept_error_action(err: &io::Error, prev_backoff: Option<Duration>) -> AcceptAction {
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut => AcceptAction::Continue,
+        _ => AcceptAction::Backoff(match prev_backoff {
+            None => MIN_ACCEPT_BACKOFF,
+            Some(prev) => min(prev * 2, MAX_ACCEPT_BACKOFF),
+        }),
+    }
+}
+
+/// Listen for incoming connections and handle requests one at a time. Connections are accepted on
+/// a separate thread, and one-way messages are read in a loop so that peers using a connection
+/// pool can keep sending over the same stream. At most [MAX_STREAM_READERS] streams are read at once,
+/// and at most [MAX_QUEUED_REQUESTS] requests wait to be handled before we stop accepting new ones.
+/// The socket should come from [bind_listener].
 pub fn listen_for_connections(
-    listen_addr: SocketAddr,
+    socket: TcpListener,
     gui_channels: &GUIChannels,
     state_arc: &Arc<Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
-    let socket = TcpListener::bind(listen_addr)?;
+    let (req_sender, req_receiver) = sync_channel::<(Request, TcpStream)>(MAX_QUEUED_REQUESTS);
+    let slots = Arc::new(StreamSlots::new(MAX_STREAM_READERS));
+
+    thread::Builder::new()
+        .name(String::from("network-acceptor"))
+        .spawn(move || accept_connections(socket, req_sender, slots))?;
+
+    for (req, conn) in req_receiver {
+        if let Err(err) = handle_request(req, conn, gui_channels, state_arc) {
+            println!("Error handling request: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn accept_connections(
+    socket: TcpListener,
+    req_sender: SyncSender<(Request, TcpStream)>,
+    slots: Arc<StreamSlots>,
+) {
+    let mut backoff: Option<Duration> = None;
 
     for stream in socket.incoming() {
         match stream {
-            Err(err) => println!("Error receiving incoming connection: {}", err),
+            Err(err) => match accept_error_action(&err, backoff) {
+                AcceptAction::Continue => println!("Error receiving incoming connection: {}", err),
+                AcceptAction::Backoff(wait) => {
+                    // Only log the first error in a row so that a persistent error doesn't flood the log
+                    if backoff.is_none() {
+                        println!("Error accepting connections: {}. Backing off until it stops", err);
+                    }
+
+                    backoff = Some(wait);
+                    thread::sleep(wait);
+                }
+            },
             Ok(conn) => {
+                if backoff.take().is_some() {
+                    println!("Accepting connections again");
+                }
+
                 conn.set_nodelay(true).unwrap();
 
-                let req: Request = match bincode::deserialize_from(&conn) {
+                let req: Request = match read_frame(&conn) {
                     Ok(data) => data,
                     Err(err) => {
                         println!("Received invalid request over TCP: {}", err);
@@ -459,42 +938,409 @@ pub fn listen_for_connections(
                     }
                 };
 
-                if let Err(err) = handle_request(req, conn, gui_channels, state_arc) {
-                    println!("Error handling request: {}", err);
+                // Only one-way messages can be followed by more messages on the same stream. If we're
+                // already reading too many streams, handle this message and let the sender reconnect
+                // for the next one.
+                if !is_one_way(&req) {
+                    req_sender.send((req, conn)).unwrap();
+                    continue;
+                }
+
+                if !slots.try_acquire() {
+                    // Nothing is sent back for one-way messages, so close our end now. The sender checks for this
+                    // before reusing the stream, and it might not be handled for a while.
+                    let _ = conn.shutdown(Shutdown::Write);
+                    req_sender.send((req, conn)).unwrap();
+                    continue;
+                }
+
+                let sender = req_sender.clone();
+                let slots_clone = Arc::clone(&slots);
+                let spawn_res = thread::Builder::new()
+                    .name(String::from("network-stream"))
+                    .spawn(move || {
+                        read_messages(req, conn, sender);
+                        slots_clone.release();
+                    });
+
+                if let Err(err) = spawn_res {
+                    slots.release();
+                    println!("Failed to start stream reader thread: {}", err);
                 }
             }
         }
     }
+}
 
-    Ok(())
+/// Forward the first message and any that follow it on the same stream until the sender
+/// closes it. Senders that don't use a connection pool close the stream after one message.
+fn read_messages(first: Request, conn: TcpStream, req_sender: SyncSender<(Request, TcpStream)>) {
+    let mut req = first;
+
+    loop {
+        let conn_clone = match conn.try_clone() {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        if req_sender.send((req, conn_clone)).is_err() {
+            return;
+        }
+
+        req = match read_frame(&conn) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+    }
 }
 
-#[cfg(not(feature = "gui"))]
-pub fn listen_for_connections(
-    listen_addr: SocketAddr,
-    gui_channels: &GUIChannels,
-    state_arc: &Arc<Mutex<State>>,
-) -> Result<(), Box<dyn Error>> {
-    let socket = TcpListener::bind(listen_addr)?;
+/// Counts the streams being read on their own threads so that a burst of connections can't start
+/// an unbounded number of threads
+pub struct StreamSlots {
+    active: AtomicUsize,
+    max: usize,
+}
 
-    for stream in socket.incoming() {
-        match stream {
-            Err(err) => println!("Error receiving incoming connection: {}", err),
-            Ok(conn) => {
-                let req: Request = match bincode::deserialize_from(&conn) {
-                    Ok(data) => data,
-                    Err(err) => {
-                        println!("Received invalid request over TCP: {}", err);
-                        continue;
-                    }
-                };
+impl StreamSlots {
+    pub fn new(max: usize) -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            max,
+        }
+    }
 
-                if let Err(err) = handle_request(req, conn, gui_channels, state_arc) {
-                    println!("Error handling request: {}", err);
+    /// Take a slot if one is free. Returns false if all slots are taken.
+    pub fn try_acquire(&self) -> bool {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < self.max {
+                    Some(n + 1)
+                } else {
+                    None
                 }
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// True if the request is a message that doesn't expect a response
+fn is_one_way(req: &Request) -> bool {
+    matches!(req, Request::Advertise(_) | Request::NewTxn(_) | Request::NewBlock(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::v1::{test_util::test_state, VERSION};
+
+    fn advert(port: u16) -> Request {
+        Request::Advertise(AdvertiseReq {
+            addr_me: SocketAddr::new([127, 0, 0, 1].into(), port),
+        })
+    }
+
+    fn read_advert_port(conn: &TcpStream) -> u16 {
+        match read_frame(conn).unwrap() {
+            Request::Advertise(data) => data.addr_me.port(),
+            other => panic!("Expected an advertisement, got {:?}", other),
+        }
+    }
+
+    /// Accept a connection, or fail the test if none comes in time
+    fn accept_soon(listener: &TcpListener) -> TcpStream {
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while Instant::now() < deadline {
+            if let Ok((conn, _)) = listener.accept() {
+                conn.set_nonblocking(false).unwrap();
+                return conn;
             }
+
+            thread::sleep(Duration::from_millis(10));
         }
+
+        panic!("No connection was made");
     }
 
-    Ok(())
+    #[test]
+    fn pool_reuses_open_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ConnectionPool::default();
+
+        pool.send_msg(&advert(1), &addr).unwrap();
+        pool.send_msg(&advert(2), &addr).unwrap();
+
+        let conn = accept_soon(&listener);
+        assert_eq!(read_advert_port(&conn), 1);
+        assert_eq!(read_advert_port(&conn), 2);
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn pool_reconnects_after_peer_closes_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ConnectionPool::default();
+
+        pool.send_msg(&advert(1), &addr).unwrap();
+
+        let first = accept_soon(&listener);
+        assert_eq!(read_advert_port(&first), 1);
+        drop(first);
+        thread::sleep(Duration::from_millis(50));
+
+        pool.send_msg(&advert(2), &addr).unwrap();
+
+        let second = accept_soon(&listener);
+        assert_eq!(read_advert_port(&second), 2);
+    }
+
+    #[test]
+    fn pool_reconnects_when_listener_declines_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (req_sender, req_receiver) = sync_channel(MAX_QUEUED_REQUESTS);
+
+        // With no free slots, every stream is declined after its first message
+        thread::spawn(move || accept_connections(listener, req_sender, Arc::new(StreamSlots::new(0))));
+
+        let pool = ConnectionPool::default();
+        pool.send_msg(&advert(1), &addr).unwrap();
+
+        // Hold on to the first stream, like a request that's still waiting to be handled
+        let (first, _first_conn) = req_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(first, Request::Advertise(data) if data.addr_me.port() == 1));
+        thread::sleep(Duration::from_millis(50));
+
+        pool.send_msg(&advert(2), &addr).unwrap();
+
+        let (second, _) = req_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(second, Request::Advertise(data) if data.addr_me.port() == 2));
+    }
+
+    #[test]
+    fn stream_slots_stop_at_max() {
+        let slots = StreamSlots::new(2);
+
+        assert!(slots.try_acquire());
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+
+        slots.release();
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+    }
+
+    #[test]
+    fn wrong_magic_frame_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let conn = accept_soon(&listener);
+
+        let mut frame = encode_frame(&advert(1)).unwrap();
+        frame[..4].copy_from_slice(b"XXXX");
+        sender.write_all(&frame).unwrap();
+
+        let err = read_frame::<Request>(&conn).unwrap_err();
+        assert!(matches!(*err, bincode::ErrorKind::Custom(_)));
+    }
+
+    #[test]
+    fn long_user_agents_are_truncated() {
+        assert_eq!(truncate_user_agent(String::from(USER_AGENT)), USER_AGENT);
+        assert_eq!(truncate_user_agent("é".repeat(MAX_USER_AGENT_LEN + 1)), "é".repeat(MAX_USER_AGENT_LEN));
+    }
+
+    #[test]
+    fn replenishes_below_min_peers() {
+        assert!(needs_more_peers(0));
+        assert!(needs_more_peers(MIN_PEERS - 1));
+        assert!(!needs_more_peers(MIN_PEERS));
+        assert!(!needs_more_peers(MAX_NEIGHBORS));
+    }
+
+    #[test]
+    fn repeated_advert_is_not_relayed_twice() {
+        let mut network = test_state().network;
+        let addr = SocketAddr::new([10, 0, 0, 1].into(), 8333);
+
+        assert!(network.mark_advert_seen(addr));
+        assert!(!network.mark_advert_seen(addr));
+        assert!(network.mark_advert_seen(SocketAddr::new([10, 0, 0, 2].into(), 8333)));
+    }
+
+    #[test]
+    fn advert_is_relayed_again_once_forgotten() {
+        let mut network = test_state().network;
+        let addr = SocketAddr::new([10, 0, 0, 1].into(), 8333);
+        network.seen_adverts.insert(addr, Utc::now() - *ADVERT_MEMORY);
+
+        assert!(network.mark_advert_seen(addr));
+    }
+
+    #[test]
+    fn stale_known_nodes_are_pruned() {
+        let mut network = test_state().network;
+        let addr = |n: u8| SocketAddr::new([10, 0, 0, n].into(), 8333);
+        let stale = Utc::now() - *KNOWN_NODE_MAX_AGE - ChronoDuration::minutes(1);
+
+        network.known_nodes = vec![
+            DistantNode::new(addr(1)),
+            DistantNode { addr: addr(2), last_seen: stale },
+            DistantNode { addr: addr(3), last_seen: stale },
+            DistantNode { addr: addr(4), last_seen: stale },
+            DistantNode::new(addr(4)),
+        ];
+        network.peers.push(Node {
+            version: VERSION,
+            addr: addr(3),
+            last_send: Utc::now(),
+            best_height: None,
+            best_hash: None,
+            user_agent: None,
+            neighbors: vec![],
+        });
+
+        network.clean(addr(5));
+
+        let known = network.known_nodes.iter().map(|n| n.addr).collect::<Vec<SocketAddr>>();
+        assert_eq!(known, vec![addr(1), addr(3), addr(4)]);
+    }
+
+    #[test]
+    fn peer_graph_reflects_stored_neighbors() {
+        let mut network = test_state().network;
+        let addr = |n: u8| SocketAddr::new([10, 0, 0, n].into(), 8333);
+        let peer = |n: u8, neighbors: Vec<SocketAddr>| Node {
+            version: VERSION,
+            addr: addr(n),
+            last_send: Utc::now(),
+            best_height: None,
+            best_hash: None,
+            user_agent: None,
+            neighbors,
+        };
+
+        network.peers = vec![peer(2, vec![addr(3), addr(4)]), peer(3, vec![])];
+
+        let graph = network.peer_graph(addr(1));
+        let expected = BTreeMap::from([
+            (addr(1), vec![addr(2), addr(3)]),
+            (addr(2), vec![addr(3), addr(4)]),
+            (addr(3), vec![]),
+        ]);
+        assert_eq!(graph, expected);
+
+        let dot = peer_graph_to_dot(&graph);
+        assert!(dot.contains("\"10.0.0.2:8333\" -> \"10.0.0.4:8333\";"));
+        assert!(dot.contains("\"10.0.0.3:8333\";"));
+        assert!(!dot.contains("\"10.0.0.3:8333\" ->"));
+
+        let json: BTreeMap<String, Vec<String>> = serde_json::from_str(&peer_graph_to_json(&graph).unwrap()).unwrap();
+        assert_eq!(json["10.0.0.1:8333"], vec!["10.0.0.2:8333", "10.0.0.3:8333"]);
+        assert!(json["10.0.0.3:8333"].is_empty());
+    }
+
+    #[test]
+    fn routable_addresses() {
+        let routable = |addr: &str, allow_loopback| is_routable(&addr.parse().unwrap(), allow_loopback);
+
+        for addr in ["10.0.0.1:8333", "93.184.216.34:8333", "[2001:db8::1]:8333"] {
+            assert!(routable(addr, false), "{}", addr);
+        }
+
+        for addr in ["0.0.0.0:8333", "255.255.255.255:8333", "224.0.0.1:8333", "[::]:8333", "[ff02::1]:8333", "10.0.0.1:0"] {
+            assert!(!routable(addr, true), "{}", addr);
+        }
+
+        for addr in ["127.0.0.1:8333", "[::1]:8333"] {
+            assert!(!routable(addr, false), "{}", addr);
+            assert!(routable(addr, true), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn dropped_connection_is_not_backed_off() {
+        let err = io::Error::from(io::ErrorKind::ConnectionAborted);
+
+        assert_eq!(accept_error_action(&err, None), AcceptAction::Continue);
+        assert_eq!(accept_error_action(&err, Some(MAX_ACCEPT_BACKOFF)), AcceptAction::Continue);
+    }
+
+    #[test]
+    fn out_of_file_descriptors_backs_off_longer_each_time() {
+        // EMFILE
+        let err = io::Error::from_raw_os_error(24);
+        let mut backoff = None;
+        let mut waits = vec![];
+
+        for _ in 0..10 {
+            match accept_error_action(&err, backoff) {
+                AcceptAction::Backoff(wait) => backoff = Some(wait),
+                AcceptAction::Continue => panic!("Expected a backoff"),
+            }
+
+            waits.push(backoff.unwrap());
+        }
+
+        assert_eq!(waits[0], MIN_ACCEPT_BACKOFF);
+        assert_eq!(waits[1], MIN_ACCEPT_BACKOFF * 2);
+        assert!(waits.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(waits[waits.len() - 1], MAX_ACCEPT_BACKOFF);
+    }
+
+    #[test]
+    fn binding_a_used_port_fails() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), DEFAULT_LISTEN_BACKLOG).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert!(bind_listener(addr, DEFAULT_LISTEN_BACKLOG).is_err());
+    }
+
+    #[test]
+    fn blast_returns_dead_nodes() {
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let dead_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let pool = ConnectionPool::default();
+
+        let dead = broadcast_async_blast(advert(1), &[live_addr, dead_addr], None, None);
+        assert_eq!(dead, vec![dead_addr]);
+
+        let dead = broadcast_async_blast(advert(2), &[live_addr, dead_addr], None, Some(&pool));
+        assert_eq!(dead, vec![dead_addr]);
+
+        let dead = broadcast_async_blast(advert(3), &[live_addr, dead_addr], Some(dead_addr), None);
+        assert!(dead.is_empty());
+    }
+
+    #[test]
+    fn broadcast_threads_stop_at_max() {
+        let addrs = (1..=(MAX_BROADCAST_THREADS as u16 * 4))
+            .map(|port| SocketAddr::new([127, 0, 0, 1].into(), port))
+            .collect::<Vec<SocketAddr>>();
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+
+        let ports = map_bounded(&addrs, |addr| {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most_running.fetch_max(now_running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+
+            addr.port()
+        });
+
+        assert!(most_running.load(Ordering::SeqCst) <= MAX_BROADCAST_THREADS);
+        assert!(most_running.load(Ordering::SeqCst) > 1);
+        assert_eq!(ports, addrs.iter().map(|a| a.port()).collect::<Vec<u16>>());
+    }
 }
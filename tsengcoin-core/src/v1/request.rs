@@ -1,22 +1,30 @@
 use std::{
     error::Error,
-    net::{SocketAddr, TcpStream},
+    net::{SocketAddr, TcpListener, TcpStream},
     cmp::min,
+    io::ErrorKind,
+    sync::{Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
 };
 
 use chrono::Utc;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    v1::{block_verify::verify_block, net::DistantNode},
+    v1::{block_verify::{verify_historical_block, BlockStatus}, net::DistantNode},
     wallet::Hash256,
 };
 
 use super::{
     block::Block,
-    net::{Node, PROTOCOL_VERSION, MAX_NEIGHBORS, broadcast_async_blast},
+    net::{
+        broadcast_async, broadcast_async_blast, read_frame, read_frame_limited, truncate_user_agent, write_frame,
+        Node, MAX_BLOCK_FRAME_SIZE, MAX_NEIGHBORS, PROTOCOL_VERSION, USER_AGENT,
+    },
     response::{
-        GetBlocksRes::{BadChainIndex, BadHashes, Blocks, DisconnectedChains, UnknownHash},
+        GetBlocksRes::{BadChainIndex, BadHashes, Blocks, DisconnectedChains, Streaming, UnknownHash},
         Response,
     },
     state::State,
@@ -25,13 +33,34 @@ use super::{
 
 const MAX_UNKNOWN_HASH_ATTEMPTS: usize = 3;
 
+/// How long to wait for our own connection to come back to us when checking our remote address
+pub const SELF_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times to try connecting to a peer before giving up
+pub const MAX_CONNECT_ATTEMPTS: u32 = 3;
+/// How long to wait before the first retry. Each retry after that waits twice as long as the last one
+pub const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// How long to wait for a peer to accept a connection. A peer that doesn't answer in this time isn't retried
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a connection to another node. See [connect].
+pub type ConnectFn = fn(&SocketAddr) -> std::io::Result<TcpStream>;
+
+lazy_static! {
+    static ref CONNECT_FN: RwLock<ConnectFn> = RwLock::new(connect_with_retry);
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     GetAddr(GetAddrReq),
     Advertise(AdvertiseReq),
     GetBlocks(GetBlocksReq),
+    GetBlocksRange(GetBlocksRangeReq),
     NewTxn(Transaction),
     NewBlock(Block),
+    /// Same as [Request::GetBlocks], but the blocks are sent one at a time over the stream. See
+    /// [Streaming].
+    StreamBlocks(GetBlocksReq),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -41,6 +70,8 @@ pub struct GetAddrReq {
     pub listen_port: u16,
     pub best_height: usize,
     pub best_hash: Hash256,
+    pub genesis_hash: Hash256,
+    pub user_agent: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -54,26 +85,75 @@ pub struct GetBlocksReq {
     pub my_hash: Hash256,
 }
 
+/// Ask for up to `count` blocks after `start_hash` along a chain. `chain_hint` is 0 for the main chain
+/// or the fork's index plus one. `start_hash` can be in the fork or in the main chain before the fork.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBlocksRangeReq {
+    pub chain_hint: usize,
+    pub start_hash: Hash256,
+    pub count: usize,
+}
+
 pub fn get_first_peers(
     known_node: SocketAddr,
     state: &mut State,
 ) -> Result<(), Box<dyn Error>> {
+    let req = make_get_addr_req(known_node, state);
+    let res = send_req(&req, &known_node)?;
+
+    add_first_peers(known_node, res, state)
+}
+
+/// Same as [get_first_peers] followed by [discover], but the state is only locked between requests. Don't call
+/// this with the state locked.
+pub fn bootstrap_unlocked(seed_addr: SocketAddr, state_mut: &Mutex<State>) -> Result<(), Box<dyn Error>> {
+    let req = make_get_addr_req(seed_addr, &state_mut.lock().unwrap());
+    let res = send_req(&req, &seed_addr)?;
+
+    let reqs = {
+        let mut guard = state_mut.lock().unwrap();
+        add_first_peers(seed_addr, res, &mut guard)?;
+
+        discover_targets(seed_addr, &guard)
+            .into_iter()
+            .map(|addr| (addr, make_get_addr_req(addr, &guard)))
+            .collect::<Vec<(SocketAddr, Request)>>()
+    };
+
+    let results = reqs
+        .iter()
+        .map(|(addr, req)| (*addr, send_req(req, addr)))
+        .collect();
+
+    add_discovered_peers(results, &mut state_mut.lock().unwrap());
+
+    Ok(())
+}
+
+fn make_get_addr_req(addr_you: SocketAddr, state: &State) -> Request {
     let (best_height, chain_idx, _) = state.blockchain.best_chain();
 
-    let req = Request::GetAddr(GetAddrReq {
+    Request::GetAddr(GetAddrReq {
         version: PROTOCOL_VERSION,
-        addr_you: known_node,
+        addr_you,
         listen_port: state.local_addr_me.port(),
         best_height,
         best_hash: state.blockchain.top_hash(chain_idx),
-    });
-
-    let res = send_req(&req, &known_node)?;
+        genesis_hash: state.blockchain.genesis_hash(),
+        user_agent: String::from(USER_AGENT),
+    })
+}
 
+/// Add a seed node and the neighbors it told us about as peers
+fn add_first_peers(known_node: SocketAddr, res: Response, state: &mut State) -> Result<(), Box<dyn Error>> {
     match res {
         Response::GetAddr(data) => {
+            check_genesis(data.genesis_hash, state)?;
+
+            let neighbor_addrs = data.neighbors.iter().map(|n| n.addr).collect::<Vec<SocketAddr>>();
+
             for node in data.neighbors {
-                if node == data.addr_you {
+                if node == data.addr_you || !state.network.is_routable(&node.addr) {
                     continue;
                 }
 
@@ -86,14 +166,16 @@ pub fn get_first_peers(
                 last_send: Utc::now(),
                 best_height: Some(data.best_height),
                 best_hash: Some(data.best_hash),
+                user_agent: Some(truncate_user_agent(data.user_agent)),
+                neighbors: neighbor_addrs,
             });
 
             state
                 .network
                 .known_nodes
-                .push(DistantNode { addr: known_node });
+                .push(DistantNode::new(known_node));
 
-            // TODO: Bootstrap with a few nodes to reduce the chances of a node lying about your remote IP
+            // If there are several seeds, [bootstrap] cross-checks this against the others
             state.remote_addr_me = Some(data.addr_you);
             state.network.clean(data.addr_you);
 
@@ -103,37 +185,148 @@ pub fn get_first_peers(
     }
 }
 
+/// Fails if another node's genesis block is not the same as ours. A node with a different genesis block is on a
+/// different network, so we can't accept its blocks or share ours.
+pub fn check_genesis(their_genesis: Hash256, state: &State) -> Result<(), Box<dyn Error>> {
+    let my_genesis = state.blockchain.genesis_hash();
+
+    if their_genesis != my_genesis {
+        return Err(format!(
+            "Node is on a different network: its genesis block is {}, ours is {}",
+            hex::encode(their_genesis),
+            hex::encode(my_genesis)
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Get first peers from every seed that responds, then discover the rest of the network through the first one.
+/// Each seed tells us what our remote address is; if they disagree, we go with the address most of them reported.
+/// We then try to reach ourselves at that address and warn if we can't. Fails only if none of the seeds respond.
+pub fn bootstrap(seeds: &[SocketAddr], state: &mut State) -> Result<(), Box<dyn Error>> {
+    let mut reported_addrs: Vec<SocketAddr> = vec![];
+    let mut first_seed: Option<SocketAddr> = None;
+
+    for seed in seeds {
+        match get_first_peers(*seed, state) {
+            Err(err) => println!("Failed to get peers from seed node {}: {}", seed, err),
+            Ok(()) => {
+                reported_addrs.push(state.remote_addr_me.unwrap());
+                first_seed.get_or_insert(*seed);
+            }
+        }
+    }
+
+    let first_seed = match first_seed {
+        None => return Err("None of the seed nodes responded".into()),
+        Some(seed) => seed,
+    };
+
+    let (addr_me, warning) = choose_remote_addr(&reported_addrs);
+
+    if let Some(warning) = warning {
+        println!("Warning: {}", warning);
+    }
+
+    if let Err(err) = probe_self(state.local_addr_me, addr_me) {
+        println!(
+            "Warning: Could not confirm our remote address {}: {}. Other nodes may not be able to reach us after we advertise it",
+            addr_me, err
+        );
+    }
+
+    state.remote_addr_me = Some(addr_me);
+    state.network.clean(addr_me);
+
+    discover(first_seed, state)
+}
+
+/// Pick our remote address out of the ones reported by seed nodes. The address reported most often wins.
+/// Also returns a warning if the seeds didn't all agree, because at least one of them is wrong or lying.
+pub fn choose_remote_addr(reported_addrs: &[SocketAddr]) -> (SocketAddr, Option<String>) {
+    let addr_me = *reported_addrs
+        .iter()
+        .max_by_key(|a| reported_addrs.iter().filter(|b| b == a).count())
+        .expect("At least one seed must report our address");
+
+    if reported_addrs.iter().all(|a| *a == addr_me) {
+        return (addr_me, None);
+    }
+
+    let warning = format!(
+        "Seed nodes disagree about our remote address: {:?}. Using {}",
+        reported_addrs, addr_me
+    );
+
+    (addr_me, Some(warning))
+}
+
+/// Check that we can be reached at our remote address by listening on our local address and connecting to the
+/// remote one. This has to happen before the network listener starts. A failure doesn't always mean the address is
+/// wrong: some routers don't let a node connect to its own external address.
+pub fn probe_self(local_addr: SocketAddr, remote_addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(local_addr)?;
+    listener.set_nonblocking(true)?;
+
+    let _conn = TcpStream::connect_timeout(&remote_addr, SELF_PROBE_TIMEOUT)?;
+    let deadline = Instant::now() + SELF_PROBE_TIMEOUT;
+
+    while Instant::now() < deadline {
+        match listener.accept() {
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Err("Connection never reached our listener".into())
+}
+
 pub fn discover(seed_addr: SocketAddr, state: &mut State) -> Result<(), Box<dyn Error>> {
-    let addrs: Vec<SocketAddr> = state
+    let results = discover_targets(seed_addr, state)
+        .into_iter()
+        .map(|addr| (addr, send_req(&make_get_addr_req(addr, state), &addr)))
+        .collect();
+
+    add_discovered_peers(results, state);
+
+    Ok(())
+}
+
+/// The peers that [discover] asks for more nodes
+fn discover_targets(seed_addr: SocketAddr, state: &State) -> Vec<SocketAddr> {
+    state
         .network
         .peers
         .iter()
         .filter(|n| *n != seed_addr)
         .map(|n| n.addr)
-        .collect();
-
-    let (best_height, chain_idx, _) = state.blockchain.best_chain();
-
-    for addr in addrs {
-        let req = Request::GetAddr(GetAddrReq {
-            version: PROTOCOL_VERSION,
-            addr_you: addr,
-            listen_port: state.local_addr_me.port(),
-            best_height,
-            best_hash: state.blockchain.top_hash(chain_idx),
-        });
-
-        let result = send_req(&req, &addr);
+        .collect()
+}
 
+/// Add the nodes that peers told us about in response to [discover]. Peers that didn't respond are removed.
+fn add_discovered_peers(results: Vec<(SocketAddr, bincode::Result<Response>)>, state: &mut State) {
+    for (addr, result) in results {
         match result {
             Err(_) => state.network.remove(addr),
+            Ok(Response::GetAddr(data)) if check_genesis(data.genesis_hash, state).is_err() => {
+                println!("Dropping peer {} because it is on a different network", addr);
+                state.network.remove(addr);
+            }
             Ok(Response::GetAddr(mut data)) => {
+                let neighbor_addrs = data.neighbors.iter().map(|n| n.addr).collect::<Vec<SocketAddr>>();
+
+                data.neighbors.retain(|n| state.network.is_routable(&n.addr));
                 state.network.peers.append(&mut data.neighbors);
 
                 for mut peer in &mut state.network.peers {
                     if peer == &addr {
                         peer.best_height = Some(data.best_height);
                         peer.best_hash = Some(data.best_hash);
+                        peer.user_agent = Some(truncate_user_agent(data.user_agent.clone()));
+                        peer.neighbors = neighbor_addrs.clone();
                     }
                 }
             }
@@ -153,8 +346,6 @@ pub fn discover(seed_addr: SocketAddr, state: &mut State) -> Result<(), Box<dyn
     state.network.shuffle();
     let num_peers = min(state.network.peers.len(), MAX_NEIGHBORS);
     state.network.peers = state.network.peers[0..num_peers].to_vec();
-
-    Ok(())
 }
 
 pub fn download_latest_blocks(state: &mut State) -> Result<(), Box<dyn Error>> {
@@ -180,39 +371,26 @@ pub fn download_latest_blocks(state: &mut State) -> Result<(), Box<dyn Error>> {
     let mut attempt: usize = 0;
 
     while attempt < MAX_UNKNOWN_HASH_ATTEMPTS && block_idx > 0 {
-        let req = Request::GetBlocks(GetBlocksReq {
+        let req = Request::StreamBlocks(GetBlocksReq {
             your_hash: best_node.best_hash.unwrap(),
             my_hash: hash,
         });
 
-        let res = send_req(&req, &best_node.addr)?;
+        let socket = connect(&best_node.addr)?;
+        socket.set_nodelay(true).unwrap();
+        write_frame(&socket, &req)?;
+
+        let res: Response = read_frame(&socket)?;
 
         match res {
             Response::GetBlocks(res_data) => {
                 match res_data {
-                    Blocks(blocks) => {
-                        if blocks[0].header.prev_hash == hash {
-                            for block in blocks {
-                                let verify_result = verify_block(block.clone(), state);
-
-                                match verify_result {
-                                    Ok(false) => (),
-                                    Err(err) => {
-                                        println!("Received a bad block: {}", err);
-                                    }
-                                    Ok(true) => {
-                                        println!("Received an orphan block as part of a blockchain from another peer");
-                                        // TODO: Remove peer for this nonsense. This really is nonsense because we checked earlier that
-                                        // this chain of blocks is connected to the top of our main chain, so it would be the peer's
-                                        // fault for inserting a disconnected block into the blocks it sends back.
-                                    }
-                                }
-                            }
-                        } else {
-                            return Err("Received block with bad prev hash".into());
-                        }
+                    Streaming(count) => {
+                        receive_block_stream(&socket, count, hash, state)?;
                         break;
                     }
+                    // We asked for a stream, so the blocks shouldn't come all at once
+                    Blocks(_) => return Err("Peer node returned nonsense".into()),
                     UnknownHash(_) => {
                         block_idx -= 1;
                         hash = state.blockchain.blocks[block_idx - 1].header.hash;
@@ -242,41 +420,312 @@ pub fn download_latest_blocks(state: &mut State) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Download every block after `my_hash` on the chain ending in `your_hash` from the node at `addr`, without
+/// verifying them. Unlike [download_latest_blocks], this doesn't need the state, so the caller doesn't have to
+/// hold the state lock while waiting on the network.
+pub fn download_blocks_after(
+    addr: &SocketAddr,
+    your_hash: Hash256,
+    my_hash: Hash256,
+) -> Result<Vec<Block>, Box<dyn Error>> {
+    let req = Request::StreamBlocks(GetBlocksReq { your_hash, my_hash });
+
+    let socket = connect(addr)?;
+    socket.set_nodelay(true).unwrap();
+    write_frame(&socket, &req)?;
+
+    let count = match read_frame(&socket)? {
+        Response::GetBlocks(Streaming(count)) => count,
+        Response::GetBlocks(UnknownHash(_)) => return Err("Node doesn't have the block to download from".into()),
+        Response::GetBlocks(DisconnectedChains) => {
+            return Err("Tried to download blockchain across unconnected forks".into())
+        }
+        _ => return Err("Peer node returned nonsense".into()),
+    };
+
+    let mut blocks: Vec<Block> = vec![];
+    let mut prev_hash = my_hash;
+
+    for i in 0..count {
+        let block = read_stream_block(&socket, i, count, prev_hash)?;
+        prev_hash = block.header.hash;
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+/// Read the `i`th of `count` blocks in a block stream. The block must come right after `prev_hash`.
+fn read_stream_block(socket: &TcpStream, i: usize, count: usize, prev_hash: Hash256) -> Result<Block, Box<dyn Error>> {
+    let block: Block = read_frame_limited(socket, MAX_BLOCK_FRAME_SIZE)
+        .map_err(|err| format!("Block stream ended after {} of {} blocks: {}", i, count, err))?;
+
+    if block.header.prev_hash != prev_hash {
+        return Err("Received block with bad prev hash".into());
+    }
+
+    Ok(block)
+}
+
+/// Read `count` blocks sent one at a time after a [Streaming] response, verifying each one as it
+/// arrives. The first block must come right after `prev_hash`, and each block after that must come right after
+/// the one before it. Bad blocks are logged and skipped, the same as blocks that are announced to us. The blocks
+/// can be as old as the blockchain, so they aren't held to the timestamp tolerance for new blocks.
+fn receive_block_stream(
+    socket: &TcpStream,
+    count: usize,
+    prev_hash: Hash256,
+    state: &mut State,
+) -> Result<(), Box<dyn Error>> {
+    let mut prev_hash = prev_hash;
+
+    for i in 0..count {
+        let block = read_stream_block(socket, i, count, prev_hash)?;
+        prev_hash = block.header.hash;
+
+        match verify_historical_block(block, state) {
+            Ok(BlockStatus::Added) => (),
+            Err(err) => {
+                println!("Received a bad block: {}", err);
+            }
+            Ok(BlockStatus::MissingParent(parent)) => {
+                println!(
+                    "Received an orphan block as part of a blockchain from another peer. Missing parent {}",
+                    hex::encode(parent)
+                );
+                // TODO: Remove peer for this nonsense. This really is nonsense because we checked that each block
+                // comes right after the one before it, so it would be the peer's fault for sending a block whose
+                // parent we couldn't add.
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn advertise_self(state: &mut State) -> Result<(), Box<dyn Error>> {
     let addr_me = state.remote_addr_me.unwrap();
 
     let req = Request::Advertise(AdvertiseReq { addr_me });
 
     let peers = state.network.peer_addrs();
-    broadcast_async_blast(req, &peers, None);
+    let mut dead_nodes = broadcast_async_blast(req, &peers, None, state.network.conn_pool.as_deref());
+    state.network.prune_dead_nodes(&mut dead_nodes);
 
     Ok(())
 }
 
 /// Broadcast a new transaction to the network. Assumes the transaction is valid - it is
-/// the caller's job to check this beforehand.
-pub fn send_new_txn(txn: Transaction, state: &mut State) -> Result<(), Box<dyn Error>> {
-    // TODO: Pay attention to these errors
+/// the caller's job to check this beforehand. Returns the number of peers that received the
+/// transaction and the number that could not be reached. Unreachable peers are pruned.
+pub fn send_new_txn(txn: Transaction, state: &mut State) -> Result<(usize, usize), Box<dyn Error>> {
     let peers = state.network.peer_addrs();
-    broadcast_async_blast(Request::NewTxn(txn), &peers, None);
+    let mut dead_nodes = broadcast_async(Request::NewTxn(txn), &peers, None, state.network.conn_pool.as_deref());
+    let failed = dead_nodes.len();
+    let accepted = peers.len() - failed;
 
-    Ok(())
+    state.network.prune_dead_nodes(&mut dead_nodes);
+
+    Ok((accepted, failed))
+}
+
+/// Get up to `count` blocks after `start_hash` from another node. See [GetBlocksRangeReq].
+pub fn get_blocks_range(
+    addr: &SocketAddr,
+    chain_hint: usize,
+    start_hash: Hash256,
+    count: usize,
+) -> Result<Vec<Block>, Box<dyn Error>> {
+    let req = Request::GetBlocksRange(GetBlocksRangeReq {
+        chain_hint,
+        start_hash,
+        count,
+    });
+
+    match send_req(&req, addr)? {
+        Response::GetBlocks(Blocks(blocks)) => Ok(blocks),
+        Response::GetBlocks(UnknownHash(hash)) => {
+            Err(format!("Node doesn't have block {}", hex::encode(hash)).into())
+        }
+        Response::GetBlocks(DisconnectedChains) => {
+            Err("Start block is not on the requested chain".into())
+        }
+        Response::GetBlocks(BadChainIndex) => Err("Node doesn't have the requested chain".into()),
+        Response::GetBlocks(BadHashes) => Err("Bad block range".into()),
+        _ => Err("Node responded with nonsense".into()),
+    }
+}
+
+/// Connect to a peer, retrying a few times with exponential backoff if the connection fails for a reason
+/// that might go away on its own. This way a peer that's briefly unavailable isn't dropped after one hiccup.
+/// Errors that aren't transient are returned right away. Each attempt gives up after [CONNECT_TIMEOUT], and a timeout
+/// isn't retried, so an unresponsive peer can't hold us up for more than that.
+pub fn connect_with_retry(addr: &SocketAddr) -> std::io::Result<TcpStream> {
+    retry_transient(|| TcpStream::connect_timeout(addr, CONNECT_TIMEOUT))
+}
+
+/// The retry loop for [connect_with_retry], separate from the connection so that tests can fail on purpose
+fn retry_transient<T, F: FnMut() -> std::io::Result<T>>(mut attempt_fn: F) -> std::io::Result<T> {
+    let mut backoff = CONNECT_RETRY_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match attempt_fn() {
+            Err(err) if attempt < MAX_CONNECT_ATTEMPTS && is_transient_connect_error(err.kind()) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient_connect_error(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+    )
+}
+
+/// Open a connection to another node. Every connection we make to another node goes through here, so that tests
+/// can put a simulated network in between nodes with [set_connect_fn]. Outside of tests this is [connect_with_retry].
+pub fn connect(addr: &SocketAddr) -> std::io::Result<TcpStream> {
+    let connect_fn = *CONNECT_FN.read().unwrap();
+
+    connect_fn(addr)
+}
+
+/// Change how [connect] opens connections
+#[cfg(test)]
+pub fn set_connect_fn(connect_fn: ConnectFn) {
+    *CONNECT_FN.write().unwrap() = connect_fn;
 }
 
 pub fn send_req(req: &Request, addr: &SocketAddr) -> bincode::Result<Response> {
-    let socket = TcpStream::connect(addr)?;
+    let socket = connect(addr)?;
     socket.set_nodelay(true).unwrap();
-    bincode::serialize_into(&socket, &req)?;
+    write_frame(&socket, &req)?;
 
-    let res: Response = bincode::deserialize_from(&socket)?;
+    let res: Response = read_frame(&socket)?;
 
     Ok(res)
 }
 
 pub fn send_msg(msg: &Request, addr: &SocketAddr) -> bincode::Result<()> {
-    let socket = TcpStream::connect(addr)?;
+    let socket = connect(addr)?;
     socket.set_nodelay(true).unwrap();
-    bincode::serialize_into(&socket, &msg)?;
+    write_frame(&socket, &msg)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io, net::TcpListener, rc::Rc};
+
+    use super::*;
+    use crate::v1::test_util::{add_blocks_after, test_state};
+
+    /// An attempt function that fails with each of the given errors in turn, then succeeds. Returns the attempt
+    /// function and a count of the calls to it.
+    fn fail_with(errors: Vec<ErrorKind>) -> (impl FnMut() -> std::io::Result<usize>, Rc<Cell<usize>>) {
+        let calls = Rc::new(Cell::new(0));
+        let calls_out = Rc::clone(&calls);
+
+        let attempt_fn = move || {
+            let call = calls.get();
+            calls.set(call + 1);
+
+            match errors.get(call) {
+                Some(kind) => Err(io::Error::new(*kind, "Simulated connection failure")),
+                None => Ok(call + 1),
+            }
+        };
+
+        (attempt_fn, calls_out)
+    }
+
+    #[test]
+    fn connect_retries_after_refused_connection() {
+        let (attempt_fn, calls) = fail_with(vec![ErrorKind::ConnectionRefused, ErrorKind::ConnectionReset]);
+
+        assert_eq!(retry_transient(attempt_fn).unwrap(), 3);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn connect_gives_up_after_max_attempts() {
+        let refusals = vec![ErrorKind::ConnectionRefused; MAX_CONNECT_ATTEMPTS as usize];
+        let (attempt_fn, calls) = fail_with(refusals);
+
+        assert_eq!(retry_transient(attempt_fn).unwrap_err().kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(calls.get(), MAX_CONNECT_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn timed_out_connection_is_not_retried() {
+        let (attempt_fn, calls) = fail_with(vec![ErrorKind::TimedOut]);
+
+        assert_eq!(retry_transient(attempt_fn).unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn timeouts_are_not_retried() {
+        assert!(!is_transient_connect_error(ErrorKind::TimedOut));
+        assert!(is_transient_connect_error(ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn conflicting_remote_addrs_warn() {
+        let a: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:8333".parse().unwrap();
+
+        assert_eq!(choose_remote_addr(&[a, a]), (a, None));
+
+        let (addr_me, warning) = choose_remote_addr(&[b, a, b]);
+        assert_eq!(addr_me, b);
+        assert!(warning.unwrap().starts_with("Seed nodes disagree about our remote address"));
+    }
+
+    #[test]
+    fn self_probe_needs_connection_to_reach_listener() {
+        let free_addr = || TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let local = free_addr();
+
+        assert!(probe_self(local, local).is_ok());
+        assert!(probe_self(free_addr(), free_addr()).is_err());
+    }
+
+    #[test]
+    fn block_stream_is_verified_as_it_arrives() {
+        let mut source = test_state();
+        let genesis_hash = source.blockchain.genesis_hash();
+        let blocks = add_blocks_after(&mut source, genesis_hash, 3);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Send only the first two blocks of three, then hang up
+        let sender_thread = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+
+            for block in &blocks[0..2] {
+                write_frame(&socket, block).unwrap();
+            }
+        });
+
+        let mut state = test_state();
+        let socket = TcpStream::connect(addr).unwrap();
+        let err = receive_block_stream(&socket, 3, genesis_hash, &mut state).unwrap_err();
+        sender_thread.join().unwrap();
+
+        assert!(err.to_string().starts_with("Block stream ended after 2 of 3 blocks"));
+        assert_eq!(state.blockchain.blocks.len(), 3);
+    }
+}
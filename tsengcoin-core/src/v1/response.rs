@@ -1,9 +1,12 @@
 use std::{
+    cmp::min,
     error::Error,
+    io::Write,
     net::{SocketAddr, TcpStream},
     sync::{
         Arc, Mutex,
     },
+    thread,
 };
 
 use chrono::Utc;
@@ -11,7 +14,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     v1::{
-        chain_request::{check_pending_dh, make_dh_response_req, make_intent_req},
+        chain_request::{check_pending_dh, make_dh_response_req, make_intent_req, MIN_KEY_ROTATION_INTERVAL},
         request::send_new_txn,
         transaction::get_p2pkh_sender,
     },
@@ -19,17 +22,26 @@ use crate::{
 };
 
 use super::{
-    block::Block,
-    block_verify::verify_block,
+    block::{Block, BlockchainDB},
+    block_verify::{verify_block, BlockStatus},
     chain_request::{decompose_dh_req, is_dh_req, is_dh_req_to_me},
     encrypted_msg::{decompose_enc_req, handle_chain_request, is_enc_req, is_enc_req_to_me},
-    net::{DistantNode, Node, PROTOCOL_VERSION, find_new_friends, broadcast_async_blast},
-    request::{AdvertiseReq, GetAddrReq, GetBlocksReq, Request},
+    net::{
+        announce_block, broadcast_async_blast, encode_frame, find_new_friends, truncate_user_agent, write_frame,
+        DistantNode,
+        Node, PROTOCOL_VERSION, USER_AGENT,
+    },
+    request::{check_genesis, AdvertiseReq, GetAddrReq, GetBlocksRangeReq, GetBlocksReq, Request},
     state::{State, GUIChannels},
-    transaction::{Transaction},
-    txn_verify::verify_transaction,
+    transaction::{compute_fee, Transaction},
+    txn_verify::{verify_pending_transaction, TxnStatus},
 };
 
+/// Most blocks we'll send back for one [GetBlocksRangeReq]
+pub const MAX_BLOCKS_RANGE: usize = 500;
+/// How many blocks we serialize at a time when streaming blocks
+pub const BLOCK_STREAM_BATCH: usize = 32;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     GetAddr(GetAddrRes),
@@ -42,7 +54,9 @@ pub struct GetAddrRes {
     pub addr_you: SocketAddr,
     pub best_height: usize,
     pub best_hash: Hash256,
+    pub genesis_hash: Hash256,
     pub neighbors: Vec<Node>,
+    pub user_agent: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -52,6 +66,9 @@ pub enum GetBlocksRes {
     BadChainIndex,
     BadHashes,
     Blocks(Vec<Block>),
+    /// Sent in response to [Request::StreamBlocks] instead of [GetBlocksRes::Blocks]. This many blocks follow,
+    /// each in its own frame, oldest first.
+    Streaming(usize),
 }
 
 pub fn handle_request(
@@ -64,6 +81,8 @@ pub fn handle_request(
         Request::GetAddr(data) => handle_get_addr(data, socket, state_arc),
         Request::Advertise(data) => handle_advertise(data, socket, state_arc),
         Request::GetBlocks(data) => handle_get_blocks(data, socket, state_arc),
+        Request::GetBlocksRange(data) => handle_get_blocks_range(data, socket, state_arc),
+        Request::StreamBlocks(data) => handle_stream_blocks(data, socket, state_arc),
         Request::NewTxn(data) => {
             handle_new_txn(data, socket, gui_channels, state_arc)
         }
@@ -96,14 +115,29 @@ fn handle_get_addr(
         neighbors,
         best_height,
         best_hash: state.blockchain.top_hash(chain_idx),
+        genesis_hash: state.blockchain.genesis_hash(),
+        user_agent: String::from(USER_AGENT),
     });
 
+    // Still reply so that the other node can see why we won't peer with it, but don't add it
+    if let Err(err) = check_genesis(data.genesis_hash, state) {
+        println!("Not peering with {}: {}", addr_you, err);
+
+        if let Err(err) = send_res(res, &socket) {
+            println!("Error sending reply back to node: {}", err);
+        }
+
+        return Ok(());
+    }
+
     let node = Node {
         version: data.version,
         addr: addr_you,
         last_send: Utc::now(),
         best_height: Some(data.best_height),
         best_hash: Some(data.best_hash),
+        user_agent: Some(truncate_user_agent(data.user_agent)),
+        neighbors: vec![],
     };
 
     // Add the node back as a peer
@@ -127,34 +161,49 @@ fn handle_get_addr(
 fn handle_advertise(
     data: AdvertiseReq,
     socket: TcpStream,
-    state_mut: &Mutex<State>,
+    state_arc: &Arc<Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
     drop(socket);
 
     let addr_you = data.addr_me;
 
-    let mut guard = state_mut.lock().unwrap();
+    let mut guard = state_arc.lock().unwrap();
     let state = &mut *guard;
 
     let addr_me = state.remote_addr_me.unwrap();
 
-    if state.network.has_known(addr_you) || (addr_you == addr_me) {
+    if state.network.has_known(addr_you) || (addr_you == addr_me) || !state.network.is_routable(&addr_you) {
+        return Ok(());
+    }
+
+    // Don't relay the same advertisement twice. Without this, an advertisement could bounce around
+    // the network if the node gets dropped from our known nodes
+    if !state.network.mark_advert_seen(addr_you) {
         return Ok(());
     }
 
     state
         .network
         .known_nodes
-        .push(DistantNode { addr: addr_you });
+        .push(DistantNode::new(addr_you));
 
     let peers = state.network.peer_addrs();
+    let pool = state.network.conn_pool.clone();
     drop(guard);
 
-    broadcast_async_blast(Request::Advertise(data), &peers, Some(addr_you));
-
-    if rand::random::<u8>() % 2 == 0 {
-        find_new_friends(state_mut);
-    }
+    // Requests are handled one at a time, so waiting on other nodes here would hold up every other request. Worse,
+    // if the node we're waiting on is also waiting on us, neither of us would ever handle another request.
+    let state_arc = Arc::clone(state_arc);
+    thread::Builder::new()
+        .name(String::from("advert-relay"))
+        .spawn(move || {
+            let mut dead_nodes = broadcast_async_blast(Request::Advertise(data), &peers, Some(addr_you), pool.as_deref());
+            state_arc.lock().unwrap().network.prune_dead_nodes(&mut dead_nodes);
+
+            if rand::random::<u8>() % 2 == 0 {
+                find_new_friends(&state_arc);
+            }
+        })?;
 
     Ok(())
 }
@@ -228,6 +277,171 @@ fn handle_get_blocks(
     Ok(())
 }
 
+/// Send the blocks asked for one at a time, so that neither side has to hold all of them in memory at once. The
+/// state is only locked while a batch of [BLOCK_STREAM_BATCH] blocks is serialized, not while the batch is sent.
+fn handle_stream_blocks(
+    data: GetBlocksReq,
+    socket: TcpStream,
+    state_mut: &Mutex<State>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state_mut.lock().unwrap();
+    let locate_result = locate_stream_blocks(&guard.blockchain, &data);
+    drop(guard);
+
+    let locations = match locate_result {
+        Err(res) => {
+            if let Err(err) = send_res(Response::GetBlocks(res), &socket) {
+                println!("Error sending reply back to node: {}", err);
+            }
+
+            return Ok(());
+        }
+        Ok(locations) => locations,
+    };
+
+    send_res(Response::GetBlocks(GetBlocksRes::Streaming(locations.len())), &socket)?;
+
+    let mut writer = &socket;
+
+    for batch in locations.chunks(BLOCK_STREAM_BATCH) {
+        let guard = state_mut.lock().unwrap();
+        let mut frames: Vec<u8> = vec![];
+        let mut stale = false;
+
+        for (chain_idx, pos, hash) in batch {
+            match guard.blockchain.block_at(*chain_idx, *pos) {
+                Some(block) if block.header.hash == *hash => frames.append(&mut encode_frame(block)?),
+                _ => {
+                    stale = true;
+                    break;
+                }
+            }
+        }
+
+        drop(guard);
+        writer.write_all(&frames)?;
+
+        // The chain changed since we found the blocks, so the rest of the stream would be wrong. The other node
+        // will see that the stream ended early and can ask again.
+        if stale {
+            return Err("Blockchain changed while streaming blocks".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the chain index, position, and hash of every block after `my_hash` up to and including `your_hash`, oldest
+/// first. This works across forks because it follows each block's parent back from `your_hash`.
+pub fn locate_stream_blocks(
+    blockchain: &BlockchainDB,
+    data: &GetBlocksReq,
+) -> Result<Vec<(usize, usize, Hash256)>, GetBlocksRes> {
+    if blockchain.get_block(data.my_hash).is_none() {
+        return Err(GetBlocksRes::UnknownHash(data.my_hash));
+    }
+
+    let (mut chain_idx, mut pos) = match blockchain.get_block(data.your_hash) {
+        None => return Err(GetBlocksRes::UnknownHash(data.your_hash)),
+        Some((_, chain_idx, pos)) => (chain_idx, pos),
+    };
+
+    let mut out: Vec<(usize, usize, Hash256)> = vec![];
+
+    loop {
+        let hash = match blockchain.block_at(chain_idx, pos) {
+            None => return Err(GetBlocksRes::BadChainIndex),
+            Some(block) => block.header.hash,
+        };
+
+        if hash == data.my_hash {
+            break;
+        }
+
+        out.push((chain_idx, pos, hash));
+
+        match blockchain.parent_pos(chain_idx, pos) {
+            // We got back to the genesis block without finding `my_hash`, so it's not before `your_hash`
+            None => return Err(GetBlocksRes::DisconnectedChains),
+            Some((parent_chain, parent_pos)) => {
+                chain_idx = parent_chain;
+                pos = parent_pos;
+            }
+        }
+    }
+
+    if out.is_empty() {
+        return Err(GetBlocksRes::BadHashes);
+    }
+
+    out.reverse();
+
+    Ok(out)
+}
+
+fn handle_get_blocks_range(
+    data: GetBlocksRangeReq,
+    socket: TcpStream,
+    state_mut: &Mutex<State>,
+) -> Result<(), Box<dyn Error>> {
+    let guard = state_mut.lock().unwrap();
+    let res = blocks_in_range(&guard.blockchain, &data);
+    drop(guard);
+
+    if let Err(err) = send_res(Response::GetBlocks(res), &socket) {
+        println!("Error sending reply back to node: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Find the blocks asked for by a [GetBlocksRangeReq]. At most [MAX_BLOCKS_RANGE] blocks are returned.
+pub fn blocks_in_range(blockchain: &BlockchainDB, data: &GetBlocksRangeReq) -> GetBlocksRes {
+    let count = min(data.count, MAX_BLOCKS_RANGE);
+
+    let (start_chain, start_pos) = match blockchain.get_block(data.start_hash) {
+        None => return GetBlocksRes::UnknownHash(data.start_hash),
+        Some((_, chain_idx, pos)) => (chain_idx, pos),
+    };
+
+    if data.chain_hint > blockchain.forks.len() {
+        return GetBlocksRes::BadChainIndex;
+    }
+
+    if data.chain_hint == 0 {
+        if start_chain != 0 {
+            return GetBlocksRes::DisconnectedChains;
+        }
+
+        let end = min(start_pos + 1 + count, blockchain.blocks.len());
+
+        return GetBlocksRes::Blocks(blockchain.get_blocks_rel(0, start_pos + 1, end));
+    }
+
+    let fork = &blockchain.forks[data.chain_hint - 1];
+
+    let mut blocks = if start_chain == 0 && start_pos <= fork.prev_index {
+        // Blocks from the main chain up to the fork point come first, then the fork
+        let main_len = fork.prev_index - start_pos;
+        let fork_end = min(count.saturating_sub(main_len), fork.blocks.len());
+
+        blockchain.get_blocks_rel(data.chain_hint, start_pos + 1, fork_end)
+    } else if start_chain == data.chain_hint {
+        // Skip the main chain and the fork blocks up to and including the start block
+        let fork_end = min(start_pos + 1 + count, fork.blocks.len());
+        let mut fork_blocks = blockchain.get_blocks_rel(data.chain_hint, fork.prev_index + 1, fork_end);
+        fork_blocks.drain(0..(start_pos + 1));
+
+        fork_blocks
+    } else {
+        return GetBlocksRes::DisconnectedChains;
+    };
+
+    blocks.truncate(count);
+
+    GetBlocksRes::Blocks(blocks)
+}
+
 pub fn handle_new_txn(
     data: Transaction,
     socket: TcpStream,
@@ -246,28 +460,52 @@ pub fn handle_new_txn(
     }
 
     // The first thing we do is verify the transaction
-    let verify_result = verify_transaction(data.clone(), state);
+    let verify_result = verify_pending_transaction(data.clone(), state);
 
-    let is_orphan = match verify_result {
+    let status = match verify_result {
         Err(_) => {
             return Ok(());
         }
-        Ok(is_orphan) => is_orphan,
+        Ok(status) => status,
     };
 
-    match is_orphan {
-        true => state.orphan_txns.push(data.clone()),
-        false => {
-            state.add_pending_txn(data.clone());
+    // A transaction below our relay fee is still valid, and if it's addressed to us we still handle it below
+    let relay = match status {
+        TxnStatus::MissingInput(_) => {
+            state.orphan_txns.push(data.clone());
+            true
+        }
+        TxnStatus::Valid => {
+            // This is only our policy, so the transaction is still valid in a block
+            let fee = compute_fee(&data, state);
+
+            if fee < state.min_relay_fee {
+                println!(
+                    "Not relaying transaction {}: fee of {} is below our minimum of {}",
+                    hex::encode(data.hash),
+                    fee,
+                    state.min_relay_fee
+                );
+                false
+            } else {
+                state.add_pending_txn(data.clone());
+                true
+            }
         }
     };
 
-    let peers = state.network.peer_addrs();
-    drop(guard);
+    if relay {
+        let peers = state.network.peer_addrs();
+        let pool = state.network.conn_pool.clone();
+        drop(guard);
 
-    broadcast_async_blast(Request::NewTxn(data.clone()), &peers, Some(sender_addr));
+        let mut dead_nodes =
+            broadcast_async_blast(Request::NewTxn(data.clone()), &peers, Some(sender_addr), pool.as_deref());
+
+        guard = state_arc.lock().unwrap();
+        guard.network.prune_dead_nodes(&mut dead_nodes);
+    }
 
-    let mut guard = state_arc.lock().unwrap();
     let state = &mut *guard;
 
     if is_enc_req(&data) && is_enc_req_to_me(&data, state) {
@@ -316,15 +554,33 @@ pub fn handle_new_txn(
             return Ok(());
         }
 
+        // We already have keys with them, so this is a key rotation. Answering costs us a fee, so it goes through
+        // the same accept policy as a new connection, and they can't ask too often.
+        let is_rotation = state.friends.is_connected(&sender);
+
+        if is_rotation && !state.friends.allow_key_rotation(sender, Utc::now()) {
+            println!(
+                "Ignoring key rotation request from {}. They already asked in the last {} minutes",
+                sender_name,
+                MIN_KEY_ROTATION_INTERVAL.num_minutes()
+            );
+            return Ok(());
+        }
+
         let has_gui = state.has_gui();
         let default = state.friends.fallback_accept_connections;
+        let prompt_name = if is_rotation {
+            format!("{} (rotating keys)", sender_name)
+        } else {
+            sender_name.clone()
+        };
 
         // Release the mutex while we wait for a response from the main thread so that we don't hold
         // up the rest of the program
         drop(guard);
 
         let accept_request = is_connection_accepted(
-            sender_name.clone(),
+            prompt_name,
             gui_channels,
             has_gui,
             default
@@ -344,6 +600,10 @@ pub fn handle_new_txn(
         let state = &mut *guard;
         let (response_req, _) = make_dh_response_req(&data, state)?;
         send_new_txn(response_req, state)?;
+
+        if is_rotation {
+            println!("Rotated keys with {}", sender_name);
+        }
     }
 
     Ok(())
@@ -352,12 +612,12 @@ pub fn handle_new_txn(
 pub fn handle_new_block(
     data: Block,
     socket: TcpStream,
-    state_mut: &Mutex<State>,
+    state_arc: &Arc<Mutex<State>>,
 ) -> Result<(), Box<dyn Error>> {
     let sender = socket.peer_addr().unwrap();
     drop(socket);
 
-    let mut guard = state_mut.lock().unwrap();
+    let mut guard = state_arc.lock().unwrap();
     let state = &mut *guard;
 
     let block_hash = data.header.hash;
@@ -374,25 +634,99 @@ pub fn handle_new_block(
             println!("Error verifying block: {}", err);
             return Ok(());
         }
-        Ok(true) => {
-            println!("Received new orphan block: {}", hex::encode(&block_hash));
+        Ok(BlockStatus::MissingParent(parent)) => {
+            println!(
+                "Received new orphan block {}. Missing parent {}",
+                hex::encode(&block_hash),
+                hex::encode(parent)
+            );
             return Ok(());
         }
-        Ok(false) => {
+        Ok(BlockStatus::Added) => {
             println!("Received new block: {}", hex::encode(&block_hash));
         }
     };
 
     state.resolve_forks();
-
-    let peers = state.network.peer_addrs();
     drop(guard);
 
-    broadcast_async_blast(Request::NewBlock(data), &peers, Some(sender));
+    // Some of the nodes we announce to may be slow or gone, so don't make the next request wait for them
+    let state_arc = Arc::clone(state_arc);
+    thread::Builder::new()
+        .name(String::from("block-announce"))
+        .spawn(move || announce_block(data, &state_arc, Some(sender)))?;
 
     Ok(())
 }
 
 pub fn send_res(res: Response, stream: &TcpStream) -> bincode::Result<()> {
-    bincode::serialize_into(stream, &res)
+    write_frame(stream, &res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::test_util::{add_blocks_after, test_state};
+
+    fn range(blockchain: &BlockchainDB, chain_hint: usize, start_hash: Hash256, count: usize) -> GetBlocksRes {
+        blocks_in_range(
+            blockchain,
+            &GetBlocksRangeReq {
+                chain_hint,
+                start_hash,
+                count,
+            },
+        )
+    }
+
+    fn hashes(res: GetBlocksRes) -> Vec<Hash256> {
+        match res {
+            GetBlocksRes::Blocks(blocks) => blocks.iter().map(|b| b.header.hash).collect(),
+            other => panic!("Expected blocks, got {:?}", other),
+        }
+    }
+
+    fn hashes_of(blocks: &[Block]) -> Vec<Hash256> {
+        blocks.iter().map(|b| b.header.hash).collect()
+    }
+
+    #[test]
+    fn range_follows_requested_chain() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        let main = add_blocks_after(&mut state, genesis, 3);
+        let fork = add_blocks_after(&mut state, main[0].header.hash, 2);
+        let db = &state.blockchain;
+
+        // Main chain
+        assert_eq!(hashes(range(db, 0, genesis, 2)), hashes_of(&main[0..2]));
+        assert_eq!(hashes(range(db, 0, main[1].header.hash, 10)), hashes_of(&main[2..]));
+
+        // From the main chain before the fork point, into the fork
+        let mut expected = hashes_of(&main[0..1]);
+        expected.extend(hashes_of(&fork));
+        assert_eq!(hashes(range(db, 1, genesis, 10)), expected);
+        assert_eq!(hashes(range(db, 1, genesis, 2)), expected[0..2].to_vec());
+
+        // From inside the fork
+        assert_eq!(hashes(range(db, 1, fork[0].header.hash, 10)), hashes_of(&fork[1..]));
+        assert!(hashes(range(db, 1, fork[1].header.hash, 10)).is_empty());
+    }
+
+    #[test]
+    fn range_rejects_bad_requests() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        let main = add_blocks_after(&mut state, genesis, 3);
+        let fork = add_blocks_after(&mut state, main[0].header.hash, 2);
+        let db = &state.blockchain;
+
+        let unknown = rand::random();
+        assert!(matches!(range(db, 0, unknown, 1), GetBlocksRes::UnknownHash(hash) if hash == unknown));
+        assert!(matches!(range(db, 2, genesis, 1), GetBlocksRes::BadChainIndex));
+        // Past the fork point on the main chain
+        assert!(matches!(range(db, 1, main[1].header.hash, 1), GetBlocksRes::DisconnectedChains));
+        // A fork block on the main chain
+        assert!(matches!(range(db, 0, fork[0].header.hash, 1), GetBlocksRes::DisconnectedChains));
+    }
 }
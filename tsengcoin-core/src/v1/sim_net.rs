@@ -0,0 +1,446 @@
+//! A simulated network for tests. Every node is a real [State] with its own data directory, listening on its own
+//! loopback port with [listen_for_connections]. Nodes talk to each other over TCP like they would outside of
+//! tests, except that every connection goes through [sim_connect], which can make a node unreachable.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+
+use super::{
+    block::Block,
+    block_verify::{verify_block, BlockStatus},
+    net::{announce_block, bind_listener, listen_for_connections, DistantNode, DEFAULT_LISTEN_BACKLOG},
+    request::{advertise_self, connect_with_retry, discover, download_latest_blocks, get_first_peers, set_connect_fn},
+    state::{GUIChannels, State},
+    test_util::{next_block, test_state_at},
+};
+
+/// How long to wait for something to happen on the simulated network before failing a test
+pub const SIM_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// Nodes that no one can connect to. Every test uses its own ports, so tests running at the same time don't
+    /// get in each other's way.
+    static ref UNREACHABLE: Mutex<HashSet<SocketAddr>> = Mutex::new(HashSet::new());
+}
+
+pub struct SimNode {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<State>>,
+}
+
+impl SimNode {
+    /// Start a node on a free loopback port. It doesn't know about any other nodes yet.
+    pub fn start() -> Self {
+        set_connect_fn(sim_connect);
+
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), DEFAULT_LISTEN_BACKLOG).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(test_state_at(addr)));
+        let state_listener = Arc::clone(&state);
+
+        thread::Builder::new()
+            .name(format!("sim-listener-{}", addr.port()))
+            .spawn(move || {
+                #[cfg(feature = "gui")]
+                let gui_channels = {
+                    let (req_channel, _) = std::sync::mpsc::channel();
+                    let (_, res_channel) = std::sync::mpsc::channel();
+
+                    GUIChannels { req_channel, res_channel }
+                };
+                #[cfg(not(feature = "gui"))]
+                let gui_channels = GUIChannels {};
+
+                listen_for_connections(listener, &gui_channels, &state_listener).unwrap();
+            })
+            .unwrap();
+
+        Self { addr, state }
+    }
+
+    /// Bootstrap from another node the same way the `connect` command does. The self-probe is skipped because the
+    /// node is already listening.
+    pub fn join(&self, seed: &SimNode) -> Result<(), Box<dyn Error>> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        state.seeds.push(seed.addr);
+        get_first_peers(seed.addr, state)?;
+        discover(seed.addr, state)?;
+        download_latest_blocks(state)?;
+        advertise_self(state)
+    }
+
+    /// Mine a block on this node's main chain and announce it to the network
+    pub fn mine(&self) -> Block {
+        let mut guard = self.state.lock().unwrap();
+        let block = next_block(&guard, vec![]);
+
+        assert_eq!(verify_block(block.clone(), &mut guard).unwrap(), BlockStatus::Added);
+        drop(guard);
+
+        announce_block(block.clone(), &self.state, None);
+
+        block
+    }
+
+    pub fn height(&self) -> usize {
+        self.state.lock().unwrap().blockchain.blocks.len()
+    }
+
+    pub fn has_peer(&self, other: &SimNode) -> bool {
+        self.state.lock().unwrap().network.has_peer(other.addr)
+    }
+
+    /// Add another node to this node's known nodes without making it a peer
+    pub fn learn_about(&self, other: &SimNode) {
+        self.state.lock().unwrap().network.known_nodes.push(DistantNode {
+            addr: other.addr,
+            last_seen: Utc::now(),
+        });
+    }
+
+    pub fn knows_about(&self, other: &SimNode) -> bool {
+        self.state.lock().unwrap().network.known_nodes.iter().any(|n| n.addr == other.addr)
+    }
+
+    /// Make every connection to this node fail, as if it went offline
+    pub fn set_reachable(&self, reachable: bool) {
+        let mut unreachable = UNREACHABLE.lock().unwrap();
+
+        if reachable {
+            unreachable.remove(&self.addr);
+        } else {
+            unreachable.insert(self.addr);
+        }
+    }
+}
+
+fn sim_connect(addr: &SocketAddr) -> io::Result<TcpStream> {
+    if UNREACHABLE.lock().unwrap().contains(addr) {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Simulated node is unreachable"));
+    }
+
+    connect_with_retry(addr)
+}
+
+/// Wait for a condition to become true, failing after [SIM_TIMEOUT]
+pub fn wait_until<F: Fn() -> bool>(cond: F) -> bool {
+    let deadline = Instant::now() + SIM_TIMEOUT;
+
+    while Instant::now() < deadline {
+        if cond() {
+            return true;
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    cond()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::{
+        net::{maintain_peers_once, ConnectionPool, USER_AGENT},
+        chain_request::make_dh_connect_req,
+        encrypted_msg::{is_enc_req, is_enc_req_to_me, ChainRequest, FindMeAtReq},
+        request::{bootstrap, send_new_txn},
+        test_util::{add_old_blocks, pay},
+    };
+
+    #[test]
+    fn new_block_reaches_every_node() {
+        let seed = SimNode::start();
+        let miner = SimNode::start();
+        let other = SimNode::start();
+
+        miner.join(&seed).unwrap();
+        other.join(&seed).unwrap();
+
+        let block = miner.mine();
+
+        assert!(wait_until(|| [&seed, &miner, &other].iter().all(|n| n.height() == 2)));
+
+        for node in [&seed, &other] {
+            assert_eq!(node.state.lock().unwrap().blockchain.top_hash(0), block.header.hash);
+        }
+    }
+
+    #[test]
+    fn new_node_downloads_existing_blocks() {
+        let seed = SimNode::start();
+        let miner = SimNode::start();
+
+        miner.join(&seed).unwrap();
+        miner.mine();
+        miner.mine();
+
+        assert!(wait_until(|| seed.height() == 3));
+
+        let late = SimNode::start();
+        late.join(&seed).unwrap();
+
+        assert_eq!(late.height(), 3);
+    }
+
+    #[test]
+    fn new_node_downloads_old_blocks() {
+        let seed = SimNode::start();
+        add_old_blocks(&mut seed.state.lock().unwrap(), 3);
+
+        let late = SimNode::start();
+        late.join(&seed).unwrap();
+
+        assert_eq!(late.height(), 4);
+    }
+
+    #[test]
+    fn peers_record_each_others_user_agent() {
+        let seed = SimNode::start();
+        let node = SimNode::start();
+
+        node.join(&seed).unwrap();
+
+        for (a, b) in [(&node, &seed), (&seed, &node)] {
+            let state = a.state.lock().unwrap();
+            let peer = state.network.peers.iter().find(|p| p.addr == b.addr).unwrap();
+
+            assert_eq!(peer.user_agent.as_deref(), Some(USER_AGENT));
+        }
+    }
+
+    #[test]
+    fn unreachable_node_is_dropped_when_announcing() {
+        let seed = SimNode::start();
+        let miner = SimNode::start();
+        let offline = SimNode::start();
+
+        miner.join(&seed).unwrap();
+        offline.join(&seed).unwrap();
+        assert!(seed.has_peer(&offline));
+
+        offline.set_reachable(false);
+        miner.mine();
+
+        assert!(wait_until(|| seed.height() == 2 && !seed.has_peer(&offline)));
+        assert_eq!(offline.height(), 1);
+    }
+
+    #[test]
+    fn new_block_reaches_sampled_known_node() {
+        let miner = SimNode::start();
+        let far = SimNode::start();
+
+        miner.learn_about(&far);
+        miner.state.lock().unwrap().network.block_announce_sample = 1;
+        assert!(!miner.has_peer(&far));
+
+        let block = miner.mine();
+
+        assert!(wait_until(|| far.height() == 2));
+        assert_eq!(far.state.lock().unwrap().blockchain.top_hash(0), block.header.hash);
+    }
+
+    #[test]
+    fn unreachable_known_node_is_dropped_when_announcing() {
+        let miner = SimNode::start();
+        let offline = SimNode::start();
+
+        miner.learn_about(&offline);
+        miner.state.lock().unwrap().network.block_announce_sample = 1;
+        offline.set_reachable(false);
+
+        miner.mine();
+
+        assert!(!miner.knows_about(&offline));
+        assert_eq!(offline.height(), 1);
+    }
+
+    #[test]
+    fn node_that_lost_all_peers_bootstraps_from_seed() {
+        let seed = SimNode::start();
+        let node = SimNode::start();
+
+        node.join(&seed).unwrap();
+        node.state.lock().unwrap().network.peers.clear();
+
+        maintain_peers_once(&node.state);
+
+        assert!(node.has_peer(&seed));
+    }
+
+    #[test]
+    fn bootstrap_skips_dead_seed() {
+        let dead = SimNode::start();
+        let seed = SimNode::start();
+        let node = SimNode::start();
+        dead.set_reachable(false);
+
+        bootstrap(&[dead.addr, seed.addr], &mut node.state.lock().unwrap()).unwrap();
+
+        assert!(node.has_peer(&seed));
+        assert!(!node.has_peer(&dead));
+        assert_eq!(node.state.lock().unwrap().remote_addr_me, Some(node.addr));
+    }
+
+    #[test]
+    fn seed_on_different_genesis_is_rejected() {
+        let seed = SimNode::start();
+        let node = SimNode::start();
+
+        seed.state.lock().unwrap().blockchain.blocks[0].header.hash = [9; 32];
+
+        let err = node.join(&seed).unwrap_err();
+
+        assert!(err.to_string().contains("different network"));
+        assert!(!node.has_peer(&seed));
+        assert!(!seed.has_peer(&node));
+    }
+
+    #[test]
+    fn bootstrap_fails_when_every_seed_is_dead() {
+        let dead = SimNode::start();
+        let node = SimNode::start();
+        dead.set_reachable(false);
+
+        assert!(bootstrap(&[dead.addr], &mut node.state.lock().unwrap()).is_err());
+    }
+
+    #[test]
+    fn node_falls_back_to_known_nodes_when_seed_is_down() {
+        let seed = SimNode::start();
+        let other = SimNode::start();
+        let node = SimNode::start();
+
+        other.join(&seed).unwrap();
+        node.join(&seed).unwrap();
+        assert!(node.knows_about(&other));
+
+        node.state.lock().unwrap().network.peers.clear();
+        seed.set_reachable(false);
+
+        maintain_peers_once(&node.state);
+
+        assert!(node.has_peer(&other));
+        assert!(!node.has_peer(&seed));
+    }
+
+    #[test]
+    fn txn_sent_to_dead_peers_reaches_no_one() {
+        let seed = SimNode::start();
+        let other = SimNode::start();
+        let node = SimNode::start();
+
+        other.join(&seed).unwrap();
+        node.join(&seed).unwrap();
+        node.mine();
+        assert!(node.has_peer(&seed) && node.has_peer(&other));
+
+        seed.set_reachable(false);
+        other.set_reachable(false);
+
+        let mut state = node.state.lock().unwrap();
+        let txn = pay(&state, &[1; 20], 10, 1);
+
+        assert_eq!(send_new_txn(txn, &mut state).unwrap(), (0, 2));
+        assert!(state.network.peers.is_empty());
+    }
+
+    #[test]
+    fn txn_below_relay_fee_is_not_relayed() {
+        let seed = SimNode::start();
+        let node = SimNode::start();
+
+        node.join(&seed).unwrap();
+        node.mine();
+        assert!(wait_until(|| seed.height() == 2));
+
+        seed.state.lock().unwrap().min_relay_fee = 5;
+
+        let mut state = node.state.lock().unwrap();
+        state.network.conn_pool = Some(Arc::new(ConnectionPool::default()));
+
+        let cheap = pay(&state, &[1; 20], 10, 2);
+        let paid = pay(&state, &[1; 20], 10, 5);
+
+        send_new_txn(cheap.clone(), &mut state).unwrap();
+        send_new_txn(paid.clone(), &mut state).unwrap();
+        drop(state);
+
+        // Both go over the same pooled stream, so the cheap one has been handled by the time the other one is in the
+        // pool. It spends the same output, so it would have kept the other one out if it had been accepted.
+        assert!(wait_until(|| seed.state.lock().unwrap().pending_txns.contains(&paid)));
+        assert!(!seed.state.lock().unwrap().pending_txns.contains(&cheap));
+    }
+
+    #[test]
+    fn requests_to_us_are_handled_below_relay_fee() {
+        let alice = SimNode::start();
+        let bob = SimNode::start();
+
+        bob.join(&alice).unwrap();
+        alice.mine();
+        assert!(wait_until(|| bob.height() == 2));
+        bob.mine();
+        assert!(wait_until(|| alice.height() == 3));
+
+        let bob_address = bob.state.lock().unwrap().address;
+        let mut state = bob.state.lock().unwrap();
+        state.friends.fallback_accept_connections = true;
+        state.min_relay_fee = 5;
+        drop(state);
+
+        let mut state = alice.state.lock().unwrap();
+        let dh_req = make_dh_connect_req(bob_address, 10, 1, None, &mut state).unwrap();
+        send_new_txn(dh_req.clone(), &mut state).unwrap();
+        drop(state);
+
+        // Bob doesn't relay or keep the request, but still answers it
+        assert!(wait_until(|| alice.state.lock().unwrap().friends.is_connected(&bob_address)));
+        assert!(!bob.state.lock().unwrap().pending_txns.contains(&dh_req));
+    }
+
+    #[test]
+    fn queued_intent_is_sent_once_key_exchange_completes() {
+        let alice = SimNode::start();
+        let bob = SimNode::start();
+
+        bob.join(&alice).unwrap();
+        alice.mine();
+        assert!(wait_until(|| bob.height() == 2));
+        bob.mine();
+        assert!(wait_until(|| alice.height() == 3));
+
+        let bob_address = bob.state.lock().unwrap().address;
+        bob.state.lock().unwrap().friends.fallback_accept_connections = true;
+
+        let intent = ChainRequest::FindMeAt(FindMeAtReq { addr: alice.addr });
+        let mut state = alice.state.lock().unwrap();
+        let dh_req = make_dh_connect_req(bob_address, 10, 1, Some(intent), &mut state).unwrap();
+        send_new_txn(dh_req, &mut state).unwrap();
+        drop(state);
+
+        // Bob answers the key exchange, then Alice sends the intent encrypted with the new keys
+        assert!(wait_until(|| {
+            let state = bob.state.lock().unwrap();
+            state.pending_txns.iter().any(|t| is_enc_req(t) && is_enc_req_to_me(t, &state))
+        }));
+
+        let state = alice.state.lock().unwrap();
+        assert!(state.friends.is_connected(&bob_address));
+        assert!(state.friends.intents.is_empty());
+        assert!(state.friends.pending_dh.is_empty());
+    }
+}
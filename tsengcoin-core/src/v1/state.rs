@@ -1,11 +1,15 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
-    fs,
+    fs::{self, OpenOptions},
+    io::{self, Write},
     net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
     sync::mpsc::{channel, Receiver, Sender},
 };
 
+use chrono::Duration;
 use ring::signature::{EcdsaKeyPair, KeyPair};
 
 #[cfg(feature = "gui")]
@@ -16,22 +20,41 @@ use crate::{
 };
 
 use super::{
-    block::{genesis_block, resolve_forks, Block, BlockchainDB},
-    chain_request::FriendState,
-    miners::{api::MinerMessage, stats::MinerStatsState},
-    net::Network,
-    transaction::{Transaction, TransactionIndex, UTXOPool, p2pkh_balance, get_balance_diff, ClaimedUTXO},
+    block::{check_orphans, genesis_block, resolve_forks, truncate_main_chain, Block, BlockchainDB},
+    block_verify_error::BlockVerifyResult,
+    chain_request::{FriendSettings, FriendState},
+    miners::{
+        api::{MinerMessage, DEFAULT_BLOCK_REFRESH},
+        stats::{MinerCounters, MinerStatsState},
+    },
+    net::{Network, DEFAULT_BLOCK_ANNOUNCE_SAMPLE},
+    txn_verify::check_pending_and_orphans,
+    transaction::{
+        Transaction, UTXOWindow, p2pkh_balance, p2pkh_utxos_for_addr, get_balance_diff,
+        ClaimedUTXO, MIN_TXN_FEE,
+    },
 };
 
 /// TODO: Implement blockchain DB in filesystem or at least have a feature to enable it so we don't have to
 /// download blocks every time
 pub const DATA_DIR: &str = ".data";
 pub const BLOCKCHAIN_DB_FILE: &str = "blockchain";
+/// Orphan transactions are saved separately from the blockchain. Orphan blocks are part of the [BlockchainDB].
+pub const ORPHAN_TXNS_FILE: &str = "orphan_txns";
+/// Exclusivity, connection policy, and other settings for encrypted chain requests
+pub const FRIEND_SETTINGS_FILE: &str = "friend_settings";
+/// Held by a running client so that two clients don't use the same data directory
+pub const LOCK_FILE: &str = "LOCK";
 
 #[derive(Debug)]
 pub struct State {
     pub local_addr_me: SocketAddr,
+    /// Where the blockchain, orphan pools, and settings are loaded from and saved to. This is always [DATA_DIR]
+    /// outside of tests.
+    pub data_dir: String,
     pub remote_addr_me: Option<SocketAddr>,
+    /// Nodes we bootstrapped from. If we ever lose all of our peers, we go back to these.
+    pub seeds: Vec<SocketAddr>,
     pub network: Network,
     pub keypair: EcdsaKeyPair,
     pub address: Address,
@@ -42,7 +65,6 @@ pub struct State {
     pub hashes_per_second: usize,
     /// A "friend" is someone who has completed a Diffie-Hellman key exchange with us. Friends can send each other encrypted requests using
     /// a shared secret.
-    /// TODO: Double ratchet
     pub friends: FriendState,
     #[cfg(feature = "gui")]
     pub gui_req_sender: Sender<GUIRequest>,
@@ -50,12 +72,26 @@ pub struct State {
     pub gui: Option<GUIState>,
     pub miner: Option<String>,
     pub miner_stats: Option<MinerStatsState>,
+    pub miner_counters: MinerCounters,
     /// Work group size, only meaningful for the CL miner.
     pub wg_size: Option<usize>,
     /// Number of work groups
     pub num_work_groups: Option<usize>,
+    /// If true, the CL miner benchmarks a few work group configurations at startup and uses the fastest one
+    /// instead of [State::wg_size] and [State::num_work_groups]
+    pub cl_autotune: bool,
     /// Default transaction fee
     pub default_fee: u64,
+    /// Transactions paying less than this aren't added to the pending pool or relayed. This is local policy:
+    /// blocks can still contain transactions that pay as little as [MIN_TXN_FEE].
+    pub min_relay_fee: u64,
+    /// If true, transactions whose scripts leave anything besides the result on the stack aren't added to the
+    /// pending pool or relayed. This is local policy: blocks can still contain them.
+    pub clean_stack: bool,
+    /// Message to put in the meta field of coinbase transactions mined by this client
+    pub coinbase_msg: String,
+    /// How often the miner should make a new candidate block
+    pub block_refresh: Duration,
     /// UTXOs with custom unlock scripts
     claimed_utxos: Vec<ClaimedUTXO>,
 
@@ -64,6 +100,63 @@ pub struct State {
     /// Total amount of TsengCoin owned by the client's address. This needs to be computed after
     /// constructing a State
     balance: u64,
+
+    _data_lock: DataDirLock,
+}
+
+/// A lock on the data directory. The lock file is deleted when this is dropped.
+#[derive(Debug)]
+pub struct DataDirLock {
+    path: PathBuf,
+}
+
+impl DataDirLock {
+    /// Create the lock file in the given directory. Fails if another process already holds the lock.
+    pub fn acquire(dir: &str) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+
+        let path = Path::new(dir).join(LOCK_FILE);
+
+        if lock_is_stale(&path) {
+            fs::remove_file(&path)?;
+        }
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", process::id())?;
+
+                Ok(Self { path })
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(format!(
+                "Another TsengCoin client is using {}. If no other client is running, delete {} and try again",
+                dir,
+                path.display()
+            )
+            .into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A lock is stale if the process that created it isn't running anymore, which can happen if the client
+/// was killed. We can only check this on Linux.
+#[cfg(target_os = "linux")]
+fn lock_is_stale(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(pid) if !pid.trim().is_empty() => !Path::new(&format!("/proc/{}", pid.trim())).exists(),
+        _ => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_is_stale(_path: &Path) -> bool {
+    false
 }
 
 #[cfg(feature = "gui")]
@@ -84,57 +177,110 @@ impl State {
         #[cfg(feature = "gui")]
         gui: Option<GUIState>,
         miner: Option<String>,
-    ) -> (Self, Receiver<MinerMessage>) {
+    ) -> Result<(Self, Receiver<MinerMessage>), Box<dyn Error>> {
+        Self::open(
+            addr_me,
+            keypair,
+            DATA_DIR,
+            #[cfg(feature = "gui")]
+            gui_req_sender,
+            #[cfg(feature = "gui")]
+            gui,
+            miner,
+        )
+    }
+
+    /// Same as [State::new], but with the data directory given. This lets tests run several nodes in one process.
+    pub fn open(
+        addr_me: SocketAddr,
+        keypair: EcdsaKeyPair,
+        data_dir: &str,
+        #[cfg(feature = "gui")]
+        gui_req_sender: Sender<GUIRequest>,
+        #[cfg(feature = "gui")]
+        gui: Option<GUIState>,
+        miner: Option<String>,
+    ) -> Result<(Self, Receiver<MinerMessage>), Box<dyn Error>> {
+        let data_lock = DataDirLock::acquire(data_dir)?;
         let address = address_from_public_key(&keypair.public_key().as_ref().to_vec());
-        let blockchain = load_blockchain_db();
+        let blockchain = load_blockchain_db(data_dir);
+        let orphan_txns = load_orphan_txns(data_dir);
         let (miner_sender, miner_receiver) = channel();
 
-        (
-            Self {
-                local_addr_me: addr_me,
-                remote_addr_me: None,
-                network: Network {
-                    peers: vec![],
-                    known_nodes: vec![],
-                },
-                keypair,
-                address,
-                blockchain,
-                pending_txns: vec![],
-                orphan_txns: vec![],
-                hashes_per_second: 0,
-                friends: FriendState {
-                    pending_dh: HashMap::new(),
-                    intents: HashMap::new(),
-                    aliases: HashMap::new(),
-                    keys: HashMap::new(),
-                    exclusivity: 1,
-                    chain_req_amount: 1,
-                    chat_sessions: HashMap::new(),
-                    fallback_accept_connections: false,
-                },
-                #[cfg(feature = "gui")]
-                gui_req_sender,
-                #[cfg(feature = "gui")]
-                gui,
-                miner,
-                miner_stats: None,
-                wg_size: None,
-                num_work_groups: None,
-                miner_channel: miner_sender,
-                balance: 0,
-                default_fee: 1,
-                claimed_utxos: vec![],
+        let mut state = Self {
+            local_addr_me: addr_me,
+            data_dir: data_dir.to_owned(),
+            remote_addr_me: None,
+            seeds: vec![],
+            network: Network {
+                peers: vec![],
+                known_nodes: vec![],
+                conn_pool: None,
+                seen_adverts: HashMap::new(),
+                block_announce_sample: DEFAULT_BLOCK_ANNOUNCE_SAMPLE,
+                allow_loopback: addr_me.ip().is_loopback(),
             },
-            miner_receiver,
-        )
+            keypair,
+            address,
+            blockchain,
+            pending_txns: vec![],
+            orphan_txns,
+            hashes_per_second: 0,
+            friends: FriendState {
+                pending_dh: HashMap::new(),
+                intents: HashMap::new(),
+                aliases: HashMap::new(),
+                keys: HashMap::new(),
+                exclusivity: 1,
+                chain_req_amount: 1,
+                chat_sessions: HashMap::new(),
+                fallback_accept_connections: false,
+                key_rotations: HashMap::new(),
+            },
+            #[cfg(feature = "gui")]
+            gui_req_sender,
+            #[cfg(feature = "gui")]
+            gui,
+            miner,
+            miner_stats: None,
+            miner_counters: MinerCounters::default(),
+            wg_size: None,
+            num_work_groups: None,
+            cl_autotune: false,
+            miner_channel: miner_sender,
+            balance: 0,
+            default_fee: 1,
+            min_relay_fee: MIN_TXN_FEE,
+            clean_stack: false,
+            coinbase_msg: String::from(""),
+            block_refresh: *DEFAULT_BLOCK_REFRESH,
+            claimed_utxos: vec![],
+            _data_lock: data_lock,
+        };
+
+        if let Some(settings) = load_friend_settings(data_dir) {
+            state.friends.apply_settings(&settings);
+        }
+
+        // Some of the orphans we saved may have been resolved by blocks we saved
+        check_orphans(&mut state);
+        check_pending_and_orphans(&mut state);
+
+        Ok((state, miner_receiver))
     }
 
-    /// TODO: Save the blockchain to a file
+    /// Save the blockchain (including orphan blocks), the orphan transaction pool, and friend settings
+    /// to the data directory
     pub fn save(&self) -> Result<(), Box<dyn Error>> {
         let db_bytes = bincode::serialize(&self.blockchain)?;
+        let orphan_bytes = bincode::serialize(&self.orphan_txns)?;
+        let settings_bytes = bincode::serialize(&self.friends.settings())?;
+
+        let data_dir = &self.data_dir;
 
-        fs::write(format!("{DATA_DIR}/{BLOCKCHAIN_DB_FILE}"), db_bytes)?;
+        fs::write(format!("{data_dir}/{BLOCKCHAIN_DB_FILE}"), db_bytes)?;
+        fs::write(format!("{data_dir}/{ORPHAN_TXNS_FILE}"), orphan_bytes)?;
+        fs::write(format!("{data_dir}/{FRIEND_SETTINGS_FILE}"), settings_bytes)?;
 
         Ok(())
     }
@@ -200,18 +346,24 @@ impl State {
         }
     }
 
-    pub fn add_block(&mut self, block: Block) {
+    pub fn add_block(&mut self, block: Block) -> BlockVerifyResult<()> {
         let hash = block.header.hash;
-        self.blockchain.add_block(block);
+        self.blockchain.add_block(block)?;
         match self.miner_channel.send(MinerMessage::NewBlock(hash, true)) {
             Ok(_) | Err(_) => (),
         };
 
         self.compute_balance();
+
+        Ok(())
     }
 
-    pub fn resolve_forks(&mut self) {
-        if resolve_forks(self) {
+    /// Resolves forks and tells the miner about it. Returns the hashes of transactions from removed blocks
+    /// that went back into the pending pool, or None if there were no forks to resolve.
+    pub fn resolve_forks(&mut self) -> Option<Vec<Hash256>> {
+        let returned = resolve_forks(self);
+
+        if returned.is_some() {
             let hash = self.blockchain.top_hash(0);
             match self.miner_channel.send(MinerMessage::NewBlock(hash, true)) {
                 Ok(_) | Err(_) => (),
@@ -219,6 +371,22 @@ impl State {
 
             self.compute_balance();
         }
+
+        returned
+    }
+
+    /// Throws away main chain blocks above the given height and tells the miner about it. See [truncate_main_chain].
+    pub fn truncate_main_chain(&mut self, height: usize) -> Result<(usize, Vec<Hash256>), Box<dyn Error>> {
+        let result = truncate_main_chain(self, height)?;
+
+        let hash = self.blockchain.top_hash(0);
+        match self.miner_channel.send(MinerMessage::NewBlock(hash, true)) {
+            Ok(_) | Err(_) => (),
+        };
+
+        self.compute_balance();
+
+        Ok(result)
     }
 
     /// Returns true if there is a main GUI attached to the program: TsengCoin core can run in
@@ -251,6 +419,26 @@ impl State {
         }
     }
 
+    /// A snapshot of the P2PKH outputs addressed to this client that can still be spent. Outputs spent by pending
+    /// transactions are already gone from the UTXO pool, but an orphan transaction can spend outputs that are
+    /// still in the pool, and so can a transaction that's still being built. Those are left out so that the
+    /// transaction builder never offers coins that are already committed to another transaction. `reserved` holds
+    /// the outputs that the caller has already picked for its in-progress transaction.
+    pub fn spendable_utxos(&self, reserved: &[UTXOWindow]) -> Vec<UTXOWindow> {
+        let spent = self
+            .pending_txns
+            .iter()
+            .chain(self.orphan_txns.iter())
+            .flat_map(|txn| txn.inputs.iter().map(|input| (input.txn_hash, input.output_idx)))
+            .chain(reserved.iter().map(|utxo| (utxo.txn, utxo.output)))
+            .collect::<HashSet<(Hash256, usize)>>();
+
+        p2pkh_utxos_for_addr(self, self.address)
+            .into_iter()
+            .filter(|utxo| !spent.contains(&(utxo.txn, utxo.output)))
+            .collect()
+    }
+
     pub fn claim_utxo(&mut self, claimed_utxo: ClaimedUTXO) -> Result<(), &str> {
         if self.claimed_utxos.iter().any(|c| c.window.txn == claimed_utxo.window.txn) {
             return Err("Output is already claimed");
@@ -262,10 +450,35 @@ impl State {
     }
 }
 
-pub fn load_blockchain_db() -> BlockchainDB {
-    fs::create_dir_all(DATA_DIR).unwrap();
+/// Load the orphan transactions saved by [State::save]. They haven't been verified against the current
+/// blockchain yet.
+pub fn load_orphan_txns(data_dir: &str) -> Vec<Transaction> {
+    match fs::read(format!("{data_dir}/{ORPHAN_TXNS_FILE}")) {
+        Err(_) => vec![],
+        Ok(bytes) => bincode::deserialize(&bytes).unwrap_or_else(|err| {
+            println!("Discarding saved orphan transactions: {}", err);
+            vec![]
+        }),
+    }
+}
+
+/// Load the friend settings saved by [State::save], if there are any
+pub fn load_friend_settings(data_dir: &str) -> Option<FriendSettings> {
+    let bytes = fs::read(format!("{data_dir}/{FRIEND_SETTINGS_FILE}")).ok()?;
+
+    match bincode::deserialize(&bytes) {
+        Ok(settings) => Some(settings),
+        Err(err) => {
+            println!("Discarding saved friend settings: {}", err);
+            None
+        }
+    }
+}
 
-    let db_res = fs::read(format!("{DATA_DIR}/{BLOCKCHAIN_DB_FILE}"));
+pub fn load_blockchain_db(data_dir: &str) -> BlockchainDB {
+    fs::create_dir_all(data_dir).unwrap();
+
+    let db_res = fs::read(format!("{data_dir}/{BLOCKCHAIN_DB_FILE}"));
     if db_res.is_ok() {
         let bytes = db_res.unwrap();
         let out: BlockchainDB = bincode::deserialize(&bytes).unwrap();
@@ -273,20 +486,129 @@ pub fn load_blockchain_db() -> BlockchainDB {
         return out;
     }
 
-    let genesis = genesis_block();
-    let block_hash = genesis.header.hash;
-    let txn_hash = genesis.transactions[0].hash;
-
-    BlockchainDB {
-        blocks: vec![genesis],
-        forks: vec![],
-        orphans: vec![],
-        utxo_pool: UTXOPool {
-            utxos: vec![TransactionIndex {
-                block: Some(block_hash),
-                txn: txn_hash,
-                outputs: vec![0],
-            }],
+    BlockchainDB::new(genesis_block())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::{
+        block_verify::{verify_block, BlockStatus},
+        test_util::{
+            add_blocks_after, make_block, next_block, open_state, sign_p2pkh, temp_data_dir, test_genesis,
+            test_keypair, test_state,
         },
+        transaction::{collect_enough_change, make_p2pkh_lock, TxnOutput},
+    };
+
+    #[test]
+    fn orphans_survive_restart() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let data_dir = temp_data_dir();
+
+        let mut state = open_state(addr, &data_dir);
+        state.blockchain = BlockchainDB::new(test_genesis());
+
+        let parent = next_block(&state, vec![]);
+        let orphan = make_block(parent.header.hash, &state.address, 0, vec![]);
+        assert!(matches!(verify_block(orphan, &mut state), Ok(BlockStatus::MissingParent(_))));
+
+        state.save().unwrap();
+        drop(state);
+
+        let mut state = open_state(addr, &data_dir);
+        assert_eq!(state.blockchain.orphans.len(), 1);
+
+        verify_block(parent, &mut state).unwrap();
+        assert_eq!(check_orphans(&mut state), 1);
+        assert_eq!(state.blockchain.blocks.len(), 3);
+    }
+
+    #[test]
+    fn friend_settings_survive_restart() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let data_dir = temp_data_dir();
+
+        let mut state = open_state(addr, &data_dir);
+        assert!(!state.friends.fallback_accept_connections);
+
+        state.friends.fallback_accept_connections = true;
+        state.friends.chain_req_amount += 1;
+        let chain_req_amount = state.friends.chain_req_amount;
+
+        state.save().unwrap();
+        drop(state);
+
+        let state = open_state(addr, &data_dir);
+        assert!(state.friends.fallback_accept_connections);
+        assert_eq!(state.friends.chain_req_amount, chain_req_amount);
+    }
+
+    #[test]
+    fn spendable_utxos_leave_out_committed_outputs() {
+        let mut state = test_state();
+        let genesis = state.blockchain.top_hash(0);
+        let blocks = add_blocks_after(&mut state, genesis, 3);
+        let coinbases = blocks.iter().map(|b| b.transactions[0].hash).collect::<Vec<Hash256>>();
+        let all = state.spendable_utxos(&[]);
+        assert_eq!(all.len(), 3);
+
+        // An orphan transaction spends the first output and the caller has already picked the second one
+        let outputs = vec![TxnOutput {
+            amount: 1,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        }];
+        state.orphan_txns.push(sign_p2pkh(&state, &[(coinbases[0], 0)], outputs));
+        let reserved = all.iter().filter(|u| u.txn == coinbases[1]).cloned().collect::<Vec<UTXOWindow>>();
+
+        let spendable = state.spendable_utxos(&reserved);
+        assert_eq!(spendable.len(), 1);
+        assert_eq!(spendable[0].txn, coinbases[2]);
+
+        // The transaction builder doesn't pick the output spent by the orphan either
+        let reward = all[0].amount;
+        assert!(collect_enough_change(&state, 3 * reward).is_none());
+
+        let change = collect_enough_change(&state, 2 * reward).unwrap();
+        assert!(change.iter().all(|u| u.txn != coinbases[0]));
+    }
+
+    #[test]
+    fn data_dir_can_only_be_locked_once() {
+        let data_dir = temp_data_dir();
+        let lock = DataDirLock::acquire(&data_dir).unwrap();
+
+        assert!(DataDirLock::acquire(&data_dir).is_err());
+
+        drop(lock);
+        assert!(DataDirLock::acquire(&data_dir).is_ok());
+    }
+
+    #[test]
+    fn second_state_cannot_open_locked_data_dir() {
+        let data_dir = temp_data_dir();
+        let _lock = DataDirLock::acquire(&data_dir).unwrap();
+        let res = State::open(
+            "127.0.0.1:0".parse().unwrap(),
+            test_keypair(),
+            &data_dir,
+            #[cfg(feature = "gui")]
+            std::sync::mpsc::channel().0,
+            #[cfg(feature = "gui")]
+            None,
+            None,
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stale_lock_is_replaced() {
+        let data_dir = temp_data_dir();
+        fs::create_dir_all(&data_dir).unwrap();
+        fs::write(Path::new(&data_dir).join(LOCK_FILE), u32::MAX.to_string()).unwrap();
+
+        assert!(DataDirLock::acquire(&data_dir).is_ok());
     }
 }
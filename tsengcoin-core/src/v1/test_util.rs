@@ -0,0 +1,238 @@
+//! Helpers for tests that need a [State] or blocks that pass verification
+
+use std::{
+    env, fs,
+    net::SocketAddr,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "gui")]
+use std::sync::mpsc::channel;
+
+use chrono::{Duration, Utc};
+use ring::{
+    digest::{Context, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+
+use crate::{
+    commands::session::make_p2pkh_txn,
+    difficulty::{get_difficulty_bits, get_difficulty_target, REGTEST_TARGET},
+    wallet::{Address, Hash256},
+};
+
+use super::{
+    block::{make_merkle_root, Block, BlockchainDB, RawBlockHeader},
+    block_verify::{verify_block, verify_historical_block, BlockStatus},
+    state::State,
+    transaction::{
+        compute_fee, hash_txn, make_coinbase_txn, make_p2pkh_unlock, sign_txn, Transaction, TxnInput, TxnOutput,
+        UnhashedTransaction, UnsignedTransaction,
+    },
+    VERSION,
+};
+
+static NEXT_DATA_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A new, empty data directory. Tests run in parallel, so every [State] needs its own.
+pub fn temp_data_dir() -> String {
+    let n = NEXT_DATA_DIR.fetch_add(1, Ordering::SeqCst);
+    let dir = env::temp_dir().join(format!("tsengcoin-test-{}-{}", process::id(), n));
+    let _ = fs::remove_dir_all(&dir);
+
+    dir.to_string_lossy().into_owned()
+}
+
+pub fn test_keypair() -> EcdsaKeyPair {
+    let rng = SystemRandom::new();
+    let alg = &ECDSA_P256_SHA256_ASN1_SIGNING;
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+
+    EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).unwrap()
+}
+
+/// The genesis block used in tests. Its target is [REGTEST_TARGET], the easiest one allowed, so that blocks on top of
+/// it can be mined in a fraction of a second.
+pub fn test_genesis() -> Block {
+    let coinbase = make_coinbase_txn(&[0; 20], String::from("test genesis block"), 0, [0; 32]);
+    let txns = vec![coinbase];
+
+    let header = RawBlockHeader {
+        version: VERSION,
+        prev_hash: [0; 32],
+        merkle_root: make_merkle_root(&txns),
+        timestamp: 1669939462,
+        bits: get_difficulty_bits(&REGTEST_TARGET),
+        nonce: [0; 32],
+    };
+
+    let hash = header_hash(&header);
+
+    Block {
+        header: header.to_block_header([0; 32], hash),
+        transactions: txns,
+    }
+}
+
+/// A [State] with its own data directory, listening on `addr`, with only the [test_genesis] block
+pub fn test_state_at(addr: SocketAddr) -> State {
+    let mut state = open_state(addr, &temp_data_dir());
+
+    state.blockchain = BlockchainDB::new(test_genesis());
+    state.compute_balance();
+
+    state
+}
+
+/// A [State] loaded from whatever is saved in `data_dir`
+pub fn open_state(addr: SocketAddr, data_dir: &str) -> State {
+    let (state, _) = State::open(
+        addr,
+        test_keypair(),
+        data_dir,
+        #[cfg(feature = "gui")]
+        channel().0,
+        #[cfg(feature = "gui")]
+        None,
+        None,
+    )
+    .unwrap();
+
+    state
+}
+
+pub fn test_state() -> State {
+    test_state_at("127.0.0.1:0".parse().unwrap())
+}
+
+/// Mine a block on top of `prev_hash` with the given transactions after the coinbase. The coinbase pays the block
+/// reward and `fees` to `miner`.
+pub fn make_block(prev_hash: Hash256, miner: &Address, fees: u64, txns: Vec<Transaction>) -> Block {
+    make_block_at(prev_hash, miner, fees, txns, Utc::now().timestamp().try_into().unwrap())
+}
+
+/// Same as [make_block], but with the given timestamp
+pub fn make_block_at(prev_hash: Hash256, miner: &Address, fees: u64, txns: Vec<Transaction>, timestamp: u64) -> Block {
+    let mut block_txns = vec![make_coinbase_txn(miner, String::from(""), fees, rand::random())];
+    block_txns.extend(txns);
+
+    let header = RawBlockHeader {
+        version: VERSION,
+        prev_hash,
+        merkle_root: make_merkle_root(&block_txns),
+        timestamp,
+        bits: get_difficulty_bits(&REGTEST_TARGET),
+        nonce: [0; 32],
+    };
+
+    mine(header, block_txns)
+}
+
+/// Mine the next block on the main chain, paying to the state's address. The transactions must already be in
+/// the pending pool so that their fees can be worked out.
+pub fn next_block(state: &State, txns: Vec<Transaction>) -> Block {
+    let fees = txns.iter().map(|t| compute_fee(t, state)).sum();
+
+    make_block(state.blockchain.top_hash(0), &state.address, fees, txns)
+}
+
+/// Add `count` blocks to the main chain that are a day older than the timestamp tolerance allows for new blocks
+pub fn add_old_blocks(state: &mut State, count: usize) {
+    let day_ago = (Utc::now() - Duration::days(1)).timestamp() as u64;
+
+    for i in 0..count {
+        let block = make_block_at(state.blockchain.top_hash(0), &state.address, 0, vec![], day_ago + i as u64);
+        assert_eq!(verify_historical_block(block, state).unwrap(), BlockStatus::Added);
+    }
+}
+
+/// Mine `count` blocks in a row on top of `prev_hash` and verify them. Blocks that don't extend the main chain
+/// end up in a fork.
+pub fn add_blocks_after(state: &mut State, prev_hash: Hash256, count: usize) -> Vec<Block> {
+    let mut out: Vec<Block> = vec![];
+    let mut prev_hash = prev_hash;
+
+    for _ in 0..count {
+        let block = make_block(prev_hash, &state.address, 0, vec![]);
+        prev_hash = block.header.hash;
+
+        assert_eq!(verify_block(block.clone(), state).unwrap(), BlockStatus::Added);
+        out.push(block);
+    }
+
+    out
+}
+
+/// Find a nonce that satisfies the header's target. Regtest doesn't check proof of work, so there's nothing to find.
+pub fn mine(mut header: RawBlockHeader, txns: Vec<Transaction>) -> Block {
+    let target = get_difficulty_target(header.bits).unwrap();
+
+    loop {
+        let hash = header_hash(&header);
+
+        if cfg!(feature = "regtest") || hash < target {
+            return Block {
+                header: header.to_block_header(header.nonce, hash),
+                transactions: txns,
+            };
+        }
+
+        header.nonce = rand::random();
+    }
+}
+
+/// A signed P2PKH transaction from the state's address
+pub fn pay(state: &State, dest: &Address, amount: u64, fee: u64) -> Transaction {
+    make_p2pkh_txn(state, dest, amount, fee).unwrap().expect("Not enough TsengCoin")
+}
+
+/// A transaction with the given inputs and outputs. The inputs aren't checked or signed.
+pub fn make_txn(inputs: Vec<TxnInput>, outputs: Vec<TxnOutput>) -> Transaction {
+    let unhashed = UnhashedTransaction {
+        version: VERSION,
+        inputs,
+        outputs,
+        meta: String::from(""),
+    };
+    let hash = hash_txn(&unhashed).unwrap();
+
+    Transaction {
+        version: unhashed.version,
+        inputs: unhashed.inputs,
+        outputs: unhashed.outputs,
+        meta: unhashed.meta,
+        hash,
+    }
+}
+
+/// A transaction that spends P2PKH outputs paying to the state's address, signed with the state's key
+pub fn sign_p2pkh(state: &State, spent: &[(Hash256, usize)], outputs: Vec<TxnOutput>) -> Transaction {
+    let unsigned = UnsignedTransaction {
+        version: VERSION,
+        outputs,
+        meta: String::from(""),
+    };
+    let sig = sign_txn(&unsigned, &state.keypair).unwrap();
+    let unlock_script = make_p2pkh_unlock(sig, state.keypair.public_key().as_ref().to_vec());
+    let inputs = spent
+        .iter()
+        .map(|(txn_hash, output_idx)| TxnInput {
+            txn_hash: *txn_hash,
+            output_idx: *output_idx,
+            unlock_script: unlock_script.clone(),
+        })
+        .collect();
+
+    make_txn(inputs, unsigned.outputs)
+}
+
+/// Same as [hash_block_header](super::block::hash_block_header), but much faster in debug builds
+fn header_hash(header: &RawBlockHeader) -> Hash256 {
+    let bytes = bincode::serialize(header).unwrap();
+    let mut context = Context::new(&SHA256);
+    context.update(&bytes);
+
+    context.finish().as_ref().try_into().unwrap()
+}
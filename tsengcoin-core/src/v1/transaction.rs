@@ -6,11 +6,16 @@ use ring::{
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     error::Error,
     mem::{size_of, size_of_val},
+    sync::Mutex,
 };
 
-use crate::wallet::{Address, Hash256};
+use crate::{
+    tsengscript_interpreter::count_sigops,
+    wallet::{hex_to_address, Address, Hash256},
+};
 
 use super::{block::Block, state::State, VERSION};
 
@@ -20,9 +25,25 @@ pub const MAX_META_LENGTH: usize = 1024;
 pub const MAX_TXN_AMOUNT: u64 = 1_000_000_000;
 /// Every transaction must give up at least 1 TsengCoin as a tx fee
 pub const MIN_TXN_FEE: u64 = 1;
+/// Fees above this are probably a typo, so the client asks for confirmation before sending them
+pub const MAX_SANE_FEE: u64 = BLOCK_REWARD;
 
 pub const COINBASE_OUTPUT_IDX: usize = 0xFFFF_FFFF;
 
+/// Max number of lock scripts to remember in [get_p2pkh_addr]
+const P2PKH_CACHE_SIZE: usize = 4096;
+/// Longest possible ASN.1 encoded P-256 signature. Signatures are usually 70 or 71 bytes, but the
+/// length depends on the values of r and s.
+pub const MAX_SIG_LEN: usize = 72;
+/// Length of an uncompressed P-256 public key
+pub const PUBKEY_LEN: usize = 65;
+
+#[cfg(test)]
+thread_local! {
+    /// How many lock scripts this thread has parsed in [get_p2pkh_addr]. Tests use this to check the cache.
+    static P2PKH_PARSES: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Transaction {
     /// Protocol version
@@ -138,6 +159,49 @@ impl Transaction {
             + size_of::<usize>()
             + size_of_val(&self.hash)
     }
+
+    /// A coinbase has exactly one input, and that input is a [coinbase input](TxnInput::is_coinbase).
+    /// Use this everywhere a coinbase needs to be recognized so that block verification and the UTXO pool
+    /// always agree on which transactions are coinbases.
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].is_coinbase()
+    }
+
+    /// The number of signature checks in this transaction's unlock scripts and output lock scripts. This doesn't
+    /// include the lock scripts of the outputs being spent, which run when the transaction is verified. See
+    /// [spent_sigops] for those.
+    pub fn sigops(&self) -> usize {
+        let unlock_sigops = self.inputs.iter().fold(0, |a, i| a + count_sigops(&i.unlock_script.code));
+        let lock_sigops = self.outputs.iter().fold(0, |a, o| a + count_sigops(&o.lock_script.code));
+
+        unlock_sigops + lock_sigops
+    }
+}
+
+impl UnsignedTransaction {
+    /// Predict the size of the signed transaction with the given number of P2PKH inputs. Signatures
+    /// don't have a fixed length, so this assumes the longest one. Use this instead of [Transaction::size]
+    /// to compute a fee before the transaction is signed.
+    pub fn vsize(&self, num_inputs: usize) -> usize {
+        let input_size = size_of::<Hash256>() + size_of::<usize>() + p2pkh_unlock_size_estimate();
+
+        size_of_val(&self.version)
+            + num_inputs * input_size
+            + self.outputs.iter().fold(0, |a, e| a + e.size())
+            + self.meta.len()
+            + size_of::<usize>()
+            + size_of::<Hash256>()
+    }
+}
+
+impl Transaction {
+    /// The size of this transaction as predicted by [UnsignedTransaction::vsize]. This doesn't depend on
+    /// the signatures in the unlock scripts, so it's the same before and after signing.
+    pub fn vsize(&self) -> usize {
+        let unsigned: UnsignedTransaction = self.into();
+
+        unsigned.vsize(self.inputs.len())
+    }
 }
 
 impl UnhashedTransaction {
@@ -162,6 +226,17 @@ impl TxnInput {
     pub fn size(&self) -> usize {
         size_of_val(&self.txn_hash) + size_of_val(&self.output_idx) + self.unlock_script.size()
     }
+
+    /// A coinbase input has a zero transaction hash and [COINBASE_OUTPUT_IDX] as its output index
+    pub fn is_coinbase(&self) -> bool {
+        self.txn_hash == [0; 32] && self.output_idx == COINBASE_OUTPUT_IDX
+    }
+
+    /// True if the input has either half of the coinbase marker. Only the coinbase input of the first transaction
+    /// in a block may have it; anywhere else it's an attempt to pass off a transaction as a coinbase.
+    pub fn has_coinbase_marker(&self) -> bool {
+        self.txn_hash == [0; 32] || self.output_idx == COINBASE_OUTPUT_IDX
+    }
 }
 
 impl Script {
@@ -179,12 +254,14 @@ impl UTXOPool {
     /// for the outputs of this transaction.
     /// Assumes that this is a valid transaction and all UTXOS are already in the pool.
     pub fn update_unconfirmed(&mut self, tx: &Transaction) {
-        // Handle coinbase transactions separately
-        if tx.inputs.len() == 1 && tx.inputs[0].output_idx == COINBASE_OUTPUT_IDX {
+        // Handle coinbase transactions separately. A coinbase doesn't spend anything, but every one
+        // of its outputs is spendable. `verify_block` only accepts single-output coinbases today, but
+        // we index every output so that the pool always matches the transaction.
+        if tx.is_coinbase() {
             let txn_idx = TransactionIndex {
                 block: None,
                 txn: tx.hash,
-                outputs: vec![0],
+                outputs: (0..tx.outputs.len()).collect::<Vec<usize>>(),
             };
 
             self.utxos.push(txn_idx);
@@ -220,11 +297,12 @@ impl UTXOPool {
     }
 
     pub fn update_confirmed(&mut self, tx: &Transaction, block: &Hash256) {
-        if tx.inputs.len() == 1 && tx.inputs[0].output_idx == COINBASE_OUTPUT_IDX {
+        // Same as in `update_unconfirmed`: index every coinbase output
+        if tx.is_coinbase() {
             let txn_idx = TransactionIndex {
                 block: Some(*block),
                 txn: tx.hash,
-                outputs: vec![0],
+                outputs: (0..tx.outputs.len()).collect::<Vec<usize>>(),
             };
 
             self.utxos.push(txn_idx);
@@ -426,6 +504,20 @@ pub fn coinbase_size_estimate() -> usize {
     TXN.size()
 }
 
+/// Cut a string down so that it fits in a transaction's meta field without splitting a character
+pub fn truncate_meta(meta: &str) -> String {
+    if meta.len() <= MAX_META_LENGTH {
+        return meta.to_owned();
+    }
+
+    let mut end = MAX_META_LENGTH;
+    while !meta.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    meta[..end].to_owned()
+}
+
 /// The coinbase transaction is the transaction in which a miner receives a block reward. The output amount
 /// is the block reward plus the transaction fees.
 pub fn make_coinbase_txn(
@@ -491,6 +583,16 @@ fn is_p2pkh_lock(code: &str) -> bool {
     RE.is_match(code)
 }
 
+/// The size of a P2PKH unlock script with the longest possible signature
+pub fn p2pkh_unlock_size_estimate() -> usize {
+    let script = Script {
+        code: format!("{} {}", "0".repeat(MAX_SIG_LEN * 2), "0".repeat(PUBKEY_LEN * 2)),
+        script_type: ScriptType::TsengScript,
+    };
+
+    script.size()
+}
+
 pub fn make_p2pkh_unlock(sig: Vec<u8>, pubkey: Vec<u8>) -> Script {
     let sig_hex = hex::encode(sig);
     let pubkey_hex = hex::encode(pubkey);
@@ -504,7 +606,35 @@ pub fn make_p2pkh_unlock(sig: Vec<u8>, pubkey: Vec<u8>) -> Script {
 
 /// P2PKH transactions generated by the software must use the full 40-byte hex representation
 /// of an address. Any leading zeroes are kept.
+///
+/// This gets called on every output when we scan the UTXO set, so results are cached by script.
 pub fn get_p2pkh_addr(code: &str) -> Option<Address> {
+    lazy_static! {
+        static ref CACHE: Mutex<HashMap<String, Option<Address>>> = Mutex::new(HashMap::new());
+    };
+
+    let mut cache = CACHE.lock().unwrap();
+
+    if let Some(addr) = cache.get(code) {
+        return *addr;
+    }
+
+    let addr = parse_p2pkh_addr(code);
+
+    // Scripts we haven't seen in a while are probably spent, so it's fine to start over
+    if cache.len() >= P2PKH_CACHE_SIZE {
+        cache.clear();
+    }
+
+    cache.insert(code.to_owned(), addr);
+
+    addr
+}
+
+fn parse_p2pkh_addr(code: &str) -> Option<Address> {
+    #[cfg(test)]
+    P2PKH_PARSES.with(|n| n.set(n.get() + 1));
+
     lazy_static! {
         static ref RE: Regex = Regex::new(r"(\d|[a-f]|[A-F]){40}").unwrap();
     };
@@ -518,11 +648,17 @@ pub fn get_p2pkh_addr(code: &str) -> Option<Address> {
         Some(caps) => caps,
     };
 
-    let addr_vec = hex::decode(&caps[0]).unwrap();
-    let mut out: Address = [0; 20];
-    out[(20 - addr_vec.len())..].copy_from_slice(&addr_vec);
+    hex_to_address(&caps[0]).ok()
+}
 
-    Some(out)
+/// True if the script looks like a P2PKH lock but isn't written exactly the way [make_p2pkh_lock] writes it, for
+/// example with uppercase hex or with extra code around the lock. A non-canonical lock pays the same address as the
+/// canonical one but gives the transaction a different hash.
+pub fn is_non_canonical_p2pkh_lock(code: &str) -> bool {
+    match get_p2pkh_addr(code) {
+        None => false,
+        Some(addr) => make_p2pkh_lock(&addr).code != code,
+    }
 }
 
 /// Get the total unspent outputs for P2PKH transactions addressed to the given
@@ -566,16 +702,14 @@ pub fn p2pkh_utxos_for_addr(state: &State, addr: Address) -> Vec<UTXOWindow> {
         })
 }
 
-/// Collect enough UTXOs to meet the required amount to make a transaction. If we don't have enough UTXOs
+/// Collect enough of our UTXOs to meet the required amount to make a transaction. If we don't have enough UTXOs
 /// to meet the threshold, return None. We use a simple algorithm that takes transactions starting from
 /// the earliest UTXOs. This enables future optimizations in which the UTXO pool is calculated from
 /// a later block in the blockchain because all early transaction outputs have already been spent.
-pub fn collect_enough_change(
-    state: &State,
-    addr: Address,
-    threshold: u64,
-) -> Option<Vec<UTXOWindow>> {
-    let my_utxos = p2pkh_utxos_for_addr(state, addr);
+/// Only [spendable](State::spendable_utxos) UTXOs are used, so the new transaction won't conflict with one
+/// we already made.
+pub fn collect_enough_change(state: &State, threshold: u64) -> Option<Vec<UTXOWindow>> {
+    let my_utxos = state.spendable_utxos(&[]);
 
     let mut amount = 0;
     let mut out: Vec<UTXOWindow> = vec![];
@@ -630,7 +764,7 @@ pub fn build_utxos_from_confirmed(blocks: &[Block]) -> UTXOPool {
         utxos: vec![TransactionIndex {
             block: Some(blocks[0].header.hash),
             txn: blocks[0].transactions[0].hash,
-            outputs: vec![0],
+            outputs: (0..blocks[0].transactions[0].outputs.len()).collect::<Vec<usize>>(),
         }],
     };
 
@@ -644,7 +778,8 @@ pub fn build_utxos_from_confirmed(blocks: &[Block]) -> UTXOPool {
 }
 
 /// This function assumes that the transaction has already been validated. Because it has been validated,
-/// its inputs must exist on the blockchain or in the pending transaction pool.
+/// its inputs must exist on the blockchain or in the pending transaction pool. The sum saturates instead of
+/// overflowing, but a valid transaction can't get anywhere near that.
 pub fn compute_input_sum(txn: &Transaction, state: &State) -> u64 {
     let mut input_sum: u64 = 0;
     for input in &txn.inputs {
@@ -652,14 +787,44 @@ pub fn compute_input_sum(txn: &Transaction, state: &State) -> u64 {
 
         let amount = input_txn.outputs[input.output_idx].amount;
 
-        input_sum += amount;
+        input_sum = input_sum.saturating_add(amount);
     }
 
     input_sum
 }
 
+/// Like [checked_output_sum], but saturates on overflow. Use this for transactions that have already been validated.
 pub fn compute_output_sum(txn: &Transaction) -> u64 {
-    txn.outputs.iter().fold(0, |a, e| a + e.amount)
+    txn.outputs.iter().fold(0, |a: u64, e| a.saturating_add(e.amount))
+}
+
+/// Add up the outputs of a transaction. Returns None if the sum doesn't fit in a u64.
+pub fn checked_output_sum(txn: &Transaction) -> Option<u64> {
+    txn.outputs.iter().try_fold(0, |a: u64, e| a.checked_add(e.amount))
+}
+
+/// True if a fee looks like a mistake: either more than the amount being sent or more than [MAX_SANE_FEE]
+pub fn is_excessive_fee(fee: u64, amount: u64) -> bool {
+    fee > amount || fee > MAX_SANE_FEE
+}
+
+/// The fee needed to pay the given rate (in TsengCoin per byte) for a transaction of the given size,
+/// rounded up.
+pub fn fee_for_rate(rate: f64, size: usize) -> u64 {
+    (rate * size as f64).ceil() as u64
+}
+
+/// The number of signature checks in the lock scripts of the outputs a transaction spends. These run when the
+/// transaction is verified, so they count against the block that spends them. Inputs we can't find are skipped.
+pub fn spent_sigops(txn: &Transaction, state: &State) -> usize {
+    txn.inputs
+        .iter()
+        .filter_map(|i| {
+            state
+                .get_pending_or_confirmed_txn(i.txn_hash)
+                .and_then(|t| t.outputs.get(i.output_idx).map(|o| count_sigops(&o.lock_script.code)))
+        })
+        .sum()
 }
 
 // Assumes a valid transaction
@@ -677,7 +842,7 @@ pub fn make_single_p2pkh_txn(
 ) -> Result<(UnsignedTransaction, Vec<UTXOWindow>, Vec<TxnOutput>), Box<dyn Error>> {
     let required_input = amount + fee;
 
-    let change = match collect_enough_change(state, state.address, required_input) {
+    let change = match collect_enough_change(state, required_input) {
         None => {
             return Err("Not enough TsengCoin".into());
         }
@@ -717,15 +882,33 @@ pub fn make_single_p2pkh_txn(
 /// Determines the address who created a transaction. Assumes the transaction is
 /// valid.
 pub fn get_p2pkh_sender(txn: &Transaction, state: &State) -> Option<Address> {
-    let input = &txn.inputs[0];
+    let input = txn.inputs.first()?;
     let input_hash = input.txn_hash;
-    let input_txn = state.get_pending_or_confirmed_txn(input_hash).unwrap();
-    let output = &input_txn.outputs[input.output_idx];
+    let input_txn = state.get_pending_or_confirmed_txn(input_hash)?;
+    let output = input_txn.outputs.get(input.output_idx)?;
     let code = &output.lock_script.code;
 
     get_p2pkh_addr(code)
 }
 
+/// True if every input of the transaction spends a P2PKH output locked to the given address. Inputs that spend
+/// outputs we don't know about don't count as belonging to the address. This isn't a consensus rule; it's a check
+/// that coin selection only picked our own outputs.
+pub fn transaction_spends_only(address: Address, txn: &Transaction, state: &State) -> bool {
+    txn.inputs.iter().all(|input| {
+        let spent_addr = state
+            .get_pending_or_confirmed_txn(input.txn_hash)
+            .and_then(|spent| {
+                spent
+                    .outputs
+                    .get(input.output_idx)
+                    .and_then(|output| get_p2pkh_addr(&output.lock_script.code))
+            });
+
+        spent_addr == Some(address)
+    })
+}
+
 pub fn p2pkh_balance(state: &State) -> u64 {
     let my_utxos = p2pkh_utxos_for_addr(state, state.address);
     my_utxos.iter().fold(0, |a, e| a + e.amount)
@@ -756,3 +939,184 @@ pub fn get_balance_diff(state: &State, txn: &Transaction) -> i128 {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::{
+        block_verify::verify_block,
+        test_util::{make_txn, next_block, pay, sign_p2pkh, test_genesis, test_state},
+    };
+
+    /// Signature length varies, so each transaction is signed a few times
+    const SIGNATURES_PER_SIZE: usize = 16;
+
+    #[test]
+    fn vsize_matches_signed_p2pkh_txns() {
+        let state = test_state();
+
+        for num_inputs in 1..=4 {
+            let outputs = vec![
+                TxnOutput {
+                    amount: 10,
+                    lock_script: make_p2pkh_lock(&state.address),
+                },
+                TxnOutput {
+                    amount: 5,
+                    lock_script: make_p2pkh_lock(&state.address),
+                },
+            ];
+            let unsigned = UnsignedTransaction {
+                version: VERSION,
+                outputs: outputs.clone(),
+                meta: String::from(""),
+            };
+            let estimate = unsigned.vsize(num_inputs);
+            let spent = (0..num_inputs).map(|i| ([0; 32], i)).collect::<Vec<(Hash256, usize)>>();
+            // Signatures are at least 70 bytes, and each byte is two hex characters
+            let tolerance = num_inputs * (MAX_SIG_LEN - 70) * 2;
+
+            for _ in 0..SIGNATURES_PER_SIZE {
+                let txn = sign_p2pkh(&state, &spent, outputs.clone());
+                let actual = txn.size();
+
+                assert!(
+                    actual <= estimate && estimate - actual <= tolerance,
+                    "{} inputs: estimated {}, actual {}",
+                    num_inputs,
+                    estimate,
+                    actual
+                );
+                assert_eq!(txn.vsize(), estimate);
+            }
+        }
+    }
+
+    #[test]
+    fn long_meta_is_truncated_on_char_boundary() {
+        assert_eq!(truncate_meta("short"), "short");
+
+        let long = format!("{}é", "a".repeat(MAX_META_LENGTH - 1));
+        let truncated = truncate_meta(&long);
+
+        assert_eq!(truncated.len(), MAX_META_LENGTH - 1);
+        assert!(long.starts_with(&truncated));
+    }
+
+    #[test]
+    fn utxo_pool_indexes_every_coinbase_output() {
+        let mut coinbase = make_coinbase_txn(&[1; 20], String::from(""), 0, [0; 32]);
+        coinbase.outputs.push(TxnOutput {
+            amount: 1,
+            lock_script: make_p2pkh_lock(&[2; 20]),
+        });
+        let block_hash = rand::random();
+
+        let mut unconfirmed = UTXOPool { utxos: vec![] };
+        unconfirmed.update_unconfirmed(&coinbase);
+        let mut confirmed = UTXOPool { utxos: vec![] };
+        confirmed.update_confirmed(&coinbase, &block_hash);
+
+        for pool in [unconfirmed, confirmed] {
+            assert_eq!(pool.find_txn_index(coinbase.hash).unwrap().outputs, vec![0, 1]);
+        }
+
+        let mut genesis = test_genesis();
+        genesis.transactions[0] = coinbase.clone();
+        let pool = build_utxos_from_confirmed(&[genesis]);
+
+        assert_eq!(pool.find_txn_index(coinbase.hash).unwrap().outputs, vec![0, 1]);
+    }
+
+    #[test]
+    fn p2pkh_addr_is_parsed_once_per_script() {
+        let addr: Address = rand::random();
+        let lock = make_p2pkh_lock(&addr);
+        let parses = P2PKH_PARSES.with(|n| n.get());
+
+        for _ in 0..10 {
+            assert_eq!(get_p2pkh_addr(&lock.code), Some(addr));
+        }
+
+        assert_eq!(P2PKH_PARSES.with(|n| n.get()), parses + 1);
+    }
+
+    #[test]
+    fn cached_p2pkh_addr_matches_parsed() {
+        let addr: Address = rand::random();
+        let scripts = [
+            make_p2pkh_lock(&addr).code,
+            make_p2pkh_lock(&addr).code.to_uppercase(),
+            String::from("01 01 ADD"),
+            String::new(),
+        ];
+
+        for code in &scripts {
+            let parsed = parse_p2pkh_addr(code);
+
+            assert_eq!(get_p2pkh_addr(code), parsed);
+            assert_eq!(get_p2pkh_addr(code), parsed);
+        }
+
+        assert_eq!(get_p2pkh_addr(&scripts[0]), Some(addr));
+    }
+
+    #[test]
+    fn excessive_fees() {
+        assert!(!is_excessive_fee(1, 10));
+        assert!(!is_excessive_fee(10, 10));
+        assert!(is_excessive_fee(11, 10));
+        assert!(!is_excessive_fee(MAX_SANE_FEE, MAX_SANE_FEE * 2));
+        assert!(is_excessive_fee(MAX_SANE_FEE + 1, MAX_SANE_FEE * 2));
+    }
+
+    #[test]
+    fn only_client_written_p2pkh_locks_are_canonical() {
+        let canonical = make_p2pkh_lock(&[0xab; 20]).code;
+        assert!(!is_non_canonical_p2pkh_lock(&canonical));
+
+        let variants = [
+            canonical.replace(&"ab".repeat(20), &"AB".repeat(20)),
+            format!("NOP1 {}", canonical),
+            format!("{} NOP1", canonical),
+            format!("{} ", canonical),
+        ];
+
+        for code in variants {
+            assert!(is_non_canonical_p2pkh_lock(&code), "{}", code);
+        }
+
+        assert!(!is_non_canonical_p2pkh_lock("01 02 ADD"));
+    }
+
+    #[test]
+    fn foreign_input_is_not_spent_by_us() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let gift = pay(&state, &[1; 20], 10, 1);
+        assert!(transaction_spends_only(state.address, &gift, &state));
+
+        let gift_hash = gift.hash;
+        state.add_pending_txn(gift);
+
+        let change = pay(&state, &[2; 20], 5, 1);
+        assert!(transaction_spends_only(state.address, &change, &state));
+
+        let spend = |output_idx| TxnInput {
+            txn_hash: gift_hash,
+            output_idx,
+            unlock_script: Script {
+                code: String::from("NOP1"),
+                script_type: ScriptType::TsengScript,
+            },
+        };
+        let mut inputs = change.inputs.clone();
+        inputs.push(spend(0));
+
+        assert!(!transaction_spends_only(state.address, &make_txn(inputs, vec![]), &state));
+        assert!(!transaction_spends_only(state.address, &make_txn(vec![spend(5)], vec![]), &state));
+        assert!(transaction_spends_only([1; 20], &make_txn(vec![spend(0)], vec![]), &state));
+    }
+}
@@ -1,31 +1,53 @@
+use std::collections::HashSet;
+
 use num_bigint::BigUint;
 
-use crate::tsengscript_interpreter::{execute, Token};
+use crate::{
+    tsengscript_interpreter::{execute, is_push_only, Token, MAX_SCRIPT_LEN},
+    wallet::Hash256,
+};
 
 use super::{
-    block::MAX_BLOCK_SIZE,
+    block::{MAX_BLOCK_SIGOPS, MAX_TRANSACTION_FIELD_SIZE},
     state::State,
     transaction::{
-        hash_txn, Transaction, UnhashedTransaction, UnsignedTransaction, MAX_TXN_AMOUNT,
+        checked_output_sum, compute_fee, hash_txn, is_non_canonical_p2pkh_lock, spent_sigops, Transaction, UnhashedTransaction, UnsignedTransaction, MAX_TXN_AMOUNT,
         MIN_TXN_FEE,
     },
     txn_verify_error::{
         ErrorKind::{
-            BadUnlockScript, Coinbase, DoubleSpend, EmptyInputs, EmptyOutputs, InvalidHash,
-            InvalidUTXOIndex, LowFee, OutOfRange, Overspend, Script, TooLarge, ZeroOutput,
+            AmountOverflow, BadUnlockScript, Coinbase, DirtyStack, DoubleSpend, DuplicateInput, EmptyInputs, EmptyOutputs, InvalidHash,
+            InvalidUTXOIndex, LowFee, NonCanonicalLock, OutOfRange, Overspend, PendingDoubleSpend, Script,
+            ScriptTooLong, TooLarge, TooManySigops, UnlockNotPushOnly, ZeroOutput,
         },
         TxnVerifyResult,
     },
 };
 
+/// The outcome of verifying a transaction that broke no rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnStatus {
+    /// Every input exists, so the transaction can go in the pending pool
+    Valid,
+    /// The transaction spends an output of a transaction we have never seen, so it's an orphan. The argument is the
+    /// hash of the missing input transaction.
+    MissingInput(Hash256),
+}
+
 /// Verifies the transaction according to an independent set of rules. If there are no errors,
-/// returns 'true' if the transaction is an orphan, and false if not. If the transaction is not an orphan,
-/// it should be added to the pending transactions pool. This function does not mutate the state in any way
+/// returns [TxnStatus::MissingInput] if the transaction is an orphan, and [TxnStatus::Valid] if not. If the
+/// transaction is not an orphan, it should be added to the pending transactions pool. This function does not mutate the state in any way
 /// so adding valid transactions to their respective pools is the caller's responsibility.
 ///
 /// This function may also be used to to verify transactions within new blocks. Again, it is the caller's
 /// responsibility to update the blockchain and the UTXO database accordingly.
-pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<bool> {
+pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<TxnStatus> {
+    verify_transaction_with_policy(tx, state, false)
+}
+
+/// Like [verify_transaction], but if `clean_stack` is true, each input's scripts must leave exactly one item on the
+/// stack. That's local policy for the pending pool, not a consensus rule, so it should never be applied to blocks.
+fn verify_transaction_with_policy(tx: Transaction, state: &State, clean_stack: bool) -> TxnVerifyResult<TxnStatus> {
     let utxos = &state.blockchain.utxo_pool;
 
     // Transaction must have at least 1 input
@@ -38,12 +60,24 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
         return Err(Box::new(EmptyOutputs));
     }
 
-    // Transaction cannot be too big to fit into a block
-    if tx.size() > MAX_BLOCK_SIZE {
+    // Transaction cannot be too big to fit into a block. The block header takes up some of the block,
+    // so a transaction needs to fit in what's left
+    if tx.size() > MAX_TRANSACTION_FIELD_SIZE {
         return Err(Box::new(TooLarge));
     }
 
-    let output_sum = tx.outputs.iter().fold(0, |a, e| a + e.amount);
+    // For the same reason, a transaction can't have more signature checks than a block allows. This includes the
+    // lock scripts of the outputs being spent, because those run too
+    let sigops = tx.sigops() + spent_sigops(&tx, state);
+    if sigops > MAX_BLOCK_SIGOPS {
+        return Err(Box::new(TooManySigops(sigops)));
+    }
+
+    // A malicious transaction could have outputs that overflow when added up
+    let output_sum = match checked_output_sum(&tx) {
+        None => return Err(Box::new(AmountOverflow)),
+        Some(sum) => sum,
+    };
 
     // Total output must be less than the max value
     if output_sum > MAX_TXN_AMOUNT {
@@ -57,14 +91,29 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
         }
     }
 
-    let zero_hash = tx.inputs.iter().find(|i| i.txn_hash == [0; 32]);
+    // P2PKH locks must be written the one way the client writes them, so that the same payment can't be
+    // given different transaction hashes
+    for (i, output) in tx.outputs.iter().enumerate() {
+        if is_non_canonical_p2pkh_lock(&output.lock_script.code) {
+            return Err(Box::new(NonCanonicalLock(i)));
+        }
+    }
 
-    // Only coinbase transactions can have a zero hash. Coinbase transactions should not be
-    // relayed
-    if zero_hash.is_some() {
+    // Only coinbase transactions can have a zero hash or the coinbase output index. Coinbase transactions
+    // should not be relayed
+    if tx.inputs.iter().any(|i| i.has_coinbase_marker()) {
         return Err(Box::new(Coinbase));
     }
 
+    // Each input must spend a different output. Otherwise the same output would be counted more than once
+    // when adding up the inputs
+    let mut spent: HashSet<(Hash256, usize)> = HashSet::new();
+    for input in &tx.inputs {
+        if !spent.insert((input.txn_hash, input.output_idx)) {
+            return Err(Box::new(DuplicateInput(input.txn_hash, input.output_idx)));
+        }
+    }
+
     // The transaction hash must be valid
     let unhashed_tx: UnhashedTransaction = (&tx).into();
     let hash_res = hash_txn(&unhashed_tx);
@@ -74,6 +123,33 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
         _ => (),
     };
 
+    // Every script that this transaction would run must be short enough to execute. The interpreter
+    // checks this too, but we check all scripts up front so that a transaction with an oversized script
+    // is rejected before we spend any time running the others.
+    for input in &tx.inputs {
+        let unlock_len = input.unlock_script.code.len();
+        if unlock_len > MAX_SCRIPT_LEN {
+            return Err(Box::new(ScriptTooLong(MAX_SCRIPT_LEN, unlock_len)));
+        }
+
+        // Unlocking scripts can only push data. Any logic belongs in the locking script
+        match is_push_only(&input.unlock_script.code) {
+            Err(err) => return Err(Box::new(Script(err))),
+            Ok(false) => return Err(Box::new(UnlockNotPushOnly(input.txn_hash, input.output_idx))),
+            Ok(true) => (),
+        };
+
+        let lock_len_opt = state
+            .get_pending_or_confirmed_txn(input.txn_hash)
+            .and_then(|t| t.outputs.get(input.output_idx).map(|o| o.lock_script.code.len()));
+
+        if let Some(lock_len) = lock_len_opt {
+            if lock_len > MAX_SCRIPT_LEN {
+                return Err(Box::new(ScriptTooLong(MAX_SCRIPT_LEN, lock_len)));
+            }
+        }
+    }
+
     let unsigned_tx: UnsignedTransaction = (&tx).into();
     let msg_data = bincode::serialize(&unsigned_tx).unwrap();
     let msg_data_bigint = BigUint::from_bytes_be(&msg_data);
@@ -82,7 +158,7 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
     // data that the sender would have signed
     let init_stack: Vec<Token> = vec![Token::UByteSeq(msg_data_bigint)];
 
-    let mut input_sum = 0;
+    let mut input_sum: u64 = 0;
 
     for input in tx.inputs {
         // Each input has to reference a valid UTXO. If not, the transaction is an orphan
@@ -101,7 +177,7 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
             );
 
             match input_opts {
-                (None, None) => return Ok(true),
+                (None, None) => return Ok(TxnStatus::MissingInput(input.txn_hash)),
                 _ => return Err(Box::new(DoubleSpend(input.txn_hash, input.output_idx))),
             };
         }
@@ -173,13 +249,22 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
             return Err(Box::new(Script(lock_result.err().unwrap())));
         }
 
-        let script_res = lock_result.unwrap().top;
-        match script_res {
+        let script_res = lock_result.unwrap();
+        match script_res.top {
             Some(Token::Bool(true)) => (),
             _ => return Err(Box::new(BadUnlockScript(txn.hash, input.output_idx))),
         };
 
-        input_sum += output.amount;
+        // Anything left under the result could have been changed by someone relaying the transaction without
+        // affecting whether it's valid
+        if clean_stack && script_res.stack.len() != 1 {
+            return Err(Box::new(DirtyStack(txn.hash, input.output_idx, script_res.stack.len())));
+        }
+
+        input_sum = match input_sum.checked_add(output.amount) {
+            None => return Err(Box::new(AmountOverflow)),
+            Some(sum) => sum,
+        };
     }
 
     // Transaction outputs cannot be more than inputs - you can't spend more
@@ -200,23 +285,69 @@ pub fn verify_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<boo
         return Err(Box::new(LowFee(fee)));
     }
 
-    Ok(false)
+    Ok(TxnStatus::Valid)
 }
 
+/// Verifies a transaction that would go into the pending or orphan pool. This does everything [verify_transaction]
+/// does, and also rejects the transaction if it spends an output that a pending transaction already spends. The
+/// first transaction we see wins.
+pub fn verify_pending_transaction(tx: Transaction, state: &State) -> TxnVerifyResult<TxnStatus> {
+    check_pending_conflicts(&tx, state)?;
+
+    verify_transaction_with_policy(tx, state, state.clean_stack)
+}
+
+/// Make sure no other pending transaction spends any of the outputs spent by this transaction
+fn check_pending_conflicts(tx: &Transaction, state: &State) -> TxnVerifyResult<()> {
+    for input in &tx.inputs {
+        let conflict = state.pending_txns.iter().find(|p| {
+            p.hash != tx.hash
+                && p.inputs
+                    .iter()
+                    .any(|i| i.txn_hash == input.txn_hash && i.output_idx == input.output_idx)
+        });
+
+        if let Some(pending) = conflict {
+            return Err(Box::new(PendingDoubleSpend(
+                input.txn_hash,
+                input.output_idx,
+                pending.hash,
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-verify every pending and orphan transaction, moving orphans whose inputs now exist into the pending pool
+/// and dropping invalid transactions. Orphans that pay less than our relay fee are dropped instead of moved.
 pub fn check_pending_and_orphans(state: &mut State) {
     let mut new_pending: Vec<Transaction> = vec![];
     let mut new_orphans: Vec<Transaction> = vec![];
 
-    for txn in state.pending_txns.iter().chain(state.orphan_txns.iter()) {
-        let verify_result = verify_transaction(txn.clone(), state);
+    let pending = state.pending_txns.iter().map(|txn| (txn, false));
+    let orphans = state.orphan_txns.iter().map(|txn| (txn, true));
+
+    for (txn, is_orphan) in pending.chain(orphans) {
+        let verify_result = verify_transaction_with_policy(txn.clone(), state, state.clean_stack);
         match verify_result {
-            Ok(true) => {
+            Ok(TxnStatus::MissingInput(_)) => {
                 new_orphans.push(txn.clone());
             }
             Err(err) => {
                 println!("Pending/orphan transaction rejected due to error: {}", err);
             }
-            Ok(false) => {
+            Ok(TxnStatus::Valid) => {
+                // Orphans skipped the relay fee check when they came in, because we couldn't compute their fee yet
+                if is_orphan && compute_fee(txn, state) < state.min_relay_fee {
+                    println!(
+                        "Dropping orphan transaction {}: its fee is below our minimum of {}",
+                        hex::encode(txn.hash),
+                        state.min_relay_fee
+                    );
+                    continue;
+                }
+
                 state.blockchain.utxo_pool.update_unconfirmed(txn);
                 new_pending.push(txn.clone());
             }
@@ -226,3 +357,264 @@ pub fn check_pending_and_orphans(state: &mut State) {
     state.pending_txns = new_pending;
     state.orphan_txns = new_orphans;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tsengscript_interpreter::EXECUTIONS,
+        v1::{
+            block::MAX_BLOCK_SIZE,
+            block_verify::verify_block,
+            test_util::{make_txn, next_block, pay, sign_p2pkh, test_state},
+            transaction::{build_utxos_from_confirmed, make_p2pkh_lock, Script, ScriptType, TxnInput, TxnOutput},
+        },
+    };
+
+    #[test]
+    fn rejects_outputs_that_overflow() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let mut txn = pay(&state, &[1; 20], 10, 1);
+        txn.outputs.push(TxnOutput {
+            amount: u64::MAX,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        });
+
+        let err = verify_transaction(txn, &state).err().unwrap();
+        assert!(matches!(*err, AmountOverflow));
+    }
+
+    #[test]
+    fn rejects_same_utxo_in_two_inputs() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        let outputs = vec![TxnOutput {
+            amount: 10,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        }];
+        let txn = sign_p2pkh(&state, &[(coinbase, 0), (coinbase, 0)], outputs);
+
+        let err = verify_transaction(txn, &state).err().unwrap();
+        assert!(matches!(*err, DuplicateInput(hash, 0) if hash == coinbase));
+    }
+
+    #[test]
+    fn oversized_unlock_script_is_rejected_before_running_scripts() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let mut txn = pay(&state, &[1; 20], 10, 1);
+        txn.inputs[0].unlock_script = Script {
+            code: "01 ".repeat(MAX_SCRIPT_LEN / 3 + 1),
+            script_type: ScriptType::TsengScript,
+        };
+        let txn = make_txn(txn.inputs, txn.outputs);
+
+        let executions = EXECUTIONS.with(|n| n.get());
+        let err = verify_transaction(txn, &state).err().unwrap();
+
+        assert!(matches!(*err, ScriptTooLong(MAX_SCRIPT_LEN, len) if len > MAX_SCRIPT_LEN));
+        assert_eq!(EXECUTIONS.with(|n| n.get()), executions);
+    }
+
+    #[test]
+    fn second_pending_spend_of_utxo_is_rejected() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        let first = pay(&state, &[1; 20], 10, 1);
+        let second = pay(&state, &[2; 20], 10, 1);
+        state.add_pending_txn(first.clone());
+
+        let err = verify_pending_transaction(second, &state).err().unwrap();
+        assert!(matches!(*err, PendingDoubleSpend(hash, 0, pending) if hash == coinbase && pending == first.hash));
+    }
+
+    #[test]
+    fn push_only_unlock_script_is_accepted() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let txn = pay(&state, &[1; 20], 10, 1);
+
+        assert_eq!(verify_transaction(txn, &state).unwrap(), TxnStatus::Valid);
+    }
+
+    #[test]
+    fn unlock_script_with_operator_is_rejected() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        let mut txn = pay(&state, &[1; 20], 10, 1);
+        txn.inputs[0].unlock_script.code.push_str(" DUP");
+        let txn = make_txn(txn.inputs, txn.outputs);
+
+        let err = verify_transaction(txn, &state).err().unwrap();
+        assert!(matches!(*err, UnlockNotPushOnly(hash, 0) if hash == coinbase));
+    }
+
+    #[test]
+    fn txn_must_leave_room_for_block_header() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let mut txn = pay(&state, &[1; 20], 10, 1);
+        txn.meta = "a".repeat(MAX_TRANSACTION_FIELD_SIZE - txn.size());
+
+        let err = verify_transaction(txn.clone(), &state).err().unwrap();
+        assert!(!matches!(*err, TooLarge));
+
+        txn.meta.push('a');
+        assert!(txn.size() < MAX_BLOCK_SIZE);
+
+        let err = verify_transaction(txn, &state).err().unwrap();
+        assert!(matches!(*err, TooLarge));
+    }
+
+    #[test]
+    fn txn_with_unknown_input_is_missing_input() {
+        let state = test_state();
+        let missing = rand::random();
+        let output = TxnOutput {
+            amount: 10,
+            lock_script: make_p2pkh_lock(&[1; 20]),
+        };
+        let txn = sign_p2pkh(&state, &[(missing, 0)], vec![output]);
+
+        assert_eq!(verify_transaction(txn, &state).unwrap(), TxnStatus::MissingInput(missing));
+    }
+
+    #[test]
+    fn non_canonical_p2pkh_output_is_rejected() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        let canonical = make_p2pkh_lock(&[0xab; 20]);
+        let mut uppercase = canonical.clone();
+        uppercase.code = uppercase.code.replace(&"ab".repeat(20), &"AB".repeat(20));
+
+        for (lock_script, valid) in [(canonical, true), (uppercase, false)] {
+            let output = TxnOutput { amount: 10, lock_script };
+            let txn = sign_p2pkh(&state, &[(coinbase, 0)], vec![output]);
+            let res = verify_transaction(txn, &state);
+
+            if valid {
+                assert_eq!(res.unwrap(), TxnStatus::Valid);
+            } else {
+                assert!(matches!(*res.err().unwrap(), NonCanonicalLock(0)));
+            }
+        }
+    }
+
+    #[test]
+    fn clean_stack_policy_rejects_leftover_items() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        let coinbase = block.transactions[0].hash;
+        verify_block(block, &mut state).unwrap();
+
+        // Anyone can spend this by pushing one item to drop with the transaction data
+        let output = TxnOutput {
+            amount: 10,
+            lock_script: Script {
+                code: String::from("2DROP TRUE"),
+                script_type: ScriptType::TsengScript,
+            },
+        };
+        let funding = sign_p2pkh(&state, &[(coinbase, 0)], vec![output]);
+        state.add_pending_txn(funding.clone());
+        let block = next_block(&state, vec![funding.clone()]);
+        verify_block(block, &mut state).unwrap();
+
+        let spend = |unlock: &str| {
+            let input = TxnInput {
+                txn_hash: funding.hash,
+                output_idx: 0,
+                unlock_script: Script {
+                    code: String::from(unlock),
+                    script_type: ScriptType::TsengScript,
+                },
+            };
+            let output = TxnOutput {
+                amount: 5,
+                lock_script: make_p2pkh_lock(&[1; 20]),
+            };
+
+            make_txn(vec![input], vec![output])
+        };
+        let (clean, dirty) = (spend("01"), spend("01 01"));
+
+        assert_eq!(verify_pending_transaction(dirty.clone(), &state).unwrap(), TxnStatus::Valid);
+
+        state.clean_stack = true;
+        assert_eq!(verify_pending_transaction(clean, &state).unwrap(), TxnStatus::Valid);
+
+        let err = verify_pending_transaction(dirty.clone(), &state).err().unwrap();
+        assert!(matches!(*err, DirtyStack(hash, 0, 2) if hash == funding.hash));
+
+        // Blocks aren't held to the policy
+        assert_eq!(verify_transaction(dirty, &state).unwrap(), TxnStatus::Valid);
+    }
+
+    #[test]
+    fn promoted_orphan_must_pay_relay_fee() {
+        let mut state = test_state();
+        let block = next_block(&state, vec![]);
+        verify_block(block, &mut state).unwrap();
+
+        let parent = pay(&state, &[1; 20], 10, 1);
+        let change_idx = parent
+            .outputs
+            .iter()
+            .position(|o| o.lock_script.code == make_p2pkh_lock(&state.address).code)
+            .unwrap();
+        let spend_change = |fee: u64| {
+            let output = TxnOutput {
+                amount: parent.outputs[change_idx].amount - fee,
+                lock_script: make_p2pkh_lock(&[2; 20]),
+            };
+            sign_p2pkh(&state, &[(parent.hash, change_idx)], vec![output])
+        };
+        let cheap_child = spend_change(2);
+        let paid_child = spend_change(5);
+
+        state.min_relay_fee = 5;
+
+        for child in [&cheap_child, &paid_child] {
+            assert!(matches!(verify_pending_transaction(child.clone(), &state).unwrap(), TxnStatus::MissingInput(_)));
+        }
+
+        // Same as when a block comes in and the parent is already pending
+        let recheck = |state: &mut State| {
+            state.blockchain.utxo_pool = build_utxos_from_confirmed(&state.blockchain.blocks);
+            check_pending_and_orphans(state);
+        };
+
+        state.pending_txns = vec![parent.clone()];
+        state.orphan_txns = vec![cheap_child];
+        recheck(&mut state);
+
+        assert_eq!(state.pending_txns, vec![parent.clone()]);
+        assert!(state.orphan_txns.is_empty());
+
+        state.orphan_txns = vec![paid_child.clone()];
+        recheck(&mut state);
+
+        assert_eq!(state.pending_txns, vec![parent, paid_child]);
+    }
+}
@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 use crate::{script_error::ScriptError, wallet::Hash256};
 
 use super::{
-    block::MAX_BLOCK_SIZE,
+    block::{MAX_BLOCK_SIGOPS, MAX_TRANSACTION_FIELD_SIZE},
     transaction::{MAX_TXN_AMOUNT, MIN_TXN_FEE},
 };
 
@@ -31,6 +31,51 @@ pub enum ErrorKind {
     DoubleSpend(Hash256, usize),
     InvalidHash,
     ZeroOutput,
+    ScriptTooLong(usize, usize),
+    PendingDoubleSpend(Hash256, usize, Hash256),
+    UnlockNotPushOnly(Hash256, usize),
+    AmountOverflow,
+    NonCanonicalLock(usize),
+    DirtyStack(Hash256, usize, usize),
+    DuplicateInput(Hash256, usize),
+    TooManySigops(usize),
+}
+
+impl ErrorKind {
+    /// True if the transaction conflicts with one in the pending pool. Conflicts depend on what we have seen so far,
+    /// so the same transaction could be valid once the conflicting one is dropped or confirmed. Every other error
+    /// means the transaction breaks a rule.
+    pub fn is_mempool_conflict(&self) -> bool {
+        matches!(self, ErrorKind::PendingDoubleSpend(_, _, _))
+    }
+
+    /// A number that identifies this kind of transaction verification error. Codes never change once assigned, so tools can
+    /// rely on them instead of parsing error messages.
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorKind::EmptyInputs => 201,
+            ErrorKind::EmptyOutputs => 202,
+            ErrorKind::TooLarge => 203,
+            ErrorKind::OutOfRange(_) => 204,
+            ErrorKind::Coinbase => 205,
+            ErrorKind::InvalidUTXOIndex => 206,
+            ErrorKind::Script(_) => 207,
+            ErrorKind::BadUnlockScript(_, _) => 208,
+            ErrorKind::Overspend(_, _) => 209,
+            ErrorKind::LowFee(_) => 210,
+            ErrorKind::DoubleSpend(_, _) => 211,
+            ErrorKind::InvalidHash => 212,
+            ErrorKind::ZeroOutput => 213,
+            ErrorKind::ScriptTooLong(_, _) => 214,
+            ErrorKind::PendingDoubleSpend(_, _, _) => 215,
+            ErrorKind::UnlockNotPushOnly(_, _) => 216,
+            ErrorKind::AmountOverflow => 217,
+            ErrorKind::NonCanonicalLock(_) => 218,
+            ErrorKind::DirtyStack(_, _, _) => 219,
+            ErrorKind::DuplicateInput(_, _) => 220,
+            ErrorKind::TooManySigops(_) => 221,
+        }
+    }
 }
 
 impl StdError for ErrorKind {
@@ -48,7 +93,15 @@ impl StdError for ErrorKind {
             ErrorKind::LowFee(_) => "Transaction fee is too low",
             ErrorKind::DoubleSpend(_, _) => "Transaction output has already been spent",
             ErrorKind::InvalidHash => "Transaction hash is invalid",
-            ErrorKind::ZeroOutput => "Transaction has at least one output with zero TsengCoin"
+            ErrorKind::ZeroOutput => "Transaction has at least one output with zero TsengCoin",
+            ErrorKind::ScriptTooLong(_, _) => "Transaction input or referenced output has a script that is too long",
+            ErrorKind::PendingDoubleSpend(_, _, _) => "Transaction output is already spent by a pending transaction",
+            ErrorKind::UnlockNotPushOnly(_, _) => "Unlocking script contains operators. Unlocking scripts can only push data",
+            ErrorKind::AmountOverflow => "Transaction amounts add up to more than can be represented",
+            ErrorKind::NonCanonicalLock(_) => "P2PKH lock script is not in canonical form",
+            ErrorKind::DirtyStack(_, _, _) => "Scripts left more than one item on the stack",
+            ErrorKind::DuplicateInput(_, _) => "Transaction spends the same output more than once",
+            ErrorKind::TooManySigops(_) => "Transaction has too many signature checks to fit in a block",
         }
     }
 
@@ -67,7 +120,7 @@ impl fmt::Display for ErrorKind {
                 fmt,
                 "{}. Cannot exceed {} bytes",
                 self.description(),
-                MAX_BLOCK_SIZE
+                MAX_TRANSACTION_FIELD_SIZE
             ),
             ErrorKind::OutOfRange(val) => write!(
                 fmt,
@@ -109,6 +162,91 @@ impl fmt::Display for ErrorKind {
             ),
             ErrorKind::InvalidHash => write!(fmt, "{}", self.description()),
             ErrorKind::ZeroOutput => write!(fmt, "{}", self.description()),
+            ErrorKind::ScriptTooLong(max_len, actual_len) => write!(
+                fmt,
+                "{}: Max length: {}B, actual length: {}B",
+                self.description(),
+                max_len,
+                actual_len
+            ),
+            ErrorKind::PendingDoubleSpend(hash, output_idx, pending_hash) => write!(
+                fmt,
+                "{}: hash: {}, output index: {}, pending transaction: {}",
+                self.description(),
+                hex::encode(hash),
+                output_idx,
+                hex::encode(pending_hash)
+            ),
+            ErrorKind::UnlockNotPushOnly(hash, output_idx) => write!(
+                fmt,
+                "{}: input transaction {}, output {}",
+                self.description(),
+                hex::encode(hash),
+                output_idx
+            ),
+            ErrorKind::AmountOverflow => write!(fmt, "{}", self.description()),
+            ErrorKind::NonCanonicalLock(output_idx) => {
+                write!(fmt, "{}: output {}", self.description(), output_idx)
+            }
+            ErrorKind::DirtyStack(hash, output_idx, stack_size) => write!(
+                fmt,
+                "{}: input transaction {}, output {}, stack size: {}",
+                self.description(),
+                hex::encode(hash),
+                output_idx,
+                stack_size
+            ),
+            ErrorKind::DuplicateInput(hash, output_idx) => write!(
+                fmt,
+                "{}: input transaction {}, output {}",
+                self.description(),
+                hex::encode(hash),
+                output_idx
+            ),
+            ErrorKind::TooManySigops(sigops) => write!(
+                fmt,
+                "{}: max is {}, transaction has {}",
+                self.description(),
+                MAX_BLOCK_SIGOPS,
+                sigops
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script_error::ErrorKind as ScriptErrorKind;
+
+    #[test]
+    fn each_kind_has_its_own_code() {
+        let kinds = [
+            (ErrorKind::EmptyInputs, 201),
+            (ErrorKind::EmptyOutputs, 202),
+            (ErrorKind::TooLarge, 203),
+            (ErrorKind::OutOfRange(0), 204),
+            (ErrorKind::Coinbase, 205),
+            (ErrorKind::InvalidUTXOIndex, 206),
+            (ErrorKind::Script(Box::new(ScriptErrorKind::IntegerOverflow)), 207),
+            (ErrorKind::BadUnlockScript([0; 32], 0), 208),
+            (ErrorKind::Overspend(0, 0), 209),
+            (ErrorKind::LowFee(0), 210),
+            (ErrorKind::DoubleSpend([0; 32], 0), 211),
+            (ErrorKind::InvalidHash, 212),
+            (ErrorKind::ZeroOutput, 213),
+            (ErrorKind::ScriptTooLong(0, 0), 214),
+            (ErrorKind::PendingDoubleSpend([0; 32], 0, [0; 32]), 215),
+            (ErrorKind::UnlockNotPushOnly([0; 32], 0), 216),
+            (ErrorKind::AmountOverflow, 217),
+            (ErrorKind::NonCanonicalLock(0), 218),
+            (ErrorKind::DirtyStack([0; 32], 0, 0), 219),
+            (ErrorKind::DuplicateInput([0; 32], 0), 220),
+            (ErrorKind::TooManySigops(0), 221),
+        ];
+
+        for (kind, code) in kinds {
+            assert_eq!(kind.code(), code, "{:?}", kind);
         }
     }
 }
@@ -1,4 +1,10 @@
-use std::{error::Error, fs::File, io::Write, num::NonZeroU32, path::Path};
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::Write,
+    num::NonZeroU32,
+    path::Path,
+};
 
 use base58check::{FromBase58Check, FromBase58CheckError, ToBase58Check};
 use ring::{
@@ -10,10 +16,15 @@ use ring::{
     digest::{Context, SHA256},
     error::Unspecified,
     pbkdf2,
-    signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    signature::{
+        EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
+        ECDSA_P256_SHA256_ASN1_SIGNING,
+    },
 };
 use ripemd::{Digest, Ripemd160};
 
+use crate::v1::transaction::{MAX_SIG_LEN, PUBKEY_LEN};
+
 /// Bitcoin uses a version prefix of 0x00 for wallets and 0x05 for P2SH addresses (and some other prefixes for other things).
 /// None of the values in between are used as far as we know, so we took 0x03 for
 /// our addresses so that they would start with a 2. Bitcoin addresses start with a 1
@@ -28,6 +39,13 @@ const CREDENTIAL_LEN: usize = digest::SHA256_OUTPUT_LEN;
 /// because the key needs to be a deterministic function of the password
 const AES_NONCE: [u8; NONCE_LEN] = [0x64; NONCE_LEN];
 
+/// Signed messages are prefixed with this before signing so that a message signature can never
+/// be passed off as a signature over something else, like a transaction
+const SIGNED_MESSAGE_PREFIX: &[u8] = b"TsengCoin Signed Message:\n";
+
+/// A signed message blob starts with the signer's address and public key, followed by the signature
+const SIGNED_MESSAGE_HEADER_LEN: usize = 20 + PUBKEY_LEN;
+
 pub type Key = [u8; CREDENTIAL_LEN];
 
 pub type Hash160 = [u8; 20];
@@ -35,6 +53,42 @@ pub type Hash256 = [u8; 32];
 
 pub type Address = Hash160;
 
+/// Parse a hex string into a fixed size byte array. Upper, lower, and mixed case are all accepted, as well as
+/// an optional "0x" prefix. Strings shorter than the full length are treated as numbers with their leading zeros
+/// trimmed, so they are padded with zeros on the left.
+pub fn parse_hex<const N: usize>(hex_str: &str) -> Result<[u8; N], Box<dyn Error>> {
+    let trimmed = hex_str.trim();
+    let digits = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+
+    if digits.len() > N * 2 {
+        return Err(format!(
+            "Hex string is too long: expected at most {} digits, got {}",
+            N * 2,
+            digits.len()
+        )
+        .into());
+    }
+
+    let padded = format!("{:0>width$}", digits, width = N * 2);
+    let bytes = hex::decode(padded).map_err(|err| format!("Invalid hex string: {}", err))?;
+
+    let mut out = [0_u8; N];
+    out.copy_from_slice(&bytes);
+
+    Ok(out)
+}
+
+pub fn hex_to_address(hex_str: &str) -> Result<Address, Box<dyn Error>> {
+    parse_hex::<20>(hex_str)
+}
+
+pub fn hex_to_hash(hex_str: &str) -> Result<Hash256, Box<dyn Error>> {
+    parse_hex::<32>(hex_str)
+}
+
 struct NonceGen {}
 
 impl NonceSequence for NonceGen {
@@ -64,6 +118,8 @@ pub fn load_keypair(password: &str, path: &str) -> Result<EcdsaKeyPair, Box<dyn
 }
 
 pub fn create_keypair(password: &str, save_to: &str) -> Result<EcdsaKeyPair, Box<dyn Error>> {
+    // Only for a nicer error message. The file could still show up before we write ours, which write_private_file
+    // catches.
     if Path::new(save_to).exists() {
         return Err(format!("Keypair already exists at {}", save_to).into());
     }
@@ -87,14 +143,42 @@ pub fn create_keypair(password: &str, save_to: &str) -> Result<EcdsaKeyPair, Box
         .seal_in_place_append_tag(Aad::empty(), &mut data)
         .unwrap();
 
-    let mut keypair_file = File::create(save_to).expect("Failed to create keypair file");
-    keypair_file
-        .write_all(&data)
-        .expect("Failed to write to keypair file");
+    write_private_file(save_to, &data)?;
 
     Ok(keypair)
 }
 
+/// Write a new file that only the owner can read, without ever leaving a partially written file at `path`.
+/// The data is written to a temporary file first and then linked into place. Fails if `path` already exists, even
+/// if it was created after we checked.
+fn write_private_file(path: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.tmp", path);
+
+    // A leftover temporary file from a crash could have the wrong permissions, so start fresh
+    let _ = fs::remove_file(&tmp_path);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    // Unlike a rename, a hard link won't replace a file that's already there, so we can't destroy someone's keys
+    let linked = fs::hard_link(&tmp_path, path);
+    fs::remove_file(&tmp_path)?;
+    linked?;
+
+    Ok(())
+}
+
 pub fn address_from_public_key(public_key: &Vec<u8>) -> Address {
     let mut context = Context::new(&SHA256);
     context.update(public_key);
@@ -122,6 +206,7 @@ pub fn b58c_to_address(b58c: String) -> Result<Address, Box<dyn Error>> {
         Err(FromBase58CheckError::InvalidChecksum) => Err("Invalid checksum".into()),
         Err(FromBase58CheckError::InvalidBase58(_)) => Err("Invalid base58".into()),
         Ok((version, _)) if version != B58C_VERSION_PREFIX => Err("Invalid version".into()),
+        Ok((_, bytes)) if bytes.len() > 20 => Err("Invalid length".into()),
         Ok((_, bytes)) => {
             let offset = 20 - bytes.len();
             let mut out = [0_u8; 20];
@@ -134,6 +219,75 @@ pub fn b58c_to_address(b58c: String) -> Result<Address, Box<dyn Error>> {
     }
 }
 
+/// Derive the address for a public key, then base58check encode and decode it and make sure nothing changed
+/// along the way. Returns the encoded address.
+pub fn check_address_round_trip(public_key: &Vec<u8>) -> Result<String, Box<dyn Error>> {
+    let address = address_from_public_key(public_key);
+    let encoded = address_to_b58c(&address.to_vec());
+    let decoded = b58c_to_address(encoded.clone())?;
+
+    if decoded != address {
+        return Err(format!(
+            "Address changed after base58check round trip: {} became {}",
+            hex::encode(address),
+            hex::encode(decoded)
+        )
+        .into());
+    }
+
+    if address_from_public_key(public_key) != decoded {
+        return Err("Public key does not hash to the decoded address".into());
+    }
+
+    Ok(encoded)
+}
+
+/// Sign a message with the given keypair. The output blob is the signer's address, then their public key,
+/// then the signature (address || pubkey || sig), so anyone can verify it knowing only the address.
+pub fn sign_message(msg: &str, keypair: &EcdsaKeyPair) -> Result<Vec<u8>, Box<dyn Error>> {
+    let public_key = keypair.public_key().as_ref().to_vec();
+    let address = address_from_public_key(&public_key);
+    let rng = ring::rand::SystemRandom::new();
+    let sig = keypair
+        .sign(&rng, &signed_message_bytes(msg))
+        .map_err(|_| "Failed to sign message")?;
+
+    Ok([address.to_vec(), public_key, sig.as_ref().to_vec()].concat())
+}
+
+/// Verify a blob made by [sign_message]. The address in the blob must be the claimed address, and the public key
+/// in the blob must hash to it; otherwise anyone could sign a message with their own key and embed someone
+/// else's address.
+pub fn verify_message(msg: &str, address: Address, blob: &[u8]) -> Result<(), Box<dyn Error>> {
+    if blob.len() <= SIGNED_MESSAGE_HEADER_LEN || blob.len() > SIGNED_MESSAGE_HEADER_LEN + MAX_SIG_LEN {
+        return Err(format!("Invalid signature length: {}B", blob.len()).into());
+    }
+
+    let (embedded_address, rest) = blob.split_at(20);
+    let (public_key, sig) = rest.split_at(PUBKEY_LEN);
+
+    if embedded_address != address {
+        return Err(format!(
+            "Message was signed by {}, not {}",
+            address_to_b58c(&embedded_address.to_vec()),
+            address_to_b58c(&address.to_vec())
+        )
+        .into());
+    }
+
+    if address_from_public_key(&public_key.to_vec()) != address {
+        return Err("Public key in signature does not hash to the address".into());
+    }
+
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+        .verify(&signed_message_bytes(msg), sig)
+        .map_err(|_| "Invalid signature".into())
+}
+
+fn signed_message_bytes(msg: &str) -> Vec<u8> {
+    [SIGNED_MESSAGE_PREFIX, msg.as_bytes()].concat()
+}
+
 fn salt_from_password(password: &str) -> [u8; 16] {
     let digest = ring::digest::digest(&digest::SHA256, password.as_bytes());
     let mut out = [0_u8; 16];
@@ -141,3 +295,91 @@ fn salt_from_password(password: &str) -> [u8; 16] {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v1::test_util::{temp_data_dir, test_keypair};
+
+    #[test]
+    fn mixed_case_hex_is_accepted() {
+        let addr = hex_to_address("0xAbCdEf0123456789aBcDeF0123456789ABCDEF01").unwrap();
+
+        assert_eq!(hex::encode(addr), "abcdef0123456789abcdef0123456789abcdef01");
+    }
+
+    #[test]
+    fn short_hex_is_padded_with_leading_zeros() {
+        let hash = hex_to_hash("1f").unwrap();
+
+        assert_eq!(hash[..31], [0; 31]);
+        assert_eq!(hash[31], 0x1f);
+        assert_eq!(hex_to_address("abc").unwrap()[18..], [0x0a, 0xbc]);
+    }
+
+    #[test]
+    fn over_length_hex_is_rejected() {
+        assert!(hex_to_address(&"1".repeat(42)).is_err());
+        assert!(hex_to_hash(&"1".repeat(66)).is_err());
+    }
+
+    #[test]
+    fn non_hex_digits_are_rejected() {
+        assert!(hex_to_address("xyz").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_keypair_file_is_private_and_loadable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_data_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/keypair", dir);
+
+        let created = create_keypair("password", &path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = load_keypair("password", &path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(loaded.public_key().as_ref(), created.public_key().as_ref());
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert!(create_keypair("password", &path).is_err());
+    }
+
+    #[test]
+    fn private_file_never_replaces_existing_file() {
+        let dir = temp_data_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/keypair", dir);
+        fs::write(&path, b"someone's keys").unwrap();
+
+        assert!(write_private_file(&path, b"new keys").is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"someone's keys");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn signed_message_verifies_against_signer_address() {
+        let keypair = test_keypair();
+        let address = address_from_public_key(&keypair.public_key().as_ref().to_vec());
+        let blob = sign_message("hello", &keypair).unwrap();
+
+        assert!(verify_message("hello", address, &blob).is_ok());
+        assert!(verify_message("goodbye", address, &blob).is_err());
+        assert!(verify_message("hello", [1; 20], &blob).is_err());
+    }
+
+    #[test]
+    fn embedded_pubkey_must_match_address() {
+        let keypair = test_keypair();
+        let address = address_from_public_key(&keypair.public_key().as_ref().to_vec());
+
+        // Someone else signs with their own key but claims our address
+        let forged = sign_message("hello", &test_keypair()).unwrap();
+        let forged = [address.to_vec(), forged[20..].to_vec()].concat();
+
+        let err = verify_message("hello", address, &forged).err().unwrap();
+        assert_eq!(err.to_string(), "Public key in signature does not hash to the address");
+    }
+}